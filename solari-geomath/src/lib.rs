@@ -21,11 +21,20 @@ pub fn cartesian_to_lat_lng(coords: [f64; 3]) -> (f64, f64) {
     (lat, lng)
 }
 
+/// Great-circle distance between two points given in degrees, in meters.
+pub fn haversine_distance_meters(lat_a: f64, lng_a: f64, lat_b: f64, lng_b: f64) -> f64 {
+    let (lat_a, lat_b) = (lat_a.to_radians(), lat_b.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lng = (lng_b - lng_a).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_APPROX * a.sqrt().asin()
+}
+
 #[cfg(test)]
 mod test {
     use approx::assert_abs_diff_eq;
 
-    use crate::{cartesian_to_lat_lng, lat_lng_to_cartesian};
+    use crate::{cartesian_to_lat_lng, haversine_distance_meters, lat_lng_to_cartesian};
 
     #[test]
     fn test_zeros() {
@@ -71,4 +80,14 @@ mod test {
         assert_abs_diff_eq!(lat, 45f64, epsilon = 0.001);
         assert_abs_diff_eq!(lng, 150f64, epsilon = 0.001);
     }
+
+    #[test]
+    fn test_haversine() {
+        assert_abs_diff_eq!(haversine_distance_meters(45f64, 60f64, 45f64, 60f64), 0f64);
+        // One degree of latitude is roughly 111km.
+        let dist = haversine_distance_meters(0f64, 0f64, 1f64, 0f64);
+        assert_abs_diff_eq!(dist, 111_195f64, epsilon = 10f64);
+        let dist = haversine_distance_meters(0f64, 179.5f64, 0f64, -179.5f64);
+        assert_abs_diff_eq!(dist, 111_195f64, epsilon = 10f64);
+    }
 }
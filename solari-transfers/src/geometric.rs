@@ -0,0 +1,67 @@
+use geo::Coord;
+use solari_geomath::haversine_distance_meters;
+
+use crate::{TransferPath, TransferProvider, TransferSearcher};
+
+/// Transfers estimated from the great-circle distance between two points, for use when no
+/// pedestrian graph is available.
+///
+/// Real walking paths are rarely straight lines, so every distance is multiplied by a detour
+/// factor. Around 1.3 is typical for a street grid; denser or more irregular networks may warrant
+/// more.
+#[derive(Debug, Clone, Copy)]
+pub struct GeometricTransfers {
+    detour_factor: f64,
+}
+
+impl GeometricTransfers {
+    /// Panics if `detour_factor` is less than 1.0 or isn't finite. Use [`parse_detour_factor`] to
+    /// check one given on the command line.
+    pub fn new(detour_factor: f64) -> GeometricTransfers {
+        assert!(
+            detour_factor.is_finite() && detour_factor >= 1.0,
+            "Detour factor must be at least 1.0"
+        );
+        GeometricTransfers { detour_factor }
+    }
+
+    pub fn detour_factor(&self) -> f64 {
+        self.detour_factor
+    }
+
+    fn distance_mm(&self, from: &Coord, to: &Coord) -> u64 {
+        (haversine_distance_meters(from.y, from.x, to.y, to.x) * self.detour_factor * 1000.0) as u64
+    }
+}
+
+/// Parse a detour factor, rejecting any that [`GeometricTransfers::new`] would. Suitable as a clap
+/// `value_parser`.
+pub fn parse_detour_factor(arg: &str) -> Result<f64, String> {
+    let detour_factor: f64 = arg
+        .parse()
+        .map_err(|_| format!("expected a number, got {arg:?}"))?;
+    if !detour_factor.is_finite() || detour_factor < 1.0 {
+        return Err(format!("must be at least 1.0, got {arg}"));
+    }
+    Ok(detour_factor)
+}
+
+impl TransferProvider for GeometricTransfers {
+    fn searcher(&self) -> Box<dyn TransferSearcher + '_> {
+        // There's no search state, so the provider is its own searcher.
+        Box::new(*self)
+    }
+}
+
+impl TransferSearcher for GeometricTransfers {
+    fn transfer_distance_mm(&mut self, from: &Coord, to: &Coord) -> Result<u64, anyhow::Error> {
+        Ok(self.distance_mm(from, to))
+    }
+
+    fn transfer_path(&mut self, from: &Coord, to: &Coord) -> Result<TransferPath, anyhow::Error> {
+        Ok(TransferPath {
+            length_mm: self.distance_mm(from, to),
+            shape: polyline::encode_coordinates(vec![*from, *to], 5)?,
+        })
+    }
+}
@@ -1,3 +1,4 @@
+pub mod geometric;
 pub mod valinor;
 pub use fast_paths;
 use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
@@ -146,17 +147,40 @@ impl<G: FastGraph, I: SphereIndex<usize>> TransferGraph<G, I> {
         })
     }
 
+    pub fn open_dir<'a>(
+        dir: PathBuf,
+    ) -> Result<TransferGraph<FastGraphStatic<'a>, SphereIndexMmap<'a, usize>>, anyhow::Error> {
+        let database = Arc::new(Database::open(dir.join("graph_metadata.db"))?);
+        Self::read_from_dir(dir, database)
+    }
+
     pub fn transfer_path(
         &self,
         search_context: &mut TransferGraphSearcher<G, I>,
         from: &Coord,
         to: &Coord,
+    ) -> Result<TransferPath, anyhow::Error> {
+        self.transfer_path_with(&mut search_context.calculator, from, to)
+    }
+
+    pub fn transfer_distance_mm(
+        &self,
+        search_context: &mut TransferGraphSearcher<G, I>,
+        from: &Coord,
+        to: &Coord,
+    ) -> Result<u64, anyhow::Error> {
+        self.transfer_distance_mm_with(&mut search_context.calculator, from, to)
+    }
+
+    fn transfer_path_with(
+        &self,
+        calculator: &mut PathCalculator,
+        from: &Coord,
+        to: &Coord,
     ) -> Result<TransferPath, anyhow::Error> {
         let from = self.get_nearest_nodes(from);
         let to = self.get_nearest_nodes(to);
-        if let Some(path) = search_context
-            .calculator
-            .calc_path_multiple_sources_and_targets(&self.graph, from, to)
+        if let Some(path) = calculator.calc_path_multiple_sources_and_targets(&self.graph, from, to)
         {
             let txn = self.database.begin_read()?;
             let shapes = txn.open_table(EDGE_SHAPE_TABLE)?;
@@ -182,17 +206,15 @@ impl<G: FastGraph, I: SphereIndex<usize>> TransferGraph<G, I> {
         }
     }
 
-    pub fn transfer_distance_mm(
+    fn transfer_distance_mm_with(
         &self,
-        search_context: &mut TransferGraphSearcher<G, I>,
+        calculator: &mut PathCalculator,
         from: &Coord,
         to: &Coord,
     ) -> Result<u64, anyhow::Error> {
         let from = self.get_nearest_nodes(from);
         let to = self.get_nearest_nodes(to);
-        if let Some(path) = search_context
-            .calculator
-            .calc_path_multiple_sources_and_targets(&self.graph, from, to)
+        if let Some(path) = calculator.calc_path_multiple_sources_and_targets(&self.graph, from, to)
         {
             return Ok(path.get_weight() as u64);
         } else {
@@ -263,6 +285,51 @@ pub struct TransferPath {
     pub shape: String,
 }
 
+/// Anything that can answer "how far is it to walk from here to there, and which way do I go?"
+///
+/// `TransferGraph` answers this by searching the Valhalla-derived pedestrian graph, while
+/// `geometric::GeometricTransfers` estimates it from the straight-line distance for deployments
+/// that don't have Valhalla tiles available.
+pub trait TransferProvider: Send + Sync {
+    /// Create a search context. Searchers may hold a lot of scratch space, so reuse them where possible.
+    fn searcher(&self) -> Box<dyn TransferSearcher + '_>;
+}
+
+pub trait TransferSearcher {
+    fn transfer_distance_mm(&mut self, from: &Coord, to: &Coord) -> Result<u64, anyhow::Error>;
+    fn transfer_path(&mut self, from: &Coord, to: &Coord) -> Result<TransferPath, anyhow::Error>;
+}
+
+impl<G: FastGraph + Send + Sync, I: SphereIndex<usize> + Send + Sync> TransferProvider
+    for TransferGraph<G, I>
+{
+    fn searcher(&self) -> Box<dyn TransferSearcher + '_> {
+        Box::new(BorrowedTransferGraphSearcher {
+            calculator: create_calculator(&self.graph),
+            graph: self,
+        })
+    }
+}
+
+struct BorrowedTransferGraphSearcher<'g, G: FastGraph, I: SphereIndex<usize>> {
+    calculator: PathCalculator,
+    graph: &'g TransferGraph<G, I>,
+}
+
+impl<'g, G: FastGraph, I: SphereIndex<usize>> TransferSearcher
+    for BorrowedTransferGraphSearcher<'g, G, I>
+{
+    fn transfer_distance_mm(&mut self, from: &Coord, to: &Coord) -> Result<u64, anyhow::Error> {
+        self.graph
+            .transfer_distance_mm_with(&mut self.calculator, from, to)
+    }
+
+    fn transfer_path(&mut self, from: &Coord, to: &Coord) -> Result<TransferPath, anyhow::Error> {
+        self.graph
+            .transfer_path_with(&mut self.calculator, from, to)
+    }
+}
+
 pub struct TransferGraphSearcher<G: FastGraph, I: SphereIndex<usize>> {
     calculator: PathCalculator,
     graph: Arc<TransferGraph<G, I>>,
@@ -1,6 +1,11 @@
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 
+use crate::{
+    realtime::{alerts::ServiceAlert, vehicle_positions::VehiclePosition},
+    stations::PathwayMode,
+};
+
 pub mod request;
 pub mod response;
 
@@ -10,6 +15,9 @@ pub struct LatLng {
     pub lon: f64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop: Option<String>,
+    /// The stop's timezone, for showing local times. Trips that cross timezones can start and end in different ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -32,6 +40,15 @@ pub enum SolariLeg {
         route_shape: Option<String>,
         transit_route: Option<String>,
         transit_agency: Option<String>,
+        /// Set for headway-based service, where vehicles come every this many seconds instead of at fixed times. The start and end times are then estimates.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        headway_secs: Option<u32>,
+        /// Service alerts for the trip, its route, or the stops it's boarded and left at.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        alerts: Vec<ServiceAlert>,
+        /// Where the vehicle running the trip is, while it's on its way to where the leg ends.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        vehicle: Option<VehiclePosition>,
     },
     #[serde(rename = "transfer")]
     Transfer {
@@ -48,6 +65,12 @@ pub enum SolariLeg {
         start_location: LatLng,
         end_location: LatLng,
         route_shape: Option<String>,
+        /// The stairs, elevators, escalators and so on used, if the transfer goes through station pathways.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pathway_modes: Vec<PathwayMode>,
+        /// Service alerts for the stops walked between.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        alerts: Vec<ServiceAlert>,
     },
 }
 
@@ -66,4 +89,34 @@ pub struct SolariItinerary {
     )]
     pub end_time: OffsetDateTime,
     pub legs: Vec<SolariLeg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fare: Option<SolariFare>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Money {
+    pub amount: f64,
+    /// ISO 4217 currency code.
+    pub currency: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SolariLegFare {
+    /// Index of the priced leg in the itinerary's `legs`.
+    pub leg_index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fare_product_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fare_product_name: Option<String>,
+    pub price: Money,
+    /// Whether a transfer discount or surcharge was applied to this leg.
+    pub transfer: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SolariFare {
+    /// Absent if some legs couldn't be priced, or were priced in different currencies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<Money>,
+    pub legs: Vec<SolariLegFare>,
 }
@@ -42,4 +42,13 @@ pub struct SolariRequest {
     pub transfer_mode: TransferMode,
     #[serde(default)]
     pub max_transfers: TransferQuantity,
+    /// A GTFS `rider_category_id` to price fares for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rider_category: Option<String>,
+    /// A GTFS `fare_media_id` to price fares for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fare_media: Option<String>,
+    /// Leave out itineraries whose total fare is known to exceed this amount.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_fare: Option<f64>,
 }
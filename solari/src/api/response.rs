@@ -1,5 +1,8 @@
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
 
+use crate::realtime::vehicle_positions::VehiclePosition;
+
 use super::SolariItinerary;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -19,3 +22,17 @@ pub struct SolariResponse {
     pub status: ResponseStatus,
     pub itineraries: Vec<SolariItinerary>,
 }
+
+/// The range of dates a timetable can answer queries for.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServiceWindowResponse {
+    pub start_date: NaiveDate,
+    /// The last service day, inclusive.
+    pub end_date: NaiveDate,
+}
+
+/// Vehicles tracked by real-time feeds.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VehiclesResponse {
+    pub vehicles: Vec<VehiclePosition>,
+}
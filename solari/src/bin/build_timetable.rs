@@ -1,15 +1,26 @@
-use std::{
-    fs,
-    hash::{DefaultHasher, Hasher},
-    path::PathBuf,
-};
+use std::{fs, io, path::PathBuf};
 
 use anyhow::bail;
+use chrono::NaiveDate;
 use clap::Parser;
 use gtfs_structures::GtfsReader;
-use log::debug;
+use log::{debug, info, warn};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use solari::raptor::timetable::{in_memory::InMemoryTimetableBuilder, mmap::MmapTimetable};
+use solari::{
+    download::{DownloadManifest, DownloadStatus},
+    raptor::timetable::{
+        in_memory::{FeedReport, InMemoryTimetableBuilder, TimetableBuildOptions},
+        incremental::{feed_hash, BuildManifest, FeedArtifact, PreviousTransfers},
+        mmap::MmapTimetable,
+        ServiceWindow, Timetable, DEFAULT_SERVICE_DAYS,
+    },
+};
+use solari_spatial::SphereIndexMmap;
+use solari_transfers::{
+    fast_paths::FastGraphStatic,
+    geometric::{parse_detour_factor, GeometricTransfers},
+    TransferGraph, TransferProvider,
+};
 
 extern crate solari;
 
@@ -19,81 +30,239 @@ struct BuildArgs {
     base_path: PathBuf,
     #[arg(long)]
     gtfs_path: PathBuf,
+    /// Directory containing the transfer graph exported by `solari-export-graph`. If omitted, transfers are estimated geometrically.
     #[arg(long)]
-    valhalla_tiles: PathBuf,
+    valhalla_tiles: Option<PathBuf>,
+    /// Multiplier applied to straight-line walking distances when no transfer graph is provided. At least 1.0.
+    #[arg(long, default_value_t = 1.3, value_parser = parse_detour_factor)]
+    detour_factor: f64,
     #[arg(short, long, default_value_t = 1)]
     num_threads: usize,
     #[arg(long, default_value_t = false)]
     concat_only: bool,
+    /// First service day to include trips for, as YYYY-MM-DD. Defaults to yesterday in UTC.
+    #[arg(long)]
+    start_date: Option<NaiveDate>,
+    /// Number of service days to include trips for, starting at the start date.
+    #[arg(long, default_value_t = DEFAULT_SERVICE_DAYS)]
+    days: u32,
+    /// Rebuild every feed and calculate every transfer, instead of reusing what hasn't changed since the last build in the base path.
+    #[arg(long, default_value_t = false)]
+    full_rebuild: bool,
 }
 
+/// Build a feed's timetable in a directory named for its contents, or open the one built there before if `reuse` is set. The feed's report is only returned if it was built.
 fn process_gtfs<'a>(
     path: &PathBuf,
     base_path: &PathBuf,
-) -> Result<MmapTimetable<'a>, anyhow::Error> {
+    service_window: ServiceWindow,
+    reuse: bool,
+) -> Result<(MmapTimetable<'a>, FeedArtifact, Option<FeedReport>), anyhow::Error> {
+    let feed_id = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let hash = feed_hash(path, &feed_id, &service_window)?;
+    let timetable_dir = base_path.join(&hash);
+    if reuse {
+        if let Some(artifact) = FeedArtifact::read(&timetable_dir) {
+            if let Ok(timetable) = MmapTimetable::open(&timetable_dir) {
+                debug!("Reusing feed {:?} from the previous build", path);
+                return Ok((timetable, artifact, None));
+            }
+        }
+    }
+
     let feed = if let Ok(feed) = GtfsReader::default().read_from_path(path.to_str().unwrap()) {
         feed
     } else {
         bail!(format!("Failed to load feed: {:?}", path));
     };
     debug!("Processing feed: {:?}", path);
-    let in_memory_timetable_builder = InMemoryTimetableBuilder::new(&feed)?;
-    let hash = {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(path.to_str().unwrap().as_bytes());
-        format!("{:x}", hasher.finish())
+    let options = TimetableBuildOptions {
+        feed_id: feed_id.clone(),
+        feed_path: Some(path.clone()),
+        service_window,
     };
+    let in_memory_timetable_builder = InMemoryTimetableBuilder::with_options(&feed, options)?;
 
-    let timetable_dir = base_path.join(hash);
-    fs::create_dir_all(&timetable_dir).unwrap();
-    Ok(MmapTimetable::from_in_memory(
-        &in_memory_timetable_builder,
-        &timetable_dir,
-    )?)
+    // Whatever was left here by an earlier build that didn't finish is written over from scratch.
+    match fs::remove_dir_all(&timetable_dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    fs::create_dir_all(&timetable_dir)?;
+    let timetable = MmapTimetable::from_in_memory(&in_memory_timetable_builder, &timetable_dir)?;
+    let artifact = FeedArtifact::new(feed_id, hash, &timetable);
+    artifact.write(&timetable_dir)?;
+    Ok((
+        timetable,
+        artifact,
+        Some(in_memory_timetable_builder.report().clone()),
+    ))
 }
 
+/// Concatenate the feed timetables already built in `paths`, and record them in the build manifest so that the next incremental build knows which feed each stop came from.
 async fn concat_timetables<'a>(
     paths: &[PathBuf],
     base_path: &PathBuf,
-    valhalla_tile_path: &PathBuf,
+    transfer_provider: &dyn TransferProvider,
+    transfers_key: &str,
 ) -> Result<MmapTimetable<'a>, anyhow::Error> {
-    let paths = paths.to_vec();
+    let mut paths = paths.to_vec();
+    // Feeds are concatenated in the order the manifest lists them.
+    paths.sort();
 
-    let timetables: Vec<MmapTimetable<'_>> = paths
+    let (timetables, artifacts): (Vec<MmapTimetable<'_>>, Vec<Option<FeedArtifact>>) = paths
         .par_iter()
-        .filter_map(|path| MmapTimetable::open(path).ok())
-        .collect();
+        .filter_map(|path| Some((MmapTimetable::open(path).ok()?, FeedArtifact::read(path))))
+        .unzip();
 
     // Combine all timetables into one.
-    let timetable = MmapTimetable::concatenate(&timetables, base_path, valhalla_tile_path).await;
+    let timetable =
+        MmapTimetable::concatenate(&timetables, base_path, transfer_provider, None).await;
+    // Without an artifact for every feed, the next build can't tell where each feed's stops went, so none of its transfers are reused.
+    match artifacts.into_iter().collect::<Option<Vec<_>>>() {
+        Some(feeds) => BuildManifest {
+            transfers: transfers_key.to_string(),
+            feeds,
+        }
+        .write(base_path)?,
+        None => {
+            warn!("Not every timetable has an artifact, so the next build won't reuse transfers");
+            BuildManifest::remove(base_path)?
+        }
+    }
     Ok(timetable)
 }
 
+/// Build a timetable from the feeds in `paths`. Unless `full_rebuild` is set, feeds that haven't changed since the last build in `base_path` are reused, along with the transfers of stops away from feeds that have. `transfers_key` says how transfers are calculated, so that they're only reused if they'd come out the same.
 async fn timetable_from_feeds<'a>(
     paths: &[PathBuf],
     base_path: &PathBuf,
-    valhalla_tile_path: &PathBuf,
+    transfer_provider: &dyn TransferProvider,
+    transfers_key: &str,
+    service_window: ServiceWindow,
+    full_rebuild: bool,
 ) -> Result<MmapTimetable<'a>, anyhow::Error> {
-    let paths = paths.to_vec();
+    let mut paths: Vec<PathBuf> = paths
+        .iter()
+        .filter(|path| path.extension().map(|ext| ext == "zip") == Some(true))
+        .cloned()
+        .collect();
+    // Feeds are concatenated in the same order from one build to the next.
+    paths.sort();
 
-    let timetables: Vec<MmapTimetable<'_>> = paths
+    let processed: Vec<_> = paths
         .par_iter()
-        .filter(|path| path.extension().map(|ext| ext == "zip") == Some(true))
         .filter_map(|path| {
-            process_gtfs(&path, base_path)
+            process_gtfs(&path, base_path, service_window, !full_rebuild)
                 .map_err(|err| {
                     log::error!("Failed to process GTFS feed: {}", err);
                     err
                 })
                 .ok()
+                .map(|processed| (path.clone(), processed))
         })
         .collect();
+    let mut timetables = vec![];
+    let mut feeds = vec![];
+    let mut reports = vec![];
+    for (path, (timetable, artifact, report)) in processed {
+        timetables.push(timetable);
+        feeds.push(artifact);
+        if let Some(report) = report {
+            reports.push((path, report));
+        }
+    }
+    info!(
+        "Reused {} of {} feeds from the previous build",
+        feeds.len() - reports.len(),
+        feeds.len()
+    );
+    for (path, report) in reports {
+        if report.is_empty() {
+            continue;
+        }
+        warn!(
+            "Feed {:?} had {} problems, and {} of its {} trips were skipped:",
+            path,
+            report.issues.len(),
+            report.skipped_trips,
+            report.total_trips
+        );
+        for issue in report.issues {
+            warn!("  {}", issue);
+        }
+    }
+
+    let previous_manifest = BuildManifest::read(base_path).unwrap_or_else(|err| {
+        warn!("Failed to read the previous build's manifest: {}", err);
+        None
+    });
+    // The previous timetable's transfers are read before it's written over.
+    let previous_transfers = previous_manifest
+        .as_ref()
+        .filter(|manifest| !full_rebuild && manifest.transfers == transfers_key)
+        .and_then(|manifest| {
+            let previous = MmapTimetable::open(base_path).ok()?;
+            Some(PreviousTransfers::new(&previous, manifest, &feeds))
+        });
 
     // Combine all timetables into one.
-    let timetable = MmapTimetable::concatenate(&timetables, base_path, valhalla_tile_path).await;
+    let timetable = MmapTimetable::concatenate(
+        &timetables,
+        base_path,
+        transfer_provider,
+        previous_transfers.as_ref(),
+    )
+    .await;
+    BuildManifest {
+        transfers: transfers_key.to_string(),
+        feeds: feeds.clone(),
+    }
+    .write(base_path)?;
+
+    // Feeds from the previous build that aren't in this one won't be reused.
+    for feed in previous_manifest
+        .iter()
+        .flat_map(|manifest| &manifest.feeds)
+    {
+        if feeds.iter().any(|current| current.hash == feed.hash) {
+            continue;
+        }
+        if let Err(err) = fs::remove_dir_all(base_path.join(&feed.hash)) {
+            warn!(
+                "Failed to remove feed {} from the previous build: {}",
+                feed.feed_id, err
+            );
+        }
+    }
     Ok(timetable)
 }
 
+/// Report what changed in the last run of `download_feeds`, whose zips are the ones built.
+fn log_downloads(manifest: &DownloadManifest) {
+    info!(
+        "Building {} downloaded feeds, {} of them changed since the previous download",
+        manifest.feeds.len(),
+        manifest.feeds.iter().filter(|feed| feed.changed()).count()
+    );
+    for feed in &manifest.feeds {
+        if feed.status != DownloadStatus::Failed {
+            continue;
+        }
+        if feed.file_name.is_some() {
+            warn!(
+                "Feed {} failed to download, building its previous download",
+                feed.feed_id
+            );
+        } else {
+            warn!("Feed {} failed to download, leaving it out", feed.feed_id);
+        }
+    }
+}
+
 #[tokio::main(worker_threads = 64)]
 async fn main() {
     env_logger::init();
@@ -102,29 +271,83 @@ async fn main() {
         .num_threads(args.num_threads)
         .build_global()
         .unwrap();
+    let service_window = if let Some(start_date) = args.start_date {
+        ServiceWindow::new(start_date, args.days)
+    } else {
+        ServiceWindow::starting_yesterday(args.days)
+    };
+    info!(
+        "Including service days {} through {}",
+        service_window.start_date,
+        service_window.end_date()
+    );
+    let transfer_provider: Box<dyn TransferProvider> =
+        if let Some(valhalla_tiles) = &args.valhalla_tiles {
+            info!("Opening transfer graph");
+            Box::new(
+                TransferGraph::<FastGraphStatic, SphereIndexMmap<usize>>::open_dir(
+                    valhalla_tiles.clone(),
+                )
+                .expect("Failed to open transfer graph"),
+            )
+        } else {
+            info!(
+                "No transfer graph provided, estimating transfers with a detour factor of {}",
+                args.detour_factor
+            );
+            Box::new(GeometricTransfers::new(args.detour_factor))
+        };
+    // A transfer graph rebuilt in the same directory isn't noticed, so pass `--full-rebuild` after updating one.
+    let transfers_key = if let Some(valhalla_tiles) = &args.valhalla_tiles {
+        format!("valhalla:{}", valhalla_tiles.display())
+    } else {
+        format!("geometric:{}", args.detour_factor)
+    };
     if args.concat_only {
         let paths: Vec<PathBuf> = fs::read_dir(&args.base_path)
             .unwrap()
             .map(|p| p.unwrap().path())
             .collect();
 
-        let _timetable = concat_timetables(&paths, &args.base_path.into(), &args.valhalla_tiles)
-            .await
-            .unwrap();
+        let _timetable = concat_timetables(
+            &paths,
+            &args.base_path.into(),
+            transfer_provider.as_ref(),
+            &transfers_key,
+        )
+        .await
+        .unwrap();
     } else if fs::metadata(&args.gtfs_path).unwrap().is_dir() {
-        let paths: Vec<PathBuf> = fs::read_dir(&args.gtfs_path)
-            .unwrap()
-            .map(|p| p.unwrap().path())
-            .collect();
+        let paths: Vec<PathBuf> = if let Some(manifest) =
+            DownloadManifest::read(&args.gtfs_path).expect("Failed to read download manifest")
+        {
+            log_downloads(&manifest);
+            manifest.zip_paths(&args.gtfs_path)
+        } else {
+            fs::read_dir(&args.gtfs_path)
+                .unwrap()
+                .map(|p| p.unwrap().path())
+                .collect()
+        };
 
-        let _timetable = timetable_from_feeds(&paths, &args.base_path.into(), &args.valhalla_tiles)
-            .await
-            .unwrap();
+        let _timetable = timetable_from_feeds(
+            &paths,
+            &args.base_path.into(),
+            transfer_provider.as_ref(),
+            &transfers_key,
+            service_window,
+            args.full_rebuild,
+        )
+        .await
+        .unwrap();
     } else {
         let _timetable = timetable_from_feeds(
             &[args.gtfs_path.into()],
             &args.base_path.into(),
-            &args.valhalla_tiles,
+            transfer_provider.as_ref(),
+            &transfers_key,
+            service_window,
+            args.full_rebuild,
         )
         .await
         .unwrap();
@@ -1,42 +1,29 @@
-use std::{ffi::OsString, path::PathBuf, str::FromStr, time::Duration};
+use std::{path::PathBuf, time::Duration};
 
 use clap::Parser;
-use solari::dmfr::DistributedMobilityFeedRegistry;
-use log::{debug, info};
-use reqwest::Client;
-use tokio::{fs, spawn};
+use log::{info, warn};
+use solari::{
+    download::{download_registries, DownloadOptions, DownloadStatus, DOWNLOAD_MANIFEST_FILE},
+    feeds::{read_registries, FeedSecrets},
+};
+use tokio::fs;
 
 #[derive(Parser)]
 struct Args {
     #[arg(long)]
     dmfr_dir: PathBuf,
+    /// Where to keep the downloaded zips and their manifest. Feeds already downloaded there are only fetched again if they've changed.
     #[arg(long)]
     zip_dir: Option<PathBuf>,
-}
-
-async fn download_dmfr(path: PathBuf, zip_dir: PathBuf) -> Result<(), anyhow::Error> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(5))
-        .build()
-        .unwrap();
-    debug!("Enumerating feeds from {:?}", &path);
-    let dmfr: DistributedMobilityFeedRegistry =
-        serde_json::from_str(&fs::read_to_string(&path).await?)?;
-    for (feed_idx, feed) in dmfr.feeds.iter().enumerate() {
-        if let Some(url) = &feed.urls.static_current {
-            debug!("Downloading feed from: {:?}", url);
-            let response = client.get(url.as_str()).send().await?;
-            let mut filename = path
-                .file_name()
-                .expect("GTFS feed not a file")
-                .to_os_string();
-            filename.push(OsString::from_str(&format!(".{feed_idx}.zip"))?);
-            let zip_path = zip_dir.join(filename);
-            fs::write(&zip_path, response.bytes().await?).await?;
-            info!("Wrote zip file to {:?}", zip_path)
-        }
-    }
-    Ok(())
+    /// JSON file of credentials for feeds that need authorization, like `[{"feed": "f-example", "key": "..."}]`.
+    #[arg(long)]
+    feed_secrets: Option<PathBuf>,
+    /// How long a single download may take.
+    #[arg(long, default_value_t = 60)]
+    timeout_secs: u64,
+    /// How many times a download that failed with a network or server error is retried, backing off between attempts.
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
 }
 
 #[tokio::main(worker_threads = 16)]
@@ -44,37 +31,45 @@ async fn main() -> Result<(), anyhow::Error> {
     env_logger::init();
     let args = Args::parse();
 
-    let mut read = fs::read_dir(&args.dmfr_dir).await?;
-    let mut handles = Vec::new();
-    while let Some(dmfr) = read.next_entry().await? {
-        let path = dmfr.path();
-        debug!("Found path {:?}", &path);
-        if path
-            .extension()
-            .map(|str| str.to_string_lossy().to_string())
-            != Some("json".to_string())
-        {
-            debug!("Skipping path {:?}", &path);
-            continue;
-        }
-        let zip_dir = args
-            .zip_dir
-            .clone()
-            .unwrap_or(args.dmfr_dir.clone())
-            .clone();
-        let handle = spawn(async move { download_dmfr(path, zip_dir) });
-        handles.push(handle);
-    }
-    for handle in handles {
-        match handle.await {
-            Ok(future) => {
-                if let Err(err) = future.await {
-                    log::warn!("Failed to download a feed: {err}")
-                }
-            }
-            Err(err) => {
-                log::warn!("Failed to join task: {err}")
-            }
+    let registries = read_registries(&args.dmfr_dir)?;
+    let secrets = args
+        .feed_secrets
+        .as_ref()
+        .map(|path| FeedSecrets::read(path))
+        .transpose()?
+        .unwrap_or_default();
+    let zip_dir = args.zip_dir.clone().unwrap_or(args.dmfr_dir.clone());
+    fs::create_dir_all(&zip_dir).await?;
+    let manifest = download_registries(
+        &registries,
+        &zip_dir,
+        &secrets,
+        DownloadOptions {
+            timeout: Duration::from_secs(args.timeout_secs),
+            retries: args.retries,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let count = |status| {
+        manifest
+            .feeds
+            .iter()
+            .filter(|feed| feed.status == status)
+            .count()
+    };
+    info!(
+        "{} new, {} updated, {} unchanged and {} failed feeds, listed in {:?}",
+        count(DownloadStatus::New),
+        count(DownloadStatus::Updated),
+        count(DownloadStatus::Unchanged),
+        count(DownloadStatus::Failed),
+        zip_dir.join(DOWNLOAD_MANIFEST_FILE)
+    );
+    for feed in &manifest.feeds {
+        if let Some(err) = &feed.error {
+            warn!("Failed to download feed {}: {err}", feed.feed_id);
         }
     }
     println!("Done");
@@ -1,13 +1,35 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
-use rocket::{serde::json::Json, State};
+use rocket::{
+    fairing::AdHoc,
+    http::Status,
+    request::{FromRequest, Outcome},
+    serde::json::Json,
+    Request, State,
+};
 use s2::latlng::LatLng;
+use sha2::{digest::Output, Digest, Sha256};
 use solari::{
-    api::{request::SolariRequest, response::SolariResponse},
+    api::{
+        request::SolariRequest,
+        response::{ServiceWindowResponse, SolariResponse, VehiclesResponse},
+    },
+    fares::FareOptions,
+    feeds::{read_registries, FeedSecrets},
     raptor::timetable::{mmap::MmapTimetable, Time},
+    realtime::{
+        poller::{realtime_sources, PollOptions, RealtimePoller},
+        read_feed_message, RealtimeTimetable,
+    },
     route::Router,
+    routers::{borrow_router, Routers, SolariRouter},
 };
+use solari_transfers::geometric::{parse_detour_factor, GeometricTransfers};
 
 #[macro_use]
 extern crate rocket;
@@ -15,15 +37,21 @@ extern crate rocket;
 #[post("/v1/plan", data = "<request>")]
 async fn plan(
     request: Json<SolariRequest>,
-    router: &State<Router<'_, MmapTimetable<'_>>>,
+    routers: &State<&'static Routers>,
 ) -> Json<SolariResponse> {
     let from = LatLng::from_degrees(request.0.from.lat, request.0.from.lon);
     let to = LatLng::from_degrees(request.0.to.lat, request.0.to.lon);
 
     let max_transfers = usize::min(5, request.0.max_transfers.0);
+    let fare_options = FareOptions {
+        rider_category: request.0.rider_category.clone(),
+        fare_media: request.0.fare_media.clone(),
+        max_fare: request.0.max_fare,
+    };
 
+    let router = routers.current();
     return Json(
-        router
+        borrow_router(&router)
             .route(
                 Time::from_epoch_seconds(request.0.start_at.unix_timestamp() as u32),
                 from,
@@ -32,31 +60,241 @@ async fn plan(
                 Some(1000),
                 Some(max_transfers),
                 Some(2),
+                &fare_options,
             )
             .await,
     );
 }
 
+#[get("/v1/service_window")]
+async fn service_window(routers: &State<&'static Routers>) -> Option<Json<ServiceWindowResponse>> {
+    let router = routers.current();
+    let service_window = borrow_router(&router).service_window()?;
+    Some(Json(ServiceWindowResponse {
+        start_date: service_window.start_date,
+        end_date: service_window.end_date(),
+    }))
+}
+
+#[get("/v1/vehicles?<feed_id>&<route_id>&<min_lat>&<min_lon>&<max_lat>&<max_lon>")]
+async fn vehicles(
+    feed_id: Option<&str>,
+    route_id: Option<&str>,
+    min_lat: Option<f64>,
+    min_lon: Option<f64>,
+    max_lat: Option<f64>,
+    max_lon: Option<f64>,
+    routers: &State<&'static Routers>,
+) -> Result<Json<VehiclesResponse>, Status> {
+    let router = routers.current();
+    let timetable = borrow_router(&router).timetable();
+    let vehicles = match (feed_id.zip(route_id), (min_lat, min_lon, max_lat, max_lon)) {
+        (Some((feed_id, route_id)), _) => timetable.vehicles_on_route(feed_id, route_id),
+        (None, (Some(min_lat), Some(min_lon), Some(max_lat), Some(max_lon))) => {
+            timetable.vehicles_within(min_lat, min_lon, max_lat, max_lon)
+        }
+        _ => return Err(Status::BadRequest),
+    };
+    Ok(Json(VehiclesResponse { vehicles }))
+}
+
+/// The SHA-256 digest of the `--admin-token`, for requests to check themselves against. Tokens are compared by digest, so that how long a comparison takes says nothing about how much of the token a request got right.
+struct AdminToken(Output<Sha256>);
+
+/// A request carrying the `--admin-token` as a bearer token.
+struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request.rocket().state::<AdminToken>();
+        let authorization = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|authorization| authorization.strip_prefix("Bearer "));
+        match (token, authorization) {
+            (Some(token), Some(authorization)) if Sha256::digest(authorization) == token.0 => {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Open the timetable in `base_path`, or the one the server was last opened from, and swap it in once it's ready. Queries are answered from the old timetable in the meantime, and are unaffected if the new one fails to open.
+#[post("/admin/reload?<base_path>")]
+async fn reload(
+    _admin: Admin,
+    base_path: Option<String>,
+    routers: &State<&'static Routers>,
+) -> Result<Status, (Status, String)> {
+    routers
+        .reload(base_path.map(PathBuf::from))
+        .await
+        .map_err(|err| {
+            log::error!("Failed to reload timetable: {err:#}");
+            (Status::InternalServerError, format!("{err:#}"))
+        })?;
+    Ok(Status::NoContent)
+}
+
 #[derive(Parser)]
 struct ServeArgs {
     #[arg(long)]
     base_path: PathBuf,
     #[arg(short, long)]
     port: Option<u16>,
+    /// Estimate transfers from straight-line distances instead of reading a transfer graph from the base path.
+    #[arg(long, default_value_t = false)]
+    geometric_transfers: bool,
+    /// Multiplier applied to straight-line walking distances when `--geometric-transfers` is set. At least 1.0.
+    #[arg(long, default_value_t = 1.3, value_parser = parse_detour_factor)]
+    detour_factor: f64,
+    /// GTFS-RT TripUpdates to apply, as `FEED_ID=PATH` where the feed ID is the file name of the feed's GTFS zip without its extension. May be repeated.
+    #[arg(long, value_parser = parse_feed_file)]
+    trip_updates: Vec<(String, PathBuf)>,
+    /// GTFS-RT service alerts to apply, as `FEED_ID=PATH` like `--trip-updates`. May be repeated.
+    #[arg(long, value_parser = parse_feed_file)]
+    alerts: Vec<(String, PathBuf)>,
+    /// GTFS-RT vehicle positions to apply, as `FEED_ID=PATH` like `--trip-updates`. May be repeated.
+    #[arg(long, value_parser = parse_feed_file)]
+    vehicle_positions: Vec<(String, PathBuf)>,
+    /// Directory of the DMFR files the timetable's feeds were downloaded from. Their real-time URLs are polled and applied for as long as the server runs.
+    #[arg(long)]
+    dmfr_dir: Option<PathBuf>,
+    /// JSON file of credentials for feeds that need authorization, like `[{"feed": "f-example~rt", "key": "..."}]`.
+    #[arg(long)]
+    feed_secrets: Option<PathBuf>,
+    /// Seconds between polls of each real-time feed.
+    #[arg(long, default_value_t = 30)]
+    realtime_interval_secs: u64,
+    /// Seconds a real-time feed's updates are kept after it stops updating.
+    #[arg(long, default_value_t = 300)]
+    realtime_max_age_secs: u64,
+    /// Bearer token for `POST /admin/reload`, which reopens the timetable without restarting. The endpoint isn't mounted without one.
+    #[arg(long)]
+    admin_token: Option<String>,
+}
+
+fn parse_feed_file(arg: &str) -> Result<(String, PathBuf), String> {
+    let (feed_id, path) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected FEED_ID=PATH, got {arg:?}"))?;
+    Ok((feed_id.to_string(), PathBuf::from(path)))
+}
+
+/// Open the timetable in `base_path` and apply the real-time files given on the command line to it.
+fn open_router(args: &ServeArgs, base_path: &Path) -> Result<SolariRouter, anyhow::Error> {
+    let timetable = RealtimeTimetable::new(MmapTimetable::open(&base_path.to_path_buf())?);
+    let router = if args.geometric_transfers {
+        Router::with_transfer_provider(
+            timetable,
+            Arc::new(GeometricTransfers::new(args.detour_factor)),
+        )
+    } else {
+        Router::new(timetable, base_path.to_path_buf())?
+    };
+    let timetable = borrow_router(&router).timetable();
+
+    // Each file is a source of its own, so that several for one feed all apply.
+    for (feed_id, path) in &args.trip_updates {
+        let source_id = path.display().to_string();
+        let report = timetable.update_trips(&source_id, feed_id, &read_feed_message(path)?);
+        log::info!(
+            "Applied trip updates for feed {feed_id}: {} trips updated, {} canceled, {} added",
+            report.updated_trips,
+            report.canceled_trips,
+            report.added_trips
+        );
+        for issue in report.issues {
+            log::warn!("{feed_id}: {issue}");
+        }
+    }
+
+    for (feed_id, path) in &args.alerts {
+        let source_id = path.display().to_string();
+        let report = timetable.update_alerts(&source_id, feed_id, &read_feed_message(path)?);
+        log::info!("Applied {} alerts for feed {feed_id}", report.alerts);
+        for issue in report.issues {
+            log::warn!("{feed_id}: {issue}");
+        }
+    }
+
+    for (feed_id, path) in &args.vehicle_positions {
+        let source_id = path.display().to_string();
+        let report =
+            timetable.update_vehicle_positions(&source_id, feed_id, &read_feed_message(path)?);
+        log::info!(
+            "Applied {} vehicle positions for feed {feed_id}, {} on known trips",
+            report.vehicles,
+            report.vehicles_on_trips
+        );
+        for issue in report.issues {
+            log::warn!("{feed_id}: {issue}");
+        }
+    }
+
+    Ok(router)
 }
 
 #[launch]
 fn rocket() -> _ {
     env_logger::init();
     let args = ServeArgs::parse();
-    let router = Router::new(
-        MmapTimetable::open(&args.base_path).expect("Failed to open timetable"),
-        args.base_path.clone(),
-    )
-    .expect("Failed to build router");
-
-    rocket::build()
-        .manage(router)
-        .configure(rocket::Config::figment().merge(("port", args.port.unwrap_or(8000))))
-        .mount("/", routes![plan])
+
+    let mut sources = vec![];
+    if let Some(dmfr_dir) = &args.dmfr_dir {
+        let secrets = args
+            .feed_secrets
+            .as_ref()
+            .map(|path| FeedSecrets::read(path).expect("Failed to read feed secrets"))
+            .unwrap_or_default();
+        for (path, dmfr) in read_registries(dmfr_dir).expect("Failed to read DMFR files") {
+            let (dmfr_sources, errors) = realtime_sources(&dmfr, &secrets);
+            sources.extend(dmfr_sources);
+            for err in errors {
+                log::warn!("{}: {err}", path.display());
+            }
+        }
+        log::info!("Polling {} real-time feeds", sources.len());
+    }
+    let poller = RealtimePoller::new(
+        sources,
+        PollOptions {
+            interval: Duration::from_secs(args.realtime_interval_secs),
+            max_age: Duration::from_secs(args.realtime_max_age_secs),
+            ..Default::default()
+        },
+    );
+
+    let port = args.port.unwrap_or(8000);
+    let admin_token = args.admin_token.clone();
+    let base_path = args.base_path.clone();
+    // Only the holder is leaked: the routers in it are dropped, and their files unmapped, once they've been replaced and the last query on them is done.
+    let routers: &'static Routers = Box::leak(Box::new(
+        Routers::open(base_path, move |base_path| open_router(&args, base_path))
+            .expect("Failed to open timetable"),
+    ));
+
+    let rocket = rocket::build()
+        .manage(routers)
+        .attach(AdHoc::on_liftoff("Real-time poller", move |_| {
+            Box::pin(async move {
+                if poller.sources().is_empty() {
+                    return;
+                }
+                tokio::spawn(poller.run(move || routers.current(), routers.reloaded()));
+            })
+        }))
+        .configure(rocket::Config::figment().merge(("port", port)))
+        .mount("/", routes![plan, service_window, vehicles]);
+    if let Some(admin_token) = admin_token {
+        rocket
+            .manage(AdminToken(Sha256::digest(admin_token)))
+            .mount("/", routes![reload])
+    } else {
+        rocket
+    }
 }
@@ -0,0 +1,619 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, StreamExt};
+use log::{debug, warn};
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Client, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::ZipArchive;
+
+use crate::{
+    dmfr::DistributedMobilityFeedRegistry,
+    feeds::{local_feed_id, AuthorizedRequest, FeedSecrets},
+};
+
+/// Lists the feeds in a download directory, what was last downloaded for each, and whether it changed in the last run.
+pub const DOWNLOAD_MANIFEST_FILE: &str = "download_manifest.json";
+/// How many feeds are downloaded at once.
+const CONCURRENT_DOWNLOADS: usize = 8;
+
+/// What happened to a feed in the last download run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadStatus {
+    /// Downloaded for the first time.
+    New,
+    /// Downloaded again, with different contents.
+    Updated,
+    /// Not modified since the last download, or downloaded again with the same contents.
+    Unchanged,
+    /// Couldn't be downloaded. The zip from the last successful download, if there is one, is kept.
+    Failed,
+}
+
+/// A feed in the download manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadedFeed {
+    /// The feed's ID once downloaded, as given by [`local_feed_id`].
+    pub feed_id: String,
+    /// The URL the feed was downloaded from, with any credentials redacted.
+    pub url: String,
+    pub status: DownloadStatus,
+    /// The zip's file name in the download directory, if there's a good download of it.
+    pub file_name: Option<String>,
+    /// Hash of the zip, which is checked before it's reused.
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// When the zip was downloaded, in seconds since the epoch.
+    pub downloaded_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DownloadedFeed {
+    /// Whether the last run downloaded a zip that wasn't there before.
+    pub fn changed(&self) -> bool {
+        matches!(self.status, DownloadStatus::New | DownloadStatus::Updated)
+    }
+}
+
+/// The feeds in a download directory, in the order their DMFR files list them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadManifest {
+    pub feeds: Vec<DownloadedFeed>,
+}
+
+impl DownloadManifest {
+    /// The manifest of the downloads in `dir`, if it has one.
+    pub fn read(dir: &Path) -> Result<Option<DownloadManifest>, anyhow::Error> {
+        match fs::File::open(dir.join(DOWNLOAD_MANIFEST_FILE)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<(), anyhow::Error> {
+        fs::write(
+            dir.join(DOWNLOAD_MANIFEST_FILE),
+            serde_json::to_vec_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    pub fn get(&self, feed_id: &str) -> Option<&DownloadedFeed> {
+        self.feeds.iter().find(|feed| feed.feed_id == feed_id)
+    }
+
+    /// The zips in `dir` of every feed that has a good download.
+    pub fn zip_paths(&self, dir: &Path) -> Vec<PathBuf> {
+        self.feeds
+            .iter()
+            .filter_map(|feed| Some(dir.join(feed.file_name.as_ref()?)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadOptions {
+    /// How long a single request may take, including reading the zip.
+    pub timeout: Duration,
+    /// How many times a request that failed in a way that might not happen again is retried.
+    pub retries: u32,
+    /// How long to wait before the first retry. The wait doubles with each one after it.
+    pub initial_backoff: Duration,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            timeout: Duration::from_secs(60),
+            retries: 3,
+            initial_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Why a feed couldn't be downloaded.
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("request to {url} failed: {err}")]
+    Request { url: String, err: reqwest::Error },
+    #[error("{url} returned {status}")]
+    Status { url: String, status: StatusCode },
+    #[error("{url} didn't return a zip: {err}")]
+    InvalidZip {
+        url: String,
+        err: zip::result::ZipError,
+    },
+    #[error("failed to write the zip from {url}: {err}")]
+    Write { url: String, err: io::Error },
+}
+
+impl DownloadError {
+    /// Whether the request might succeed if it's made again.
+    fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Request { .. } => true,
+            DownloadError::Status { status, .. } => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            DownloadError::InvalidZip { .. } | DownloadError::Write { .. } => false,
+        }
+    }
+}
+
+/// A zip that was downloaded, before it's written to the download directory.
+struct Fetched {
+    bytes: Vec<u8>,
+    sha256: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Downloads GTFS zips into a directory, only fetching those that changed since they were last downloaded there.
+pub struct FeedDownloader {
+    client: Client,
+    dir: PathBuf,
+    options: DownloadOptions,
+}
+
+impl FeedDownloader {
+    pub fn new(dir: &Path, options: DownloadOptions) -> Result<FeedDownloader, anyhow::Error> {
+        Ok(FeedDownloader {
+            client: Client::builder().timeout(options.timeout).build()?,
+            dir: dir.to_path_buf(),
+            options,
+        })
+    }
+
+    /// What's known about the feed `feed_id` before it's downloaded from `url`: `previous`, if its zip is still there as it was written, and nothing otherwise.
+    pub fn previous_download(
+        &self,
+        feed_id: &str,
+        url: &str,
+        previous: Option<&DownloadedFeed>,
+    ) -> DownloadedFeed {
+        let path = self.dir.join(format!("{feed_id}.zip"));
+        let mut feed = match previous {
+            Some(previous) if is_intact(&path, previous) => previous.clone(),
+            _ => DownloadedFeed {
+                feed_id: feed_id.to_string(),
+                url: url.to_string(),
+                status: DownloadStatus::Failed,
+                file_name: None,
+                sha256: None,
+                etag: None,
+                last_modified: None,
+                downloaded_at: None,
+                error: None,
+            },
+        };
+        feed.url = url.to_string();
+        feed.error = None;
+        feed
+    }
+
+    /// Download the feed `feed_id` with `request`, unless it hasn't changed since `previous`.
+    pub async fn download(
+        &self,
+        feed_id: &str,
+        request: &AuthorizedRequest,
+        previous: Option<&DownloadedFeed>,
+    ) -> DownloadedFeed {
+        let url = request.to_string();
+        let file_name = format!("{feed_id}.zip");
+        let path = self.dir.join(&file_name);
+        let mut feed = self.previous_download(feed_id, &url, previous);
+        // The server is only asked whether the zip changed if it's still there.
+        let cached = previous.filter(|_| feed.sha256.is_some());
+
+        let fetched = match self.fetch_with_retries(request, cached).await {
+            Ok(Some(fetched)) => fetched,
+            Ok(None) => {
+                debug!("Feed {} hasn't changed", feed_id);
+                feed.status = DownloadStatus::Unchanged;
+                return feed;
+            }
+            Err(err) => {
+                warn!("Failed to download feed {}: {}", feed_id, err);
+                feed.status = DownloadStatus::Failed;
+                feed.error = Some(err.to_string());
+                return feed;
+            }
+        };
+        if let Err(err) = write_atomically(&path, &fetched.bytes) {
+            let err = DownloadError::Write { url, err };
+            warn!("Failed to download feed {}: {}", feed_id, err);
+            feed.status = DownloadStatus::Failed;
+            feed.error = Some(err.to_string());
+            return feed;
+        }
+        feed.status = match previous.and_then(|previous| previous.sha256.as_ref()) {
+            None => DownloadStatus::New,
+            Some(sha256) if *sha256 == fetched.sha256 => DownloadStatus::Unchanged,
+            Some(_) => DownloadStatus::Updated,
+        };
+        feed.file_name = Some(file_name);
+        feed.sha256 = Some(fetched.sha256);
+        feed.etag = fetched.etag;
+        feed.last_modified = fetched.last_modified;
+        feed.downloaded_at = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        feed
+    }
+
+    async fn fetch_with_retries(
+        &self,
+        request: &AuthorizedRequest,
+        cached: Option<&DownloadedFeed>,
+    ) -> Result<Option<Fetched>, DownloadError> {
+        let mut backoff = self.options.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match self.fetch(request, cached).await {
+                Err(err) if err.is_transient() && retries < self.options.retries => {
+                    debug!("Retrying {} in {:?} after: {}", request, backoff, err);
+                    retries += 1;
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Fetch the zip `request` is for, or `None` if the server says it hasn't been modified since `cached`. Errors only include the URL with its credentials redacted.
+    async fn fetch(
+        &self,
+        request: &AuthorizedRequest,
+        cached: Option<&DownloadedFeed>,
+    ) -> Result<Option<Fetched>, DownloadError> {
+        let url = request.to_string();
+        let request_err = |err: reqwest::Error| DownloadError::Request {
+            url: url.clone(),
+            err: err.without_url(),
+        };
+        let mut builder = request.get(&self.client);
+        if let Some(cached) = cached {
+            if let Some(etag) = &cached.etag {
+                builder = builder.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                builder = builder.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let response = builder.send().await.map_err(request_err)?;
+        if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(DownloadError::Status {
+                url,
+                status: response.status(),
+            });
+        }
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let bytes = response.bytes().await.map_err(request_err)?;
+        ZipArchive::new(Cursor::new(bytes.as_ref()))
+            .map_err(|err| DownloadError::InvalidZip { url, err })?;
+        Ok(Some(Fetched {
+            sha256: format!("{:x}", Sha256::digest(&bytes)),
+            bytes: bytes.to_vec(),
+            etag,
+            last_modified,
+        }))
+    }
+}
+
+/// Whether the zip at `path` is the one `previous` describes.
+fn is_intact(path: &Path, previous: &DownloadedFeed) -> bool {
+    match (&previous.sha256, fs::read(path)) {
+        (Some(sha256), Ok(bytes)) => *sha256 == format!("{:x}", Sha256::digest(&bytes)),
+        _ => false,
+    }
+}
+
+/// Write `bytes` to `path` so that a download that's cut off never leaves half a zip behind.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<(), io::Error> {
+    let partial = path.with_extension("zip.part");
+    fs::write(&partial, bytes)?;
+    fs::rename(&partial, path)
+}
+
+/// Download the static feeds of every DMFR registry into `dir` and write its manifest, with `secrets` applied to feeds that need authorization. Feeds downloaded there before are only fetched again if they've changed, and the zips of feeds that are no longer listed are removed.
+pub async fn download_registries(
+    registries: &[(PathBuf, DistributedMobilityFeedRegistry)],
+    dir: &Path,
+    secrets: &FeedSecrets,
+    options: DownloadOptions,
+) -> Result<DownloadManifest, anyhow::Error> {
+    let previous = DownloadManifest::read(dir)
+        .unwrap_or_else(|err| {
+            warn!("Failed to read the previous download manifest: {}", err);
+            None
+        })
+        .unwrap_or_default();
+    let downloader = FeedDownloader::new(dir, options)?;
+
+    let mut feeds = vec![];
+    let mut feed_ids = HashSet::new();
+    for (path, dmfr) in registries {
+        for feed in &dmfr.feeds {
+            let url = if let Some(url) = &feed.urls.static_current {
+                url
+            } else {
+                continue;
+            };
+            let feed_id = local_feed_id(&feed.id);
+            if !feed_ids.insert(feed_id.clone()) {
+                warn!(
+                    "Skipping feed {} in {}, since another feed already goes by that ID",
+                    feed.id,
+                    path.display()
+                );
+                continue;
+            }
+            let request = AuthorizedRequest::new(
+                &feed.id,
+                url,
+                feed.authorization.as_ref(),
+                secrets.get(&feed.id),
+            )
+            .map_err(|err| (url.to_string(), err));
+            feeds.push((feed_id, request));
+        }
+    }
+    let (previous, downloader) = (&previous, &downloader);
+    let manifest = DownloadManifest {
+        feeds: stream::iter(&feeds)
+            .map(|(feed_id, request)| async move {
+                match request {
+                    Ok(request) => {
+                        downloader
+                            .download(feed_id, request, previous.get(feed_id))
+                            .await
+                    }
+                    Err((url, err)) => {
+                        warn!("Failed to download feed {}: {}", feed_id, err);
+                        let mut feed =
+                            downloader.previous_download(feed_id, url, previous.get(feed_id));
+                        feed.status = DownloadStatus::Failed;
+                        feed.error = Some(err.to_string());
+                        feed
+                    }
+                }
+            })
+            .buffered(CONCURRENT_DOWNLOADS)
+            .collect()
+            .await,
+    };
+    manifest.write(dir)?;
+
+    for feed in &previous.feeds {
+        if feed_ids.contains(&feed.feed_id) {
+            continue;
+        }
+        if let Some(file_name) = &feed.file_name {
+            if let Err(err) = fs::remove_file(dir.join(file_name)) {
+                warn!("Failed to remove feed {}: {}", feed.feed_id, err);
+            }
+        }
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Cursor, Write},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tempdir::TempDir;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use crate::{
+        dmfr::DistributedMobilityFeedRegistry,
+        feeds::{AuthorizedRequest, FeedSecret, FeedSecrets},
+        test_support::{self, Reply},
+    };
+
+    use super::{
+        download_registries, DownloadManifest, DownloadOptions, DownloadStatus, FeedDownloader,
+        DOWNLOAD_MANIFEST_FILE,
+    };
+
+    /// What the test server returns: its zip and ETag, and how many requests to fail first.
+    struct Served {
+        body: Vec<u8>,
+        etag: String,
+        failures: usize,
+        requests: Vec<String>,
+    }
+
+    fn zip_with(agency_name: &str) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        writer
+            .start_file("agency.txt", SimpleFileOptions::default())
+            .unwrap();
+        write!(
+            writer,
+            "agency_name,agency_url,agency_timezone\n{agency_name},https://example.com,UTC\n"
+        )
+        .unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    async fn serve(served: Arc<Mutex<Served>>) -> String {
+        let addr = test_support::serve(move |head| {
+            let head = head.to_lowercase();
+            let mut served = served.lock().unwrap();
+            served.requests.push(head.clone());
+            let (status, body) = if served.failures > 0 {
+                served.failures -= 1;
+                (503, vec![])
+            } else if head.contains(&format!("if-none-match: {}", served.etag)) {
+                (304, vec![])
+            } else {
+                (200, served.body.clone())
+            };
+            Reply::new(status, body).header("ETag", &served.etag)
+        })
+        .await;
+        format!("http://{addr}/gtfs.zip")
+    }
+
+    #[tokio::test]
+    async fn downloads_only_changed_feeds() {
+        let served = Arc::new(Mutex::new(Served {
+            body: zip_with("First"),
+            etag: "\"v1\"".to_string(),
+            failures: 1,
+            requests: vec![],
+        }));
+        let request =
+            AuthorizedRequest::new("f-test", &serve(served.clone()).await, None, None).unwrap();
+        let dir = TempDir::new("downloads").unwrap();
+        let downloader = FeedDownloader::new(
+            dir.path(),
+            DownloadOptions {
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let zip_path = dir.path().join("f-test.zip");
+
+        // The first request fails and is retried.
+        let first = downloader.download("f-test", &request, None).await;
+        assert_eq!(first.status, DownloadStatus::New);
+        assert_eq!(first.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(std::fs::read(&zip_path).unwrap(), zip_with("First"));
+        assert_eq!(served.lock().unwrap().requests.len(), 2);
+
+        let unchanged = downloader.download("f-test", &request, Some(&first)).await;
+        assert_eq!(unchanged.status, DownloadStatus::Unchanged);
+        assert_eq!(unchanged.sha256, first.sha256);
+        assert!(served.lock().unwrap().requests[2].contains("if-none-match: \"v1\""));
+
+        // A zip that doesn't match its hash is downloaded again without asking whether it changed.
+        std::fs::write(&zip_path, b"garbage").unwrap();
+        let repaired = downloader.download("f-test", &request, Some(&first)).await;
+        assert_eq!(repaired.status, DownloadStatus::Unchanged);
+        assert!(!served.lock().unwrap().requests[3].contains("if-none-match"));
+        assert_eq!(std::fs::read(&zip_path).unwrap(), zip_with("First"));
+
+        {
+            let mut served = served.lock().unwrap();
+            served.body = zip_with("Second");
+            served.etag = "\"v2\"".to_string();
+        }
+        let updated = downloader
+            .download("f-test", &request, Some(&repaired))
+            .await;
+        assert_eq!(updated.status, DownloadStatus::Updated);
+        assert_ne!(updated.sha256, first.sha256);
+        assert_eq!(std::fs::read(&zip_path).unwrap(), zip_with("Second"));
+
+        // What isn't a zip is rejected, and the last good one kept.
+        {
+            let mut served = served.lock().unwrap();
+            served.body = b"<html>Not found</html>".to_vec();
+            served.etag = "\"v3\"".to_string();
+        }
+        let failed = downloader
+            .download("f-test", &request, Some(&updated))
+            .await;
+        assert_eq!(failed.status, DownloadStatus::Failed);
+        assert!(failed.error.is_some());
+        assert_eq!(failed.sha256, updated.sha256);
+        assert_eq!(std::fs::read(&zip_path).unwrap(), zip_with("Second"));
+    }
+
+    #[tokio::test]
+    async fn applies_and_redacts_credentials() {
+        let served = Arc::new(Mutex::new(Served {
+            body: zip_with("Private"),
+            etag: "\"v1\"".to_string(),
+            failures: 0,
+            requests: vec![],
+        }));
+        let url = serve(served.clone()).await;
+        let dmfr: DistributedMobilityFeedRegistry = serde_json::from_value(serde_json::json!({
+            "feeds": [
+                {
+                    "id": "f-private",
+                    "spec": "gtfs",
+                    "urls": { "static_current": url },
+                    "authorization": { "type": "query_param", "param_name": "api_key" }
+                },
+                {
+                    "id": "f-no-secret",
+                    "spec": "gtfs",
+                    "urls": { "static_current": url },
+                    "authorization": { "type": "header", "param_name": "x-api-key" }
+                }
+            ]
+        }))
+        .unwrap();
+        let secrets = FeedSecrets::new(vec![FeedSecret {
+            feed: "f-private".to_string(),
+            key: Some("s3cret".to_string()),
+            username: None,
+            password: None,
+            replace_url: None,
+        }]);
+        let dir = TempDir::new("downloads").unwrap();
+        let manifest = download_registries(
+            &[(dir.path().join("test.dmfr.json"), dmfr)],
+            dir.path(),
+            &secrets,
+            DownloadOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let requests = served.lock().unwrap().requests.clone();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("get /gtfs.zip?api_key=s3cret "));
+        assert_eq!(manifest.feeds[0].status, DownloadStatus::New);
+        assert_eq!(manifest.feeds[0].url, format!("{url}?api_key=REDACTED"));
+        assert_eq!(manifest.feeds[1].status, DownloadStatus::Failed);
+        assert!(manifest.feeds[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("there's no secret"));
+        let written = std::fs::read_to_string(dir.path().join(DOWNLOAD_MANIFEST_FILE)).unwrap();
+        assert!(!written.contains("s3cret"));
+        assert_eq!(DownloadManifest::read(dir.path()).unwrap(), Some(manifest));
+    }
+}
@@ -0,0 +1,184 @@
+pub mod v1;
+pub mod v2;
+
+use std::sync::Arc;
+
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{Money, SolariFare, SolariLegFare};
+
+use self::{
+    v1::{FaresV1, FaresV1Index},
+    v2::{FaresV2, FaresV2Index},
+};
+
+/// The fare data of a single GTFS feed, stored alongside the timetable and keyed by feed ID.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FeedFares {
+    /// The timezone of the feed's agencies, used to interpret fare timeframes.
+    pub timezone: Option<String>,
+    pub v2: Option<FaresV2>,
+    #[serde(default)]
+    pub v1: Option<FaresV1>,
+}
+
+impl FeedFares {
+    pub fn is_empty(&self) -> bool {
+        self.v2.is_none() && self.v1.is_none()
+    }
+}
+
+/// Who is riding and how they pay, used to pick between fare products.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FareOptions {
+    /// A `rider_category_id` from the feed. If absent, the feed's default rider category is used.
+    pub rider_category: Option<String>,
+    /// A `fare_media_id` from the feed. If absent, products on any fare media are considered.
+    pub fare_media: Option<String>,
+    /// The most an itinerary may cost. Itineraries whose total can't be calculated are kept.
+    #[serde(default)]
+    pub max_fare: Option<f64>,
+}
+
+/// The parts of a GTFS stop that fare rules can refer to.
+#[derive(Debug, Clone)]
+pub struct FareStop {
+    pub stop_id: String,
+    pub parent_station: Option<String>,
+    pub zone_id: Option<String>,
+}
+
+impl From<&gtfs_structures::Stop> for FareStop {
+    fn from(stop: &gtfs_structures::Stop) -> Self {
+        FareStop {
+            stop_id: stop.id.clone(),
+            parent_station: stop.parent_station.clone(),
+            zone_id: stop.zone_id.clone(),
+        }
+    }
+}
+
+/// A transit leg of an itinerary, as seen by the fare calculator.
+#[derive(Debug, Clone)]
+pub struct FareLeg {
+    pub feed_id: String,
+    pub route_id: Option<String>,
+    pub from: FareStop,
+    pub to: FareStop,
+    pub departure_epoch_seconds: u64,
+    pub arrival_epoch_seconds: u64,
+}
+
+/// The price of a single leg, including any transfer discount or surcharge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegPrice {
+    pub amount: f64,
+    pub currency: String,
+    pub fare_product_id: Option<String>,
+    pub fare_product_name: Option<String>,
+    /// Whether a transfer rule was applied to this leg.
+    pub transfer: bool,
+}
+
+impl From<&v2::FareProduct> for LegPrice {
+    fn from(product: &v2::FareProduct) -> Self {
+        LegPrice {
+            amount: product.amount,
+            currency: product.currency.clone(),
+            fare_product_id: Some(product.fare_product_id.clone()),
+            fare_product_name: product.fare_product_name.clone(),
+            transfer: false,
+        }
+    }
+}
+
+/// Prices legs for a single feed.
+#[derive(Debug)]
+pub struct FareCalculator {
+    timezone: Tz,
+    v2: Option<FaresV2Index>,
+    v1: Option<FaresV1Index>,
+}
+
+impl FareCalculator {
+    pub fn new(fares: FeedFares) -> FareCalculator {
+        let timezone = fares
+            .timezone
+            .as_ref()
+            .and_then(|timezone| timezone.parse().ok())
+            .unwrap_or(Tz::UTC);
+        FareCalculator {
+            timezone,
+            v2: fares.v2.map(FaresV2Index::new),
+            v1: fares.v1.map(FaresV1Index::new),
+        }
+    }
+
+    /// Price a run of consecutive legs from this feed, returning one entry per leg. Legs that can't be priced are `None`. Fares v2 takes precedence for feeds that publish both.
+    pub fn price_legs(&self, legs: &[FareLeg], options: &FareOptions) -> Vec<Option<LegPrice>> {
+        if let Some(v2) = &self.v2 {
+            v2.price_legs(legs, options, &self.timezone)
+        } else if let Some(v1) = &self.v1 {
+            v1.price_legs(legs)
+        } else {
+            vec![None; legs.len()]
+        }
+    }
+}
+
+/// Price the transit legs of an itinerary. Legs are priced in runs of consecutive legs from the same feed, so transfer rules never apply across feeds.
+pub fn price_legs<F: Fn(&str) -> Option<Arc<FareCalculator>>>(
+    legs: &[FareLeg],
+    options: &FareOptions,
+    calculator_for_feed: F,
+) -> Vec<Option<LegPrice>> {
+    let mut prices = Vec::with_capacity(legs.len());
+    for run in legs.chunk_by(|a, b| a.feed_id == b.feed_id) {
+        if let Some(calculator) = calculator_for_feed(&run[0].feed_id) {
+            prices.extend(calculator.price_legs(run, options));
+        } else {
+            prices.extend(run.iter().map(|_| None));
+        }
+    }
+    prices
+}
+
+/// Assemble the fare of an itinerary from the prices of its transit legs. `leg_indices` maps each price to its position in the itinerary's legs.
+pub fn itinerary_fare(leg_indices: &[usize], prices: &[Option<LegPrice>]) -> Option<SolariFare> {
+    if prices.iter().all(Option::is_none) {
+        return None;
+    }
+    let legs: Vec<SolariLegFare> = leg_indices
+        .iter()
+        .zip(prices)
+        .filter_map(|(leg_index, price)| {
+            let price = price.as_ref()?;
+            Some(SolariLegFare {
+                leg_index: *leg_index,
+                fare_product_id: price.fare_product_id.clone(),
+                fare_product_name: price.fare_product_name.clone(),
+                price: Money {
+                    amount: price.amount,
+                    currency: price.currency.clone(),
+                },
+                transfer: price.transfer,
+            })
+        })
+        .collect();
+    // A total is only meaningful if every leg was priced, in the same currency.
+    let total = if legs.len() == prices.len()
+        && legs
+            .windows(2)
+            .all(|pair| pair[0].price.currency == pair[1].price.currency)
+    {
+        let amount: f64 = legs.iter().map(|leg| leg.price.amount).sum();
+        Some(Money {
+            amount: (amount * 10_000.0).round() / 10_000.0,
+            currency: legs[0].price.currency.clone(),
+        })
+    } else {
+        None
+    };
+    Some(SolariFare { total, legs })
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    slice,
+};
+
+use gtfs_structures::Gtfs;
+use serde::{Deserialize, Serialize};
+
+use crate::{gtfs_files::read_optional, raptor::timetable::in_memory::route_agency};
+
+use super::{FareLeg, LegPrice};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FareAttribute {
+    pub fare_id: String,
+    pub price: f64,
+    pub currency_type: String,
+    pub payment_method: u8,
+    /// The number of transfers permitted on this fare. Empty means unlimited.
+    pub transfers: Option<u32>,
+    pub agency_id: Option<String>,
+    pub transfer_duration: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FareRule {
+    pub fare_id: String,
+    pub route_id: Option<String>,
+    pub origin_id: Option<String>,
+    pub destination_id: Option<String>,
+    pub contains_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteAgency {
+    pub route_id: String,
+    pub agency_id: Option<String>,
+}
+
+/// The GTFS Fares v1 files of a single feed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FaresV1 {
+    pub fare_attributes: Vec<FareAttribute>,
+    pub fare_rules: Vec<FareRule>,
+    /// The agency of every route, for fares restricted with `agency_id`. Filled in by the timetable builder with [`FaresV1::resolve_route_agencies`], since a route's agency may be implied by the feed having only one.
+    pub route_agencies: Vec<RouteAgency>,
+}
+
+impl FaresV1 {
+    /// Read the Fares v1 files of the feed at `feed_path`. Returns `None` if the feed has no fare_attributes.txt.
+    pub fn read(feed_path: &Path) -> Result<Option<FaresV1>, anyhow::Error> {
+        let fare_attributes =
+            if let Some(attributes) = read_optional(feed_path, "fare_attributes.txt")? {
+                attributes
+            } else {
+                return Ok(None);
+            };
+        Ok(Some(FaresV1 {
+            fare_attributes,
+            fare_rules: read_optional(feed_path, "fare_rules.txt")?.unwrap_or_default(),
+            route_agencies: vec![],
+        }))
+    }
+
+    /// Record the agency of every route the same way the timetable does. Routes whose agency can't be worked out aren't covered by fares restricted to an agency.
+    pub fn resolve_route_agencies(&mut self, gtfs: &Gtfs) {
+        self.route_agencies = gtfs
+            .routes
+            .values()
+            .map(|route| RouteAgency {
+                route_id: route.id.clone(),
+                agency_id: route_agency(gtfs, route)
+                    .ok()
+                    .and_then(|agency| agency.id.clone()),
+            })
+            .collect();
+    }
+}
+
+/// Every rule of a single fare, merged together.
+#[derive(Debug, Default)]
+struct FareRuleSet {
+    route_ids: HashSet<String>,
+    origin_destinations: HashSet<(Option<String>, Option<String>)>,
+    contains: HashSet<String>,
+}
+
+impl FareRuleSet {
+    fn matches(&self, ride: &Ride) -> bool {
+        if !self.route_ids.is_empty()
+            && !ride
+                .route_ids
+                .iter()
+                .all(|route_id| route_id.is_some_and(|id| self.route_ids.contains(id)))
+        {
+            return false;
+        }
+        if !self.origin_destinations.is_empty() {
+            let origin = ride.origin.cloned();
+            let destination = ride.destination.cloned();
+            let candidates = [
+                (origin.clone(), destination.clone()),
+                (origin, None),
+                (None, destination),
+                (None, None),
+            ];
+            if !candidates
+                .iter()
+                .any(|candidate| self.origin_destinations.contains(candidate))
+            {
+                return false;
+            }
+        }
+        if !self.contains.is_empty()
+            && (ride.zones.len() != self.contains.len()
+                || !ride.zones.iter().all(|zone| self.contains.contains(*zone)))
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// A run of consecutive legs that might be covered by a single fare.
+struct Ride<'l> {
+    route_ids: Vec<Option<&'l String>>,
+    origin: Option<&'l String>,
+    destination: Option<&'l String>,
+    zones: HashSet<&'l String>,
+}
+
+/// Fares v1 data with the lookups needed to price legs.
+#[derive(Debug)]
+pub(crate) struct FaresV1Index {
+    fares: FaresV1,
+    rule_sets: HashMap<String, FareRuleSet>,
+    route_agencies: HashMap<String, String>,
+}
+
+impl FaresV1Index {
+    pub(crate) fn new(fares: FaresV1) -> FaresV1Index {
+        let mut rule_sets: HashMap<String, FareRuleSet> = HashMap::new();
+        for rule in &fares.fare_rules {
+            let rule_set = rule_sets.entry(rule.fare_id.clone()).or_default();
+            if let Some(route_id) = &rule.route_id {
+                rule_set.route_ids.insert(route_id.clone());
+            }
+            if rule.origin_id.is_some() || rule.destination_id.is_some() {
+                rule_set
+                    .origin_destinations
+                    .insert((rule.origin_id.clone(), rule.destination_id.clone()));
+            }
+            if let Some(contains_id) = &rule.contains_id {
+                rule_set.contains.insert(contains_id.clone());
+            }
+        }
+        let route_agencies = fares
+            .route_agencies
+            .iter()
+            .filter_map(|route| Some((route.route_id.clone(), route.agency_id.clone()?)))
+            .collect();
+        FaresV1Index {
+            fares,
+            rule_sets,
+            route_agencies,
+        }
+    }
+
+    /// Price a run of consecutive legs. Legs are grouped into rides that share a single fare so as to minimize the total price, and each ride's fare is attributed to its first leg. Legs that no fare covers are `None`, and split the run in two.
+    pub(crate) fn price_legs(&self, legs: &[FareLeg]) -> Vec<Option<LegPrice>> {
+        let mut prices = Vec::with_capacity(legs.len());
+        for segment in
+            legs.split_inclusive(|leg| self.cheapest_fare(slice::from_ref(leg)).is_none())
+        {
+            let (last, rest) = segment.split_last().expect("Segments are never empty");
+            if self.cheapest_fare(slice::from_ref(last)).is_some() {
+                prices.extend(self.price_segment(segment));
+            } else {
+                prices.extend(self.price_segment(rest));
+                prices.push(None);
+            }
+        }
+        prices
+    }
+
+    fn price_segment(&self, legs: &[FareLeg]) -> Vec<Option<LegPrice>> {
+        // best[i] is the cheapest way to pay for the first i legs, along with the start of the last ride and its fare.
+        let mut best: Vec<Option<(f64, usize, &FareAttribute)>> = vec![None; legs.len() + 1];
+        for end in 1..=legs.len() {
+            for start in 0..end {
+                let cost_before = if start == 0 {
+                    0.0
+                } else if let Some((cost, _, _)) = best[start] {
+                    cost
+                } else {
+                    continue;
+                };
+                if let Some(fare) = self.cheapest_fare(&legs[start..end]) {
+                    let cost = cost_before + fare.price;
+                    // On ties, prefer starting the last ride later, so that transfers are used up as early as possible.
+                    if best[end].is_none_or(|(best_cost, _, _)| cost <= best_cost) {
+                        best[end] = Some((cost, start, fare));
+                    }
+                }
+            }
+        }
+
+        let mut prices = vec![None; legs.len()];
+        let mut end = legs.len();
+        while end > 0 {
+            let (_, start, fare) = best[end].expect("Every leg of a segment has a fare");
+            for (idx, price) in prices.iter_mut().enumerate().take(end).skip(start) {
+                *price = Some(LegPrice {
+                    amount: if idx == start { fare.price } else { 0.0 },
+                    currency: fare.currency_type.clone(),
+                    fare_product_id: Some(fare.fare_id.clone()),
+                    fare_product_name: None,
+                    transfer: idx != start,
+                });
+            }
+            end = start;
+        }
+        prices
+    }
+
+    fn cheapest_fare(&self, legs: &[FareLeg]) -> Option<&FareAttribute> {
+        let first = legs.first()?;
+        let last = legs.last()?;
+        let ride = Ride {
+            route_ids: legs.iter().map(|leg| leg.route_id.as_ref()).collect(),
+            origin: first.from.zone_id.as_ref(),
+            destination: last.to.zone_id.as_ref(),
+            zones: legs
+                .iter()
+                .flat_map(|leg| [leg.from.zone_id.as_ref(), leg.to.zone_id.as_ref()])
+                .flatten()
+                .collect(),
+        };
+        let transfers = legs.len() as u32 - 1;
+        let transfer_window = last
+            .departure_epoch_seconds
+            .saturating_sub(first.departure_epoch_seconds);
+
+        self.fares
+            .fare_attributes
+            .iter()
+            .filter(|fare| fare.transfers.is_none_or(|allowed| transfers <= allowed))
+            .filter(|fare| {
+                transfers == 0
+                    || fare
+                        .transfer_duration
+                        .is_none_or(|duration| transfer_window <= duration)
+            })
+            .filter(|fare| {
+                fare.agency_id.as_ref().is_none_or(|agency_id| {
+                    ride.route_ids.iter().all(|route_id| {
+                        route_id.and_then(|id| self.route_agencies.get(id)) == Some(agency_id)
+                    })
+                })
+            })
+            .filter(|fare| {
+                // A fare without any rules applies everywhere.
+                self.rule_sets
+                    .get(&fare.fare_id)
+                    .is_none_or(|rule_set| rule_set.matches(&ride))
+            })
+            .min_by(|a, b| a.price.total_cmp(&b.price))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fares::{FareLeg, FareStop};
+
+    use super::{FareAttribute, FareRule, FaresV1, FaresV1Index};
+
+    fn fare(id: &str, price: f64, transfers: Option<u32>) -> FareAttribute {
+        FareAttribute {
+            fare_id: id.to_string(),
+            price,
+            currency_type: "USD".to_string(),
+            payment_method: 0,
+            transfers,
+            agency_id: None,
+            transfer_duration: Some(5400),
+        }
+    }
+
+    fn rule(fare_id: &str, route_id: Option<&str>, origin_id: Option<&str>) -> FareRule {
+        FareRule {
+            fare_id: fare_id.to_string(),
+            route_id: route_id.map(str::to_string),
+            origin_id: origin_id.map(str::to_string),
+            destination_id: None,
+            contains_id: None,
+        }
+    }
+
+    fn leg(route_id: &str, from_zone: &str, departure: u64) -> FareLeg {
+        let stop = |zone_id: &str| FareStop {
+            stop_id: format!("stop-{zone_id}"),
+            parent_station: None,
+            zone_id: Some(zone_id.to_string()),
+        };
+        FareLeg {
+            feed_id: "test".to_string(),
+            route_id: Some(route_id.to_string()),
+            from: stop(from_zone),
+            to: stop("1"),
+            departure_epoch_seconds: departure,
+            arrival_epoch_seconds: departure + 600,
+        }
+    }
+
+    #[test]
+    fn test_transfer_allowance() {
+        let fares = FaresV1Index::new(FaresV1 {
+            fare_attributes: vec![
+                fare("local", 2.50, Some(1)),
+                fare("ferry", 8.00, Some(0)),
+                fare("airport", 3.00, Some(0)),
+            ],
+            fare_rules: vec![
+                rule("local", Some("1"), None),
+                rule("local", Some("2"), None),
+                rule("ferry", Some("F"), None),
+                rule("airport", Some("A"), Some("airport")),
+            ],
+            route_agencies: vec![],
+        });
+
+        let amounts = |legs: &[FareLeg]| -> Vec<Option<f64>> {
+            fares
+                .price_legs(legs)
+                .iter()
+                .map(|price| price.as_ref().map(|price| price.amount))
+                .collect()
+        };
+
+        // One transfer is included in the local fare.
+        assert_eq!(
+            amounts(&[leg("1", "1", 0), leg("2", "1", 1200)]),
+            vec![Some(2.50), Some(0.0)]
+        );
+        // A second transfer needs a new fare.
+        assert_eq!(
+            amounts(&[leg("1", "1", 0), leg("2", "1", 1200), leg("1", "1", 2400)]),
+            vec![Some(2.50), Some(0.0), Some(2.50)]
+        );
+        // The transfer has expired.
+        assert_eq!(
+            amounts(&[leg("1", "1", 0), leg("2", "1", 6000)]),
+            vec![Some(2.50), Some(2.50)]
+        );
+        // Fares for different routes don't combine.
+        assert_eq!(
+            amounts(&[leg("1", "1", 0), leg("F", "1", 1200)]),
+            vec![Some(2.50), Some(8.00)]
+        );
+        // The airport fare only applies to rides starting in the airport zone.
+        assert_eq!(amounts(&[leg("A", "airport", 0)]), vec![Some(3.00)]);
+        assert_eq!(amounts(&[leg("A", "1", 0)]), vec![None]);
+        // Legs that no fare covers are left unpriced.
+        assert_eq!(
+            amounts(&[leg("1", "1", 0), leg("X", "1", 1200), leg("2", "1", 1800)]),
+            vec![Some(2.50), None, Some(2.50)]
+        );
+    }
+}
@@ -0,0 +1,717 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use chrono::{Days, NaiveDate, TimeZone, Timelike};
+use chrono_tz::Tz;
+use gtfs_structures::Gtfs;
+use serde::{Deserialize, Serialize};
+
+use crate::{gtfs_files::read_optional, raptor::timetable::ServiceWindow};
+
+use super::{FareLeg, FareOptions, FareStop, LegPrice};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FareMedia {
+    pub fare_media_id: String,
+    pub fare_media_name: Option<String>,
+    pub fare_media_type: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FareProduct {
+    pub fare_product_id: String,
+    pub fare_product_name: Option<String>,
+    pub rider_category_id: Option<String>,
+    pub fare_media_id: Option<String>,
+    pub amount: f64,
+    pub currency: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RiderCategory {
+    pub rider_category_id: String,
+    pub rider_category_name: Option<String>,
+    pub is_default_fare_category: Option<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FareLegRule {
+    pub leg_group_id: Option<String>,
+    pub network_id: Option<String>,
+    pub from_area_id: Option<String>,
+    pub to_area_id: Option<String>,
+    pub from_timeframe_group_id: Option<String>,
+    pub to_timeframe_group_id: Option<String>,
+    pub fare_product_id: String,
+    pub rule_priority: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FareTransferRule {
+    pub from_leg_group_id: Option<String>,
+    pub to_leg_group_id: Option<String>,
+    pub transfer_count: Option<i32>,
+    pub duration_limit: Option<u32>,
+    pub duration_limit_type: Option<u8>,
+    pub fare_transfer_type: u8,
+    pub fare_product_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Area {
+    pub area_id: String,
+    pub area_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopArea {
+    pub area_id: String,
+    pub stop_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Network {
+    pub network_id: String,
+    pub network_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteNetwork {
+    pub network_id: String,
+    pub route_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timeframe {
+    pub timeframe_group_id: String,
+    pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    pub service_id: String,
+}
+
+/// Networks may also be assigned with the `network_id` column of routes.txt instead of route_networks.txt.
+#[derive(Debug, Deserialize)]
+struct RouteNetworkColumn {
+    route_id: String,
+    network_id: Option<String>,
+}
+
+/// The GTFS Fares v2 files of a single feed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FaresV2 {
+    pub fare_media: Vec<FareMedia>,
+    pub fare_products: Vec<FareProduct>,
+    pub rider_categories: Vec<RiderCategory>,
+    pub fare_leg_rules: Vec<FareLegRule>,
+    pub fare_transfer_rules: Vec<FareTransferRule>,
+    pub areas: Vec<Area>,
+    pub stop_areas: Vec<StopArea>,
+    pub networks: Vec<Network>,
+    pub route_networks: Vec<RouteNetwork>,
+    pub timeframes: Vec<Timeframe>,
+    /// The dates each timeframe's `service_id` runs on, within the timetable's service window. `None` if the calendar wasn't known, in which case timeframes apply every day.
+    #[serde(default)]
+    pub timeframe_service_dates: Option<HashMap<String, Vec<NaiveDate>>>,
+}
+
+impl FaresV2 {
+    /// Read the Fares v2 files of the feed at `feed_path`. Returns `None` if the feed has no fare_leg_rules.txt, since nothing can be priced without it.
+    pub fn read(feed_path: &Path) -> Result<Option<FaresV2>, anyhow::Error> {
+        let fare_leg_rules = if let Some(rules) = read_optional(feed_path, "fare_leg_rules.txt")? {
+            rules
+        } else {
+            return Ok(None);
+        };
+        let mut route_networks: Vec<RouteNetwork> =
+            read_optional(feed_path, "route_networks.txt")?.unwrap_or_default();
+        let routes: Vec<RouteNetworkColumn> =
+            read_optional(feed_path, "routes.txt")?.unwrap_or_default();
+        route_networks.extend(routes.into_iter().filter_map(|route| {
+            Some(RouteNetwork {
+                network_id: route.network_id?,
+                route_id: route.route_id,
+            })
+        }));
+
+        Ok(Some(FaresV2 {
+            fare_media: read_optional(feed_path, "fare_media.txt")?.unwrap_or_default(),
+            fare_products: read_optional(feed_path, "fare_products.txt")?.unwrap_or_default(),
+            rider_categories: read_optional(feed_path, "rider_categories.txt")?.unwrap_or_default(),
+            fare_leg_rules,
+            fare_transfer_rules: read_optional(feed_path, "fare_transfer_rules.txt")?
+                .unwrap_or_default(),
+            areas: read_optional(feed_path, "areas.txt")?.unwrap_or_default(),
+            stop_areas: read_optional(feed_path, "stop_areas.txt")?.unwrap_or_default(),
+            networks: read_optional(feed_path, "networks.txt")?.unwrap_or_default(),
+            route_networks,
+            timeframes: read_optional(feed_path, "timeframes.txt")?.unwrap_or_default(),
+            timeframe_service_dates: None,
+        }))
+    }
+
+    /// Look up the dates in `service_window` that the services of the timeframes run on, in the feed's calendar.
+    pub fn resolve_timeframe_services(&mut self, gtfs: &Gtfs, service_window: &ServiceWindow) {
+        let mut service_dates: HashMap<String, Vec<NaiveDate>> = HashMap::new();
+        for timeframe in &self.timeframes {
+            if service_dates.contains_key(&timeframe.service_id) {
+                continue;
+            }
+            let dates = gtfs
+                .trip_days(&timeframe.service_id, service_window.start_date)
+                .into_iter()
+                .filter(|day| (*day as u32) < service_window.days)
+                .filter_map(|day| {
+                    service_window
+                        .start_date
+                        .checked_add_days(Days::new(day as u64))
+                })
+                .collect();
+            service_dates.insert(timeframe.service_id.clone(), dates);
+        }
+        self.timeframe_service_dates = Some(service_dates);
+    }
+}
+
+/// Fares v2 data with the lookups needed to price legs.
+#[derive(Debug)]
+pub(crate) struct FaresV2Index {
+    fares: FaresV2,
+    products: HashMap<String, Vec<usize>>,
+    stop_areas: HashMap<String, Vec<String>>,
+    route_networks: HashMap<String, String>,
+    /// The intervals of the day each timeframe group covers, and the service whose dates they apply on.
+    timeframes: HashMap<String, Vec<(u32, u32, String)>>,
+    service_dates: Option<HashMap<String, HashSet<NaiveDate>>>,
+    default_rider_categories: HashSet<String>,
+    uses_rule_priority: bool,
+}
+
+#[derive(Debug, Clone)]
+struct MatchedLeg<'f> {
+    leg_group_id: Option<&'f String>,
+    product: &'f FareProduct,
+}
+
+impl FaresV2Index {
+    pub(crate) fn new(fares: FaresV2) -> FaresV2Index {
+        let mut products: HashMap<String, Vec<usize>> = HashMap::new();
+        for (idx, product) in fares.fare_products.iter().enumerate() {
+            products
+                .entry(product.fare_product_id.clone())
+                .or_default()
+                .push(idx);
+        }
+        let mut stop_areas: HashMap<String, Vec<String>> = HashMap::new();
+        for stop_area in &fares.stop_areas {
+            stop_areas
+                .entry(stop_area.stop_id.clone())
+                .or_default()
+                .push(stop_area.area_id.clone());
+        }
+        let route_networks = fares
+            .route_networks
+            .iter()
+            .map(|route_network| {
+                (
+                    route_network.route_id.clone(),
+                    route_network.network_id.clone(),
+                )
+            })
+            .collect();
+        let mut timeframes: HashMap<String, Vec<(u32, u32, String)>> = HashMap::new();
+        for timeframe in &fares.timeframes {
+            let start = timeframe
+                .start_time
+                .as_deref()
+                .and_then(parse_time)
+                .unwrap_or(0);
+            let end = timeframe
+                .end_time
+                .as_deref()
+                .and_then(parse_time)
+                .unwrap_or(86_400);
+            timeframes
+                .entry(timeframe.timeframe_group_id.clone())
+                .or_default()
+                .push((start, end, timeframe.service_id.clone()));
+        }
+        let service_dates = fares.timeframe_service_dates.as_ref().map(|service_dates| {
+            service_dates
+                .iter()
+                .map(|(service_id, dates)| (service_id.clone(), dates.iter().copied().collect()))
+                .collect()
+        });
+        let default_rider_categories = fares
+            .rider_categories
+            .iter()
+            .filter(|category| category.is_default_fare_category == Some(1))
+            .map(|category| category.rider_category_id.clone())
+            .collect();
+        let uses_rule_priority = fares
+            .fare_leg_rules
+            .iter()
+            .any(|rule| rule.rule_priority.is_some());
+        FaresV2Index {
+            fares,
+            products,
+            stop_areas,
+            route_networks,
+            timeframes,
+            service_dates,
+            default_rider_categories,
+            uses_rule_priority,
+        }
+    }
+
+    /// Price a run of consecutive legs, applying transfer rules between adjacent legs. Legs that no rule matches are `None`, and break any chain of transfers.
+    pub(crate) fn price_legs(
+        &self,
+        legs: &[FareLeg],
+        options: &FareOptions,
+        timezone: &Tz,
+    ) -> Vec<Option<LegPrice>> {
+        let matched: Vec<Option<MatchedLeg>> = legs
+            .iter()
+            .map(|leg| self.match_leg(leg, options, timezone))
+            .collect();
+        let mut prices: Vec<Option<LegPrice>> = Vec::with_capacity(legs.len());
+        // The number of transfers made so far in the current chain of discounted legs.
+        let mut transfers = 0u32;
+        for (idx, current) in matched.iter().enumerate() {
+            let current = if let Some(current) = current {
+                current
+            } else {
+                transfers = 0;
+                prices.push(None);
+                continue;
+            };
+            let previous = if idx == 0 {
+                None
+            } else {
+                matched[idx - 1].as_ref().zip(prices[idx - 1].as_ref())
+            };
+            let transfer = previous.and_then(|(previous, previous_price)| {
+                self.match_transfer(
+                    previous,
+                    current,
+                    &legs[idx - 1],
+                    &legs[idx],
+                    transfers,
+                    options,
+                )
+                .map(|(rule, product)| (rule, product, previous_price.amount))
+            });
+            let price = if let Some((rule, transfer_product, previous_amount)) = transfer {
+                transfers += 1;
+                let transfer_amount = transfer_product
+                    .map(|product| product.amount)
+                    .unwrap_or(0.0);
+                let amount = match rule.fare_transfer_type {
+                    // A + AB: the transfer product replaces this leg's fare.
+                    0 => transfer_amount,
+                    // A + AB + B: the transfer product is paid on top of this leg's fare.
+                    1 => transfer_amount + current.product.amount,
+                    // AB: the transfer product covers both legs, so charge whatever the previous leg didn't.
+                    _ => (transfer_amount - previous_amount).max(0.0),
+                };
+                let product = transfer_product.unwrap_or(current.product);
+                LegPrice {
+                    amount,
+                    currency: current.product.currency.clone(),
+                    fare_product_id: Some(product.fare_product_id.clone()),
+                    fare_product_name: product.fare_product_name.clone(),
+                    transfer: true,
+                }
+            } else {
+                transfers = 0;
+                LegPrice::from(current.product)
+            };
+            prices.push(Some(price));
+        }
+        prices
+    }
+
+    fn match_leg(
+        &self,
+        leg: &FareLeg,
+        options: &FareOptions,
+        timezone: &Tz,
+    ) -> Option<MatchedLeg<'_>> {
+        let network = leg
+            .route_id
+            .as_ref()
+            .and_then(|route_id| self.route_networks.get(route_id));
+        let from_areas = self.areas_of(&leg.from);
+        let to_areas = self.areas_of(&leg.to);
+        let from_timeframes = self.timeframes_at(leg.departure_epoch_seconds, timezone);
+        let to_timeframes = self.timeframes_at(leg.arrival_epoch_seconds, timezone);
+
+        let rules: Vec<&FareLegRule> = self.fares.fare_leg_rules.iter().collect();
+        let rules = if self.uses_rule_priority {
+            // With rule priorities, an empty field matches anything and the highest priority rules win.
+            let rules: Vec<&FareLegRule> = rules
+                .into_iter()
+                .filter(|rule| {
+                    matches_or_empty(&rule.network_id, |id| Some(id) == network)
+                        && matches_or_empty(&rule.from_area_id, |id| from_areas.contains(&id))
+                        && matches_or_empty(&rule.to_area_id, |id| to_areas.contains(&id))
+                        && matches_or_empty(&rule.from_timeframe_group_id, |id| {
+                            from_timeframes.contains(&id)
+                        })
+                        && matches_or_empty(&rule.to_timeframe_group_id, |id| {
+                            to_timeframes.contains(&id)
+                        })
+                })
+                .collect();
+            let max_priority = rules
+                .iter()
+                .map(|rule| rule.rule_priority.unwrap_or(0))
+                .max();
+            rules
+                .into_iter()
+                .filter(|rule| Some(rule.rule_priority.unwrap_or(0)) == max_priority)
+                .collect()
+        } else {
+            // Without them, an empty field only matches when no rule names the leg's value explicitly.
+            let rules = filter_with_default(
+                rules,
+                |rule| rule.network_id.as_ref(),
+                |id| Some(id) == network,
+            );
+            let rules = filter_with_default(
+                rules,
+                |rule| rule.from_area_id.as_ref(),
+                |id| from_areas.contains(&id),
+            );
+            let rules = filter_with_default(
+                rules,
+                |rule| rule.to_area_id.as_ref(),
+                |id| to_areas.contains(&id),
+            );
+            let rules = filter_with_default(
+                rules,
+                |rule| rule.from_timeframe_group_id.as_ref(),
+                |id| from_timeframes.contains(&id),
+            );
+            filter_with_default(
+                rules,
+                |rule| rule.to_timeframe_group_id.as_ref(),
+                |id| to_timeframes.contains(&id),
+            )
+        };
+
+        rules
+            .into_iter()
+            .flat_map(|rule| {
+                self.eligible_products(&rule.fare_product_id, options)
+                    .into_iter()
+                    .map(move |product| MatchedLeg {
+                        leg_group_id: rule.leg_group_id.as_ref(),
+                        product,
+                    })
+            })
+            .min_by(|a, b| a.product.amount.total_cmp(&b.product.amount))
+    }
+
+    fn match_transfer(
+        &self,
+        previous: &MatchedLeg,
+        current: &MatchedLeg,
+        previous_leg: &FareLeg,
+        current_leg: &FareLeg,
+        transfers: u32,
+        options: &FareOptions,
+    ) -> Option<(&FareTransferRule, Option<&FareProduct>)> {
+        let rules: Vec<&FareTransferRule> = self.fares.fare_transfer_rules.iter().collect();
+        let rules = filter_with_default(
+            rules,
+            |rule| rule.from_leg_group_id.as_ref(),
+            |id| Some(id) == previous.leg_group_id,
+        );
+        let rules = filter_with_default(
+            rules,
+            |rule| rule.to_leg_group_id.as_ref(),
+            |id| Some(id) == current.leg_group_id,
+        );
+        rules.into_iter().find_map(|rule| {
+            // A missing or negative transfer count means there's no limit.
+            if let Some(count) = rule.transfer_count {
+                if count >= 0 && transfers >= count as u32 {
+                    return None;
+                }
+            }
+            if let Some(limit) = rule.duration_limit {
+                let (start, end) = match rule.duration_limit_type.unwrap_or(0) {
+                    1 => (
+                        previous_leg.departure_epoch_seconds,
+                        current_leg.departure_epoch_seconds,
+                    ),
+                    2 => (
+                        previous_leg.arrival_epoch_seconds,
+                        current_leg.departure_epoch_seconds,
+                    ),
+                    3 => (
+                        previous_leg.arrival_epoch_seconds,
+                        current_leg.arrival_epoch_seconds,
+                    ),
+                    _ => (
+                        previous_leg.departure_epoch_seconds,
+                        current_leg.arrival_epoch_seconds,
+                    ),
+                };
+                if end.saturating_sub(start) > limit as u64 {
+                    return None;
+                }
+            }
+            if let Some(product_id) = &rule.fare_product_id {
+                // A transfer product the rider can't use means the rule doesn't apply to them.
+                let product = self
+                    .eligible_products(product_id, options)
+                    .into_iter()
+                    .min_by(|a, b| a.amount.total_cmp(&b.amount))?;
+                Some((rule, Some(product)))
+            } else {
+                Some((rule, None))
+            }
+        })
+    }
+
+    fn eligible_products(&self, fare_product_id: &str, options: &FareOptions) -> Vec<&FareProduct> {
+        self.products
+            .get(fare_product_id)
+            .into_iter()
+            .flatten()
+            .map(|idx| &self.fares.fare_products[*idx])
+            .filter(|product| {
+                let rider_category_ok = match (&options.rider_category, &product.rider_category_id)
+                {
+                    (_, None) => true,
+                    (Some(requested), Some(category)) => requested == category,
+                    (None, Some(category)) => self.default_rider_categories.contains(category),
+                };
+                let fare_media_ok = match (&options.fare_media, &product.fare_media_id) {
+                    (Some(requested), Some(media)) => requested == media,
+                    _ => true,
+                };
+                rider_category_ok && fare_media_ok
+            })
+            .collect()
+    }
+
+    fn areas_of(&self, stop: &FareStop) -> Vec<&String> {
+        // A station's areas apply to all of its stops.
+        [Some(&stop.stop_id), stop.parent_station.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter_map(|stop_id| self.stop_areas.get(stop_id))
+            .flatten()
+            .collect()
+    }
+
+    fn timeframes_at(&self, epoch_seconds: u64, timezone: &Tz) -> Vec<&String> {
+        let local = timezone.timestamp_opt(epoch_seconds as i64, 0).single();
+        let seconds_since_midnight = local
+            .map(|local| local.num_seconds_from_midnight())
+            .unwrap_or(0);
+        let date = local.map(|local| local.date_naive());
+        self.timeframes
+            .iter()
+            .filter(|(_, intervals)| {
+                intervals.iter().any(|(start, end, service_id)| {
+                    *start <= seconds_since_midnight
+                        && seconds_since_midnight < *end
+                        && self.service_runs_on(service_id, date)
+                })
+            })
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Whether the service of a timeframe runs on the local `date`.
+    fn service_runs_on(&self, service_id: &str, date: Option<NaiveDate>) -> bool {
+        match (&self.service_dates, date) {
+            (None, _) => true,
+            (Some(service_dates), Some(date)) => service_dates
+                .get(service_id)
+                .is_some_and(|dates| dates.contains(&date)),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+fn matches_or_empty(field: &Option<String>, matches: impl Fn(&String) -> bool) -> bool {
+    field.as_ref().map(matches).unwrap_or(true)
+}
+
+/// Keep the candidates whose field matches, or if there are none, the candidates whose field is empty.
+fn filter_with_default<R>(
+    candidates: Vec<&R>,
+    field: impl Fn(&R) -> Option<&String>,
+    matches: impl Fn(&String) -> bool,
+) -> Vec<&R> {
+    let exact: Vec<&R> = candidates
+        .iter()
+        .copied()
+        .filter(|candidate| field(candidate).is_some_and(&matches))
+        .collect();
+    if !exact.is_empty() {
+        exact
+    } else {
+        candidates
+            .into_iter()
+            .filter(|candidate| field(candidate).is_none())
+            .collect()
+    }
+}
+
+/// Parse an `HH:MM:SS` time into seconds since midnight.
+fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    let hours = parts.next()??;
+    let minutes = parts.next()??;
+    let seconds = parts.next()??;
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono_tz::Tz;
+
+    use crate::fares::{FareLeg, FareOptions, FareStop};
+
+    use super::{
+        FareLegRule, FareProduct, FareTransferRule, FaresV2, FaresV2Index, RouteNetwork, StopArea,
+    };
+
+    fn product(id: &str, amount: f64, rider_category_id: Option<&str>) -> FareProduct {
+        FareProduct {
+            fare_product_id: id.to_string(),
+            fare_product_name: None,
+            rider_category_id: rider_category_id.map(str::to_string),
+            fare_media_id: None,
+            amount,
+            currency: "USD".to_string(),
+        }
+    }
+
+    fn leg_rule(
+        network_id: Option<&str>,
+        from_area_id: Option<&str>,
+        product: &str,
+    ) -> FareLegRule {
+        FareLegRule {
+            leg_group_id: Some("regular".to_string()),
+            network_id: network_id.map(str::to_string),
+            from_area_id: from_area_id.map(str::to_string),
+            to_area_id: None,
+            from_timeframe_group_id: None,
+            to_timeframe_group_id: None,
+            fare_product_id: product.to_string(),
+            rule_priority: None,
+        }
+    }
+
+    fn leg(route_id: &str, from: &str, departure: u64, arrival: u64) -> FareLeg {
+        let stop = |stop_id: &str| FareStop {
+            stop_id: stop_id.to_string(),
+            parent_station: None,
+            zone_id: None,
+        };
+        FareLeg {
+            feed_id: "test".to_string(),
+            route_id: Some(route_id.to_string()),
+            from: stop(from),
+            to: stop("elsewhere"),
+            departure_epoch_seconds: departure,
+            arrival_epoch_seconds: arrival,
+        }
+    }
+
+    fn fares() -> FaresV2Index {
+        FaresV2Index::new(FaresV2 {
+            fare_products: vec![
+                product("bus", 2.75, None),
+                product("express", 3.50, None),
+                product("airport", 5.00, None),
+                product("reduced", 1.00, Some("senior")),
+            ],
+            fare_leg_rules: vec![
+                leg_rule(None, None, "bus"),
+                leg_rule(None, None, "reduced"),
+                leg_rule(Some("express"), None, "express"),
+                leg_rule(Some("express"), Some("airport"), "airport"),
+            ],
+            fare_transfer_rules: vec![FareTransferRule {
+                from_leg_group_id: Some("regular".to_string()),
+                to_leg_group_id: Some("regular".to_string()),
+                transfer_count: Some(1),
+                duration_limit: Some(7200),
+                duration_limit_type: Some(0),
+                fare_transfer_type: 0,
+                fare_product_id: None,
+            }],
+            stop_areas: vec![StopArea {
+                area_id: "airport".to_string(),
+                stop_id: "sea".to_string(),
+            }],
+            route_networks: vec![RouteNetwork {
+                network_id: "express".to_string(),
+                route_id: "X".to_string(),
+            }],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_leg_rules() {
+        let fares = fares();
+        let prices = fares.price_legs(&[leg("1", "a", 0, 600)], &FareOptions::default(), &Tz::UTC);
+        assert_eq!(prices[0].as_ref().unwrap().amount, 2.75);
+
+        let prices = fares.price_legs(&[leg("X", "a", 0, 600)], &FareOptions::default(), &Tz::UTC);
+        assert_eq!(prices[0].as_ref().unwrap().amount, 3.50);
+
+        let prices = fares.price_legs(
+            &[leg("X", "sea", 0, 600)],
+            &FareOptions::default(),
+            &Tz::UTC,
+        );
+        assert_eq!(prices[0].as_ref().unwrap().amount, 5.00);
+
+        let options = FareOptions {
+            rider_category: Some("senior".to_string()),
+            fare_media: None,
+            max_fare: None,
+        };
+        let prices = fares.price_legs(&[leg("1", "a", 0, 600)], &options, &Tz::UTC);
+        assert_eq!(prices[0].as_ref().unwrap().amount, 1.00);
+    }
+
+    #[test]
+    fn test_transfer_rules() {
+        let fares = fares();
+        let prices = fares.price_legs(
+            &[
+                leg("1", "a", 0, 600),
+                leg("2", "b", 900, 1800),
+                leg("3", "c", 2000, 2400),
+            ],
+            &FareOptions::default(),
+            &Tz::UTC,
+        );
+        let amounts: Vec<f64> = prices.iter().map(|p| p.as_ref().unwrap().amount).collect();
+        // One free transfer, then the transfer count is exhausted.
+        assert_eq!(amounts, vec![2.75, 0.0, 2.75]);
+        assert!(prices[1].as_ref().unwrap().transfer);
+
+        let prices = fares.price_legs(
+            &[leg("1", "a", 0, 600), leg("2", "b", 7000, 7800)],
+            &FareOptions::default(),
+            &Tz::UTC,
+        );
+        // Outside the duration limit.
+        assert_eq!(prices[1].as_ref().unwrap().amount, 2.75);
+    }
+}
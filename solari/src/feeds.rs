@@ -0,0 +1,457 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::Deserialize;
+
+use crate::dmfr::{Authorization, AuthorizationType, DistributedMobilityFeedRegistry};
+
+const REDACTED: &str = "REDACTED";
+/// Everything but the characters URLs leave unescaped, so that a key put in a path segment stays in that segment.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// The ID a feed from a DMFR file goes by once downloaded: its ID in the DMFR file, with anything that can't go in a file name replaced. It's the file name of the feed's GTFS zip without the extension, and the feed ID in the timetable built from it.
+pub fn local_feed_id(dmfr_feed_id: &str) -> String {
+    dmfr_feed_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_~.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Read every `.json` DMFR file in `dir`.
+pub fn read_registries(
+    dir: &Path,
+) -> Result<Vec<(PathBuf, DistributedMobilityFeedRegistry)>, anyhow::Error> {
+    let mut registries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let dmfr = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        registries.push((path, dmfr));
+    }
+    registries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(registries)
+}
+
+/// The index of the static GTFS feed that the feed at `feed_idx` of `dmfr` belongs with. That's the feed itself if it has a static URL, otherwise a static feed in the same file that one of its operators also runs.
+pub fn static_feed_index(dmfr: &DistributedMobilityFeedRegistry, feed_idx: usize) -> Option<usize> {
+    let feed = dmfr.feeds.get(feed_idx)?;
+    if feed.urls.static_current.is_some() {
+        return Some(feed_idx);
+    }
+    // An operator nested in a feed is associated with that feed as well as the ones it lists.
+    let operators = dmfr
+        .operators
+        .iter()
+        .map(|operator| (None, operator))
+        .chain(dmfr.feeds.iter().flat_map(|feed| {
+            feed.operators
+                .iter()
+                .map(move |operator| (Some(feed.id.as_str()), operator))
+        }));
+    for (parent_feed_id, operator) in operators {
+        let associated: HashSet<&str> = operator
+            .associated_feeds
+            .iter()
+            .filter_map(|associated| associated.feed_onestop_id.as_ref()?.as_str())
+            .chain(parent_feed_id)
+            .collect();
+        if !associated.contains(feed.id.as_str()) {
+            continue;
+        }
+        if let Some(static_idx) = dmfr.feeds.iter().position(|candidate| {
+            candidate.urls.static_current.is_some() && associated.contains(candidate.id.as_str())
+        }) {
+            return Some(static_idx);
+        }
+    }
+    None
+}
+
+/// Credentials for a feed that needs authorization, in the format of Transitland's secrets files.
+#[derive(Clone, Deserialize)]
+pub struct FeedSecret {
+    /// The Onestop ID of the feed in its DMFR file.
+    pub feed: String,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub replace_url: Option<String>,
+}
+
+impl std::fmt::Debug for FeedSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FeedSecret")
+            .field("feed", &self.feed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FeedSecret {
+    /// `text` with every credential in it replaced, so that it can be logged. Credentials are also replaced as they're escaped in a query parameter or path segment.
+    pub fn redact(&self, text: &str) -> String {
+        [&self.replace_url, &self.key, &self.password]
+            .into_iter()
+            .flatten()
+            .filter(|secret| !secret.is_empty())
+            .flat_map(|secret| {
+                [
+                    secret.clone(),
+                    form_urlencoded::byte_serialize(secret.as_bytes()).collect(),
+                    utf8_percent_encode(secret, PATH_SEGMENT).to_string(),
+                ]
+            })
+            .fold(text.to_string(), |text, secret| {
+                text.replace(secret.as_str(), REDACTED)
+            })
+    }
+}
+
+/// Credentials for feeds that need authorization, by the feeds' Onestop IDs.
+#[derive(Debug, Clone, Default)]
+pub struct FeedSecrets {
+    secrets: HashMap<String, FeedSecret>,
+}
+
+impl FeedSecrets {
+    /// Read a JSON array of secrets, like `[{"feed": "f-example~rt", "key": "..."}]`.
+    pub fn read(path: &Path) -> Result<FeedSecrets, anyhow::Error> {
+        let secrets: Vec<FeedSecret> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(FeedSecrets::new(secrets))
+    }
+
+    pub fn new(secrets: Vec<FeedSecret>) -> FeedSecrets {
+        FeedSecrets {
+            secrets: secrets
+                .into_iter()
+                .map(|secret| (secret.feed.clone(), secret))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, feed_id: &str) -> Option<&FeedSecret> {
+        self.secrets.get(feed_id)
+    }
+}
+
+/// Why a request for a feed couldn't be authorized.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AuthorizationError {
+    #[error("feed {feed_id} needs authorization, but there's no secret for it")]
+    MissingSecret { feed_id: String },
+    #[error("feed {feed_id} authorizes with {type_:?}, but its secret has no {field}")]
+    IncompleteSecret {
+        feed_id: String,
+        type_: AuthorizationType,
+        field: &'static str,
+    },
+    #[error("feed {feed_id} authorizes with {type_:?}, but its authorization has no param_name")]
+    MissingParamName {
+        feed_id: String,
+        type_: AuthorizationType,
+    },
+    #[error("feed {feed_id} authorizes with a path segment, but its URL has no {{}} to put it in")]
+    MissingPathSegment { feed_id: String },
+    #[error("feed {feed_id} has an invalid URL {url}")]
+    InvalidUrl { feed_id: String, url: String },
+}
+
+/// A request for a feed, with its credentials applied.
+#[derive(Clone)]
+pub struct AuthorizedRequest {
+    url: String,
+    header: Option<(String, String)>,
+    basic_auth: Option<(String, Option<String>)>,
+    secret: Option<FeedSecret>,
+}
+
+impl AuthorizedRequest {
+    /// Apply `secret` to a request for `url` the way the DMFR `authorization` of feed `feed_id` says to.
+    pub fn new(
+        feed_id: &str,
+        url: &str,
+        authorization: Option<&Authorization>,
+        secret: Option<&FeedSecret>,
+    ) -> Result<AuthorizedRequest, AuthorizationError> {
+        let mut request = AuthorizedRequest {
+            url: url.to_string(),
+            header: None,
+            basic_auth: None,
+            secret: None,
+        };
+        let authorization = if let Some(authorization) = authorization {
+            authorization
+        } else {
+            return Ok(request);
+        };
+        let secret = secret.ok_or_else(|| AuthorizationError::MissingSecret {
+            feed_id: feed_id.to_string(),
+        })?;
+        let type_ = authorization.type_;
+        let incomplete = |field| AuthorizationError::IncompleteSecret {
+            feed_id: feed_id.to_string(),
+            type_,
+            field,
+        };
+        let param_name = || {
+            authorization
+                .param_name
+                .clone()
+                .ok_or_else(|| AuthorizationError::MissingParamName {
+                    feed_id: feed_id.to_string(),
+                    type_,
+                })
+        };
+        match type_ {
+            AuthorizationType::Header => {
+                let key = secret.key.clone().ok_or_else(|| incomplete("key"))?;
+                request.header = Some((param_name()?, key));
+            }
+            AuthorizationType::BasicAuth => {
+                let username = secret
+                    .username
+                    .clone()
+                    .ok_or_else(|| incomplete("username"))?;
+                request.basic_auth = Some((username, secret.password.clone()));
+            }
+            AuthorizationType::QueryParam => {
+                let key = secret.key.as_ref().ok_or_else(|| incomplete("key"))?;
+                let mut parsed =
+                    Url::parse(url).map_err(|_err| AuthorizationError::InvalidUrl {
+                        feed_id: feed_id.to_string(),
+                        url: url.to_string(),
+                    })?;
+                parsed.query_pairs_mut().append_pair(&param_name()?, key);
+                request.url = parsed.to_string();
+            }
+            AuthorizationType::PathSegment => {
+                let key = secret.key.as_ref().ok_or_else(|| incomplete("key"))?;
+                if !url.contains("{}") {
+                    return Err(AuthorizationError::MissingPathSegment {
+                        feed_id: feed_id.to_string(),
+                    });
+                }
+                request.url =
+                    url.replace("{}", &utf8_percent_encode(key, PATH_SEGMENT).to_string());
+            }
+            AuthorizationType::ReplaceUrl => {
+                request.url = secret
+                    .replace_url
+                    .clone()
+                    .ok_or_else(|| incomplete("replace_url"))?;
+            }
+        }
+        request.secret = Some(secret.clone());
+        Ok(request)
+    }
+
+    /// The URL to request, credentials and all.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// `text` with the request's credentials replaced, so that it can be logged.
+    pub fn redact(&self, text: &str) -> String {
+        match &self.secret {
+            Some(secret) => secret.redact(text),
+            None => text.to_string(),
+        }
+    }
+
+    /// A GET request for the feed from `client`.
+    pub fn get(&self, client: &Client) -> RequestBuilder {
+        let mut builder = client.get(&self.url);
+        if let Some((name, value)) = &self.header {
+            builder = builder.header(name, value);
+        }
+        if let Some((username, password)) = &self.basic_auth {
+            builder = builder.basic_auth(username, password.as_ref());
+        }
+        builder
+    }
+}
+
+/// Shows the URL with its credentials redacted.
+impl std::fmt::Display for AuthorizedRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.redact(&self.url))
+    }
+}
+
+impl std::fmt::Debug for AuthorizedRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthorizedRequest")
+            .field("url", &self.to_string())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dmfr::{Authorization, AuthorizationType};
+
+    use super::{AuthorizationError, AuthorizedRequest, FeedSecret};
+
+    fn authorization(type_: AuthorizationType, param_name: Option<&str>) -> Authorization {
+        Authorization {
+            info_url: None,
+            param_name: param_name.map(str::to_string),
+            type_,
+        }
+    }
+
+    fn secret() -> FeedSecret {
+        FeedSecret {
+            feed: "f-test~rt".to_string(),
+            key: Some("s3cret".to_string()),
+            username: Some("rider".to_string()),
+            password: Some("hunter2".to_string()),
+            replace_url: Some("https://example.com/private/s3cret/feed.pb".to_string()),
+        }
+    }
+
+    #[test]
+    fn applies_credentials_by_type() {
+        let url = "https://example.com/feed.pb?format=pb";
+        let request = AuthorizedRequest::new(
+            "f-test~rt",
+            url,
+            Some(&authorization(
+                AuthorizationType::QueryParam,
+                Some("api_key"),
+            )),
+            Some(&secret()),
+        )
+        .unwrap();
+        assert_eq!(
+            request.url(),
+            "https://example.com/feed.pb?format=pb&api_key=s3cret"
+        );
+        assert_eq!(
+            request.to_string(),
+            "https://example.com/feed.pb?format=pb&api_key=REDACTED"
+        );
+
+        let request = AuthorizedRequest::new(
+            "f-test~rt",
+            "https://example.com/{}/feed.pb",
+            Some(&authorization(AuthorizationType::PathSegment, None)),
+            Some(&secret()),
+        )
+        .unwrap();
+        assert_eq!(request.url(), "https://example.com/s3cret/feed.pb");
+
+        let request = AuthorizedRequest::new(
+            "f-test~rt",
+            url,
+            Some(&authorization(AuthorizationType::ReplaceUrl, None)),
+            Some(&secret()),
+        )
+        .unwrap();
+        assert_eq!(request.url(), "https://example.com/private/s3cret/feed.pb");
+        assert_eq!(request.to_string(), "REDACTED");
+
+        let request = AuthorizedRequest::new(
+            "f-test~rt",
+            url,
+            Some(&authorization(AuthorizationType::Header, Some("x-api-key"))),
+            Some(&secret()),
+        )
+        .unwrap();
+        assert_eq!(request.url(), url);
+        assert_eq!(
+            request.header,
+            Some(("x-api-key".to_string(), "s3cret".to_string()))
+        );
+    }
+
+    #[test]
+    fn escapes_and_redacts_keys_with_reserved_characters() {
+        let secret = FeedSecret {
+            key: Some("a+b/c= d".to_string()),
+            ..secret()
+        };
+        let request = AuthorizedRequest::new(
+            "f-test~rt",
+            "https://example.com/feed.pb?format=pb",
+            Some(&authorization(
+                AuthorizationType::QueryParam,
+                Some("api_key"),
+            )),
+            Some(&secret),
+        )
+        .unwrap();
+        assert_eq!(
+            request.url(),
+            "https://example.com/feed.pb?format=pb&api_key=a%2Bb%2Fc%3D+d"
+        );
+        assert_eq!(
+            request.to_string(),
+            "https://example.com/feed.pb?format=pb&api_key=REDACTED"
+        );
+
+        let request = AuthorizedRequest::new(
+            "f-test~rt",
+            "https://example.com/{}/feed.pb",
+            Some(&authorization(AuthorizationType::PathSegment, None)),
+            Some(&secret),
+        )
+        .unwrap();
+        assert_eq!(
+            request.url(),
+            "https://example.com/a%2Bb%2Fc%3D%20d/feed.pb"
+        );
+        assert_eq!(request.to_string(), "https://example.com/REDACTED/feed.pb");
+    }
+
+    #[test]
+    fn reports_missing_credentials() {
+        let url = "https://example.com/feed.pb";
+        assert_eq!(
+            AuthorizedRequest::new(
+                "f-test~rt",
+                url,
+                Some(&authorization(AuthorizationType::Header, Some("x-api-key"))),
+                None,
+            )
+            .unwrap_err(),
+            AuthorizationError::MissingSecret {
+                feed_id: "f-test~rt".to_string()
+            }
+        );
+        assert_eq!(
+            AuthorizedRequest::new(
+                "f-test~rt",
+                url,
+                Some(&authorization(AuthorizationType::Header, None)),
+                Some(&secret()),
+            )
+            .unwrap_err(),
+            AuthorizationError::MissingParamName {
+                feed_id: "f-test~rt".to_string(),
+                type_: AuthorizationType::Header
+            }
+        );
+        assert!(AuthorizedRequest::new("f-test~rt", url, None, None).is_ok());
+    }
+}
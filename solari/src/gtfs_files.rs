@@ -0,0 +1,52 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+
+/// Read every record of a GTFS file that `gtfs_structures` doesn't parse, from either a zipped feed or a directory. Returns `Ok(None)` if the feed doesn't contain the file at all, which for optional files is not an error.
+pub(crate) fn read_optional<T: DeserializeOwned>(
+    feed_path: &Path,
+    file_name: &str,
+) -> Result<Option<Vec<T>>, anyhow::Error> {
+    if feed_path.is_dir() {
+        let path = feed_path.join(file_name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+        return Ok(Some(read_records(BufReader::new(file), file_name)?));
+    }
+
+    let mut archive = zip::ZipArchive::new(File::open(feed_path)?)
+        .with_context(|| format!("Failed to open {:?} as a zip archive", feed_path))?;
+    // Some feeds nest their files in a directory inside the archive, so match on the file name alone.
+    let suffix = format!("/{file_name}");
+    let entry_name = archive
+        .file_names()
+        .find(|name| *name == file_name || name.ends_with(&suffix))
+        .map(|name| name.to_string());
+    let entry_name = if let Some(entry_name) = entry_name {
+        entry_name
+    } else {
+        return Ok(None);
+    };
+    let entry = archive.by_name(&entry_name)?;
+    Ok(Some(read_records(entry, file_name)?))
+}
+
+fn read_records<T: DeserializeOwned, R: Read>(
+    reader: R,
+    file_name: &str,
+) -> Result<Vec<T>, anyhow::Error> {
+    csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(reader)
+        .deserialize()
+        .collect::<Result<Vec<T>, csv::Error>>()
+        .with_context(|| format!("Failed to parse {file_name}"))
+}
@@ -1,5 +1,15 @@
 pub mod api;
 pub mod dmfr;
+pub mod download;
+pub mod fares;
+pub mod feeds;
+mod gtfs_files;
 pub mod raptor;
+pub mod realtime;
 pub mod route;
+pub mod routers;
 mod spatial;
+pub mod stations;
+#[cfg(test)]
+#[path = "../tests/support/mod.rs"]
+mod test_support;
@@ -1,37 +1,57 @@
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
-    f32, u32,
+    f32,
+    path::PathBuf,
+    u32,
 };
 
 use anyhow::bail;
-use chrono::{offset::LocalResult, DateTime, Days, Local, NaiveTime, TimeDelta, TimeZone};
+use chrono::{offset::LocalResult, DateTime, Days, NaiveDate, NaiveTime, TimeDelta, TimeZone};
 use chrono_tz::Tz;
-use gtfs_structures::{Agency, Gtfs, StopTime};
+use gtfs_structures::{
+    Agency, ExactTimes, Frequency, Gtfs, LocationType, PickupDropOffType, StopTime,
+};
 use log::{debug, warn};
 use rstar::RTree;
 use s2::{cellid::CellID, latlng::LatLng};
+use solari_geomath::{haversine_distance_meters, lat_lng_to_cartesian};
+use solari_transfers::TransferProvider;
 
 use crate::{
-    raptor::timetable::{Route, RouteStop, Stop, StopRoute, Transfer, Trip, TripStopTime},
-    spatial::IndexedStop,
+    fares::{v1::FaresV1, v2::FaresV2, FeedFares},
+    raptor::timetable::{
+        service_day_words, Headway, Route, RouteStop, StationEntrance, Stop, StopRoute, Time,
+        Transfer, Trip, TripStopTime,
+    },
+    spatial::{IndexedStop, WALK_SPEED_MM_PER_SECOND},
+    stations::StationGraph,
 };
 
-use super::{ShapeCoordinate, Timetable, TripMetadata};
+use super::{calculate_all_transfers, ServiceWindow, ShapeCoordinate, Timetable, TripMetadata};
 
+/// A timetable held entirely in memory. Useful for small feeds, embedding, and tests, where writing out a memory-mapped timetable isn't worth the trouble.
 #[derive(Debug, Clone)]
 #[repr(C)]
-pub(crate) struct InMemoryTimetable {
+pub struct InMemoryTimetable {
     routes: Vec<Route>,
     route_stops: Vec<RouteStop>,
     route_trips: Vec<Trip>,
     stops: Vec<Stop>,
     stop_routes: Vec<StopRoute>,
     trip_stop_times: Vec<TripStopTime>,
+    route_service_days: Vec<Time>,
+    trip_service_days: Vec<u64>,
+    headways: Vec<Headway>,
     transfer_index: Vec<usize>,
     transfers: Vec<Transfer>,
+    pathway_transfers: Vec<Transfer>,
+    entrances: Vec<StationEntrance>,
     trip_metadata_map: HashMap<Trip, TripMetadata>,
     stop_metadata_map: HashMap<Stop, gtfs_structures::Stop>,
     route_shapes: HashMap<Route, Option<Vec<ShapeCoordinate>>>,
+    feed_fares: HashMap<String, FeedFares>,
+    service_window: Option<ServiceWindow>,
+    rtree: RTree<IndexedStop>,
 }
 
 impl<'a> Timetable<'a> for InMemoryTimetable {
@@ -85,6 +105,21 @@ impl<'a> Timetable<'a> for InMemoryTimetable {
         &self.trip_stop_times
     }
 
+    #[inline]
+    fn route_service_days(&'a self) -> &'a [Time] {
+        &self.route_service_days
+    }
+
+    #[inline]
+    fn trip_service_days(&'a self) -> &'a [u64] {
+        &self.trip_service_days
+    }
+
+    #[inline]
+    fn headways(&'a self) -> &'a [Headway] {
+        &self.headways
+    }
+
     #[inline]
     fn transfers(&'a self) -> &'a [Transfer] {
         &self.transfers
@@ -95,6 +130,16 @@ impl<'a> Timetable<'a> for InMemoryTimetable {
         &self.transfer_index
     }
 
+    #[inline]
+    fn pathway_transfers(&'a self) -> &'a [Transfer] {
+        &self.pathway_transfers
+    }
+
+    #[inline]
+    fn entrances(&'a self) -> &'a [StationEntrance] {
+        &self.entrances
+    }
+
     fn stop_metadata(&'a self, stop: &Stop) -> gtfs_structures::Stop {
         self.stop_metadata_map[stop].clone()
     }
@@ -104,16 +149,28 @@ impl<'a> Timetable<'a> for InMemoryTimetable {
     }
 
     fn stop_index_copy(&'a self) -> RTree<IndexedStop> {
-        RTree::new()
+        self.rtree.clone()
     }
 
-    fn nearest_stops(&'a self, _lat: f64, _lng: f64, _n: usize) -> Vec<(&'a Stop, f64)> {
-        Vec::new()
+    fn nearest_stops(&'a self, lat: f64, lng: f64, n: usize) -> Vec<(&'a Stop, f64)> {
+        self.rtree
+            .nearest_neighbor_iter_with_distance_2(&lat_lng_to_cartesian(lat, lng))
+            .take(n)
+            .map(|(stop, dist_sq)| (self.stop(stop.id), dist_sq.sqrt()))
+            .collect()
     }
 
     fn route_shape(&'a self, route: &Route) -> Option<Vec<ShapeCoordinate>> {
         self.route_shapes[route].clone()
     }
+
+    fn feed_fares(&'a self, feed_id: &str) -> Option<FeedFares> {
+        self.feed_fares.get(feed_id).cloned()
+    }
+
+    fn service_window(&'a self) -> Option<ServiceWindow> {
+        self.service_window
+    }
 }
 
 impl<'a> InMemoryTimetable {
@@ -125,11 +182,34 @@ impl<'a> InMemoryTimetable {
             stops: vec![],
             stop_routes: vec![],
             trip_stop_times: vec![],
+            route_service_days: vec![],
+            trip_service_days: vec![],
+            headways: vec![],
             transfer_index: vec![],
             transfers: vec![],
+            pathway_transfers: vec![],
+            entrances: vec![],
             trip_metadata_map: HashMap::new(),
             stop_metadata_map: HashMap::new(),
             route_shapes: HashMap::new(),
+            feed_fares: HashMap::new(),
+            service_window: None,
+            rtree: RTree::new(),
+        }
+    }
+
+    pub(crate) fn all_feed_fares(&self) -> &HashMap<String, FeedFares> {
+        &self.feed_fares
+    }
+
+    /// Replace the transfer table with walking transfers between nearby stops, as calculated by `transfer_provider`.
+    pub fn calculate_transfers(&mut self, transfer_provider: &dyn TransferProvider) {
+        let transfers = calculate_all_transfers(&*self, transfer_provider, None);
+        self.transfer_index = Vec::with_capacity(transfers.len());
+        self.transfers = Vec::with_capacity(transfers.iter().map(|t| t.len()).sum());
+        for stop_transfers in transfers {
+            self.transfer_index.push(self.transfers.len());
+            self.transfers.extend(stop_transfers);
         }
     }
 }
@@ -140,8 +220,100 @@ pub enum InMemoryTimetableBuilderError {
     ParseError(String),
 }
 
+/// A problem with a feed that was worked around by leaving some of its data out, rather than failing the whole build.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum FeedIssue {
+    #[error("route {route_id:?} doesn't exist, skipping its trips")]
+    UnknownRoute { route_id: String },
+    #[error(
+        "route {route_id:?} has no agency ID and the feed has several agencies, skipping its trips"
+    )]
+    MissingAgencyId { route_id: String },
+    #[error("no agency with ID {agency_id:?}, skipping its trips")]
+    UnknownAgency { agency_id: String },
+    #[error("agency {agency_id:?} has an invalid timezone {timezone:?}, skipping its trips")]
+    InvalidAgencyTimezone { agency_id: String, timezone: String },
+    #[error("stop {stop_id:?} has an invalid timezone {timezone:?}, using the feed's instead")]
+    InvalidStopTimezone { stop_id: String, timezone: String },
+    #[error("stop {stop_id:?} has no coordinates, and neither do its parents, leaving it out")]
+    UnlocatableStop { stop_id: String },
+    #[error("trip {trip_id:?} has no time at its first or last stop, skipping it")]
+    UntimedTripEnd { trip_id: String },
+}
+
+/// What was left out of a feed while building its timetable, and why.
+#[derive(Debug, Clone, Default)]
+pub struct FeedReport {
+    pub issues: Vec<FeedIssue>,
+    /// Trips that couldn't be added because of one of the issues.
+    pub skipped_trips: usize,
+    pub total_trips: usize,
+}
+
+impl FeedReport {
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn record(&mut self, issue: FeedIssue) {
+        if !self.issues.contains(&issue) {
+            self.issues.push(issue);
+        }
+    }
+}
+
+/// A stop's coordinates, taken from the closest ancestor that has them. Generic nodes and boarding areas are allowed to leave them out.
+fn stop_location(gtfs: &Gtfs, stop: &gtfs_structures::Stop) -> Option<(f64, f64)> {
+    let mut current = stop;
+    // Station hierarchies are at most three levels deep, so anything longer is a cycle.
+    for _ in 0..4 {
+        if let (Some(lat), Some(lng)) = (current.latitude, current.longitude) {
+            return Some((lat, lng));
+        }
+        current = gtfs.get_stop(current.parent_station.as_ref()?).ok()?;
+    }
+    None
+}
+
+/// The agency that runs a route. Routes can leave out `agency_id` when the feed only has one agency.
+pub(crate) fn route_agency<'g>(
+    gtfs: &'g Gtfs,
+    route: &gtfs_structures::Route,
+) -> Result<&'g Agency, FeedIssue> {
+    let only_agency = if gtfs.agencies.len() == 1 {
+        gtfs.agencies.first()
+    } else {
+        None
+    };
+    match &route.agency_id {
+        Some(agency_id) => gtfs
+            .agencies
+            .iter()
+            .find(|agency| agency.id.as_ref() == Some(agency_id))
+            .or(only_agency)
+            .ok_or_else(|| FeedIssue::UnknownAgency {
+                agency_id: agency_id.clone(),
+            }),
+        None => only_agency.ok_or_else(|| FeedIssue::MissingAgencyId {
+            route_id: route.id.clone(),
+        }),
+    }
+}
+
+/// Options that control how a GTFS feed is turned into a timetable.
+#[derive(Debug, Clone, Default)]
+pub struct TimetableBuildOptions {
+    /// Identifies the feed within a combined timetable, e.g. when looking up its fare rules.
+    pub feed_id: String,
+    /// Where the feed was read from, either a zip file or a directory. Needed for files `gtfs_structures` doesn't parse, like GTFS Fares v2; if absent they're skipped.
+    pub feed_path: Option<PathBuf>,
+    /// The service days to include trips for. Defaults to yesterday plus two weeks.
+    pub service_window: ServiceWindow,
+}
+
 #[derive(Debug)]
 pub struct InMemoryTimetableBuilder {
+    options: TimetableBuildOptions,
     next_stop_id: usize,
     next_stop_route_id: usize,
     next_route_id: usize,
@@ -153,6 +325,9 @@ pub struct InMemoryTimetableBuilder {
     stop_table: BTreeMap<StopKey, StopData>,
     route_index: BTreeMap<RouteKey, RouteId>,
     route_table: BTreeMap<RouteId, RouteData>,
+    /// Coordinates of every stop in the feed that has them, directly or through a parent.
+    stop_locations: HashMap<String, (f64, f64)>,
+    report: FeedReport,
 }
 
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Ord)]
@@ -163,25 +338,21 @@ struct RouteId(usize);
 
 #[derive(Debug, Clone)]
 struct TripInternal {
-    service_day_start: DateTime<chrono_tz::Tz>,
+    /// The days of the service window the trip runs on, counting from the window's start date.
+    service_days: Vec<usize>,
     stop_times: Vec<StopTime>,
     gtfs_trip_id: String,
+    /// The windows of headway-based service this trip is a template for. If there are any, the trip doesn't run at its own stop times.
+    headways: Vec<Frequency>,
 }
 
 impl TripInternal {
-    fn get_departure(&self) -> Option<DateTime<chrono_tz::Tz>> {
-        let first_departure_time = self
-            .stop_times
+    /// Seconds after the start of the service day that the trip departs its first stop. Trips without a time there are skipped when the feed is read, so there always is one.
+    fn get_departure(&self) -> u32 {
+        self.stop_times
             .first()
-            .map(|stop_time| stop_time.departure_time)??;
-        if first_departure_time == u32::MAX {
-            return None;
-        }
-        Some(
-            self.service_day_start
-                .checked_add_signed(TimeDelta::seconds(first_departure_time as i64))
-                .expect("Failed to add departure time to service day start"),
-        )
+            .and_then(|stop_time| stop_time.departure_time)
+            .expect("trips have a time at their first stop")
     }
 }
 
@@ -197,6 +368,8 @@ struct RouteData {
     gtfs_route_id: String,
     shape: Option<Vec<ShapeCoordinate>>,
     trip_list: Vec<TripInternal>,
+    /// The timezone the route's service days start in.
+    timezone: Tz,
     stops: Vec<StopId>,
     shape_distances: Vec<f32>,
     agency_name: Option<String>,
@@ -214,9 +387,119 @@ struct StopData {
     stop_routes: BTreeSet<RouteId>,
 }
 
+/// GTFS only requires times at timepoints, so fill in the stops between them by interpolating along the distance travelled. The first and last stops must be timepoints, see [`FeedIssue::UntimedTripEnd`], so every stop ends up with a time.
+fn interpolate_stop_times(
+    stop_times: &[StopTime],
+    stop_locations: &HashMap<String, (f64, f64)>,
+) -> Vec<StopTime> {
+    let mut stop_times = stop_times.to_vec();
+    // A stop with only one of its times given arrives and departs at the same time.
+    for stop_time in &mut stop_times {
+        stop_time.arrival_time = stop_time.arrival_time.or(stop_time.departure_time);
+        stop_time.departure_time = stop_time.departure_time.or(stop_time.arrival_time);
+    }
+    let timepoints: Vec<usize> = (0..stop_times.len())
+        .filter(|seq| stop_times[*seq].departure_time.is_some())
+        .collect();
+    for pair in timepoints.windows(2) {
+        let (from, to) = (pair[0], pair[1]);
+        if to == from + 1 {
+            continue;
+        }
+        let distances = distances_along_trip(&stop_times[from..=to], stop_locations);
+        let total = distances[distances.len() - 1];
+        let start = stop_times[from].departure_time.unwrap();
+        let duration = stop_times[to].arrival_time.unwrap().saturating_sub(start);
+        for seq in from + 1..to {
+            let fraction = if total > 0.0 {
+                (distances[seq - from] / total).clamp(0.0, 1.0)
+            } else {
+                (seq - from) as f64 / (to - from) as f64
+            };
+            let time = start + (duration as f64 * fraction).round() as u32;
+            stop_times[seq].arrival_time = Some(time);
+            stop_times[seq].departure_time = Some(time);
+        }
+    }
+    stop_times
+}
+
+/// How far along the trip each stop is from the first, using `shape_dist_traveled` if every stop has it and the straight-line distance between stops otherwise. Stops are located as in `stop_locations`, so those placed by their parent station count too.
+fn distances_along_trip(
+    stop_times: &[StopTime],
+    stop_locations: &HashMap<String, (f64, f64)>,
+) -> Vec<f64> {
+    if let Some(shape_distances) = stop_times
+        .iter()
+        .map(|stop_time| {
+            stop_time
+                .shape_dist_traveled
+                .map(|distance| distance as f64)
+        })
+        .collect::<Option<Vec<f64>>>()
+    {
+        return shape_distances
+            .iter()
+            .map(|distance| distance - shape_distances[0])
+            .collect();
+    }
+    let mut distance = 0f64;
+    let mut distances = vec![distance];
+    for pair in stop_times.windows(2) {
+        if let (Some((from_lat, from_lng)), Some((to_lat, to_lng))) = (
+            stop_locations.get(&pair[0].stop.id),
+            stop_locations.get(&pair[1].stop.id),
+        ) {
+            distance += haversine_distance_meters(*from_lat, *from_lng, *to_lat, *to_lng);
+        }
+        distances.push(distance);
+    }
+    distances
+}
+
+/// The trip's stop times, moved `shift` seconds later (or earlier, if negative).
+fn shift_stop_times(stop_times: &[StopTime], shift: i64) -> Vec<StopTime> {
+    let shift_time = |time: Option<u32>| time.map(|time| (time as i64 + shift).max(0) as u32);
+    stop_times
+        .iter()
+        .map(|stop_time| StopTime {
+            arrival_time: shift_time(stop_time.arrival_time),
+            departure_time: shift_time(stop_time.departure_time),
+            ..stop_time.clone()
+        })
+        .collect()
+}
+
+/// The start of a service day is defined as noon minus 12 hours, which differs from midnight on days when daylight saving time begins or ends.
+pub(crate) fn service_day_start(tz: &Tz, date: NaiveDate) -> Result<DateTime<Tz>, anyhow::Error> {
+    let noon_service_day = match tz.from_local_datetime(&date.and_time(
+        NaiveTime::from_hms_opt(12, 0, 0).expect("Failed to add 12 hours to service day"),
+    )) {
+        LocalResult::Single(date_time) => date_time,
+        LocalResult::Ambiguous(a, _b) => {
+            // Pick one and call it good.
+            a
+        }
+        LocalResult::None => {
+            bail!("Gap in time (at noon? shouldn't be possible), can't determine service day start")
+        }
+    };
+    Ok(noon_service_day
+        .checked_sub_signed(TimeDelta::hours(12))
+        .expect("Failed to subtract 12 hours from noon on the given service day."))
+}
+
 impl<'a> InMemoryTimetableBuilder {
     pub fn new(gtfs: &Gtfs) -> Result<Self, anyhow::Error> {
+        Self::with_options(gtfs, TimetableBuildOptions::default())
+    }
+
+    pub fn with_options(
+        gtfs: &Gtfs,
+        options: TimetableBuildOptions,
+    ) -> Result<Self, anyhow::Error> {
         let mut builder = InMemoryTimetableBuilder {
+            options,
             next_stop_id: 0,
             next_stop_route_id: 0,
             next_route_id: 0,
@@ -227,11 +510,25 @@ impl<'a> InMemoryTimetableBuilder {
             stop_table: BTreeMap::new(),
             route_index: BTreeMap::new(),
             route_table: BTreeMap::new(),
+            stop_locations: HashMap::new(),
+            report: FeedReport::default(),
         };
         builder.preprocess_gtfs(gtfs)?;
         Ok(builder)
     }
 
+    /// Problems found in the feed, each of which caused some of its data to be left out or replaced.
+    pub fn report(&self) -> &FeedReport {
+        &self.report
+    }
+
+    /// Finish building and compute the transfers between nearby stops, producing a timetable that can be routed on directly.
+    pub fn build(self, transfer_provider: &dyn TransferProvider) -> InMemoryTimetable {
+        let mut timetable = self.timetable;
+        timetable.calculate_transfers(transfer_provider);
+        timetable
+    }
+
     fn lookup_stop_data(&'a mut self, gtfs_id: &String) -> &'a mut StopData {
         let key = StopKey {
             gtfs_id: gtfs_id.clone(),
@@ -257,10 +554,10 @@ impl<'a> InMemoryTimetableBuilder {
         &'a mut self,
         gtfs: &Gtfs,
         trip: &gtfs_structures::Trip,
+        stop_times: &[StopTime],
     ) -> &'a mut RouteData {
         // Solari defines a "route" as something distinct from a GTFS route, because in GTFS there's no guarantee that a route always has the same stops in the same order. In fact, for bidirectional lines, the same route is usually used for trips in both directions, which violates RAPTOR's assumptions. To deal with this, we define a "route" as a set of trips that all visit the same stops in the same order and have the same GTFS route ID in the same GTFS feed.
-        let trip_stop_ids: Vec<String> = trip
-            .stop_times
+        let trip_stop_ids: Vec<String> = stop_times
             .iter()
             .map(|stop_time| stop_time.stop.id.clone())
             .collect();
@@ -296,30 +593,24 @@ impl<'a> InMemoryTimetableBuilder {
             };
 
             // Determine the distance along the travel path of each stop.
-            let shape_distances: Vec<f32> = trip
-                .stop_times
+            let shape_distances: Vec<f32> = stop_times
                 .iter()
                 .map(|time| time.shape_dist_traveled.unwrap_or(f32::NAN))
                 .collect();
 
-            let stops = trip
-                .stop_times
+            let stops = stop_times
                 .iter()
                 .map(|stop_time| self.lookup_stop_data(&stop_time.stop.id).id.clone())
                 .collect();
 
             // Determine the human-readable agency name.
-            let agency_name = gtfs
-                .agencies
-                .iter()
-                .find(|agency| {
-                    agency.id
-                        == gtfs
-                            .get_route(&gtfs_route_id)
-                            .expect("Trip's route ID not found in route table.")
-                            .agency_id
-                })
-                .map(|agency| agency.name.clone());
+            let agency_name = route_agency(
+                gtfs,
+                gtfs.get_route(&gtfs_route_id)
+                    .expect("Trip's route ID not found in route table."),
+            )
+            .ok()
+            .map(|agency| agency.name.clone());
 
             self.route_index.insert(route_key.clone(), route_id);
             self.route_table.insert(
@@ -329,6 +620,7 @@ impl<'a> InMemoryTimetableBuilder {
                     gtfs_route_id,
                     shape,
                     trip_list: vec![],
+                    timezone: Tz::UTC,
                     stops,
                     shape_distances,
                     agency_name,
@@ -346,72 +638,75 @@ impl<'a> InMemoryTimetableBuilder {
     }
 
     fn preprocess_gtfs(&mut self, gtfs: &Gtfs) -> Result<(), anyhow::Error> {
-        let agencies: HashMap<String, &Agency> = gtfs
-            .agencies
-            .iter()
-            .map(|agency| (agency.id.clone().unwrap_or(String::new()), agency))
-            .collect();
-        let start_date = Local::now().date_naive().pred_opt().unwrap();
+        let service_window = self.options.service_window;
+        let start_date = service_window.start_date;
+        self.timetable.service_window = Some(service_window);
+
+        self.report.total_trips = gtfs.trips.len();
+
+        for (stop_id, stop) in &gtfs.stops {
+            if let Some(location) = stop_location(gtfs, stop) {
+                self.stop_locations.insert(stop_id.clone(), location);
+            } else {
+                self.report.record(FeedIssue::UnlocatableStop {
+                    stop_id: stop_id.clone(),
+                });
+            }
+        }
 
         // First things first, go through every trip in the feed.
         for (gtfs_trip_id, trip) in &gtfs.trips {
+            // Stops that can't be placed on a map can't be routed to, so trips act as if they weren't there.
+            let stop_times: Vec<StopTime> = trip
+                .stop_times
+                .iter()
+                .filter(|stop_time| self.stop_locations.contains_key(&stop_time.stop.id))
+                .cloned()
+                .collect();
+            // GTFS requires times at both ends of a trip. Without them there's nothing to interpolate the stops outside the timepoints from, or to order the trip by.
+            let timed = |stop_time: Option<&StopTime>| {
+                stop_time.is_some_and(|stop_time| {
+                    stop_time.arrival_time.is_some() || stop_time.departure_time.is_some()
+                })
+            };
+            if !timed(stop_times.first()) || !timed(stop_times.last()) {
+                self.report.record(FeedIssue::UntimedTripEnd {
+                    trip_id: gtfs_trip_id.clone(),
+                });
+                self.report.skipped_trips += 1;
+                continue;
+            }
             {
-                let agency_id = if let Some(agency_id) = gtfs
-                    .routes
-                    .get(&trip.route_id)
-                    .map(|route| route.agency_id.clone())
-                    .flatten()
-                {
-                    agency_id
-                } else {
-                    continue;
-                };
-                let tz = self
-                    .trip_agency_timezone(&agencies, &agency_id)
-                    .expect("Failed to parse timezone");
-                let route_data = self.lookup_route_data(gtfs, trip);
-                let trip_days = gtfs.trip_days(&trip.service_id, start_date.clone());
-                for day in trip_days {
-                    if day <= 14 {
-                        let date_time_offset = start_date
-                            .checked_add_days(Days::new(day as u64))
-                            .expect(&format!(
-                                "Failed to add {day} days to date {:?}",
-                                start_date
-                            ));
-                        // The start of a service day is defined as noon minus 12 hours.
-                        let noon_service_day = match tz.from_local_datetime(
-                            &date_time_offset.and_time(
-                                NaiveTime::from_hms_opt(12, 0, 0)
-                                    .expect("Failed to add 12 hours to service day"),
-                            ),
-                        ) {
-                            LocalResult::Single(date_time) => date_time,
-                            LocalResult::Ambiguous(a, _b) => {
-                                // Pick one and call it good.
-                                a
-                            }
-                            LocalResult::None => {
-                                bail!("Gap in time (at noon? shouldn't be possible), can't determine service day start")
-                            }
-                        };
-                        let service_day_start = noon_service_day
-                            .checked_sub_signed(TimeDelta::hours(12))
-                            .expect(
-                                "Failed to subtract 12 hours from noon on the given service day.",
-                            );
-
-                        // Once we've assembled all the necessary data, push a trip to the route_data's trip_list for use later in `process_routes_trips`.
-                        route_data.trip_list.push(TripInternal {
-                            service_day_start,
-                            stop_times: trip.stop_times.clone(),
-                            gtfs_trip_id: gtfs_trip_id.clone(),
-                        });
+                let tz = match Self::trip_agency_timezone(gtfs, trip) {
+                    Ok(tz) => tz,
+                    Err(issue) => {
+                        self.report.record(issue);
+                        self.report.skipped_trips += 1;
+                        continue;
                     }
+                };
+                let service_days: Vec<usize> = gtfs
+                    .trip_days(&trip.service_id, start_date)
+                    .into_iter()
+                    .map(|day| day as usize)
+                    .filter(|day| *day < service_window.days as usize)
+                    .collect();
+                if !service_days.is_empty() {
+                    // Each trip is stored once, along with the days it runs on. See `process_routes_trips`.
+                    let trips = Self::expand_frequencies(
+                        trip,
+                        &stop_times,
+                        gtfs_trip_id,
+                        service_days,
+                        &self.stop_locations,
+                    );
+                    let route_data = self.lookup_route_data(gtfs, trip, &stop_times);
+                    route_data.timezone = tz;
+                    route_data.trip_list.extend(trips);
                 }
             }
-            let route_id = self.lookup_route_data(gtfs, trip).id;
-            for stop_time in &trip.stop_times {
+            let route_id = self.lookup_route_data(gtfs, trip, &stop_times).id;
+            for stop_time in &stop_times {
                 self.lookup_stop_data(&stop_time.stop.id)
                     .stop_routes
                     .insert(route_id);
@@ -424,16 +719,182 @@ impl<'a> InMemoryTimetableBuilder {
 
         self.process_stops(gtfs)?;
 
+        self.build_stop_index();
+
+        self.process_fares(gtfs)?;
+
+        self.process_stations(gtfs)?;
+
         Result::Ok(())
     }
 
+    /// Turn a GTFS trip into the trips to store. Frequencies with `exact_times=1` are expanded into a trip per departure, and the rest are kept as templates for headway-based service.
+    fn expand_frequencies(
+        trip: &gtfs_structures::Trip,
+        stop_times: &[StopTime],
+        gtfs_trip_id: &String,
+        service_days: Vec<usize>,
+        stop_locations: &HashMap<String, (f64, f64)>,
+    ) -> Vec<TripInternal> {
+        let template = TripInternal {
+            service_days,
+            stop_times: interpolate_stop_times(stop_times, stop_locations),
+            gtfs_trip_id: gtfs_trip_id.clone(),
+            headways: vec![],
+        };
+        if trip.frequencies.is_empty() {
+            return vec![template];
+        }
+        let first_departure = template.get_departure() as i64;
+        let mut trips = vec![];
+        let mut headways = vec![];
+        for frequency in &trip.frequencies {
+            if frequency.headway_secs == 0 {
+                warn!("Ignoring frequency with a zero headway for trip {gtfs_trip_id}");
+                continue;
+            }
+            if frequency.exact_times == Some(ExactTimes::ScheduleBased) {
+                let mut departure = frequency.start_time;
+                while departure < frequency.end_time {
+                    trips.push(TripInternal {
+                        stop_times: shift_stop_times(
+                            &template.stop_times,
+                            departure as i64 - first_departure,
+                        ),
+                        ..template.clone()
+                    });
+                    departure += frequency.headway_secs;
+                }
+            } else {
+                headways.push(frequency.clone());
+            }
+        }
+        if !headways.is_empty() {
+            trips.push(TripInternal {
+                headways,
+                ..template
+            });
+        }
+        trips
+    }
+
+    fn process_fares(&mut self, gtfs: &Gtfs) -> Result<(), anyhow::Error> {
+        let feed_path = if let Some(feed_path) = &self.options.feed_path {
+            feed_path
+        } else {
+            return Ok(());
+        };
+        let mut v2 = FaresV2::read(feed_path)?;
+        if let Some(v2) = &mut v2 {
+            v2.resolve_timeframe_services(gtfs, &self.options.service_window);
+        }
+        let mut v1 = FaresV1::read(feed_path)?;
+        if let Some(v1) = &mut v1 {
+            v1.resolve_route_agencies(gtfs);
+        }
+        let fares = FeedFares {
+            timezone: gtfs.agencies.first().map(|agency| agency.timezone.clone()),
+            v2,
+            v1,
+        };
+        if !fares.is_empty() {
+            self.timetable
+                .feed_fares
+                .insert(self.options.feed_id.clone(), fares);
+        }
+        Ok(())
+    }
+
+    /// Find the fastest way through station pathways between every pair of stops that pathways connect, and the entrances of the stations that stops are in.
+    fn process_stations(&mut self, gtfs: &Gtfs) -> Result<(), anyhow::Error> {
+        let graph = if let Some(feed_path) = &self.options.feed_path {
+            StationGraph::read(feed_path, gtfs, &self.stop_locations)?
+        } else {
+            None
+        };
+        let stop_ids: HashMap<&String, usize> = self
+            .stop_table
+            .values()
+            .map(|stop_data| (&stop_data.gtfs_id, stop_data.id.0))
+            .collect();
+        if let Some(graph) = &graph {
+            for (gtfs_id, from) in &stop_ids {
+                for (to_gtfs_id, route) in graph.routes_from(gtfs_id) {
+                    if let Some(to) = stop_ids.get(&to_gtfs_id) {
+                        self.timetable
+                            .pathway_transfers
+                            .push(Transfer::through_pathways(*from, *to, route));
+                    }
+                }
+            }
+            self.timetable
+                .pathway_transfers
+                .sort_by_key(|transfer| (transfer.from, transfer.to));
+        }
+
+        let mut station_entrances: HashMap<&String, Vec<&String>> = HashMap::new();
+        for stop in gtfs.stops.values() {
+            if let (LocationType::StationEntrance, Some(station)) =
+                (&stop.location_type, &stop.parent_station)
+            {
+                if self.stop_locations.contains_key(&stop.id) {
+                    station_entrances.entry(station).or_default().push(&stop.id);
+                }
+            }
+        }
+        let mut routes_from_entrances = HashMap::new();
+        for (gtfs_id, stop_index) in &stop_ids {
+            let station = gtfs
+                .stops
+                .get(*gtfs_id)
+                .and_then(|stop| stop.parent_station.as_ref());
+            let entrances = station
+                .and_then(|station| station_entrances.get(station))
+                .into_iter()
+                .flatten();
+            let (lat, lng) = self.stop_locations[*gtfs_id];
+            for entrance_id in entrances {
+                let (entrance_lat, entrance_lng) = self.stop_locations[*entrance_id];
+                let pathway_seconds = graph.as_ref().and_then(|graph| {
+                    routes_from_entrances
+                        .entry(*entrance_id)
+                        .or_insert_with(|| graph.routes_from(entrance_id))
+                        .get(*gtfs_id)
+                        .map(|route| route.seconds)
+                });
+                // Without pathways, entrances are assumed to lead straight to the stop.
+                let seconds = pathway_seconds.unwrap_or_else(|| {
+                    (haversine_distance_meters(entrance_lat, entrance_lng, lat, lng) * 1000.0
+                        / WALK_SPEED_MM_PER_SECOND as f64) as u32
+                });
+                self.timetable.entrances.push(StationEntrance::new(
+                    *stop_index,
+                    LatLng::from_degrees(entrance_lat, entrance_lng),
+                    seconds,
+                ));
+            }
+        }
+        self.timetable.entrances.sort();
+        Ok(())
+    }
+
     fn process_routes_trips(&mut self, gtfs: &Gtfs) -> Result<(), anyhow::Error> {
         // TODO: How to deal with this route_table.clone()? It indicates an architectural problem IMO.
         for (_, route_data) in self.route_table.clone().iter() {
+            // Scheduled trips come first, sorted by departure, followed by the templates for headway-based service.
+            let (mut trips, headway_trips): (Vec<TripInternal>, Vec<TripInternal>) = route_data
+                .trip_list
+                .iter()
+                .cloned()
+                .partition(|trip| trip.headways.is_empty());
+            trips.sort_by_cached_key(|trip| trip.get_departure());
             let route = Route {
                 route_index: route_data.id.0,
                 first_route_stop: self.next_route_stop_id,
                 first_route_trip: self.next_route_trip_id,
+                first_service_day: self.timetable.route_service_days.len(),
+                first_headway_trip: self.next_route_trip_id + trips.len(),
+                first_headway: self.timetable.headways.len(),
             };
             self.timetable
                 .route_shapes
@@ -449,36 +910,82 @@ impl<'a> InMemoryTimetableBuilder {
                 });
                 self.next_route_stop_id += 1;
             }
-            let mut trips = route_data.trip_list.clone();
-            trips.sort_by_cached_key(|trip| trip.get_departure());
+            let service_window = self.options.service_window;
+            for day in 0..service_window.days {
+                let date = service_window
+                    .start_date
+                    .checked_add_days(Days::new(day as u64))
+                    .expect(&format!(
+                        "Failed to add {day} days to date {:?}",
+                        service_window.start_date
+                    ));
+                let service_day_start = service_day_start(&route_data.timezone, date)?;
+                self.timetable
+                    .route_service_days
+                    .push(Time::from_epoch_seconds(
+                        service_day_start.timestamp() as u32
+                    ));
+            }
+
             for trip in &trips {
                 self.process_trip(gtfs, route_data, trip)?;
             }
+            for trip in &headway_trips {
+                for frequency in &trip.headways {
+                    self.timetable.headways.push(Headway::new(
+                        self.next_route_trip_id,
+                        frequency.start_time,
+                        frequency.end_time,
+                        frequency.headway_secs,
+                    ));
+                }
+                self.process_trip(gtfs, route_data, trip)?;
+            }
         }
         Ok(())
     }
 
-    fn trip_agency_timezone(
-        &self,
-        agencies: &HashMap<String, &Agency>,
-        trip_agency_id: &String,
-    ) -> Result<Tz, anyhow::Error> {
-        let trip_agency = if let Some(agency) = agencies.get(trip_agency_id) {
-            agency
-        } else {
-            if agencies.len() == 1 {
-                agencies.values().next().unwrap()
-            } else {
-                bail!("No matching agency: {}, {:?}", trip_agency_id, agencies);
+    /// The timezone a trip's stop times are given in. That's always the agency's, even for trips that cross into other timezones.
+    fn trip_agency_timezone(gtfs: &Gtfs, trip: &gtfs_structures::Trip) -> Result<Tz, FeedIssue> {
+        let route = gtfs
+            .routes
+            .get(&trip.route_id)
+            .ok_or_else(|| FeedIssue::UnknownRoute {
+                route_id: trip.route_id.clone(),
+            })?;
+        let agency = route_agency(gtfs, route)?;
+        agency
+            .timezone
+            .parse()
+            .map_err(|_err| FeedIssue::InvalidAgencyTimezone {
+                agency_id: agency.id.clone().unwrap_or_default(),
+                timezone: agency.timezone.clone(),
+            })
+    }
+
+    /// The timezone to show a stop's local times in: its parent station's `stop_timezone` if it has a parent, otherwise its own, falling back to the feed's.
+    fn stop_timezone(&mut self, gtfs: &Gtfs, stop: &gtfs_structures::Stop) -> Option<String> {
+        let station = stop
+            .parent_station
+            .as_ref()
+            .and_then(|parent_id| gtfs.get_stop(parent_id).ok())
+            .unwrap_or(stop);
+        let feed_timezone = gtfs
+            .agencies
+            .first()
+            .map(|agency| agency.timezone.clone())
+            .filter(|timezone| timezone.parse::<Tz>().is_ok());
+        match &station.timezone {
+            Some(timezone) if timezone.parse::<Tz>().is_ok() => Some(timezone.clone()),
+            Some(timezone) if !timezone.is_empty() => {
+                self.report.record(FeedIssue::InvalidStopTimezone {
+                    stop_id: station.id.clone(),
+                    timezone: timezone.clone(),
+                });
+                feed_timezone
             }
-        };
-        Ok(trip_agency.timezone.parse().map_err(|_err| {
-            anyhow::anyhow!(
-                "Failed to parse tz for agency {}: {}",
-                trip_agency,
-                trip_agency.timezone
-            )
-        })?)
+            _ => feed_timezone,
+        }
     }
 
     fn process_trip(
@@ -497,40 +1004,50 @@ impl<'a> InMemoryTimetableBuilder {
                 assert!(arrival_time >= prev_time);
                 prev_time = arrival_time;
             }
-            let arrival_time = trip
-                .service_day_start
-                .checked_add_signed(TimeDelta::seconds(
-                    stop_time.arrival_time.unwrap_or(u32::MAX) as i64,
-                ))
-                .unwrap();
-            let departure_time = trip
-                .service_day_start
-                .checked_add_signed(TimeDelta::seconds(
-                    stop_time.departure_time.unwrap_or(u32::MAX) as i64,
-                ))
-                .unwrap();
+            // Trips without times at their ends were skipped and the stops between timepoints interpolated, so every stop has a time by now.
             self.timetable.trip_stop_times.push(TripStopTime::new(
                 self.next_route_trip_id,
                 stop_seq,
-                arrival_time,
-                departure_time,
+                stop_time.arrival_time.expect("stop times are interpolated"),
+                stop_time
+                    .departure_time
+                    .expect("stop times are interpolated"),
+                stop_time.pickup_type != PickupDropOffType::NotAvailable,
+                stop_time.drop_off_type != PickupDropOffType::NotAvailable,
             ));
             self.next_trip_stop_time_id += 1;
         }
         let gtfs_trip = gtfs
             .get_trip(&trip.gtfs_trip_id)
             .expect("Trip not found in trip table.");
+        let first_service_word = self.timetable.trip_service_days.len();
+        let mut service_days =
+            vec![0u64; service_day_words(self.options.service_window.days as usize)];
+        for day in &trip.service_days {
+            service_days[day / 64] |= 1 << (day % 64);
+        }
+        self.timetable.trip_service_days.extend(service_days);
         let trip = Trip {
             trip_index: self.next_route_trip_id,
             route_index: route_data.id.0,
             first_trip_stop_time,
             last_trip_stop_time: self.next_trip_stop_time_id,
+            first_service_word,
         };
         self.timetable.route_trips.push(trip);
         let metadata = TripMetadata {
             agency_name: route_data.agency_name.clone(),
             headsign: gtfs_trip.trip_headsign.clone(),
             route_name: gtfs.routes[&route_data.gtfs_route_id].short_name.clone(),
+            feed_id: self.options.feed_id.clone(),
+            gtfs_route_id: Some(route_data.gtfs_route_id.clone()),
+            gtfs_trip_id: Some(gtfs_trip.id.clone()),
+            timezone: Some(route_data.timezone.name().to_string()),
+            stop_sequences: trip
+                .stop_times
+                .iter()
+                .map(|stop_time| stop_time.stop_sequence)
+                .collect(),
         };
         self.timetable.trip_metadata_map.insert(trip, metadata);
 
@@ -539,21 +1056,33 @@ impl<'a> InMemoryTimetableBuilder {
         Ok(())
     }
 
+    fn build_stop_index(&mut self) {
+        let timetable = &mut self.timetable;
+        timetable.rtree = RTree::bulk_load(
+            timetable
+                .stops
+                .iter()
+                .map(|stop| {
+                    let latlng = stop.location();
+                    IndexedStop {
+                        coords: lat_lng_to_cartesian(latlng.lat.deg(), latlng.lng.deg()),
+                        id: stop.id(),
+                    }
+                })
+                .collect(),
+        );
+        // Every stop starts out without any transfers, so that the timetable is usable even if transfers are never calculated.
+        timetable.transfer_index = vec![0; timetable.stops.len()];
+        timetable.transfers = vec![];
+    }
+
     fn process_stops(&mut self, gtfs: &Gtfs) -> Result<(), anyhow::Error> {
-        let mut sorted_stops: Vec<&StopData> = self.stop_table.values().collect();
+        let mut sorted_stops: Vec<StopData> = self.stop_table.values().cloned().collect();
         sorted_stops.sort_by_cached_key(|stop_data| stop_data.id);
-        for stop_data in sorted_stops {
+        for stop_data in &sorted_stops {
             let gtfs_stop = gtfs.get_stop(&stop_data.gtfs_id).unwrap();
-            let lat = if let Some(lat) = gtfs_stop.latitude {
-                lat
-            } else {
-                bail!("Can't process feeds containing stop IDs without lat/lng")
-            };
-            let lng = if let Some(lng) = gtfs_stop.longitude {
-                lng
-            } else {
-                bail!("Can't process feeds containing stop IDs without lat/lng")
-            };
+            // Trips only refer to stops that have a location.
+            let (lat, lng) = self.stop_locations[&stop_data.gtfs_id];
             let s2cell: CellID = LatLng::from_degrees(lat, lng).into();
             let stop = Stop {
                 stop_index: stop_data.id.0,
@@ -561,25 +1090,25 @@ impl<'a> InMemoryTimetableBuilder {
                 first_stop_route_index: self.next_stop_route_id,
             };
             self.timetable.stops.push(stop);
-            self.timetable
-                .stop_metadata_map
-                .insert(stop, gtfs_stop.clone());
+            let mut stop_metadata = gtfs_stop.clone();
+            stop_metadata.latitude = Some(lat);
+            stop_metadata.longitude = Some(lng);
+            stop_metadata.timezone = self.stop_timezone(gtfs, gtfs_stop);
+            self.timetable.stop_metadata_map.insert(stop, stop_metadata);
             for route in &stop_data.stop_routes {
-                let mut seq = 0usize;
+                // Loops and routes that come back through a terminal visit the same stop more than once, and can be boarded at each visit.
                 let mut found_seq = false;
-                for route_stop_seq_candidate in &self.route_table[&route].stops {
-                    if &stop_data.id == route_stop_seq_candidate {
+                for (seq, route_stop) in self.route_table[&route].stops.iter().enumerate() {
+                    if &stop_data.id == route_stop {
                         found_seq = true;
-                        break;
+                        self.timetable.stop_routes.push(StopRoute {
+                            route_index: route.0,
+                            stop_seq: seq,
+                        });
+                        self.next_stop_route_id += 1;
                     }
-                    seq += 1;
                 }
                 assert!(found_seq);
-                self.timetable.stop_routes.push(StopRoute {
-                    route_index: route.0,
-                    stop_seq: seq,
-                });
-                self.next_stop_route_id += 1;
             }
         }
         Ok(())
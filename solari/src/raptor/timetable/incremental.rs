@@ -0,0 +1,364 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    generate_transfer_candidates, mmap::MmapTimetable, ServiceWindow, Stop, Timetable, Transfer,
+};
+
+/// Records how the timetable in a directory was built, so that the next build can reuse parts of it.
+pub const BUILD_MANIFEST_FILE: &str = "build_manifest.json";
+/// Written into a feed's directory once its timetable is complete.
+const FEED_ARTIFACT_FILE: &str = "artifact.json";
+/// Bump whenever the timetable format changes, so that feeds built with an older format are rebuilt.
+const ARTIFACT_VERSION: u32 = 2;
+
+/// A feed's timetable, built on its own before being concatenated with the others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeedArtifact {
+    pub feed_id: String,
+    /// Hash of the feed's contents and the options it was built with, which also names its directory.
+    pub hash: String,
+    pub stops: usize,
+    /// Hash of everything transfers are calculated from: where the feed's stops and station entrances are, and the pathways between them. Unlike `hash`, it doesn't change when only the feed's trips or service window do.
+    pub stops_hash: String,
+}
+
+impl FeedArtifact {
+    /// The artifact for `timetable`, the feed `feed_id` built on its own.
+    pub fn new<'a, T: Timetable<'a>>(
+        feed_id: String,
+        hash: String,
+        timetable: &'a T,
+    ) -> FeedArtifact {
+        let mut hasher = Sha256::new();
+        hasher.update(ARTIFACT_VERSION.to_le_bytes());
+        // The rest of a stop depends on which routes serve it.
+        let cells: Vec<u64> = timetable.stops().iter().map(|stop| stop.s2cell).collect();
+        hash_bytes(&mut hasher, bytemuck::cast_slice(&cells));
+        hash_bytes(&mut hasher, bytemuck::cast_slice(timetable.entrances()));
+        hash_bytes(
+            &mut hasher,
+            bytemuck::cast_slice(timetable.pathway_transfers()),
+        );
+        FeedArtifact {
+            feed_id,
+            hash,
+            stops: timetable.stops().len(),
+            stops_hash: format!("{:x}", hasher.finalize()),
+        }
+    }
+
+    /// The artifact in `dir`, if a timetable was completely written there.
+    pub fn read(dir: &Path) -> Option<FeedArtifact> {
+        let file = File::open(dir.join(FEED_ARTIFACT_FILE)).ok()?;
+        serde_json::from_reader(file).ok()
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<(), anyhow::Error> {
+        fs::write(dir.join(FEED_ARTIFACT_FILE), serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+}
+
+/// How the timetable in a directory was put together.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildManifest {
+    /// What transfers were calculated with. They're only reused by a build that would calculate them the same way.
+    pub transfers: String,
+    /// The feeds, in the order they were concatenated.
+    pub feeds: Vec<FeedArtifact>,
+}
+
+impl BuildManifest {
+    /// The manifest of the timetable in `base_path`, if it has one.
+    pub fn read(base_path: &Path) -> Result<Option<BuildManifest>, anyhow::Error> {
+        match File::open(base_path.join(BUILD_MANIFEST_FILE)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn write(&self, base_path: &Path) -> Result<(), anyhow::Error> {
+        fs::write(
+            base_path.join(BUILD_MANIFEST_FILE),
+            serde_json::to_vec_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    /// Remove the manifest from `base_path`, for a timetable written there that it no longer describes.
+    pub fn remove(base_path: &Path) -> Result<(), anyhow::Error> {
+        match fs::remove_file(base_path.join(BUILD_MANIFEST_FILE)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Hash a GTFS feed, either a zip or a directory, along with what goes into building its timetable. Feeds with the same hash build the same timetable.
+pub fn feed_hash(
+    path: &Path,
+    feed_id: &str,
+    service_window: &ServiceWindow,
+) -> Result<String, anyhow::Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(ARTIFACT_VERSION.to_le_bytes());
+    hash_bytes(&mut hasher, feed_id.as_bytes());
+    hash_bytes(&mut hasher, &serde_json::to_vec(service_window)?);
+    if path.is_dir() {
+        let mut files = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, _>>()?;
+        files.sort();
+        for file in files.iter().filter(|file| file.is_file()) {
+            hash_bytes(&mut hasher, file.file_name().unwrap().as_encoded_bytes());
+            hash_bytes(&mut hasher, &fs::read(file)?);
+        }
+    } else {
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hash `bytes` with their length, so that consecutive fields can't run into each other.
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+/// Transfers from an earlier build that still hold, by stop in the timetable being built. A stop keeps its transfers if its feed's stops haven't changed and no stop it could walk to has been added or removed. Changes to trips or the service window alone don't stop transfers from being reused.
+pub struct PreviousTransfers {
+    /// `None` for stops whose transfers have to be calculated again.
+    transfers: Vec<Option<Vec<Transfer>>>,
+    /// Stops from feeds that weren't in the earlier build, or whose stops have changed since.
+    changed: Vec<bool>,
+    reused: AtomicUsize,
+}
+
+impl PreviousTransfers {
+    /// Work out which of `previous`'s transfers carry over to a timetable concatenated from `feeds`, in that order. `previous_manifest` says how `previous` was built.
+    pub fn new<'a>(
+        previous: &'a MmapTimetable<'a>,
+        previous_manifest: &BuildManifest,
+        feeds: &[FeedArtifact],
+    ) -> PreviousTransfers {
+        let total_stops = feeds.iter().map(|feed| feed.stops).sum();
+        let mut previous_transfers = PreviousTransfers {
+            transfers: vec![None; total_stops],
+            changed: vec![true; total_stops],
+            reused: AtomicUsize::new(0),
+        };
+
+        let mut offsets = HashMap::new();
+        let mut offset = 0;
+        for feed in feeds {
+            offsets.insert((&feed.feed_id, &feed.stops_hash), offset);
+            offset += feed.stops;
+        }
+        // Where each of the previous timetable's stops is in the new one, if its feed is still there with the same stops.
+        let mut stop_map = Vec::with_capacity(previous.stops().len());
+        for feed in &previous_manifest.feeds {
+            let offset = offsets.get(&(&feed.feed_id, &feed.stops_hash));
+            stop_map.extend((0..feed.stops).map(|stop| offset.map(|offset| offset + stop)));
+        }
+        if stop_map.len() != previous.stops().len() {
+            warn!("The previous build's manifest doesn't match its timetable, calculating all transfers");
+            return previous_transfers;
+        }
+
+        for stop in stop_map.iter().flatten() {
+            previous_transfers.changed[*stop] = false;
+        }
+        for stop in previous.stops() {
+            let new_stop = if let Some(new_stop) = stop_map[stop.id()] {
+                new_stop
+            } else {
+                continue;
+            };
+            // A stop that's gone may have been walked to, or kept another stop from being walked to.
+            if generate_transfer_candidates(previous, stop)
+                .iter()
+                .any(|candidate| stop_map[candidate.id()].is_none())
+            {
+                continue;
+            }
+            previous_transfers.transfers[new_stop] = previous
+                .transfers_from(stop.id())
+                .iter()
+                .map(|transfer| {
+                    Some(Transfer {
+                        from: new_stop,
+                        to: stop_map[transfer.to]?,
+                        ..*transfer
+                    })
+                })
+                .collect();
+        }
+        previous_transfers
+    }
+
+    /// The transfers out of `stop` in `timetable`, if they can be reused. They can't if a stop it could walk to is new.
+    pub(crate) fn reusable<'a, T: Timetable<'a>>(
+        &self,
+        timetable: &'a T,
+        stop: &Stop,
+    ) -> Option<Vec<Transfer>> {
+        let transfers = self.transfers.get(stop.id())?.as_ref()?;
+        if generate_transfer_candidates(timetable, stop)
+            .iter()
+            .any(|candidate| self.changed[candidate.id()])
+        {
+            return None;
+        }
+        self.reused.fetch_add(1, Ordering::Relaxed);
+        Some(transfers.clone())
+    }
+
+    /// How many stops' transfers have been reused so far.
+    pub fn reused(&self) -> usize {
+        self.reused.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use chrono::NaiveDate;
+    use gtfs_structures::GtfsReader;
+    use solari_transfers::geometric::GeometricTransfers;
+    use tempdir::TempDir;
+
+    use crate::raptor::timetable::{
+        in_memory::{InMemoryTimetableBuilder, TimetableBuildOptions},
+        mmap::MmapTimetable,
+        ServiceWindow, Timetable, Transfer,
+    };
+
+    use super::{feed_hash, BuildManifest, FeedArtifact, PreviousTransfers};
+
+    fn window_starting(day: u32) -> ServiceWindow {
+        ServiceWindow::new(NaiveDate::from_ymd_opt(2030, 1, day).unwrap(), 3)
+    }
+
+    fn build_feeds<'a>(
+        base_path: &PathBuf,
+        names: &[&str],
+        service_window: ServiceWindow,
+    ) -> (Vec<MmapTimetable<'a>>, Vec<FeedArtifact>) {
+        names
+            .iter()
+            .map(|name| {
+                let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests")
+                    .join("fixtures")
+                    .join(name);
+                let hash = feed_hash(&path, name, &service_window).unwrap();
+                let gtfs = GtfsReader::default()
+                    .read_from_path(path.to_str().unwrap())
+                    .unwrap();
+                let builder = InMemoryTimetableBuilder::with_options(
+                    &gtfs,
+                    TimetableBuildOptions {
+                        feed_id: name.to_string(),
+                        service_window,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+                let timetable =
+                    MmapTimetable::from_in_memory(&builder, &base_path.join(&hash)).unwrap();
+                let artifact = FeedArtifact::new(name.to_string(), hash, &timetable);
+                (timetable, artifact)
+            })
+            .unzip()
+    }
+
+    fn all_transfers<'a>(timetable: &'a MmapTimetable<'a>) -> Vec<Vec<Transfer>> {
+        timetable
+            .stops()
+            .iter()
+            .map(|stop| timetable.transfers_from(stop.id()).to_vec())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reuses_transfers_away_from_changed_feeds() {
+        let transfers = GeometricTransfers::new(1.3);
+        let previous_dir = TempDir::new("previous").unwrap();
+        let previous_path = previous_dir.path().to_path_buf();
+        let (timetables, feeds) =
+            build_feeds(&previous_path, &["simple", "timezones"], window_starting(7));
+        let previous =
+            MmapTimetable::concatenate(&timetables, &previous_path, &transfers, None).await;
+        let manifest = BuildManifest {
+            transfers: "geometric:1.3".to_string(),
+            feeds,
+        };
+
+        // The timezones feed is dropped and the fares feed added, which has a stop where the simple feed's first stop is.
+        let dir = TempDir::new("incremental").unwrap();
+        let base_path = dir.path().to_path_buf();
+        let (timetables, feeds) = build_feeds(&base_path, &["simple", "fares"], window_starting(7));
+        let previous_transfers = PreviousTransfers::new(&previous, &manifest, &feeds);
+        let incremental = MmapTimetable::concatenate(
+            &timetables,
+            &base_path,
+            &transfers,
+            Some(&previous_transfers),
+        )
+        .await;
+        assert_eq!(previous_transfers.reused(), 4);
+
+        let full_dir = TempDir::new("full").unwrap();
+        let full_path = full_dir.path().to_path_buf();
+        let full = MmapTimetable::concatenate(&timetables, &full_path, &transfers, None).await;
+        assert_eq!(all_transfers(&incremental), all_transfers(&full));
+    }
+
+    #[tokio::test]
+    async fn reuses_transfers_when_the_service_window_moves() {
+        let transfers = GeometricTransfers::new(1.3);
+        let previous_dir = TempDir::new("previous").unwrap();
+        let previous_path = previous_dir.path().to_path_buf();
+        let (timetables, feeds) =
+            build_feeds(&previous_path, &["simple", "timezones"], window_starting(7));
+        let previous =
+            MmapTimetable::concatenate(&timetables, &previous_path, &transfers, None).await;
+        let manifest = BuildManifest {
+            transfers: "geometric:1.3".to_string(),
+            feeds,
+        };
+
+        // A nightly build moves the window by a day, which changes every feed's hash but none of its stops.
+        let dir = TempDir::new("incremental").unwrap();
+        let base_path = dir.path().to_path_buf();
+        let (timetables, feeds) =
+            build_feeds(&base_path, &["simple", "timezones"], window_starting(8));
+        assert!(feeds
+            .iter()
+            .zip(&manifest.feeds)
+            .all(|(feed, previous)| feed.hash != previous.hash
+                && feed.stops_hash == previous.stops_hash));
+        let previous_transfers = PreviousTransfers::new(&previous, &manifest, &feeds);
+        let incremental = MmapTimetable::concatenate(
+            &timetables,
+            &base_path,
+            &transfers,
+            Some(&previous_transfers),
+        )
+        .await;
+        assert_eq!(previous_transfers.reused(), incremental.stops().len());
+        assert_eq!(all_transfers(&incremental), all_transfers(&previous));
+    }
+}
@@ -5,30 +5,25 @@ use std::{
     path::PathBuf,
     pin::Pin,
     slice,
-    sync::Arc,
 };
 
 use anyhow::{Error, Ok};
 use bytemuck::{cast_slice_mut, checked::cast_slice};
-use geo::Coord;
 use log::{debug, info};
 use memmap2::{Mmap, MmapMut, MmapOptions};
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use redb::Database;
+use redb::{Database, ReadableTable};
 use rstar::RTree;
 use s2::latlng::LatLng;
 use solari_geomath::lat_lng_to_cartesian;
-use solari_spatial::{SphereIndex, SphereIndexMmap};
-use solari_transfers::{
-    fast_paths::{FastGraph, FastGraphStatic},
-    {TransferGraph, TransferGraphSearcher},
-};
+use solari_transfers::TransferProvider;
 
-use crate::spatial::{IndexedStop, WALK_SPEED_MM_PER_SECOND};
+use crate::{fares::FeedFares, spatial::IndexedStop};
 
 use super::{
-    in_memory::InMemoryTimetableBuilder, Route, RouteStop, ShapeCoordinate, Stop, StopRoute,
-    Timetable, Transfer, Trip, TripMetadata, TripStopTime, ROUTE_SHAPE_TABLE, STOP_METADATA_TABLE,
+    calculate_all_transfers, in_memory::InMemoryTimetableBuilder, incremental::PreviousTransfers,
+    Headway, Route, RouteStop, ServiceWindow, ShapeCoordinate, StationEntrance, Stop, StopRoute,
+    Time, Timetable, Transfer, Trip, TripMetadata, TripStopTime, FEED_FARES_TABLE,
+    ROUTE_SHAPE_TABLE, SERVICE_WINDOW_KEY, STOP_METADATA_TABLE, TIMETABLE_INFO_TABLE,
     TRIP_METADATA_TABLE,
 };
 
@@ -42,6 +37,11 @@ pub struct MmapTimetable<'a> {
     backing_stops: Pin<Mmap>,
     backing_stop_routes: Pin<Mmap>,
     backing_trip_stop_times: Pin<Mmap>,
+    backing_route_service_days: Pin<Mmap>,
+    backing_trip_service_days: Pin<Mmap>,
+    backing_headways: Pin<Mmap>,
+    backing_pathway_transfers: Pin<Mmap>,
+    backing_entrances: Pin<Mmap>,
     backing_transfer_index: Pin<Mmap>,
     backing_transfers: Pin<Mmap>,
 
@@ -51,6 +51,11 @@ pub struct MmapTimetable<'a> {
     stops_slice: &'a [Stop],
     stop_routes_slice: &'a [StopRoute],
     trip_stop_times_slice: &'a [TripStopTime],
+    route_service_days_slice: &'a [Time],
+    trip_service_days_slice: &'a [u64],
+    headways_slice: &'a [Headway],
+    pathway_transfers_slice: &'a [Transfer],
+    entrances_slice: &'a [StationEntrance],
     transfer_index_slice: &'a [usize],
     transfers_slice: &'a [Transfer],
     rtree: RTree<IndexedStop>,
@@ -111,6 +116,21 @@ impl<'a> Timetable<'a> for MmapTimetable<'a> {
         self.trip_stop_times_slice
     }
 
+    #[inline]
+    fn route_service_days(&'a self) -> &'a [Time] {
+        self.route_service_days_slice
+    }
+
+    #[inline]
+    fn trip_service_days(&'a self) -> &'a [u64] {
+        self.trip_service_days_slice
+    }
+
+    #[inline]
+    fn headways(&'a self) -> &'a [Headway] {
+        self.headways_slice
+    }
+
     #[inline]
     fn transfers(&'a self) -> &'a [Transfer] {
         self.transfers_slice
@@ -121,6 +141,16 @@ impl<'a> Timetable<'a> for MmapTimetable<'a> {
         self.transfer_index_slice
     }
 
+    #[inline]
+    fn pathway_transfers(&'a self) -> &'a [Transfer] {
+        self.pathway_transfers_slice
+    }
+
+    #[inline]
+    fn entrances(&'a self) -> &'a [StationEntrance] {
+        self.entrances_slice
+    }
+
     #[inline]
     fn stop_index_copy(&'a self) -> RTree<IndexedStop> {
         self.rtree.clone()
@@ -178,6 +208,32 @@ impl<'a> Timetable<'a> for MmapTimetable<'a> {
             None
         }
     }
+
+    fn feed_fares(&'a self, feed_id: &str) -> Option<FeedFares> {
+        // Timetables built before fares were supported don't have this table at all.
+        let table = self
+            .metadata_db
+            .begin_read()
+            .expect("Read failed")
+            .open_table(FEED_FARES_TABLE)
+            .ok()?;
+
+        let bytes = table.get(feed_id).expect("DB error")?;
+        Some(rmp_serde::from_slice(bytes.value()).expect("Deserialization failed"))
+    }
+
+    fn service_window(&'a self) -> Option<ServiceWindow> {
+        // Timetables built before the service window was recorded don't have this table.
+        let table = self
+            .metadata_db
+            .begin_read()
+            .expect("Read failed")
+            .open_table(TIMETABLE_INFO_TABLE)
+            .ok()?;
+
+        let bytes = table.get(SERVICE_WINDOW_KEY).expect("DB error")?;
+        Some(rmp_serde::from_slice(bytes.value()).expect("Deserialization failed"))
+    }
 }
 
 impl<'a> MmapTimetable<'a> {
@@ -189,6 +245,11 @@ impl<'a> MmapTimetable<'a> {
         backing_stops: Pin<Mmap>,
         backing_stop_routes: Pin<Mmap>,
         backing_trip_stop_times: Pin<Mmap>,
+        backing_route_service_days: Pin<Mmap>,
+        backing_trip_service_days: Pin<Mmap>,
+        backing_headways: Pin<Mmap>,
+        backing_pathway_transfers: Pin<Mmap>,
+        backing_entrances: Pin<Mmap>,
         backing_transfer_index: Pin<Mmap>,
         backing_transfers: Pin<Mmap>,
         metadata_db: Database,
@@ -217,6 +278,26 @@ impl<'a> MmapTimetable<'a> {
             let s = cast_slice::<u8, TripStopTime>(&backing_trip_stop_times);
             slice::from_raw_parts(s.as_ptr(), s.len())
         };
+        let route_service_days = unsafe {
+            let s = cast_slice::<u8, Time>(&backing_route_service_days);
+            slice::from_raw_parts(s.as_ptr(), s.len())
+        };
+        let trip_service_days = unsafe {
+            let s = cast_slice::<u8, u64>(&backing_trip_service_days);
+            slice::from_raw_parts(s.as_ptr(), s.len())
+        };
+        let headways = unsafe {
+            let s = cast_slice::<u8, Headway>(&backing_headways);
+            slice::from_raw_parts(s.as_ptr(), s.len())
+        };
+        let pathway_transfers = unsafe {
+            let s = cast_slice::<u8, Transfer>(&backing_pathway_transfers);
+            slice::from_raw_parts(s.as_ptr(), s.len())
+        };
+        let entrances = unsafe {
+            let s = cast_slice::<u8, StationEntrance>(&backing_entrances);
+            slice::from_raw_parts(s.as_ptr(), s.len())
+        };
         let transfer_index = unsafe {
             let s = cast_slice::<u8, usize>(&backing_transfer_index);
             slice::from_raw_parts(s.as_ptr(), s.len())
@@ -250,6 +331,11 @@ impl<'a> MmapTimetable<'a> {
             backing_stops,
             backing_stop_routes,
             backing_trip_stop_times,
+            backing_route_service_days,
+            backing_trip_service_days,
+            backing_headways,
+            backing_pathway_transfers,
+            backing_entrances,
             backing_transfer_index,
             backing_transfers,
             phantom: &PhantomData,
@@ -261,6 +347,11 @@ impl<'a> MmapTimetable<'a> {
             stops_slice: stops,
             stop_routes_slice: stop_routes,
             trip_stop_times_slice: trip_stop_times,
+            route_service_days_slice: route_service_days,
+            trip_service_days_slice: trip_service_days,
+            headways_slice: headways,
+            pathway_transfers_slice: pathway_transfers,
+            entrances_slice: entrances,
             transfer_index_slice: transfer_index,
             transfers_slice: transfers,
 
@@ -283,6 +374,15 @@ impl<'a> MmapTimetable<'a> {
         let stop_routes = File::open(base_path.join("stop_routes"))?;
         debug!("Opening stop times.");
         let trip_stop_times = File::open(base_path.join("trip_stop_times"))?;
+        debug!("Opening service days.");
+        let route_service_days = File::open(base_path.join("route_service_days"))?;
+        let trip_service_days = File::open(base_path.join("trip_service_days"))?;
+        debug!("Opening headways.");
+        let headways = File::open(base_path.join("headways"))?;
+        debug!("Opening pathway transfers.");
+        let pathway_transfers = File::open(base_path.join("pathway_transfers"))?;
+        debug!("Opening station entrances.");
+        let entrances = File::open(base_path.join("entrances"))?;
         debug!("Opening transfer index.");
         let transfer_index = File::open(base_path.join("transfer_index"))?;
         debug!("Opening transfers.");
@@ -301,6 +401,17 @@ impl<'a> MmapTimetable<'a> {
         let backing_stop_routes = unsafe { MmapOptions::new().huge(page_bits).map(&stop_routes)? };
         let backing_trip_stop_times =
             unsafe { MmapOptions::new().huge(page_bits).map(&trip_stop_times)? };
+        let backing_route_service_days = unsafe {
+            MmapOptions::new()
+                .huge(page_bits)
+                .map(&route_service_days)?
+        };
+        let backing_trip_service_days =
+            unsafe { MmapOptions::new().huge(page_bits).map(&trip_service_days)? };
+        let backing_headways = unsafe { MmapOptions::new().huge(page_bits).map(&headways)? };
+        let backing_pathway_transfers =
+            unsafe { MmapOptions::new().huge(page_bits).map(&pathway_transfers)? };
+        let backing_entrances = unsafe { MmapOptions::new().huge(page_bits).map(&entrances)? };
         let backing_transfer_index =
             unsafe { MmapOptions::new().huge(page_bits).map(&transfer_index)? };
         let backing_transfers = unsafe { MmapOptions::new().huge(page_bits).map(&transfers)? };
@@ -313,6 +424,11 @@ impl<'a> MmapTimetable<'a> {
             Pin::new(backing_stops),
             Pin::new(backing_stop_routes),
             Pin::new(backing_trip_stop_times),
+            Pin::new(backing_route_service_days),
+            Pin::new(backing_trip_service_days),
+            Pin::new(backing_headways),
+            Pin::new(backing_pathway_transfers),
+            Pin::new(backing_entrances),
             Pin::new(backing_transfer_index),
             Pin::new(backing_transfers),
             metadata_db,
@@ -336,6 +452,11 @@ impl<'a> MmapTimetable<'a> {
                 let stops = File::create(base_path.join("stops"))?;
                 let stop_routes = File::create(base_path.join("stop_routes"))?;
                 let trip_stop_times = File::create(base_path.join("trip_stop_times"))?;
+                let route_service_days = File::create(base_path.join("route_service_days"))?;
+                let trip_service_days = File::create(base_path.join("trip_service_days"))?;
+                let headways = File::create(base_path.join("headways"))?;
+                let pathway_transfers = File::create(base_path.join("pathway_transfers"))?;
+                let entrances = File::create(base_path.join("entrances"))?;
                 let _ = File::create(base_path.join("transfer_index"))?;
                 let _ = File::create(base_path.join("transfers"))?;
 
@@ -354,6 +475,21 @@ impl<'a> MmapTimetable<'a> {
                     (size_of::<TripStopTime>() * in_memory_timetable.trip_stop_times().len())
                         as u64,
                 )?;
+                route_service_days.set_len(
+                    (size_of::<Time>() * in_memory_timetable.route_service_days().len()) as u64,
+                )?;
+                trip_service_days.set_len(
+                    (size_of::<u64>() * in_memory_timetable.trip_service_days().len()) as u64,
+                )?;
+                headways.set_len(
+                    (size_of::<Headway>() * in_memory_timetable.headways().len()) as u64,
+                )?;
+                pathway_transfers.set_len(
+                    (size_of::<Transfer>() * in_memory_timetable.pathway_transfers().len()) as u64,
+                )?;
+                entrances.set_len(
+                    (size_of::<StationEntrance>() * in_memory_timetable.entrances().len()) as u64,
+                )?;
             }
 
             let routes = File::options()
@@ -380,6 +516,26 @@ impl<'a> MmapTimetable<'a> {
                 .write(true)
                 .read(true)
                 .open(base_path.join("trip_stop_times"))?;
+            let route_service_days = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("route_service_days"))?;
+            let trip_service_days = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("trip_service_days"))?;
+            let headways = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("headways"))?;
+            let pathway_transfers = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("pathway_transfers"))?;
+            let entrances = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("entrances"))?;
 
             let mut backing_routes = unsafe { MmapOptions::new().map_mut(&routes)? };
             let mut backing_route_stops = unsafe { MmapOptions::new().map_mut(&route_stops)? };
@@ -388,6 +544,14 @@ impl<'a> MmapTimetable<'a> {
             let mut backing_stop_routes = unsafe { MmapOptions::new().map_mut(&stop_routes)? };
             let mut backing_trip_stop_times =
                 unsafe { MmapOptions::new().map_mut(&trip_stop_times)? };
+            let mut backing_route_service_days =
+                unsafe { MmapOptions::new().map_mut(&route_service_days)? };
+            let mut backing_trip_service_days =
+                unsafe { MmapOptions::new().map_mut(&trip_service_days)? };
+            let mut backing_headways = unsafe { MmapOptions::new().map_mut(&headways)? };
+            let mut backing_pathway_transfers =
+                unsafe { MmapOptions::new().map_mut(&pathway_transfers)? };
+            let mut backing_entrances = unsafe { MmapOptions::new().map_mut(&entrances)? };
 
             backing_routes.copy_from_slice(cast_slice(in_memory_timetable.routes()));
             backing_route_stops.copy_from_slice(cast_slice(in_memory_timetable.route_stops()));
@@ -396,6 +560,14 @@ impl<'a> MmapTimetable<'a> {
             backing_stop_routes.copy_from_slice(cast_slice(in_memory_timetable.stop_routes()));
             backing_trip_stop_times
                 .copy_from_slice(cast_slice(in_memory_timetable.trip_stop_times()));
+            backing_route_service_days
+                .copy_from_slice(cast_slice(in_memory_timetable.route_service_days()));
+            backing_trip_service_days
+                .copy_from_slice(cast_slice(in_memory_timetable.trip_service_days()));
+            backing_headways.copy_from_slice(cast_slice(in_memory_timetable.headways()));
+            backing_pathway_transfers
+                .copy_from_slice(cast_slice(in_memory_timetable.pathway_transfers()));
+            backing_entrances.copy_from_slice(cast_slice(in_memory_timetable.entrances()));
 
             let metadata_db = Database::create(base_path.join("metadata.db"))?;
             {
@@ -431,15 +603,37 @@ impl<'a> MmapTimetable<'a> {
                 }
                 write.commit()?;
             }
+            {
+                let write = metadata_db.begin_write()?;
+                {
+                    let mut table = write.open_table(FEED_FARES_TABLE)?;
+                    for (feed_id, fares) in in_memory_timetable.all_feed_fares() {
+                        let bytes = rmp_serde::to_vec(fares)?;
+                        table.insert(feed_id.as_str(), bytes.as_slice())?;
+                    }
+                }
+                write.commit()?;
+            }
+            if let Some(service_window) = in_memory_timetable.service_window() {
+                let write = metadata_db.begin_write()?;
+                {
+                    let mut table = write.open_table(TIMETABLE_INFO_TABLE)?;
+                    let bytes = rmp_serde::to_vec(&service_window)?;
+                    table.insert(SERVICE_WINDOW_KEY, bytes.as_slice())?;
+                }
+                write.commit()?;
+            }
             info!("Done writing timetable");
         }
         MmapTimetable::open(base_path)
     }
 
+    /// Concatenate per-feed timetables into one in `base_path`, and calculate the transfers between all of their stops. Transfers in `previous` are reused rather than calculated again.
     pub async fn concatenate<'b>(
         timetables: &[MmapTimetable<'b>],
         base_path: &PathBuf,
-        valhalla_tile_path: &PathBuf,
+        transfer_provider: &dyn TransferProvider,
+        previous: Option<&PreviousTransfers>,
     ) -> MmapTimetable<'b> {
         {
             let total_routes: usize = timetables.iter().map(|tt| tt.routes().len()).sum();
@@ -449,6 +643,20 @@ impl<'a> MmapTimetable<'a> {
             let total_stop_routes: usize = timetables.iter().map(|tt| tt.stop_routes().len()).sum();
             let total_trip_stop_times: usize =
                 timetables.iter().map(|tt| tt.trip_stop_times().len()).sum();
+            let total_route_service_days: usize = timetables
+                .iter()
+                .map(|tt| tt.route_service_days().len())
+                .sum();
+            let total_trip_service_days: usize = timetables
+                .iter()
+                .map(|tt| tt.trip_service_days().len())
+                .sum();
+            let total_headways: usize = timetables.iter().map(|tt| tt.headways().len()).sum();
+            let total_pathway_transfers: usize = timetables
+                .iter()
+                .map(|tt| tt.pathway_transfers().len())
+                .sum();
+            let total_entrances: usize = timetables.iter().map(|tt| tt.entrances().len()).sum();
             {
                 let routes = File::create(base_path.join("routes")).unwrap();
                 let route_stops = File::create(base_path.join("route_stops")).unwrap();
@@ -456,6 +664,12 @@ impl<'a> MmapTimetable<'a> {
                 let stops = File::create(base_path.join("stops")).unwrap();
                 let stop_routes = File::create(base_path.join("stop_routes")).unwrap();
                 let trip_stop_times = File::create(base_path.join("trip_stop_times")).unwrap();
+                let route_service_days =
+                    File::create(base_path.join("route_service_days")).unwrap();
+                let trip_service_days = File::create(base_path.join("trip_service_days")).unwrap();
+                let headways = File::create(base_path.join("headways")).unwrap();
+                let pathway_transfers = File::create(base_path.join("pathway_transfers")).unwrap();
+                let entrances = File::create(base_path.join("entrances")).unwrap();
 
                 routes
                     .set_len((size_of::<Route>() * total_routes) as u64)
@@ -475,6 +689,21 @@ impl<'a> MmapTimetable<'a> {
                 trip_stop_times
                     .set_len((size_of::<TripStopTime>() * total_trip_stop_times) as u64)
                     .unwrap();
+                route_service_days
+                    .set_len((size_of::<Time>() * total_route_service_days) as u64)
+                    .unwrap();
+                trip_service_days
+                    .set_len((size_of::<u64>() * total_trip_service_days) as u64)
+                    .unwrap();
+                headways
+                    .set_len((size_of::<Headway>() * total_headways) as u64)
+                    .unwrap();
+                pathway_transfers
+                    .set_len((size_of::<Transfer>() * total_pathway_transfers) as u64)
+                    .unwrap();
+                entrances
+                    .set_len((size_of::<StationEntrance>() * total_entrances) as u64)
+                    .unwrap();
             }
 
             let routes = File::options()
@@ -507,6 +736,31 @@ impl<'a> MmapTimetable<'a> {
                 .read(true)
                 .open(base_path.join("trip_stop_times"))
                 .unwrap();
+            let route_service_days = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("route_service_days"))
+                .unwrap();
+            let trip_service_days = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("trip_service_days"))
+                .unwrap();
+            let headways = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("headways"))
+                .unwrap();
+            let pathway_transfers = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("pathway_transfers"))
+                .unwrap();
+            let entrances = File::options()
+                .write(true)
+                .read(true)
+                .open(base_path.join("entrances"))
+                .unwrap();
             let _ = File::create(base_path.join("transfer_index")).unwrap();
             let _ = File::create(base_path.join("transfers")).unwrap();
 
@@ -520,6 +774,14 @@ impl<'a> MmapTimetable<'a> {
                 unsafe { MmapOptions::new().map_mut(&stop_routes).unwrap() };
             let mut backing_trip_stop_times =
                 unsafe { MmapOptions::new().map_mut(&trip_stop_times).unwrap() };
+            let mut backing_route_service_days =
+                unsafe { MmapOptions::new().map_mut(&route_service_days).unwrap() };
+            let mut backing_trip_service_days =
+                unsafe { MmapOptions::new().map_mut(&trip_service_days).unwrap() };
+            let mut backing_headways = unsafe { MmapOptions::new().map_mut(&headways).unwrap() };
+            let mut backing_pathway_transfers =
+                unsafe { MmapOptions::new().map_mut(&pathway_transfers).unwrap() };
+            let mut backing_entrances = unsafe { MmapOptions::new().map_mut(&entrances).unwrap() };
 
             let mut route_cursor = 0usize;
             let mut route_stop_cursor = 0usize;
@@ -527,6 +789,11 @@ impl<'a> MmapTimetable<'a> {
             let mut stop_cursor = 0usize;
             let mut stop_route_cursor = 0usize;
             let mut trip_stop_time_cursor = 0usize;
+            let mut route_service_day_cursor = 0usize;
+            let mut trip_service_day_cursor = 0usize;
+            let mut headway_cursor = 0usize;
+            let mut pathway_transfer_cursor = 0usize;
+            let mut entrance_cursor = 0usize;
 
             let route_slice: &mut [Route] = cast_slice_mut(&mut backing_routes);
             let route_stop_slice: &mut [RouteStop] = cast_slice_mut(&mut backing_route_stops);
@@ -535,6 +802,13 @@ impl<'a> MmapTimetable<'a> {
             let stop_route_slice: &mut [StopRoute] = cast_slice_mut(&mut backing_stop_routes);
             let trip_stop_time_slice: &mut [TripStopTime] =
                 cast_slice_mut(&mut backing_trip_stop_times);
+            let route_service_day_slice: &mut [Time] =
+                cast_slice_mut(&mut backing_route_service_days);
+            let trip_service_day_slice: &mut [u64] = cast_slice_mut(&mut backing_trip_service_days);
+            let headway_slice: &mut [Headway] = cast_slice_mut(&mut backing_headways);
+            let pathway_transfer_slice: &mut [Transfer] =
+                cast_slice_mut(&mut backing_pathway_transfers);
+            let entrance_slice: &mut [StationEntrance] = cast_slice_mut(&mut backing_entrances);
 
             {
                 // Make mutable copies of the slices.
@@ -550,6 +824,17 @@ impl<'a> MmapTimetable<'a> {
                         [stop_route_cursor..stop_route_cursor + tt.stop_routes().len()];
                     let trip_stop_time_slice = &mut trip_stop_time_slice
                         [trip_stop_time_cursor..trip_stop_time_cursor + tt.trip_stop_times().len()];
+                    let route_service_day_slice = &mut route_service_day_slice
+                        [route_service_day_cursor
+                            ..route_service_day_cursor + tt.route_service_days().len()];
+                    let trip_service_day_slice = &mut trip_service_day_slice[trip_service_day_cursor
+                        ..trip_service_day_cursor + tt.trip_service_days().len()];
+                    let headway_slice =
+                        &mut headway_slice[headway_cursor..headway_cursor + tt.headways().len()];
+                    let pathway_transfer_slice = &mut pathway_transfer_slice[pathway_transfer_cursor
+                        ..pathway_transfer_cursor + tt.pathway_transfers().len()];
+                    let entrance_slice = &mut entrance_slice
+                        [entrance_cursor..entrance_cursor + tt.entrances().len()];
 
                     route_slice.copy_from_slice(tt.routes());
                     route_stop_slice.copy_from_slice(tt.route_stops());
@@ -557,11 +842,19 @@ impl<'a> MmapTimetable<'a> {
                     stop_slice.copy_from_slice(tt.stops());
                     stop_route_slice.copy_from_slice(tt.stop_routes());
                     trip_stop_time_slice.copy_from_slice(tt.trip_stop_times());
+                    route_service_day_slice.copy_from_slice(tt.route_service_days());
+                    trip_service_day_slice.copy_from_slice(tt.trip_service_days());
+                    headway_slice.copy_from_slice(tt.headways());
+                    pathway_transfer_slice.copy_from_slice(tt.pathway_transfers());
+                    entrance_slice.copy_from_slice(tt.entrances());
 
                     for route in route_slice {
                         route.first_route_stop += route_stop_cursor;
                         route.first_route_trip += route_trip_cursor;
                         route.route_index += route_cursor;
+                        route.first_service_day += route_service_day_cursor;
+                        route.first_headway_trip += route_trip_cursor;
+                        route.first_headway += headway_cursor;
                     }
                     for route_stop in route_stop_slice {
                         route_stop.route_index += route_cursor;
@@ -572,6 +865,7 @@ impl<'a> MmapTimetable<'a> {
                         route_trip.route_index += route_cursor;
                         route_trip.first_trip_stop_time += trip_stop_time_cursor;
                         route_trip.last_trip_stop_time += trip_stop_time_cursor;
+                        route_trip.first_service_word += trip_service_day_cursor;
                     }
                     for stop in stop_slice {
                         stop.stop_index += stop_cursor;
@@ -583,6 +877,16 @@ impl<'a> MmapTimetable<'a> {
                     for trip_stop_time in trip_stop_time_slice {
                         trip_stop_time.trip_index += route_trip_cursor;
                     }
+                    for headway in headway_slice {
+                        headway.trip_index += route_trip_cursor;
+                    }
+                    for transfer in pathway_transfer_slice {
+                        transfer.from += stop_cursor;
+                        transfer.to += stop_cursor;
+                    }
+                    for entrance in entrance_slice {
+                        entrance.stop_index += stop_cursor;
+                    }
 
                     route_cursor += tt.routes().len();
                     route_stop_cursor += tt.route_stops().len();
@@ -590,6 +894,11 @@ impl<'a> MmapTimetable<'a> {
                     stop_cursor += tt.stops().len();
                     stop_route_cursor += tt.stop_routes().len();
                     trip_stop_time_cursor += tt.trip_stop_times().len();
+                    route_service_day_cursor += tt.route_service_days().len();
+                    trip_service_day_cursor += tt.trip_service_days().len();
+                    headway_cursor += tt.headways().len();
+                    pathway_transfer_cursor += tt.pathway_transfers().len();
+                    entrance_cursor += tt.entrances().len();
                 }
             }
             let metadata_db = Database::create(base_path.join("metadata.db")).unwrap();
@@ -644,15 +953,52 @@ impl<'a> MmapTimetable<'a> {
                 }
                 write.commit().unwrap();
             }
+            {
+                let write = metadata_db.begin_write().unwrap();
+                {
+                    let mut table = write.open_table(FEED_FARES_TABLE).unwrap();
+                    for tt in timetables {
+                        let read = tt.metadata_db.begin_read().unwrap();
+                        let source = if let std::result::Result::Ok(source) =
+                            read.open_table(FEED_FARES_TABLE)
+                        {
+                            source
+                        } else {
+                            continue;
+                        };
+                        for entry in source.iter().unwrap() {
+                            let (feed_id, bytes) = entry.unwrap();
+                            table.insert(feed_id.value(), bytes.value()).unwrap();
+                        }
+                    }
+                }
+                write.commit().unwrap();
+            }
+            let service_window = timetables
+                .iter()
+                .filter_map(|tt| tt.service_window())
+                .reduce(|a, b| a.union(&b));
+            if let Some(service_window) = service_window {
+                let write = metadata_db.begin_write().unwrap();
+                {
+                    let mut table = write.open_table(TIMETABLE_INFO_TABLE).unwrap();
+                    let bytes = rmp_serde::to_vec(&service_window).unwrap();
+                    table.insert(SERVICE_WINDOW_KEY, bytes.as_slice()).unwrap();
+                }
+                write.commit().unwrap();
+            }
         }
         let mut tt = MmapTimetable::open(base_path).unwrap();
-        tt.calculate_transfers(valhalla_tile_path).await.unwrap();
+        tt.calculate_transfers(transfer_provider, previous)
+            .await
+            .unwrap();
         tt
     }
 
     pub(crate) async fn calculate_transfers(
         &mut self,
-        valhalla_tile_path: &PathBuf,
+        transfer_provider: &dyn TransferProvider,
+        previous: Option<&PreviousTransfers>,
     ) -> Result<(), Error> {
         {
             let mut rtree = RTree::<IndexedStop>::new();
@@ -669,27 +1015,15 @@ impl<'a> MmapTimetable<'a> {
         }
         assert_eq!(self.stops().len(), self.rtree.size());
 
-        info!("Opening transfer graph");
-        let transfer_graph = Arc::new(
-            TransferGraph::<FastGraphStatic, SphereIndexMmap<usize>>::read_from_dir(
-                valhalla_tile_path.clone(),
-                Arc::new(redb::Database::open(
-                    valhalla_tile_path.join("graph_metadata.db"),
-                )?),
-            )?,
-        );
-
         info!("Calculating transfer times");
-        let transfers: Vec<Vec<Transfer>> = self
-            .stops()
-            .par_iter()
-            .map_with(
-                TransferGraphSearcher::new(transfer_graph.clone()),
-                |searcher, from_stop| {
-                    self.calculate_transfer_matrix(&transfer_graph, searcher, from_stop)
-                },
-            )
-            .collect();
+        let transfers = calculate_all_transfers(&*self, transfer_provider, previous);
+        if let Some(previous) = previous {
+            info!(
+                "Reused transfers from the previous build for {} of {} stops",
+                previous.reused(),
+                transfers.len()
+            );
+        }
 
         let transfer_index_file = File::options()
             .write(true)
@@ -736,58 +1070,4 @@ impl<'a> MmapTimetable<'a> {
         }
         Ok(())
     }
-
-    fn generate_transfer_candidates(&self, stop: &Stop) -> Vec<&Stop> {
-        let latlng = stop.location();
-        let mut transfer_candidates = vec![];
-        for (count, (to_stop, dist_sq)) in self
-            .rtree
-            .nearest_neighbor_iter_with_distance_2(&lat_lng_to_cartesian(
-                latlng.lat.deg(),
-                latlng.lng.deg(),
-            ))
-            .enumerate()
-        {
-            let dist = dist_sq.sqrt();
-            if dist > 1000f64 || count > 20 {
-                break;
-            }
-            transfer_candidates.push(self.stop(to_stop.id));
-        }
-        transfer_candidates
-    }
-
-    fn calculate_transfer_matrix<G: FastGraph, I: SphereIndex<usize>>(
-        &self,
-        graph: &TransferGraph<G, I>,
-        search_context: &mut TransferGraphSearcher<G, I>,
-        stop: &Stop,
-    ) -> Vec<Transfer> {
-        let transfer_candidates = self.generate_transfer_candidates(stop);
-        transfer_candidates
-            .iter()
-            .filter_map(|to_stop| {
-                let transfer_time = graph
-                    .transfer_distance_mm(
-                        search_context,
-                        &Self::location_to_coords(&stop.location()),
-                        &Self::location_to_coords(&to_stop.location()),
-                    )
-                    .ok()?
-                    / WALK_SPEED_MM_PER_SECOND;
-                Some(Transfer {
-                    to: to_stop.id(),
-                    from: stop.id(),
-                    time: transfer_time,
-                })
-            })
-            .collect()
-    }
-
-    fn location_to_coords(location: &LatLng) -> Coord {
-        Coord {
-            x: location.lng.deg(),
-            y: location.lat.deg(),
-        }
-    }
 }
@@ -1,23 +1,38 @@
 pub mod in_memory;
+pub mod incremental;
 pub mod mmap;
 
-use std::{time::UNIX_EPOCH, u32};
+use std::{sync::Arc, time::UNIX_EPOCH, u32};
 
 use bytemuck::{Pod, Zeroable};
-use chrono::{DateTime, Days, NaiveDate, NaiveDateTime, NaiveTime};
+use chrono::{Days, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 
-use chrono_tz::Tz;
+use geo::Coord;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use redb::TableDefinition;
 use rstar::RTree;
 use s2::latlng::LatLng;
 use serde::{Deserialize, Serialize};
+use solari_transfers::{TransferProvider, TransferSearcher};
 
-use crate::spatial::IndexedStop;
+use self::incremental::PreviousTransfers;
+use crate::{
+    fares::FeedFares,
+    realtime::{
+        added_trips::AddedTrips, alerts::Alerts, trip_updates::TripUpdates,
+        vehicle_positions::VehiclePosition,
+    },
+    spatial::{IndexedStop, WALK_SPEED_MM_PER_SECOND},
+    stations::{PathwayMode, PathwayRoute},
+};
 
 static DAY_SECONDS: u32 = 86_400;
 const STOP_METADATA_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("stop_metadata");
 const TRIP_METADATA_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("trip_metadata");
 const ROUTE_SHAPE_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("route_shapes");
+const FEED_FARES_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("feed_fares");
+const TIMETABLE_INFO_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("timetable_info");
+const SERVICE_WINDOW_KEY: &str = "service_window";
 
 pub trait Timetable<'a> {
     fn route(&'a self, route_id: usize) -> &'a Route;
@@ -30,9 +45,16 @@ pub trait Timetable<'a> {
     fn route_stops(&'a self) -> &'a [RouteStop];
     fn route_trips(&'a self) -> &'a [Trip];
     fn trip_stop_times(&'a self) -> &'a [TripStopTime];
+    fn route_service_days(&'a self) -> &'a [Time];
+    fn trip_service_days(&'a self) -> &'a [u64];
+    fn headways(&'a self) -> &'a [Headway];
     fn transfers(&'a self) -> &'a [Transfer];
     fn transfer_index(&'a self) -> &'a [usize];
     fn transfers_from(&'a self, stop_id: usize) -> &'a [Transfer];
+    /// Transfers through station pathways, sorted by the stop they start from. These take precedence over walking transfers between the same stops.
+    fn pathway_transfers(&'a self) -> &'a [Transfer];
+    /// The entrances of the station each stop is in, sorted by stop.
+    fn entrances(&'a self) -> &'a [StationEntrance];
     fn stop_index_copy(&'a self) -> RTree<IndexedStop>;
     fn nearest_stops(&'a self, lat: f64, lng: f64, n: usize) -> Vec<(&'a Stop, f64)>;
 
@@ -40,6 +62,52 @@ pub trait Timetable<'a> {
     fn trip_metadata(&'a self, trip: &Trip) -> TripMetadata;
 
     fn route_shape(&'a self, route: &Route) -> Option<Vec<ShapeCoordinate>>;
+
+    /// Fare data for the feed with the given ID, if that feed had any.
+    fn feed_fares(&'a self, feed_id: &str) -> Option<FeedFares>;
+
+    /// The service days the timetable was built for, if known.
+    fn service_window(&'a self) -> Option<ServiceWindow>;
+
+    /// The real-time predictions and cancellations applied to trips, if there are any. Queries take this once and look every trip up in it.
+    fn trip_updates(&'a self) -> Option<Arc<TripUpdates>> {
+        None
+    }
+
+    /// The service alerts applied, if there are any. Queries take this once, both to tell which stops alerts close and to show riders the alerts for each leg.
+    fn alerts(&'a self) -> Option<Arc<Alerts>> {
+        None
+    }
+
+    /// Where the vehicle running `trip` is, from a real-time feed, unless it's already past `until_stop_seq`.
+    fn vehicle_position(
+        &'a self,
+        _trip: &ScheduledTrip,
+        _until_stop_seq: usize,
+    ) -> Option<VehiclePosition> {
+        None
+    }
+
+    /// Trips from real-time feeds that aren't in the timetable, if there are any.
+    fn added_trips(&'a self) -> Option<Arc<AddedTrips>> {
+        None
+    }
+}
+
+/// A trip's predicted times at a stop, from a real-time feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RealtimeStopTime {
+    pub arrival: Time,
+    pub departure: Time,
+    /// The trip won't stop here, so riders can neither board nor get off.
+    pub skipped: bool,
+}
+
+/// The most that any real-time prediction moves a departure, in seconds. Searches for the next trip look this far around the schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RealtimeSlack {
+    pub max_delay: u32,
+    pub max_early: u32,
 }
 
 #[derive(
@@ -75,6 +143,91 @@ impl<'a> Stop {
     pub fn metadata(&self, timetable: &'a dyn Timetable<'a>) -> gtfs_structures::Stop {
         timetable.stop_metadata(self).clone()
     }
+
+    /// The entrances of the station this stop is in, if the feed has any. Walking legs start and end at these rather than at the stop itself.
+    pub fn entrances(&self, timetable: &'a dyn Timetable<'a>) -> &'a [StationEntrance] {
+        let entrances = timetable.entrances();
+        let start = entrances.partition_point(|entrance| entrance.stop_index < self.stop_index);
+        let end = entrances.partition_point(|entrance| entrance.stop_index <= self.stop_index);
+        &entrances[start..end]
+    }
+
+    /// Where walks can leave or reach this stop: the entrances of its station, or the stop itself if it has none. Also returns how many seconds it takes to get between each one and the stop.
+    fn access_points(&self, timetable: &'a dyn Timetable<'a>) -> Vec<(LatLng, u32)> {
+        let entrances = self.entrances(timetable);
+        if entrances.is_empty() {
+            return vec![(self.location(), 0)];
+        }
+        entrances
+            .iter()
+            .map(|entrance| (entrance.location(), entrance.seconds()))
+            .collect()
+    }
+
+    /// The quickest walk from this stop's station to the one `to` is in, out through one entrance and in through another, going by how far `searcher` says each pair of entrances is apart. Returns where the walk leaves and arrives, and how many seconds it takes from stop to stop, or `None` if the searcher can't connect any of them.
+    pub fn walk_between_stations(
+        &self,
+        to: &Stop,
+        timetable: &'a dyn Timetable<'a>,
+        searcher: &mut dyn TransferSearcher,
+    ) -> Option<(LatLng, LatLng, u32)> {
+        let entries = to.access_points(timetable);
+        self.access_points(timetable)
+            .into_iter()
+            .flat_map(|exit| entries.iter().map(move |entry| (exit, *entry)))
+            .filter_map(|((from, exit_seconds), (to, entry_seconds))| {
+                let walk_seconds = searcher
+                    .transfer_distance_mm(&location_to_coords(&from), &location_to_coords(&to))
+                    .ok()?
+                    / WALK_SPEED_MM_PER_SECOND;
+                Some((from, to, exit_seconds + walk_seconds as u32 + entry_seconds))
+            })
+            .min_by_key(|(_, _, seconds)| *seconds)
+    }
+
+    /// Whether both stops are in the same station, going by their entrances. Walking between them doesn't go outside.
+    pub fn shares_entrances_with(&self, other: &Stop, timetable: &'a dyn Timetable<'a>) -> bool {
+        let cells = |stop: &Stop| {
+            stop.entrances(timetable)
+                .iter()
+                .map(|entrance| entrance.s2cell)
+                .collect::<Vec<_>>()
+        };
+        cells(self) == cells(other)
+    }
+}
+
+/// An entrance to the station a stop is in, along with how long it takes to get from there to the stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Pod, Zeroable)]
+#[repr(C)]
+pub struct StationEntrance {
+    stop_index: usize,
+    s2cell: u64,
+    seconds: u32,
+    padding: u32,
+}
+
+impl StationEntrance {
+    pub(crate) fn new(stop_index: usize, location: LatLng, seconds: u32) -> StationEntrance {
+        let s2cell: s2::cellid::CellID = location.into();
+        StationEntrance {
+            stop_index,
+            s2cell: s2cell.0,
+            seconds,
+            padding: 0,
+        }
+    }
+
+    #[inline]
+    pub fn location(&self) -> LatLng {
+        s2::cellid::CellID(self.s2cell).into()
+    }
+
+    /// How long it takes to get from the entrance to the stop, or back.
+    #[inline]
+    pub fn seconds(&self) -> u32 {
+        self.seconds
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Copy, Pod, Zeroable)]
@@ -126,6 +279,8 @@ pub struct Trip {
     route_index: usize,
     first_trip_stop_time: usize,
     last_trip_stop_time: usize,
+    /// Index of the first word of the bitset of service days this trip runs on, one bit per entry in the route's service days.
+    first_service_word: usize,
 }
 
 impl<'a> Trip {
@@ -141,6 +296,250 @@ impl<'a> Trip {
     pub fn metadata(&self, timetable: &'a dyn Timetable<'a>) -> TripMetadata {
         timetable.trip_metadata(self).clone()
     }
+
+    /// Whether the trip runs on the `day`th of its route's service days.
+    pub fn runs_on(&self, timetable: &'a dyn Timetable<'a>, day: usize) -> bool {
+        let word = timetable.trip_service_days()[self.first_service_word + day / 64];
+        word & (1 << (day % 64)) != 0
+    }
+}
+
+/// The number of bitset words needed for a route with `service_days` service days.
+pub(crate) fn service_day_words(service_days: usize) -> usize {
+    service_days.div_ceil(64)
+}
+
+/// The time `offset` seconds into a service day. Every stored stop time has an offset, since untimed stops are interpolated between timepoints when the timetable is built.
+#[inline]
+fn at_offset(service_day_start: Time, offset: u32) -> Time {
+    Time::from_epoch_seconds(service_day_start.epoch_seconds().saturating_add(offset))
+}
+
+/// A window of headway-based service from `frequencies.txt` with `exact_times=0`. Vehicles follow the template trip's stop times, leaving its first stop every `headway_secs` between `start_time` and `end_time`, but without a fixed schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Pod, Zeroable)]
+#[repr(C)]
+pub struct Headway {
+    trip_index: usize,
+    /// Seconds after the start of the service day.
+    start_time: u32,
+    /// Seconds after the start of the service day.
+    end_time: u32,
+    headway_secs: u32,
+    padding: u32,
+}
+
+impl<'a> Headway {
+    pub(crate) fn new(
+        trip_index: usize,
+        start_time: u32,
+        end_time: u32,
+        headway_secs: u32,
+    ) -> Headway {
+        Headway {
+            trip_index,
+            start_time,
+            end_time,
+            headway_secs,
+            padding: 0,
+        }
+    }
+
+    #[inline]
+    pub fn trip(&self, timetable: &'a dyn Timetable<'a>) -> &'a Trip {
+        &timetable.route_trips()[self.trip_index]
+    }
+
+    #[inline]
+    pub fn headway_secs(&self) -> u32 {
+        self.headway_secs
+    }
+}
+
+/// A trip on a particular service day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScheduledTrip {
+    trip: Trip,
+    service_day_start: Time,
+    /// For headway-based service, how often vehicles come. The stop times are then estimates.
+    headway_secs: Option<u32>,
+}
+
+impl<'a> ScheduledTrip {
+    #[inline]
+    pub fn trip(&self) -> &Trip {
+        &self.trip
+    }
+
+    #[inline]
+    pub fn service_day_start(&self) -> Time {
+        self.service_day_start
+    }
+
+    #[inline]
+    pub fn headway_secs(&self) -> Option<u32> {
+        self.headway_secs
+    }
+
+    /// When the trip arrives at `stop_seq`, as predicted by `trip_updates` if there's a prediction.
+    #[inline]
+    pub fn arrival(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        trip_updates: Option<&TripUpdates>,
+        stop_seq: usize,
+    ) -> Time {
+        if let Some(realtime) = self.realtime(trip_updates, stop_seq) {
+            return realtime.arrival;
+        }
+        at_offset(
+            self.service_day_start,
+            self.trip.stop_times(timetable)[stop_seq].arrival_offset(),
+        )
+    }
+
+    /// When the trip departs `stop_seq`, as predicted by `trip_updates` if there's a prediction.
+    #[inline]
+    pub fn departure(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        trip_updates: Option<&TripUpdates>,
+        stop_seq: usize,
+    ) -> Time {
+        if let Some(realtime) = self.realtime(trip_updates, stop_seq) {
+            return realtime.departure;
+        }
+        at_offset(
+            self.service_day_start,
+            self.trip.stop_times(timetable)[stop_seq].departure_offset(),
+        )
+    }
+
+    /// Whether riders can board at `stop_seq`: the schedule allows it, the stop isn't being skipped, and none of `alerts` closes it.
+    pub fn pickup_allowed(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        trip_updates: Option<&TripUpdates>,
+        alerts: Option<&Alerts>,
+        stop_seq: usize,
+    ) -> bool {
+        self.trip.stop_times(timetable)[stop_seq].pickup_allowed()
+            && !self.skips(trip_updates, stop_seq)
+            && !alerts.is_some_and(|alerts| {
+                alerts.closes(
+                    timetable,
+                    self,
+                    stop_seq,
+                    self.departure(timetable, trip_updates, stop_seq),
+                )
+            })
+    }
+
+    /// Whether riders can get off at `stop_seq`: the schedule allows it, the stop isn't being skipped, and none of `alerts` closes it.
+    pub fn drop_off_allowed(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        trip_updates: Option<&TripUpdates>,
+        alerts: Option<&Alerts>,
+        stop_seq: usize,
+    ) -> bool {
+        self.trip.stop_times(timetable)[stop_seq].drop_off_allowed()
+            && !self.skips(trip_updates, stop_seq)
+            && !alerts.is_some_and(|alerts| {
+                alerts.closes(
+                    timetable,
+                    self,
+                    stop_seq,
+                    self.arrival(timetable, trip_updates, stop_seq),
+                )
+            })
+    }
+
+    fn skips(&self, trip_updates: Option<&TripUpdates>, stop_seq: usize) -> bool {
+        self.realtime(trip_updates, stop_seq)
+            .is_some_and(|realtime| realtime.skipped)
+    }
+
+    /// Headway-based trips are estimates to begin with, so they don't get predictions.
+    fn realtime(
+        &self,
+        trip_updates: Option<&TripUpdates>,
+        stop_seq: usize,
+    ) -> Option<RealtimeStopTime> {
+        if self.headway_secs.is_some() {
+            return None;
+        }
+        trip_updates?.stop_time(&self.trip, self.service_day_start, stop_seq)
+    }
+}
+
+/// The number of service days in a timetable when no window is given: yesterday, so that trips running past midnight are included, plus two weeks.
+pub const DEFAULT_SERVICE_DAYS: u32 = 15;
+
+/// The range of service days a timetable contains trips for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceWindow {
+    pub start_date: NaiveDate,
+    /// The number of service days, including `start_date`.
+    pub days: u32,
+}
+
+impl ServiceWindow {
+    pub fn new(start_date: NaiveDate, days: u32) -> ServiceWindow {
+        ServiceWindow { start_date, days }
+    }
+
+    /// A window of `days` service days starting yesterday, in UTC.
+    pub fn starting_yesterday(days: u32) -> ServiceWindow {
+        let start_date = Utc::now()
+            .date_naive()
+            .pred_opt()
+            .expect("Date out of range");
+        ServiceWindow::new(start_date, days)
+    }
+
+    /// The last service day in the window.
+    pub fn end_date(&self) -> NaiveDate {
+        self.start_date
+            .checked_add_days(Days::new(self.days.saturating_sub(1) as u64))
+            .expect("Date out of range")
+    }
+
+    /// The earliest time that falls on a service day in the window, in any timezone. Service days in UTC+14 start 14 hours before they do in UTC.
+    pub fn earliest_time(&self) -> Time {
+        let start = self
+            .start_date
+            .and_time(NaiveTime::MIN)
+            .and_utc()
+            .timestamp()
+            - 14 * 3600;
+        Time::from_epoch_seconds(start.max(0) as u32)
+    }
+
+    /// The latest time that falls on a service day in the window, in any timezone. Service days in UTC-12 end 12 hours after they do in UTC.
+    pub fn latest_time(&self) -> Time {
+        let end = self
+            .end_date()
+            .succ_opt()
+            .expect("Date out of range")
+            .and_time(NaiveTime::MIN)
+            .and_utc()
+            .timestamp()
+            + 12 * 3600;
+        Time::from_epoch_seconds(end.min(u32::MAX as i64) as u32)
+    }
+
+    /// The smallest window that contains both windows.
+    pub fn union(&self, other: &ServiceWindow) -> ServiceWindow {
+        let start_date = self.start_date.min(other.start_date);
+        let end_date = self.end_date().max(other.end_date());
+        ServiceWindow::new(start_date, (end_date - start_date).num_days() as u32 + 1)
+    }
+}
+
+impl Default for ServiceWindow {
+    fn default() -> Self {
+        ServiceWindow::starting_yesterday(DEFAULT_SERVICE_DAYS)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -148,6 +547,19 @@ pub struct TripMetadata {
     pub headsign: Option<String>,
     pub route_name: Option<String>,
     pub agency_name: Option<String>,
+    /// The ID of the feed this trip came from, as passed to the timetable builder.
+    #[serde(default)]
+    pub feed_id: String,
+    #[serde(default)]
+    pub gtfs_route_id: Option<String>,
+    #[serde(default)]
+    pub gtfs_trip_id: Option<String>,
+    /// The timezone of the trip's service days, which real-time updates give their dates in.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// The GTFS `stop_sequence` of each of the trip's stops, for matching real-time updates.
+    #[serde(default)]
+    pub stop_sequences: Vec<u32>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Pod, Zeroable)]
@@ -156,6 +568,10 @@ pub struct Route {
     route_index: usize,
     first_route_stop: usize,
     pub(crate) first_route_trip: usize,
+    first_service_day: usize,
+    /// Trips from here to the end of the route's trips are templates for headway-based service, and aren't run on their own.
+    first_headway_trip: usize,
+    first_headway: usize,
 }
 
 impl<'a> Route {
@@ -177,6 +593,188 @@ impl<'a> Route {
         &timetable.route_trips()[self.first_route_trip..range_end]
     }
 
+    /// The route's trips that run at fixed times, sorted by departure.
+    pub fn scheduled_trips(&self, timetable: &'a dyn Timetable<'a>) -> &'a [Trip] {
+        &timetable.route_trips()[self.first_route_trip..self.first_headway_trip]
+    }
+
+    pub fn headways(&self, timetable: &'a dyn Timetable<'a>) -> &'a [Headway] {
+        let range_end = if self.route_index == timetable.routes().len() - 1 {
+            timetable.headways().len()
+        } else {
+            timetable.routes()[self.route_index + 1].first_headway
+        };
+        &timetable.headways()[self.first_headway..range_end]
+    }
+
+    /// The start of every service day the route's trips might run on, in order. Trips store their stop times relative to these.
+    pub fn service_days(&self, timetable: &'a dyn Timetable<'a>) -> &'a [Time] {
+        let range_end = if self.route_index == timetable.routes().len() - 1 {
+            timetable.route_service_days().len()
+        } else {
+            timetable.routes()[self.route_index + 1].first_service_day
+        };
+        &timetable.route_service_days()[self.first_service_day..range_end]
+    }
+
+    /// Find the trip that departs `stop_seq` earliest, at or after `not_before`, on any service day, skipping trips that don't pick up there.
+    pub fn earliest_trip(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        trip_updates: Option<&TripUpdates>,
+        alerts: Option<&Alerts>,
+        stop_seq: usize,
+        not_before: Time,
+    ) -> Option<ScheduledTrip> {
+        let scheduled =
+            self.earliest_scheduled_trip(timetable, trip_updates, alerts, stop_seq, not_before);
+        let headway = self.earliest_headway_trip(timetable, alerts, stop_seq, not_before);
+        match (scheduled, headway) {
+            (Some(scheduled), Some(headway)) => {
+                if headway.departure(timetable, None, stop_seq)
+                    < scheduled.departure(timetable, trip_updates, stop_seq)
+                {
+                    Some(headway)
+                } else {
+                    Some(scheduled)
+                }
+            }
+            (scheduled, headway) => scheduled.or(headway),
+        }
+    }
+
+    fn earliest_scheduled_trip(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        trip_updates: Option<&TripUpdates>,
+        alerts: Option<&Alerts>,
+        stop_seq: usize,
+        not_before: Time,
+    ) -> Option<ScheduledTrip> {
+        let trips = self.scheduled_trips(timetable);
+        let service_days = self.service_days(timetable);
+        let offset = |trip: &Trip| trip.stop_times(timetable)[stop_seq].departure_offset();
+        let first_offset = offset(trips.first()?);
+        let last_offset = offset(trips.last()?);
+        // A delayed trip scheduled before `not_before` may still be ahead, and a trip running early may overtake the ones scheduled before it.
+        let slack = trip_updates
+            .map(|trip_updates| trip_updates.slack())
+            .unwrap_or_default();
+        let scheduled_not_before =
+            Time::from_epoch_seconds(not_before.epoch_seconds().saturating_sub(slack.max_delay));
+        let cannot_beat = |scheduled: Time, best: &ScheduledTrip| {
+            scheduled.epoch_seconds().saturating_sub(slack.max_early)
+                >= best
+                    .departure(timetable, trip_updates, stop_seq)
+                    .epoch_seconds()
+        };
+
+        // Trips can run past midnight, so a trip from an earlier service day may still be the first to depart.
+        let first_day =
+            service_days.partition_point(|day| at_offset(*day, last_offset) < scheduled_not_before);
+        let mut best: Option<ScheduledTrip> = None;
+        for (day, service_day_start) in service_days.iter().enumerate().skip(first_day) {
+            if let Some(best) = &best {
+                if cannot_beat(at_offset(*service_day_start, first_offset), best) {
+                    break;
+                }
+            }
+            let position = trips.partition_point(|trip| {
+                at_offset(*service_day_start, offset(trip)) < scheduled_not_before
+            });
+            for trip in &trips[position..] {
+                if let Some(best) = &best {
+                    if cannot_beat(at_offset(*service_day_start, offset(trip)), best) {
+                        break;
+                    }
+                }
+                if !trip.runs_on(timetable, day)
+                    || trip_updates
+                        .is_some_and(|trip_updates| trip_updates.canceled(trip, *service_day_start))
+                {
+                    continue;
+                }
+                let candidate = ScheduledTrip {
+                    trip: *trip,
+                    service_day_start: *service_day_start,
+                    headway_secs: None,
+                };
+                if candidate.departure(timetable, trip_updates, stop_seq) < not_before
+                    || !candidate.pickup_allowed(timetable, trip_updates, alerts, stop_seq)
+                {
+                    continue;
+                }
+                if best.is_none_or(|best| {
+                    candidate.departure(timetable, trip_updates, stop_seq)
+                        < best.departure(timetable, trip_updates, stop_seq)
+                }) {
+                    best = Some(candidate);
+                }
+            }
+        }
+        best
+    }
+
+    /// Without a schedule there's no way to know when the next vehicle comes, so assume the rider has just missed one and waits a full headway.
+    fn earliest_headway_trip(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        alerts: Option<&Alerts>,
+        stop_seq: usize,
+        not_before: Time,
+    ) -> Option<ScheduledTrip> {
+        let service_days = self.service_days(timetable);
+        let mut best: Option<ScheduledTrip> = None;
+        for headway in self.headways(timetable) {
+            let trip = headway.trip(timetable);
+            let stop_times = trip.stop_times(timetable);
+            if !stop_times[stop_seq].pickup_allowed() {
+                continue;
+            }
+            let first_departure = stop_times.first()?.departure_offset();
+            let from_first_stop = stop_times[stop_seq]
+                .departure_offset()
+                .saturating_sub(first_departure);
+            let earliest_first_departure = not_before
+                .epoch_seconds()
+                .saturating_add(headway.headway_secs)
+                .saturating_sub(from_first_stop);
+            for (day, service_day_start) in service_days.iter().enumerate() {
+                let window_start = service_day_start
+                    .epoch_seconds()
+                    .saturating_add(headway.start_time);
+                let window_end = service_day_start
+                    .epoch_seconds()
+                    .saturating_add(headway.end_time);
+                if window_end <= earliest_first_departure || !trip.runs_on(timetable, day) {
+                    continue;
+                }
+                // Shift the template trip so that it leaves its first stop at the estimated time.
+                let departs_first_stop = earliest_first_departure.max(window_start);
+                let candidate = ScheduledTrip {
+                    trip: *trip,
+                    service_day_start: Time::from_epoch_seconds(
+                        departs_first_stop.saturating_sub(first_departure),
+                    ),
+                    headway_secs: Some(headway.headway_secs),
+                };
+                // Headway-based trips don't get predictions, so there's nothing to look up.
+                if !candidate.pickup_allowed(timetable, None, alerts, stop_seq) {
+                    continue;
+                }
+                if best.is_none_or(|best| {
+                    candidate.departure(timetable, None, stop_seq)
+                        < best.departure(timetable, None, stop_seq)
+                }) {
+                    best = Some(candidate);
+                }
+                // Later service days only depart later.
+                break;
+            }
+        }
+        best
+    }
+
     #[inline]
     pub fn id(&self) -> usize {
         self.route_index
@@ -207,57 +805,76 @@ impl<'a> StopRoute {
     }
 }
 
+/// Set on a stop time where riders can't board (`pickup_type=1`).
+const NO_PICKUP: u32 = 1 << 0;
+/// Set on a stop time where riders can't get off (`drop_off_type=1`).
+const NO_DROP_OFF: u32 = 1 << 1;
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Pod, Zeroable)]
 #[repr(C)]
 pub struct TripStopTime {
     pub(crate) trip_index: usize,
-    pub(crate) route_stop_seq: usize,
+    pub(crate) route_stop_seq: u32,
+    /// Seconds after the start of the service day.
     arrival_time: u32,
+    /// Seconds after the start of the service day.
     departure_time: u32,
+    flags: u32,
 }
 
 impl<'a> TripStopTime {
+    /// Seconds from the start of the service day until the trip arrives.
     #[inline]
-    pub fn arrival(&self) -> Time {
-        Time {
-            epoch_seconds: self.arrival_time,
-        }
+    pub fn arrival_offset(&self) -> u32 {
+        self.arrival_time
     }
 
+    /// Seconds from the start of the service day until the trip departs.
     #[inline]
-    pub fn departure(&self) -> Time {
-        Time {
-            epoch_seconds: self.departure_time,
-        }
+    pub fn departure_offset(&self) -> u32 {
+        self.departure_time
+    }
+
+    /// Whether riders can board here.
+    #[inline]
+    pub fn pickup_allowed(&self) -> bool {
+        self.flags & NO_PICKUP == 0
+    }
+
+    /// Whether riders can get off here.
+    #[inline]
+    pub fn drop_off_allowed(&self) -> bool {
+        self.flags & NO_DROP_OFF == 0
     }
 
     pub(crate) fn new(
         trip_index: usize,
         route_stop_seq: usize,
-        arrival_time: DateTime<Tz>,
-        departure_time: DateTime<Tz>,
+        arrival_time: u32,
+        departure_time: u32,
+        pickup_allowed: bool,
+        drop_off_allowed: bool,
     ) -> TripStopTime {
-        TripStopTime {
-            trip_index,
-            route_stop_seq,
-            arrival_time: arrival_time.timestamp() as u32,
-            departure_time: departure_time.timestamp() as u32,
+        let mut flags = 0;
+        if !pickup_allowed {
+            flags |= NO_PICKUP;
+        }
+        if !drop_off_allowed {
+            flags |= NO_DROP_OFF;
         }
-    }
-
-    pub(crate) fn marked() -> TripStopTime {
         TripStopTime {
-            trip_index: usize::MAX,
-            route_stop_seq: usize::MAX,
-            arrival_time: u32::MAX,
-            departure_time: u32::MAX,
+            trip_index,
+            route_stop_seq: route_stop_seq as u32,
+            arrival_time,
+            departure_time,
+            flags,
         }
     }
 
     #[inline]
     pub fn route_stop(&self, timetable: &'a dyn Timetable<'a>) -> &'a RouteStop {
         let route = &timetable.route_trips()[self.trip_index].route(timetable);
-        &timetable.route_stops()[route.first_route_stop + self.route_stop_seq]
+        &timetable.route_stops()[route.first_route_stop + self.route_stop_seq as usize]
     }
 }
 
@@ -266,10 +883,21 @@ impl<'a> TripStopTime {
 pub struct Transfer {
     to: usize,
     from: usize,
-    time: u64,
+    time: u32,
+    /// Flags of the pathway modes used, if the transfer goes through station pathways.
+    pathway_modes: u32,
 }
 
 impl<'a> Transfer {
+    pub(crate) fn through_pathways(from: usize, to: usize, route: PathwayRoute) -> Transfer {
+        Transfer {
+            to,
+            from,
+            time: route.seconds,
+            pathway_modes: route.modes,
+        }
+    }
+
     pub fn all_transfers(from: &Stop, timetable: &'a dyn Timetable<'a>) -> &'a [Transfer] {
         let from = from.stop_index;
         let range_end = if from == timetable.transfer_index().len() - 1 {
@@ -280,6 +908,13 @@ impl<'a> Transfer {
         &timetable.transfers()[timetable.transfer_index()[from]..range_end]
     }
 
+    pub fn pathway_transfers_from(from: &Stop, timetable: &'a dyn Timetable<'a>) -> &'a [Transfer] {
+        let transfers = timetable.pathway_transfers();
+        let start = transfers.partition_point(|transfer| transfer.from < from.stop_index);
+        let end = transfers.partition_point(|transfer| transfer.from <= from.stop_index);
+        &transfers[start..end]
+    }
+
     #[inline]
     pub fn to(&self, timetable: &'a dyn Timetable<'a>) -> &'a Stop {
         timetable.stop(self.to)
@@ -287,7 +922,96 @@ impl<'a> Transfer {
 
     #[inline]
     pub fn time_seconds(&self) -> u32 {
-        self.time as u32
+        self.time
+    }
+
+    /// The stairs, elevators, escalators and so on along the way, if the transfer goes through station pathways.
+    pub fn pathway_modes(&self) -> Vec<PathwayMode> {
+        PathwayMode::from_flags(self.pathway_modes)
+    }
+}
+
+/// Calculate the walking transfers out of every stop in the timetable, in stop order, reusing those from `previous` where they still hold.
+pub(crate) fn calculate_all_transfers<'a, T: Timetable<'a> + Sync>(
+    timetable: &'a T,
+    transfer_provider: &dyn TransferProvider,
+    previous: Option<&PreviousTransfers>,
+) -> Vec<Vec<Transfer>> {
+    timetable
+        .stops()
+        .par_iter()
+        .map_init(
+            || transfer_provider.searcher(),
+            |searcher, from_stop| {
+                previous
+                    .and_then(|previous| previous.reusable(timetable, from_stop))
+                    .unwrap_or_else(|| {
+                        calculate_transfer_matrix(timetable, searcher.as_mut(), from_stop)
+                    })
+            },
+        )
+        .collect()
+}
+
+fn generate_transfer_candidates<'a, T: Timetable<'a>>(
+    timetable: &'a T,
+    stop: &Stop,
+) -> Vec<&'a Stop> {
+    let latlng = stop.location();
+    timetable
+        .nearest_stops(latlng.lat.deg(), latlng.lng.deg(), 21)
+        .into_iter()
+        .take_while(|(_to_stop, dist)| *dist <= 1000f64)
+        .map(|(to_stop, _dist)| to_stop)
+        .collect()
+}
+
+fn calculate_transfer_matrix<'a, T: Timetable<'a>>(
+    timetable: &'a T,
+    search_context: &mut dyn TransferSearcher,
+    stop: &Stop,
+) -> Vec<Transfer> {
+    let pathway_transfers = Transfer::pathway_transfers_from(stop, timetable);
+    let transfer_candidates = generate_transfer_candidates(timetable, stop);
+    // Walking between stops of a station connected by pathways would cut through walls and floors, so the pathways are used instead.
+    transfer_candidates
+        .iter()
+        .filter(|to_stop| {
+            !pathway_transfers
+                .iter()
+                .any(|transfer| transfer.to == to_stop.id())
+        })
+        .filter_map(|to_stop| {
+            // Leaving one station for another goes out through an entrance and in through another.
+            let time = if stop.shares_entrances_with(to_stop, timetable) {
+                let walk_time = search_context
+                    .transfer_distance_mm(
+                        &location_to_coords(&stop.location()),
+                        &location_to_coords(&to_stop.location()),
+                    )
+                    .ok()?
+                    / WALK_SPEED_MM_PER_SECOND;
+                walk_time as u32
+            } else {
+                let (_, _, seconds) =
+                    stop.walk_between_stations(to_stop, timetable, search_context)?;
+                seconds
+            };
+            Some(Transfer {
+                to: to_stop.id(),
+                from: stop.id(),
+                time,
+                pathway_modes: 0,
+            })
+        })
+        .chain(pathway_transfers.iter().copied())
+        .collect()
+}
+
+fn location_to_coords(location: &LatLng) -> Coord {
+    Coord {
+        x: location.lng.deg(),
+        y: location.lat.deg(),
     }
 }
 
@@ -363,7 +1087,20 @@ impl ShapeCoordinate {
 mod test {
     use chrono::{NaiveDate, NaiveTime};
 
-    use super::Time;
+    use super::{ServiceWindow, Time};
+
+    #[test]
+    fn service_window_union() {
+        let a = ServiceWindow::new(NaiveDate::from_ymd_opt(2025, 3, 1).unwrap(), 15);
+        let b = ServiceWindow::new(NaiveDate::from_ymd_opt(2025, 3, 10).unwrap(), 15);
+        assert_eq!(a.end_date(), NaiveDate::from_ymd_opt(2025, 3, 15).unwrap());
+        let union = a.union(&b);
+        assert_eq!(union.start_date, a.start_date);
+        assert_eq!(union.end_date(), b.end_date());
+        // 2025-03-01T00:00Z minus 14 hours, and 2025-03-25T00:00Z plus 12 hours.
+        assert_eq!(union.earliest_time().epoch_seconds(), 1_740_736_800);
+        assert_eq!(union.latest_time().epoch_seconds(), 1_742_904_000);
+    }
 
     #[test]
     fn time_with_24hr_service_day() {
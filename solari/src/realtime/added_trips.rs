@@ -0,0 +1,78 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::raptor::timetable::{Time, TripMetadata};
+
+/// A trip that isn't in the static timetable, from an `ADDED`, `NEW` or `REPLACEMENT` TripUpdate. Its times are absolute rather than offsets into a service day, since it runs only once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedTrip {
+    /// The real-time source the trip came from.
+    pub(crate) source_id: String,
+    pub(crate) metadata: TripMetadata,
+    pub(crate) stop_times: Vec<AddedStopTime>,
+}
+
+impl AddedTrip {
+    pub fn metadata(&self) -> &TripMetadata {
+        &self.metadata
+    }
+
+    pub fn stop_times(&self) -> &[AddedStopTime] {
+        &self.stop_times
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddedStopTime {
+    /// The index of the stop in the static timetable.
+    pub stop: usize,
+    pub arrival: Time,
+    pub departure: Time,
+}
+
+/// The trips added by real-time feeds, as of one update. Routing takes a snapshot of these at the start of each query, so an update can't change them partway through. Every trip is its own pattern, since there are too few of them for grouping trips by stops to pay off.
+#[derive(Debug, Default)]
+pub struct AddedTrips {
+    trips: Vec<Arc<AddedTrip>>,
+    /// The trips that stop at each stop, and where along each trip it does.
+    by_stop: HashMap<usize, Vec<(usize, usize)>>,
+}
+
+impl AddedTrips {
+    fn new(trips: Vec<Arc<AddedTrip>>) -> AddedTrips {
+        let mut by_stop: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+        for (trip_idx, trip) in trips.iter().enumerate() {
+            for (stop_seq, stop_time) in trip.stop_times.iter().enumerate() {
+                by_stop
+                    .entry(stop_time.stop)
+                    .or_default()
+                    .push((trip_idx, stop_seq));
+            }
+        }
+        AddedTrips { trips, by_stop }
+    }
+
+    /// These trips with those from the source `source_id` replaced by `trips`.
+    pub(crate) fn replace_source(&self, source_id: &str, trips: Vec<AddedTrip>) -> AddedTrips {
+        AddedTrips::new(
+            self.trips
+                .iter()
+                .filter(|trip| trip.source_id != source_id)
+                .cloned()
+                .chain(trips.into_iter().map(Arc::new))
+                .collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trips.is_empty()
+    }
+
+    pub fn trip(&self, trip_idx: usize) -> &Arc<AddedTrip> {
+        &self.trips[trip_idx]
+    }
+
+    /// The trips that stop at `stop`, as the index of the trip and the stop's sequence along it.
+    pub fn stopping_at(&self, stop: usize) -> &[(usize, usize)] {
+        self.by_stop.get(&stop).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
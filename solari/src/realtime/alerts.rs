@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use gtfs_rt::{alert, EntitySelector, FeedMessage, TimeRange, TranslatedString};
+use serde::{Deserialize, Serialize};
+
+use crate::raptor::timetable::{ScheduledTrip, Stop, Time, Timetable, Trip};
+
+use super::{trip_service_day, trip_updates::find_trip, EntityIndex};
+
+/// A service alert from a GTFS-RT feed, as shown to riders.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceAlert {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The GTFS-RT cause, e.g. `CONSTRUCTION`.
+    pub cause: String,
+    /// The GTFS-RT effect, e.g. `NO_SERVICE`.
+    pub effect: String,
+    /// When the alert is in effect. Empty if it always is.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub active_periods: Vec<ActivePeriod>,
+}
+
+/// A time range an alert is in effect for, in seconds since the Unix epoch. Either end may be open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActivePeriod {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<u64>,
+}
+
+impl ActivePeriod {
+    fn overlaps(&self, start: Time, end: Time) -> bool {
+        self.start
+            .is_none_or(|period_start| period_start <= end.epoch_seconds() as u64)
+            && self
+                .end
+                .is_none_or(|period_end| period_end > start.epoch_seconds() as u64)
+    }
+}
+
+/// A problem with an alert's informed entities that meant some of them couldn't be matched to the timetable.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AlertIssue {
+    #[error("alert {alert_id:?} refers to stop {stop_id:?}, which isn't in the timetable")]
+    UnknownStop { alert_id: String, stop_id: String },
+    #[error("alert {alert_id:?} refers to route {route_id:?}, which isn't in the timetable")]
+    UnknownRoute { alert_id: String, route_id: String },
+    #[error("alert {alert_id:?} refers to trip {trip_id:?}, which isn't in the timetable or doesn't run on its date")]
+    UnknownTrip { alert_id: String, trip_id: String },
+    #[error("alert {alert_id:?} selects agencies or route types, which aren't supported")]
+    UnsupportedSelector { alert_id: String },
+}
+
+/// What came of applying a feed's alerts.
+#[derive(Debug, Clone, Default)]
+pub struct AlertReport {
+    pub issues: Vec<AlertIssue>,
+    /// Alerts matched to at least one stop, route or trip.
+    pub alerts: usize,
+}
+
+impl AlertReport {
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn record(&mut self, issue: AlertIssue) {
+        if !self.issues.contains(&issue) {
+            self.issues.push(issue);
+        }
+    }
+}
+
+/// The part of the timetable an informed entity selects. Each of the fields that's set narrows it down further.
+#[derive(Debug, Clone, Default)]
+struct AffectedEntity {
+    stops: Option<HashSet<usize>>,
+    routes: Option<HashSet<usize>>,
+    /// Trips, and the service day they're affected on if the alert gives one.
+    trips: Option<HashSet<(Trip, Option<Time>)>>,
+}
+
+impl AffectedEntity {
+    fn matches(&self, trip: Option<&ScheduledTrip>, route: Option<usize>, stops: &[usize]) -> bool {
+        self.stops
+            .as_ref()
+            .is_none_or(|affected| stops.iter().any(|stop| affected.contains(stop)))
+            && self
+                .routes
+                .as_ref()
+                .is_none_or(|affected| route.is_some_and(|route| affected.contains(&route)))
+            && self.trips.as_ref().is_none_or(|affected| {
+                trip.is_some_and(|trip| {
+                    affected.contains(&(*trip.trip(), Some(trip.service_day_start())))
+                        || affected.contains(&(*trip.trip(), None))
+                })
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FeedAlert {
+    /// The real-time source the alert came from.
+    source_id: String,
+    alert: ServiceAlert,
+    /// Riders can't use what the alert affects at all, rather than just being told about it.
+    closure: bool,
+    entities: Vec<AffectedEntity>,
+}
+
+impl FeedAlert {
+    fn matches(
+        &self,
+        trip: Option<&ScheduledTrip>,
+        route: Option<usize>,
+        stops: &[usize],
+        start: Time,
+        end: Time,
+    ) -> bool {
+        (self.alert.active_periods.is_empty()
+            || self
+                .alert
+                .active_periods
+                .iter()
+                .any(|period| period.overlaps(start, end)))
+            && self
+                .entities
+                .iter()
+                .any(|entity| entity.matches(trip, route, stops))
+    }
+}
+
+/// Every alert currently applied, as of one update. Replaced rather than changed when a feed updates, so that a query can look alerts up in one snapshot.
+#[derive(Debug, Default)]
+pub struct Alerts {
+    alerts: Vec<FeedAlert>,
+    /// The closures that might apply at each stop, as indices into `alerts`. A closure that doesn't select stops is indexed by the routes it selects, or failing that by its trips.
+    closures_by_stop: HashMap<usize, Vec<usize>>,
+    closures_by_route: HashMap<usize, Vec<usize>>,
+    closures_by_trip: HashMap<Trip, Vec<usize>>,
+}
+
+impl Alerts {
+    fn new(alerts: Vec<FeedAlert>) -> Alerts {
+        let mut closures_by_stop: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut closures_by_route: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut closures_by_trip: HashMap<Trip, Vec<usize>> = HashMap::new();
+        for (alert_idx, alert) in alerts.iter().enumerate() {
+            if !alert.closure {
+                continue;
+            }
+            for entity in &alert.entities {
+                if let Some(stops) = &entity.stops {
+                    for stop in stops {
+                        closures_by_stop.entry(*stop).or_default().push(alert_idx);
+                    }
+                } else if let Some(routes) = &entity.routes {
+                    for route in routes {
+                        closures_by_route.entry(*route).or_default().push(alert_idx);
+                    }
+                } else if let Some(trips) = &entity.trips {
+                    for (trip, _service_day) in trips {
+                        closures_by_trip.entry(*trip).or_default().push(alert_idx);
+                    }
+                }
+            }
+        }
+        Alerts {
+            alerts,
+            closures_by_stop,
+            closures_by_route,
+            closures_by_trip,
+        }
+    }
+
+    /// These alerts with those from the source `source_id` replaced by `alerts`.
+    pub(crate) fn replace_source(&self, source_id: &str, alerts: Vec<FeedAlert>) -> Alerts {
+        Alerts::new(
+            self.alerts
+                .iter()
+                .filter(|alert| alert.source_id != source_id)
+                .cloned()
+                .chain(alerts)
+                .collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alerts.is_empty()
+    }
+
+    /// Whether an alert closes `stop_seq` of `trip` at `at`, so that riders can neither board nor get off there.
+    pub(crate) fn closes<'a>(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        trip: &ScheduledTrip,
+        stop_seq: usize,
+        at: Time,
+    ) -> bool {
+        if self.closures_by_stop.is_empty()
+            && self.closures_by_route.is_empty()
+            && self.closures_by_trip.is_empty()
+        {
+            return false;
+        }
+        let route = trip.trip().route(timetable);
+        let stop = route.route_stops(timetable)[stop_seq].id();
+        self.closures_by_stop
+            .get(&stop)
+            .into_iter()
+            .chain(self.closures_by_route.get(&route.id()))
+            .chain(self.closures_by_trip.get(trip.trip()))
+            .flatten()
+            .any(|alert_idx| {
+                self.alerts[*alert_idx].matches(Some(trip), Some(route.id()), &[stop], at, at)
+            })
+    }
+
+    /// The alerts for a leg that rides `trip`, or walks if there's no trip, between `stops` from `start` to `end`.
+    pub(crate) fn matching<'a>(
+        &self,
+        timetable: &'a dyn Timetable<'a>,
+        trip: Option<&ScheduledTrip>,
+        stops: &[&Stop],
+        start: Time,
+        end: Time,
+    ) -> Vec<ServiceAlert> {
+        let route = trip.map(|trip| trip.trip().route(timetable).id());
+        let stops: Vec<usize> = stops.iter().map(|stop| stop.id()).collect();
+        self.alerts
+            .iter()
+            .filter(|alert| alert.matches(trip, route, &stops, start, end))
+            .map(|alert| alert.alert.clone())
+            .collect()
+    }
+}
+
+/// Match the alerts in `message` to the stops, routes and trips of `timetable` they inform riders about.
+pub(crate) fn match_alerts<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    index: &EntityIndex,
+    source_id: &str,
+    feed_id: &str,
+    message: &FeedMessage,
+) -> (Vec<FeedAlert>, AlertReport) {
+    let mut report = AlertReport::default();
+    let mut alerts = vec![];
+    for entity in &message.entity {
+        let alert = if let Some(alert) = &entity.alert {
+            alert
+        } else {
+            continue;
+        };
+        let entities: Vec<AffectedEntity> = alert
+            .informed_entity
+            .iter()
+            .filter_map(|selector| {
+                match_selector(timetable, index, feed_id, &entity.id, selector)
+                    .map_err(|issue| report.record(issue))
+                    .ok()
+            })
+            .collect();
+        if entities.is_empty() {
+            continue;
+        }
+        report.alerts += 1;
+        alerts.push(FeedAlert {
+            source_id: source_id.to_string(),
+            alert: ServiceAlert {
+                id: entity.id.clone(),
+                header: alert.header_text.as_ref().and_then(translation),
+                description: alert.description_text.as_ref().and_then(translation),
+                url: alert.url.as_ref().and_then(translation),
+                cause: alert.cause().as_str_name().to_string(),
+                effect: alert.effect().as_str_name().to_string(),
+                active_periods: alert.active_period.iter().map(active_period).collect(),
+            },
+            closure: matches!(
+                alert.effect(),
+                alert::Effect::NoService | alert::Effect::StopMoved
+            ),
+            entities,
+        });
+    }
+    (alerts, report)
+}
+
+fn match_selector<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    index: &EntityIndex,
+    feed_id: &str,
+    alert_id: &str,
+    selector: &EntitySelector,
+) -> Result<AffectedEntity, AlertIssue> {
+    let mut entity = AffectedEntity::default();
+    if let Some(stop_id) = &selector.stop_id {
+        let stops = index
+            .stops
+            .get(&(feed_id.to_string(), stop_id.clone()))
+            .ok_or_else(|| AlertIssue::UnknownStop {
+                alert_id: alert_id.to_string(),
+                stop_id: stop_id.clone(),
+            })?;
+        entity.stops = Some(stops.iter().copied().collect());
+    }
+    let route_id = selector.route_id.as_ref().or(selector
+        .trip
+        .as_ref()
+        .and_then(|trip| trip.route_id.as_ref()));
+    if let Some(route_id) = route_id {
+        let routes = index
+            .routes
+            .get(&(feed_id.to_string(), route_id.clone()))
+            .ok_or_else(|| AlertIssue::UnknownRoute {
+                alert_id: alert_id.to_string(),
+                route_id: route_id.clone(),
+            })?;
+        entity.routes = Some(routes.iter().copied().collect());
+    }
+    if let Some(descriptor) = &selector.trip {
+        if let Some(trip_id) = &descriptor.trip_id {
+            let unknown_trip = || AlertIssue::UnknownTrip {
+                alert_id: alert_id.to_string(),
+                trip_id: trip_id.clone(),
+            };
+            let candidates = index
+                .trips
+                .get(&(feed_id.to_string(), trip_id.clone()))
+                .ok_or_else(unknown_trip)?;
+            // Without a start time, an alert is about every run of a trip from `frequencies.txt`.
+            let trips = if descriptor.start_time.is_some() {
+                vec![find_trip(
+                    timetable,
+                    candidates,
+                    trip_id,
+                    descriptor.start_time.as_deref(),
+                )
+                .map_err(|_issue| unknown_trip())?]
+            } else {
+                candidates.clone()
+            };
+            let mut affected = HashSet::new();
+            for trip in trips {
+                let service_day = match &descriptor.start_date {
+                    Some(start_date) => {
+                        let date = NaiveDate::parse_from_str(start_date, "%Y%m%d")
+                            .map_err(|_err| unknown_trip())?;
+                        let timezone: Tz = trip
+                            .metadata(timetable)
+                            .timezone
+                            .and_then(|timezone| timezone.parse().ok())
+                            .ok_or_else(unknown_trip)?;
+                        Some(
+                            trip_service_day(timetable, &trip, &timezone, date)
+                                .ok_or_else(unknown_trip)?,
+                        )
+                    }
+                    None => None,
+                };
+                affected.insert((trip, service_day));
+            }
+            entity.trips = Some(affected);
+        }
+    }
+    if entity.stops.is_none() && entity.routes.is_none() && entity.trips.is_none() {
+        return Err(AlertIssue::UnsupportedSelector {
+            alert_id: alert_id.to_string(),
+        });
+    }
+    Ok(entity)
+}
+
+/// The text of a translated string, preferring the translation that doesn't name a language, which is the feed's default.
+fn translation(translated: &TranslatedString) -> Option<String> {
+    translated
+        .translation
+        .iter()
+        .find(|translation| translation.language.is_none())
+        .or(translated.translation.first())
+        .map(|translation| translation.text.clone())
+}
+
+fn active_period(range: &TimeRange) -> ActivePeriod {
+    ActivePeriod {
+        start: range.start,
+        end: range.end,
+    }
+}
@@ -0,0 +1,380 @@
+pub mod added_trips;
+pub mod alerts;
+pub mod poller;
+pub mod trip_updates;
+pub mod vehicle_positions;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    marker::PhantomData,
+    path::Path,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use gtfs_rt::FeedMessage;
+use prost::Message;
+use rstar::RTree;
+
+use crate::{
+    fares::FeedFares,
+    raptor::timetable::{
+        in_memory::service_day_start, Headway, Route, RouteStop, ScheduledTrip, ServiceWindow,
+        ShapeCoordinate, StationEntrance, Stop, StopRoute, Time, Timetable, Transfer, Trip,
+        TripMetadata, TripStopTime,
+    },
+    spatial::IndexedStop,
+};
+
+use self::{
+    added_trips::AddedTrips,
+    alerts::{match_alerts, AlertReport, Alerts},
+    trip_updates::{TripUpdateReport, TripUpdates},
+    vehicle_positions::{
+        match_vehicle_positions, VehiclePosition, VehiclePositionReport, VehiclePositions,
+    },
+};
+
+/// Read a GTFS-RT feed message from a protobuf file.
+pub fn read_feed_message(path: &Path) -> Result<FeedMessage, anyhow::Error> {
+    let bytes = fs::read(path)?;
+    Ok(FeedMessage::decode(bytes.as_slice())?)
+}
+
+/// A timetable with GTFS-RT updates laid over it. The timetable underneath, usually an `MmapTimetable`, isn't changed: predictions are kept alongside it and looked up whenever a trip's times are.
+pub struct RealtimeTimetable<'a, T: Timetable<'a>> {
+    timetable: T,
+    /// Replaced rather than changed in place, like `added_trips`, so that queries can keep a snapshot.
+    trip_updates: RwLock<Arc<TripUpdates>>,
+    /// Replaced rather than changed in place, so that queries can keep a snapshot.
+    added_trips: RwLock<Arc<AddedTrips>>,
+    /// Replaced rather than changed in place, so that queries can keep a snapshot.
+    alerts: RwLock<Arc<Alerts>>,
+    vehicles: RwLock<VehiclePositions>,
+    /// Built on the first update.
+    index: OnceLock<EntityIndex>,
+    phantom: PhantomData<&'a ()>,
+}
+
+/// The timetable's trips, routes and stops by feed ID and GTFS ID, for matching real-time updates to them.
+#[derive(Debug, Default)]
+pub(crate) struct EntityIndex {
+    /// Trips expanded from `frequencies.txt` share a GTFS trip ID.
+    trips: HashMap<(String, String), Vec<Trip>>,
+    /// Routes are split up by stop pattern, so a GTFS route may be several of them.
+    routes: HashMap<(String, String), Vec<usize>>,
+    /// A station's ID also refers to every stop in it.
+    stops: HashMap<(String, String), Vec<usize>>,
+}
+
+impl EntityIndex {
+    fn build<'a>(timetable: &'a dyn Timetable<'a>) -> EntityIndex {
+        let mut index = EntityIndex::default();
+        let mut indexed_stops = HashSet::new();
+        for route in timetable.routes() {
+            let mut route_ids = HashSet::new();
+            for trip in route.route_trips(timetable) {
+                let metadata = timetable.trip_metadata(trip);
+                if let Some(gtfs_trip_id) = metadata.gtfs_trip_id {
+                    index
+                        .trips
+                        .entry((metadata.feed_id.clone(), gtfs_trip_id))
+                        .or_default()
+                        .push(*trip);
+                }
+                if let Some(gtfs_route_id) = metadata.gtfs_route_id {
+                    route_ids.insert((metadata.feed_id, gtfs_route_id));
+                }
+            }
+            for (feed_id, gtfs_route_id) in &route_ids {
+                index
+                    .routes
+                    .entry((feed_id.clone(), gtfs_route_id.clone()))
+                    .or_default()
+                    .push(route.id());
+            }
+            let feed_id = if let Some((feed_id, _)) = route_ids.iter().next() {
+                feed_id
+            } else {
+                continue;
+            };
+            for route_stop in route.route_stops(timetable) {
+                if !indexed_stops.insert(route_stop.id()) {
+                    continue;
+                }
+                let metadata = timetable.stop_metadata(route_stop.stop(timetable));
+                for gtfs_stop_id in std::iter::once(metadata.id).chain(metadata.parent_station) {
+                    index
+                        .stops
+                        .entry((feed_id.clone(), gtfs_stop_id))
+                        .or_default()
+                        .push(route_stop.id());
+                }
+            }
+        }
+        index
+    }
+}
+
+/// The start of `trip`'s service day on `date`, if it runs then.
+pub(crate) fn trip_service_day<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    trip: &Trip,
+    timezone: &Tz,
+    date: NaiveDate,
+) -> Option<Time> {
+    let service_day_start =
+        Time::from_epoch_seconds(service_day_start(timezone, date).ok()?.timestamp() as u32);
+    trip.route(timetable)
+        .service_days(timetable)
+        .iter()
+        .position(|day| *day == service_day_start)
+        .filter(|day| trip.runs_on(timetable, *day))
+        .map(|_day| service_day_start)
+}
+
+impl<'a, T: Timetable<'a>> RealtimeTimetable<'a, T> {
+    pub fn new(timetable: T) -> RealtimeTimetable<'a, T> {
+        RealtimeTimetable {
+            timetable,
+            trip_updates: RwLock::new(Arc::new(TripUpdates::default())),
+            added_trips: RwLock::new(Arc::new(AddedTrips::default())),
+            alerts: RwLock::new(Arc::new(Alerts::default())),
+            vehicles: RwLock::new(VehiclePositions::default()),
+            index: OnceLock::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// The static timetable underneath.
+    pub fn inner(&self) -> &T {
+        &self.timetable
+    }
+
+    /// Apply the TripUpdates in `message` to the static feed with ID `feed_id`, replacing any previously applied from the source `source_id`, along with the trips it added. A static feed can have several sources, e.g. one per line, which don't replace each other's updates.
+    pub fn update_trips(
+        &'a self,
+        source_id: &str,
+        feed_id: &str,
+        message: &FeedMessage,
+    ) -> TripUpdateReport {
+        let (trips, added_trips, report) = trip_updates::match_trip_updates(
+            &self.timetable,
+            self.index(),
+            source_id,
+            feed_id,
+            message,
+        );
+        let mut trip_updates = self.trip_updates.write().unwrap();
+        *trip_updates = Arc::new(trip_updates.replace_source(source_id, trips));
+        let mut current = self.added_trips.write().unwrap();
+        *current = Arc::new(current.replace_source(source_id, added_trips));
+        report
+    }
+
+    /// Apply the alerts in `message` to the static feed with ID `feed_id`, replacing any previously applied from the source `source_id`.
+    pub fn update_alerts(
+        &'a self,
+        source_id: &str,
+        feed_id: &str,
+        message: &FeedMessage,
+    ) -> AlertReport {
+        let (alerts, report) =
+            match_alerts(&self.timetable, self.index(), source_id, feed_id, message);
+        let mut current = self.alerts.write().unwrap();
+        *current = Arc::new(current.replace_source(source_id, alerts));
+        report
+    }
+
+    /// Apply the vehicle positions in `message` to the static feed with ID `feed_id`, replacing any previously applied from the source `source_id`. Vehicles are placed along their trips using the trip updates applied so far.
+    pub fn update_vehicle_positions(
+        &'a self,
+        source_id: &str,
+        feed_id: &str,
+        message: &FeedMessage,
+    ) -> VehiclePositionReport {
+        let (vehicles, report) =
+            match_vehicle_positions(self, self.index(), source_id, feed_id, message);
+        self.vehicles
+            .write()
+            .unwrap()
+            .replace_source(source_id, vehicles);
+        report
+    }
+
+    /// The vehicles currently running the GTFS route with ID `route_id` in the feed with ID `feed_id`.
+    pub fn vehicles_on_route(&self, feed_id: &str, route_id: &str) -> Vec<VehiclePosition> {
+        self.vehicles.read().unwrap().on_route(feed_id, route_id)
+    }
+
+    /// The vehicles currently within a bounding box.
+    pub fn vehicles_within(
+        &self,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    ) -> Vec<VehiclePosition> {
+        self.vehicles
+            .read()
+            .unwrap()
+            .within(min_lat, min_lon, max_lat, max_lon)
+    }
+
+    fn index(&'a self) -> &'a EntityIndex {
+        self.index
+            .get_or_init(|| EntityIndex::build(&self.timetable))
+    }
+}
+
+impl<'a, T: Timetable<'a>> Timetable<'a> for RealtimeTimetable<'a, T> {
+    #[inline]
+    fn route(&'a self, route_id: usize) -> &'a Route {
+        self.timetable.route(route_id)
+    }
+
+    #[inline]
+    fn stop(&'a self, stop_id: usize) -> &'a Stop {
+        self.timetable.stop(stop_id)
+    }
+
+    #[inline]
+    fn stop_count(&self) -> usize {
+        self.timetable.stop_count()
+    }
+
+    #[inline]
+    fn stops(&'a self) -> &'a [Stop] {
+        self.timetable.stops()
+    }
+
+    #[inline]
+    fn routes(&'a self) -> &'a [Route] {
+        self.timetable.routes()
+    }
+
+    #[inline]
+    fn stop_routes(&'a self) -> &'a [StopRoute] {
+        self.timetable.stop_routes()
+    }
+
+    #[inline]
+    fn route_stops(&'a self) -> &'a [RouteStop] {
+        self.timetable.route_stops()
+    }
+
+    #[inline]
+    fn route_trips(&'a self) -> &'a [Trip] {
+        self.timetable.route_trips()
+    }
+
+    #[inline]
+    fn trip_stop_times(&'a self) -> &'a [TripStopTime] {
+        self.timetable.trip_stop_times()
+    }
+
+    #[inline]
+    fn route_service_days(&'a self) -> &'a [Time] {
+        self.timetable.route_service_days()
+    }
+
+    #[inline]
+    fn trip_service_days(&'a self) -> &'a [u64] {
+        self.timetable.trip_service_days()
+    }
+
+    #[inline]
+    fn headways(&'a self) -> &'a [Headway] {
+        self.timetable.headways()
+    }
+
+    #[inline]
+    fn transfers(&'a self) -> &'a [Transfer] {
+        self.timetable.transfers()
+    }
+
+    #[inline]
+    fn transfer_index(&'a self) -> &'a [usize] {
+        self.timetable.transfer_index()
+    }
+
+    #[inline]
+    fn transfers_from(&'a self, stop_id: usize) -> &'a [Transfer] {
+        self.timetable.transfers_from(stop_id)
+    }
+
+    #[inline]
+    fn pathway_transfers(&'a self) -> &'a [Transfer] {
+        self.timetable.pathway_transfers()
+    }
+
+    #[inline]
+    fn entrances(&'a self) -> &'a [StationEntrance] {
+        self.timetable.entrances()
+    }
+
+    fn stop_index_copy(&'a self) -> RTree<IndexedStop> {
+        self.timetable.stop_index_copy()
+    }
+
+    fn nearest_stops(&'a self, lat: f64, lng: f64, n: usize) -> Vec<(&'a Stop, f64)> {
+        self.timetable.nearest_stops(lat, lng, n)
+    }
+
+    fn stop_metadata(&'a self, stop: &Stop) -> gtfs_structures::Stop {
+        self.timetable.stop_metadata(stop)
+    }
+
+    fn trip_metadata(&'a self, trip: &Trip) -> TripMetadata {
+        self.timetable.trip_metadata(trip)
+    }
+
+    fn route_shape(&'a self, route: &Route) -> Option<Vec<ShapeCoordinate>> {
+        self.timetable.route_shape(route)
+    }
+
+    fn feed_fares(&'a self, feed_id: &str) -> Option<FeedFares> {
+        self.timetable.feed_fares(feed_id)
+    }
+
+    fn service_window(&'a self) -> Option<ServiceWindow> {
+        self.timetable.service_window()
+    }
+
+    fn trip_updates(&'a self) -> Option<Arc<TripUpdates>> {
+        let trip_updates = self.trip_updates.read().unwrap();
+        if trip_updates.is_empty() {
+            None
+        } else {
+            Some(trip_updates.clone())
+        }
+    }
+
+    fn alerts(&'a self) -> Option<Arc<Alerts>> {
+        let alerts = self.alerts.read().unwrap();
+        if alerts.is_empty() {
+            None
+        } else {
+            Some(alerts.clone())
+        }
+    }
+
+    fn vehicle_position(
+        &'a self,
+        trip: &ScheduledTrip,
+        until_stop_seq: usize,
+    ) -> Option<VehiclePosition> {
+        self.vehicles.read().unwrap().on_trip(trip, until_stop_seq)
+    }
+
+    fn added_trips(&'a self) -> Option<Arc<AddedTrips>> {
+        let added_trips = self.added_trips.read().unwrap();
+        if added_trips.is_empty() {
+            None
+        } else {
+            Some(added_trips.clone())
+        }
+    }
+}
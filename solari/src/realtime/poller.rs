@@ -0,0 +1,331 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use gtfs_rt::FeedMessage;
+use prost::Message;
+use reqwest::Client;
+use tokio::sync::Notify;
+
+use crate::{
+    dmfr::DistributedMobilityFeedRegistry,
+    feeds::{local_feed_id, static_feed_index, AuthorizationError, AuthorizedRequest, FeedSecrets},
+    raptor::timetable::Timetable,
+    routers::{borrow_router, SolariRouter},
+};
+
+use super::RealtimeTimetable;
+
+/// The kinds of GTFS-RT feed a DMFR can list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RealtimeKind {
+    TripUpdates,
+    Alerts,
+    VehiclePositions,
+}
+
+impl std::fmt::Display for RealtimeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RealtimeKind::TripUpdates => "trip updates",
+            RealtimeKind::Alerts => "alerts",
+            RealtimeKind::VehiclePositions => "vehicle positions",
+        })
+    }
+}
+
+/// A GTFS-RT feed to poll, and the static feed its updates apply to.
+#[derive(Debug, Clone)]
+pub struct RealtimeSource {
+    /// Tells the source's updates apart from those of other sources for the same static feed: the real-time feed's DMFR ID and the kind of updates.
+    pub source_id: String,
+    /// The ID of the static feed in the timetable.
+    pub feed_id: String,
+    pub kind: RealtimeKind,
+    pub request: AuthorizedRequest,
+}
+
+/// The real-time feeds listed in `dmfr`, with `secrets` applied to those that need authorization. Feeds whose static feed can't be found, or whose requests can't be authorized, are returned as errors rather than left out silently.
+pub fn realtime_sources(
+    dmfr: &DistributedMobilityFeedRegistry,
+    secrets: &FeedSecrets,
+) -> (Vec<RealtimeSource>, Vec<PollError>) {
+    let mut sources = vec![];
+    let mut errors = vec![];
+    for (feed_idx, feed) in dmfr.feeds.iter().enumerate() {
+        let urls = [
+            (
+                RealtimeKind::TripUpdates,
+                feed.urls.realtime_trip_updates.as_deref(),
+            ),
+            (RealtimeKind::Alerts, feed.urls.realtime_alerts.as_deref()),
+            (
+                RealtimeKind::VehiclePositions,
+                feed.urls.realtime_vehicle_positions.as_deref(),
+            ),
+        ];
+        if urls.iter().all(|(_, url)| url.is_none()) {
+            continue;
+        }
+        let static_idx = if let Some(static_idx) = static_feed_index(dmfr, feed_idx) {
+            static_idx
+        } else {
+            errors.push(PollError::NoStaticFeed {
+                feed_id: feed.id.clone(),
+            });
+            continue;
+        };
+        for (kind, url) in urls {
+            let url = if let Some(url) = url {
+                url
+            } else {
+                continue;
+            };
+            match AuthorizedRequest::new(
+                &feed.id,
+                url,
+                feed.authorization.as_ref(),
+                secrets.get(&feed.id),
+            ) {
+                Ok(request) => sources.push(RealtimeSource {
+                    source_id: format!("{} {kind}", feed.id),
+                    feed_id: local_feed_id(&dmfr.feeds[static_idx].id),
+                    kind,
+                    request,
+                }),
+                Err(err) => errors.push(err.into()),
+            }
+        }
+    }
+    (sources, errors)
+}
+
+/// Why a real-time feed couldn't be polled or applied.
+#[derive(Debug, thiserror::Error)]
+pub enum PollError {
+    #[error("real-time feed {feed_id} doesn't belong with any static feed")]
+    NoStaticFeed { feed_id: String },
+    #[error(transparent)]
+    Authorization(#[from] AuthorizationError),
+    #[error("failed to fetch {url}: {err}")]
+    Request { url: String, err: reqwest::Error },
+    #[error("fetching {url} returned {status}")]
+    Status {
+        url: String,
+        status: reqwest::StatusCode,
+    },
+    #[error("failed to decode {url}: {err}")]
+    Decode {
+        url: String,
+        err: prost::DecodeError,
+    },
+    #[error("{url} was last updated {age_secs}s ago")]
+    Stale { url: String, age_secs: u64 },
+}
+
+/// What came of polling a real-time feed once.
+#[derive(Debug)]
+pub enum PollOutcome {
+    /// The feed's updates were applied, and any issues with them logged.
+    Applied,
+    /// The feed couldn't be polled, but its last updates are recent enough to keep.
+    Failed(PollError),
+    /// The feed couldn't be polled and its last updates are too old to trust, so they were removed.
+    Expired(PollError),
+}
+
+/// How often to poll real-time feeds, and how long their updates stay trusted once they can't be.
+#[derive(Debug, Clone, Copy)]
+pub struct PollOptions {
+    pub interval: Duration,
+    /// How old the last updates applied from a feed can get, going by when they were fetched or the feed's own timestamp, before they're removed.
+    pub max_age: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for PollOptions {
+    fn default() -> Self {
+        PollOptions {
+            interval: Duration::from_secs(30),
+            max_age: Duration::from_secs(300),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SourceState {
+    /// When the feed's updates currently applied were fetched. `None` if there are none.
+    applied_at: Option<Instant>,
+}
+
+/// Polls real-time feeds and applies them to a `RealtimeTimetable`.
+pub struct RealtimePoller {
+    client: Client,
+    sources: Vec<RealtimeSource>,
+    states: Vec<SourceState>,
+    options: PollOptions,
+}
+
+impl RealtimePoller {
+    pub fn new(sources: Vec<RealtimeSource>, options: PollOptions) -> RealtimePoller {
+        let client = Client::builder()
+            .timeout(options.timeout)
+            .build()
+            .expect("Failed to build HTTP client");
+        RealtimePoller {
+            client,
+            states: sources.iter().map(|_| SourceState::default()).collect(),
+            sources,
+            options,
+        }
+    }
+
+    pub fn sources(&self) -> &[RealtimeSource] {
+        &self.sources
+    }
+
+    pub fn options(&self) -> PollOptions {
+        self.options
+    }
+
+    /// Poll every feed once, in order, and apply what they return.
+    pub async fn poll<'a, T: Timetable<'a>>(
+        &mut self,
+        timetable: &'a RealtimeTimetable<'a, T>,
+    ) -> Vec<PollOutcome> {
+        let mut outcomes = vec![];
+        for (source, state) in self.sources.iter().zip(self.states.iter_mut()) {
+            let fetched_at = Instant::now();
+            let result = fetch(&self.client, &source.request)
+                .await
+                .and_then(|message| check_age(source, message, self.options.max_age));
+            let outcome = match result {
+                Ok(message) => {
+                    apply(timetable, source, &message);
+                    state.applied_at = Some(fetched_at);
+                    PollOutcome::Applied
+                }
+                Err(err) => {
+                    let expired = state
+                        .applied_at
+                        .is_some_and(|applied_at| applied_at.elapsed() >= self.options.max_age);
+                    if expired {
+                        apply(timetable, source, &FeedMessage::default());
+                        state.applied_at = None;
+                        PollOutcome::Expired(err)
+                    } else {
+                        PollOutcome::Failed(err)
+                    }
+                }
+            };
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+
+    /// Poll every feed each interval, for as long as the server runs. Updates go to whichever router `current` returns when they arrive. A reloaded timetable starts without any, so the feeds are polled again as soon as `reloaded` is notified rather than on the next interval.
+    pub async fn run(mut self, current: impl Fn() -> Arc<SolariRouter>, reloaded: &Notify) {
+        let mut interval = tokio::time::interval(self.options.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = reloaded.notified() => interval.reset(),
+            }
+            let router = current();
+            let outcomes = self.poll(borrow_router(&router).timetable()).await;
+            self.log_outcomes(outcomes);
+        }
+    }
+
+    /// Log the feeds that couldn't be polled, given the outcomes of `poll`.
+    pub fn log_outcomes(&self, outcomes: Vec<PollOutcome>) {
+        for (source, outcome) in self.sources.iter().zip(outcomes) {
+            match outcome {
+                PollOutcome::Applied => {}
+                PollOutcome::Failed(err) => {
+                    log::warn!("{}: {err}", source.source_id)
+                }
+                PollOutcome::Expired(err) => {
+                    log::warn!("{}: {err}, removed its updates", source.source_id)
+                }
+            }
+        }
+    }
+}
+
+async fn fetch(client: &Client, request: &AuthorizedRequest) -> Result<FeedMessage, PollError> {
+    let url = request.to_string();
+    let response = request
+        .get(client)
+        .send()
+        .await
+        .map_err(|err| PollError::Request {
+            url: url.clone(),
+            err: err.without_url(),
+        })?;
+    if !response.status().is_success() {
+        return Err(PollError::Status {
+            url,
+            status: response.status(),
+        });
+    }
+    let bytes = response.bytes().await.map_err(|err| PollError::Request {
+        url: url.clone(),
+        err: err.without_url(),
+    })?;
+    FeedMessage::decode(bytes).map_err(|err| PollError::Decode { url, err })
+}
+
+/// Reject a feed whose header says it hasn't been updated in `max_age`, since its producer has probably stopped.
+fn check_age(
+    source: &RealtimeSource,
+    message: FeedMessage,
+    max_age: Duration,
+) -> Result<FeedMessage, PollError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match message.header.timestamp {
+        Some(timestamp) if now.saturating_sub(timestamp) > max_age.as_secs() => {
+            Err(PollError::Stale {
+                url: source.request.to_string(),
+                age_secs: now - timestamp,
+            })
+        }
+        _ => Ok(message),
+    }
+}
+
+fn apply<'a, T: Timetable<'a>>(
+    timetable: &'a RealtimeTimetable<'a, T>,
+    source: &RealtimeSource,
+    message: &FeedMessage,
+) {
+    let issues: Vec<String> = match source.kind {
+        RealtimeKind::TripUpdates => timetable
+            .update_trips(&source.source_id, &source.feed_id, message)
+            .issues
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        RealtimeKind::Alerts => timetable
+            .update_alerts(&source.source_id, &source.feed_id, message)
+            .issues
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        RealtimeKind::VehiclePositions => timetable
+            .update_vehicle_positions(&source.source_id, &source.feed_id, message)
+            .issues
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+    };
+    for issue in issues {
+        log::debug!("{}: {issue}", source.source_id);
+    }
+}
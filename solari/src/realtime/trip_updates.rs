@@ -0,0 +1,704 @@
+use std::collections::HashMap;
+
+use chrono::{NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use gtfs_rt::{
+    trip_descriptor, trip_update::stop_time_update, trip_update::StopTimeEvent,
+    trip_update::StopTimeUpdate, FeedMessage, TripDescriptor, TripUpdate,
+};
+
+use crate::raptor::timetable::{
+    RealtimeSlack, RealtimeStopTime, Time, Timetable, Trip, TripMetadata, TripStopTime,
+};
+
+use super::{
+    added_trips::{AddedStopTime, AddedTrip},
+    trip_service_day, EntityIndex,
+};
+
+/// `NEW` took over from `ADDED` after the protobuf bindings were generated, so it's matched by number.
+const SCHEDULE_RELATIONSHIP_NEW: i32 = 8;
+
+/// A problem with a TripUpdate that meant it, or part of it, couldn't be applied.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TripUpdateIssue {
+    #[error("entity {entity_id:?} doesn't give a trip ID, skipping it")]
+    MissingTripId { entity_id: String },
+    #[error("no trip with ID {trip_id:?} in the timetable, skipping its update")]
+    UnknownTrip { trip_id: String },
+    #[error(
+        "trip {trip_id:?} runs several times a day, but its update has no start time to tell which"
+    )]
+    AmbiguousTrip { trip_id: String },
+    #[error("trip {trip_id:?} doesn't depart at {start_time:?}, skipping its update")]
+    NoTripAtStartTime { trip_id: String, start_time: String },
+    #[error("trip {trip_id:?} has an invalid start date {start_date:?}, skipping its update")]
+    InvalidStartDate { trip_id: String, start_date: String },
+    #[error("trip {trip_id:?} doesn't run on {date} in the timetable, skipping its update")]
+    NotRunning { trip_id: String, date: NaiveDate },
+    #[error("the timetable doesn't record the timezone of trip {trip_id:?}, rebuild it to apply updates")]
+    MissingTimezone { trip_id: String },
+    #[error("{schedule_relationship} trips aren't supported, skipping trip {trip_id:?}")]
+    UnsupportedScheduleRelationship {
+        trip_id: String,
+        schedule_relationship: String,
+    },
+    #[error("trip {trip_id:?} doesn't stop at {stop:?}, ignoring that part of its update")]
+    UnknownStop { trip_id: String, stop: String },
+    #[error("added trip {trip_id:?} has no time at {stop:?}, leaving the stop out")]
+    MissingStopTime { trip_id: String, stop: String },
+    #[error("added trip {trip_id:?} has fewer than two stops with times, skipping it")]
+    TooFewStops { trip_id: String },
+}
+
+/// What came of applying a feed's TripUpdates.
+#[derive(Debug, Clone, Default)]
+pub struct TripUpdateReport {
+    pub issues: Vec<TripUpdateIssue>,
+    /// Trips that had their times updated.
+    pub updated_trips: usize,
+    pub canceled_trips: usize,
+    /// Trips that aren't in the timetable, including those replacing a scheduled trip.
+    pub added_trips: usize,
+}
+
+impl TripUpdateReport {
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn record(&mut self, issue: TripUpdateIssue) {
+        if !self.issues.contains(&issue) {
+            self.issues.push(issue);
+        }
+    }
+}
+
+/// The predictions for one trip on one service day.
+#[derive(Debug, Clone)]
+pub(crate) struct TripRealtime {
+    /// The real-time source the predictions came from.
+    source_id: String,
+    canceled: bool,
+    /// Predictions by stop sequence. Stops without one run as scheduled.
+    stop_times: Vec<Option<RealtimeStopTime>>,
+    slack: RealtimeSlack,
+}
+
+/// Every prediction currently applied, by trip and service day. Replaced rather than changed when a feed updates, so that a query can look predictions up in one snapshot.
+#[derive(Debug, Default)]
+pub struct TripUpdates {
+    trips: HashMap<(Trip, Time), TripRealtime>,
+    slack: RealtimeSlack,
+}
+
+impl TripUpdates {
+    /// These predictions with those from the source `source_id` replaced by `trips`.
+    pub(crate) fn replace_source(
+        &self,
+        source_id: &str,
+        trips: HashMap<(Trip, Time), TripRealtime>,
+    ) -> TripUpdates {
+        let trips: HashMap<(Trip, Time), TripRealtime> = self
+            .trips
+            .iter()
+            .filter(|(_key, trip)| trip.source_id != source_id)
+            .map(|(key, trip)| (*key, trip.clone()))
+            .chain(trips)
+            .collect();
+        let slack = trips
+            .values()
+            .fold(RealtimeSlack::default(), |slack, trip| RealtimeSlack {
+                max_delay: slack.max_delay.max(trip.slack.max_delay),
+                max_early: slack.max_early.max(trip.slack.max_early),
+            });
+        TripUpdates { trips, slack }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trips.is_empty()
+    }
+
+    pub(crate) fn stop_time(
+        &self,
+        trip: &Trip,
+        service_day_start: Time,
+        stop_seq: usize,
+    ) -> Option<RealtimeStopTime> {
+        *self
+            .trips
+            .get(&(*trip, service_day_start))?
+            .stop_times
+            .get(stop_seq)?
+    }
+
+    pub(crate) fn canceled(&self, trip: &Trip, service_day_start: Time) -> bool {
+        self.trips
+            .get(&(*trip, service_day_start))
+            .is_some_and(|trip| trip.canceled)
+    }
+
+    pub(crate) fn slack(&self) -> RealtimeSlack {
+        self.slack
+    }
+}
+
+/// How a TripUpdate changes the timetable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TripChange {
+    Update,
+    Cancel,
+    Add,
+    Replace,
+}
+
+impl TripChange {
+    fn of(descriptor: &TripDescriptor, trip_id: &str) -> Result<TripChange, TripUpdateIssue> {
+        if descriptor.schedule_relationship == Some(SCHEDULE_RELATIONSHIP_NEW) {
+            return Ok(TripChange::Add);
+        }
+        match descriptor.schedule_relationship() {
+            trip_descriptor::ScheduleRelationship::Scheduled => Ok(TripChange::Update),
+            trip_descriptor::ScheduleRelationship::Canceled
+            | trip_descriptor::ScheduleRelationship::Deleted => Ok(TripChange::Cancel),
+            trip_descriptor::ScheduleRelationship::Added => Ok(TripChange::Add),
+            trip_descriptor::ScheduleRelationship::Replacement => Ok(TripChange::Replace),
+            other => Err(TripUpdateIssue::UnsupportedScheduleRelationship {
+                trip_id: trip_id.to_string(),
+                schedule_relationship: other.as_str_name().to_string(),
+            }),
+        }
+    }
+}
+
+impl TripRealtime {
+    fn canceled(source_id: &str) -> TripRealtime {
+        TripRealtime {
+            source_id: source_id.to_string(),
+            canceled: true,
+            stop_times: vec![],
+            slack: RealtimeSlack::default(),
+        }
+    }
+}
+
+/// Match the TripUpdates in `message` to trips in `timetable` by GTFS trip ID and service date, and work out their predicted times. Updates for trips that aren't in the timetable become added trips.
+pub(crate) fn match_trip_updates<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    index: &EntityIndex,
+    source_id: &str,
+    feed_id: &str,
+    message: &FeedMessage,
+) -> (
+    HashMap<(Trip, Time), TripRealtime>,
+    Vec<AddedTrip>,
+    TripUpdateReport,
+) {
+    let mut report = TripUpdateReport::default();
+    let mut trips = HashMap::new();
+    let mut added_trips = vec![];
+    // Updates without a start date are for the runs in progress when the feed was generated.
+    let now = message
+        .header
+        .timestamp
+        .map(|timestamp| Time::from_epoch_seconds(timestamp as u32))
+        .unwrap_or_else(Time::now);
+    for entity in &message.entity {
+        let trip_update = if let Some(trip_update) = &entity.trip_update {
+            trip_update
+        } else {
+            continue;
+        };
+        let trip_id = if let Some(trip_id) = &trip_update.trip.trip_id {
+            trip_id
+        } else {
+            report.record(TripUpdateIssue::MissingTripId {
+                entity_id: entity.id.clone(),
+            });
+            continue;
+        };
+        let change = match TripChange::of(&trip_update.trip, trip_id) {
+            Ok(change) => change,
+            Err(issue) => {
+                report.record(issue);
+                continue;
+            }
+        };
+        match change {
+            TripChange::Update | TripChange::Cancel => {
+                match match_trip_update(
+                    timetable,
+                    &index.trips,
+                    source_id,
+                    feed_id,
+                    trip_id,
+                    change == TripChange::Cancel,
+                    trip_update,
+                    now,
+                    &mut report,
+                ) {
+                    Ok((key, trip)) => {
+                        if trip.canceled {
+                            report.canceled_trips += 1;
+                        } else {
+                            report.updated_trips += 1;
+                        }
+                        trips.insert(key, trip);
+                    }
+                    Err(issue) => report.record(issue),
+                }
+            }
+            TripChange::Add => {
+                match added_trip(
+                    timetable,
+                    index,
+                    source_id,
+                    feed_id,
+                    trip_id,
+                    trip_update,
+                    None,
+                    &mut report,
+                ) {
+                    Ok(trip) => {
+                        report.added_trips += 1;
+                        added_trips.push(trip);
+                    }
+                    Err(issue) => report.record(issue),
+                }
+            }
+            TripChange::Replace => {
+                // The scheduled trip is canceled, and the replacement runs in its place.
+                let replaced = resolve_trip(
+                    timetable,
+                    &index.trips,
+                    feed_id,
+                    trip_id,
+                    &trip_update.trip,
+                    now,
+                )
+                .and_then(|replaced| {
+                    let trip = added_trip(
+                        timetable,
+                        index,
+                        source_id,
+                        feed_id,
+                        trip_id,
+                        trip_update,
+                        Some(&replaced),
+                        &mut report,
+                    )?;
+                    Ok((replaced, trip))
+                });
+                match replaced {
+                    Ok(((trip, service_day_start, _), added)) => {
+                        report.added_trips += 1;
+                        trips.insert((trip, service_day_start), TripRealtime::canceled(source_id));
+                        added_trips.push(added);
+                    }
+                    Err(issue) => report.record(issue),
+                }
+            }
+        }
+    }
+    (trips, added_trips, report)
+}
+
+fn match_trip_update<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    trip_index: &HashMap<(String, String), Vec<Trip>>,
+    source_id: &str,
+    feed_id: &str,
+    trip_id: &str,
+    canceled: bool,
+    trip_update: &TripUpdate,
+    now: Time,
+    report: &mut TripUpdateReport,
+) -> Result<((Trip, Time), TripRealtime), TripUpdateIssue> {
+    let (trip, service_day_start, metadata) = resolve_trip(
+        timetable,
+        trip_index,
+        feed_id,
+        trip_id,
+        &trip_update.trip,
+        now,
+    )?;
+    let route = trip.route(timetable);
+
+    if canceled {
+        return Ok(((trip, service_day_start), TripRealtime::canceled(source_id)));
+    }
+
+    let stop_times = trip.stop_times(timetable);
+    let route_stops = route.route_stops(timetable);
+    let mut updates: Vec<Option<&StopTimeUpdate>> = vec![None; stop_times.len()];
+    // Stops are matched in order, so that a trip visiting a stop twice has each visit updated.
+    let mut next_stop_seq = 0;
+    for stop_time_update in &trip_update.stop_time_update {
+        let stop_seq = if let Some(stop_sequence) = stop_time_update.stop_sequence {
+            metadata
+                .stop_sequences
+                .iter()
+                .position(|gtfs_stop_sequence| *gtfs_stop_sequence == stop_sequence)
+        } else if let Some(stop_id) = &stop_time_update.stop_id {
+            (next_stop_seq..stop_times.len()).find(|stop_seq| {
+                route_stops[*stop_seq]
+                    .stop(timetable)
+                    .metadata(timetable)
+                    .id
+                    == *stop_id
+            })
+        } else {
+            None
+        };
+        if let Some(stop_seq) = stop_seq {
+            updates[stop_seq] = Some(stop_time_update);
+            next_stop_seq = stop_seq + 1;
+        } else {
+            report.record(TripUpdateIssue::UnknownStop {
+                trip_id: trip_id.to_string(),
+                stop: stop_label(stop_time_update),
+            });
+        }
+    }
+
+    // A delay carries on to the following stops until another update says otherwise.
+    let mut delay = trip_update.delay;
+    let mut slack = RealtimeSlack::default();
+    let mut realtime_stop_times = Vec::with_capacity(stop_times.len());
+    for (stop_time, update) in stop_times.iter().zip(updates) {
+        let mut skipped = false;
+        let mut arrival_delay = delay;
+        if let Some(update) = update {
+            match update.schedule_relationship() {
+                stop_time_update::ScheduleRelationship::NoData => {
+                    delay = None;
+                    arrival_delay = None;
+                }
+                stop_time_update::ScheduleRelationship::Skipped => skipped = true,
+                _ => {
+                    let arrival = update.arrival.as_ref().and_then(|event| {
+                        event_delay(event, service_day_start, stop_time.arrival_offset())
+                    });
+                    let departure = update.departure.as_ref().and_then(|event| {
+                        event_delay(event, service_day_start, stop_time.departure_offset())
+                    });
+                    arrival_delay = arrival.or(departure).or(delay);
+                    delay = departure.or(arrival_delay);
+                }
+            }
+        }
+        if arrival_delay.is_none() && delay.is_none() && !skipped {
+            realtime_stop_times.push(None);
+            continue;
+        }
+        if let Some(delay) = delay {
+            slack.max_delay = slack.max_delay.max(delay.max(0) as u32);
+            slack.max_early = slack.max_early.max(delay.min(0).unsigned_abs());
+        }
+        let arrival = delayed(
+            service_day_start,
+            stop_time.arrival_offset(),
+            arrival_delay.unwrap_or(0),
+        );
+        let departure = delayed(
+            service_day_start,
+            stop_time.departure_offset(),
+            delay.unwrap_or(0),
+        );
+        realtime_stop_times.push(Some(RealtimeStopTime {
+            arrival,
+            departure: departure.max(arrival),
+            skipped,
+        }));
+    }
+
+    Ok((
+        (trip, service_day_start),
+        TripRealtime {
+            source_id: source_id.to_string(),
+            canceled: false,
+            stop_times: realtime_stop_times,
+            slack,
+        },
+    ))
+}
+
+/// Build a trip that isn't in the timetable from an update's stop times. Without a schedule to go by, these need stop IDs and times, except on a replacement trip, where stop sequences and delays refer to the trip it replaces.
+fn added_trip<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    index: &EntityIndex,
+    source_id: &str,
+    feed_id: &str,
+    trip_id: &str,
+    trip_update: &TripUpdate,
+    replaced: Option<&(Trip, Time, TripMetadata)>,
+    report: &mut TripUpdateReport,
+) -> Result<AddedTrip, TripUpdateIssue> {
+    let mut stop_times = vec![];
+    let mut stop_sequences = vec![];
+    for update in &trip_update.stop_time_update {
+        if matches!(
+            update.schedule_relationship(),
+            stop_time_update::ScheduleRelationship::Skipped
+                | stop_time_update::ScheduleRelationship::NoData
+        ) {
+            continue;
+        }
+        let replaced_stop_seq = replaced.and_then(|(trip, _, metadata)| {
+            if let Some(stop_sequence) = update.stop_sequence {
+                metadata
+                    .stop_sequences
+                    .iter()
+                    .position(|gtfs_stop_sequence| *gtfs_stop_sequence == stop_sequence)
+            } else {
+                let stop_id = update.stop_id.as_ref()?;
+                trip.route(timetable)
+                    .route_stops(timetable)
+                    .iter()
+                    .position(|route_stop| {
+                        route_stop.stop(timetable).metadata(timetable).id == *stop_id
+                    })
+            }
+        });
+        let stop = update
+            .stop_id
+            .as_ref()
+            .and_then(|stop_id| index.stops.get(&(feed_id.to_string(), stop_id.clone())))
+            .and_then(|stops| stops.first().copied())
+            .or_else(|| {
+                let (trip, _, _) = replaced?;
+                Some(trip.route(timetable).route_stops(timetable)[replaced_stop_seq?].id())
+            });
+        let stop = if let Some(stop) = stop {
+            stop
+        } else {
+            report.record(TripUpdateIssue::UnknownStop {
+                trip_id: trip_id.to_string(),
+                stop: stop_label(update),
+            });
+            continue;
+        };
+        let event_time = |event: Option<&StopTimeEvent>, scheduled: fn(&TripStopTime) -> u32| {
+            let event = event?;
+            if let Some(time) = event.time {
+                return Some(Time::from_epoch_seconds(
+                    time.clamp(0, u32::MAX as i64) as u32
+                ));
+            }
+            let (trip, service_day_start, _) = replaced?;
+            let offset = scheduled(&trip.stop_times(timetable)[replaced_stop_seq?]);
+            Some(delayed(*service_day_start, offset, event.delay?))
+        };
+        let departure = event_time(update.departure.as_ref(), TripStopTime::departure_offset);
+        let arrival = if let Some(arrival) =
+            event_time(update.arrival.as_ref(), TripStopTime::arrival_offset).or(departure)
+        {
+            arrival
+        } else {
+            report.record(TripUpdateIssue::MissingStopTime {
+                trip_id: trip_id.to_string(),
+                stop: stop_label(update),
+            });
+            continue;
+        };
+        stop_times.push(AddedStopTime {
+            stop,
+            arrival,
+            departure: departure.unwrap_or(arrival).max(arrival),
+        });
+        stop_sequences.push(update.stop_sequence);
+    }
+    if stop_times.len() < 2 {
+        return Err(TripUpdateIssue::TooFewStops {
+            trip_id: trip_id.to_string(),
+        });
+    }
+
+    let route_id = trip_update
+        .trip
+        .route_id
+        .clone()
+        .or_else(|| replaced.and_then(|(_, _, metadata)| metadata.gtfs_route_id.clone()));
+    // An added trip is named for its route, going by a scheduled trip on it.
+    let route_metadata = route_id
+        .as_ref()
+        .and_then(|route_id| {
+            let route = index
+                .routes
+                .get(&(feed_id.to_string(), route_id.clone()))?
+                .first()?;
+            Some(
+                timetable
+                    .route(*route)
+                    .route_trips(timetable)
+                    .first()?
+                    .metadata(timetable),
+            )
+        })
+        .or_else(|| replaced.map(|(_, _, metadata)| metadata.clone()));
+    Ok(AddedTrip {
+        source_id: source_id.to_string(),
+        metadata: TripMetadata {
+            headsign: replaced.and_then(|(_, _, metadata)| metadata.headsign.clone()),
+            route_name: route_metadata
+                .as_ref()
+                .and_then(|metadata| metadata.route_name.clone()),
+            agency_name: route_metadata
+                .as_ref()
+                .and_then(|metadata| metadata.agency_name.clone()),
+            feed_id: feed_id.to_string(),
+            gtfs_route_id: route_id,
+            gtfs_trip_id: Some(trip_id.to_string()),
+            timezone: route_metadata.and_then(|metadata| metadata.timezone),
+            // Only useful if every stop has one.
+            stop_sequences: stop_sequences
+                .into_iter()
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_default(),
+        },
+        stop_times,
+    })
+}
+
+/// How an update names its stop, for reporting.
+fn stop_label(update: &StopTimeUpdate) -> String {
+    update
+        .stop_id
+        .clone()
+        .or(update
+            .stop_sequence
+            .map(|stop_sequence| stop_sequence.to_string()))
+        .unwrap_or_default()
+}
+
+/// The trip a trip descriptor refers to, the start of the service day it's on, and its metadata. A descriptor without a start date is for the run of the trip closest to being in progress at `now`: today's in the trip's timezone, or yesterday's if that one runs past midnight and hasn't finished.
+pub(super) fn resolve_trip<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    trip_index: &HashMap<(String, String), Vec<Trip>>,
+    feed_id: &str,
+    trip_id: &str,
+    descriptor: &TripDescriptor,
+    now: Time,
+) -> Result<(Trip, Time, TripMetadata), TripUpdateIssue> {
+    let candidates = trip_index
+        .get(&(feed_id.to_string(), trip_id.to_string()))
+        .ok_or_else(|| TripUpdateIssue::UnknownTrip {
+            trip_id: trip_id.to_string(),
+        })?;
+    let trip = find_trip(
+        timetable,
+        candidates,
+        trip_id,
+        descriptor.start_time.as_deref(),
+    )?;
+    let metadata = trip.metadata(timetable);
+    let timezone: Tz = metadata
+        .timezone
+        .as_ref()
+        .and_then(|timezone| timezone.parse().ok())
+        .ok_or_else(|| TripUpdateIssue::MissingTimezone {
+            trip_id: trip_id.to_string(),
+        })?;
+    let not_running = |date: NaiveDate| TripUpdateIssue::NotRunning {
+        trip_id: trip_id.to_string(),
+        date,
+    };
+    let service_day_start = if let Some(start_date) = &descriptor.start_date {
+        let date = NaiveDate::parse_from_str(start_date, "%Y%m%d").map_err(|_err| {
+            TripUpdateIssue::InvalidStartDate {
+                trip_id: trip_id.to_string(),
+                start_date: start_date.clone(),
+            }
+        })?;
+        trip_service_day(timetable, &trip, &timezone, date).ok_or_else(|| not_running(date))?
+    } else {
+        let today = timezone
+            .timestamp_opt(now.epoch_seconds() as i64, 0)
+            .unwrap()
+            .date_naive();
+        let stop_times = trip.stop_times(timetable);
+        let first_departure = stop_times.first().map_or(0, TripStopTime::departure_offset);
+        let last_arrival = stop_times.last().map_or(0, TripStopTime::arrival_offset);
+        // How far the run is from `now`, which is zero while it's in progress.
+        let distance = |service_day_start: &Time| {
+            let start = service_day_start.epoch_seconds() + first_departure;
+            let end = service_day_start.epoch_seconds() + last_arrival;
+            start
+                .saturating_sub(now.epoch_seconds())
+                .max(now.epoch_seconds().saturating_sub(end))
+        };
+        [Some(today), today.pred_opt()]
+            .into_iter()
+            .flatten()
+            .filter_map(|date| trip_service_day(timetable, &trip, &timezone, date))
+            .min_by_key(distance)
+            .ok_or_else(|| not_running(today))?
+    };
+    Ok((trip, service_day_start, metadata))
+}
+
+/// Pick the trip an update is for. Trips from `frequencies.txt` with `exact_times=1` share a trip ID, and are told apart by when they leave their first stop.
+pub(super) fn find_trip<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    candidates: &[Trip],
+    trip_id: &str,
+    start_time: Option<&str>,
+) -> Result<Trip, TripUpdateIssue> {
+    if let [trip] = candidates {
+        return Ok(*trip);
+    }
+    let start_time = start_time.ok_or_else(|| TripUpdateIssue::AmbiguousTrip {
+        trip_id: trip_id.to_string(),
+    })?;
+    let no_trip = || TripUpdateIssue::NoTripAtStartTime {
+        trip_id: trip_id.to_string(),
+        start_time: start_time.to_string(),
+    };
+    let start_offset = parse_start_time(start_time).ok_or_else(no_trip)?;
+    candidates
+        .iter()
+        .find(|trip| {
+            trip.stop_times(timetable)
+                .first()
+                .is_some_and(|stop_time| stop_time.departure_offset() == start_offset)
+        })
+        .copied()
+        .ok_or_else(no_trip)
+}
+
+/// Seconds after the start of the service day for a GTFS-RT start time, which like GTFS times may go past 24:00:00.
+fn parse_start_time(start_time: &str) -> Option<u32> {
+    let mut parts = start_time.split(':').map(|part| part.parse::<u32>().ok());
+    let (hours, minutes, seconds) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+/// How many seconds late an event is, from its predicted time if it has one, otherwise its delay.
+fn event_delay(event: &StopTimeEvent, service_day_start: Time, offset: u32) -> Option<i32> {
+    let scheduled = service_day_start.epoch_seconds() as i64 + offset as i64;
+    event
+        .time
+        .map(|time| (time - scheduled) as i32)
+        .or(event.delay)
+}
+
+fn delayed(service_day_start: Time, offset: u32, delay: i32) -> Time {
+    let seconds = service_day_start.epoch_seconds() as i64 + offset as i64 + delay as i64;
+    Time::from_epoch_seconds(seconds.clamp(0, u32::MAX as i64) as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_start_time;
+
+    #[test]
+    fn start_times_past_midnight() {
+        assert_eq!(parse_start_time("08:30:15"), Some(8 * 3600 + 30 * 60 + 15));
+        assert_eq!(parse_start_time("25:00:00"), Some(25 * 3600));
+        assert_eq!(parse_start_time("8:30"), None);
+        assert_eq!(parse_start_time("08:30:00:00"), None);
+    }
+}
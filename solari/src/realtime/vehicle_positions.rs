@@ -0,0 +1,273 @@
+use gtfs_rt::FeedMessage;
+use serde::{Deserialize, Serialize};
+
+use crate::raptor::timetable::{RouteStop, ScheduledTrip, Time, Timetable, Trip};
+
+use super::{
+    trip_updates::{resolve_trip, TripUpdates},
+    EntityIndex,
+};
+
+/// Where a vehicle is, from a GTFS-RT feed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VehiclePosition {
+    /// The vehicle's ID, or the feed entity's if the feed doesn't identify the vehicle.
+    pub vehicle_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+    /// Degrees clockwise from north.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bearing: Option<f32>,
+    /// Meters per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f32>,
+    /// When the position was measured, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    pub feed_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trip_id: Option<String>,
+    /// The name of the stop the vehicle is at or heading to, if it's on a trip in the timetable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_stop: Option<String>,
+    /// The GTFS-RT stop status, e.g. `IN_TRANSIT_TO` or `STOPPED_AT`, relative to `next_stop`.
+    pub status: String,
+}
+
+/// A problem with a vehicle position that meant it, or its trip, couldn't be matched to the timetable.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum VehiclePositionIssue {
+    #[error("vehicle {vehicle_id:?} has no position, skipping it")]
+    MissingPosition { vehicle_id: String },
+    #[error("vehicle {vehicle_id:?} is on trip {trip_id:?}, which couldn't be matched to the timetable: {reason}")]
+    UnmatchedTrip {
+        vehicle_id: String,
+        trip_id: String,
+        reason: String,
+    },
+}
+
+/// What came of applying a feed's vehicle positions.
+#[derive(Debug, Clone, Default)]
+pub struct VehiclePositionReport {
+    pub issues: Vec<VehiclePositionIssue>,
+    pub vehicles: usize,
+    /// Vehicles matched to a trip in the timetable.
+    pub vehicles_on_trips: usize,
+}
+
+impl VehiclePositionReport {
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn record(&mut self, issue: VehiclePositionIssue) {
+        if !self.issues.contains(&issue) {
+            self.issues.push(issue);
+        }
+    }
+}
+
+/// A vehicle, linked to the trip it's running and the stop it's at or heading to.
+#[derive(Debug, Clone)]
+pub(crate) struct TrackedVehicle {
+    /// The real-time source the position came from.
+    source_id: String,
+    position: VehiclePosition,
+    /// The trip and the start of its service day.
+    trip: Option<(Trip, Time)>,
+    next_route_stop: Option<RouteStop>,
+}
+
+/// Every vehicle position currently applied.
+#[derive(Debug, Default)]
+pub(crate) struct VehiclePositions {
+    vehicles: Vec<TrackedVehicle>,
+}
+
+impl VehiclePositions {
+    pub(crate) fn replace_source(&mut self, source_id: &str, vehicles: Vec<TrackedVehicle>) {
+        self.vehicles
+            .retain(|vehicle| vehicle.source_id != source_id);
+        self.vehicles.extend(vehicles);
+    }
+
+    /// The vehicle running `trip`, unless it's already past `until_stop_seq`.
+    pub(crate) fn on_trip(
+        &self,
+        trip: &ScheduledTrip,
+        until_stop_seq: usize,
+    ) -> Option<VehiclePosition> {
+        self.vehicles
+            .iter()
+            .find(|vehicle| vehicle.trip == Some((*trip.trip(), trip.service_day_start())))
+            .filter(|vehicle| {
+                vehicle
+                    .next_route_stop
+                    .is_none_or(|route_stop| route_stop.stop_seq() <= until_stop_seq)
+            })
+            .map(|vehicle| vehicle.position.clone())
+    }
+
+    pub(crate) fn on_route(&self, feed_id: &str, route_id: &str) -> Vec<VehiclePosition> {
+        self.vehicles
+            .iter()
+            .map(|vehicle| &vehicle.position)
+            .filter(|position| {
+                position.feed_id == feed_id && position.route_id.as_deref() == Some(route_id)
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn within(
+        &self,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    ) -> Vec<VehiclePosition> {
+        self.vehicles
+            .iter()
+            .map(|vehicle| &vehicle.position)
+            .filter(|position| {
+                (min_lat..=max_lat).contains(&position.lat)
+                    && (min_lon..=max_lon).contains(&position.lon)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Match the vehicles in `message` to the trips they're running. `timetable` should include any trip updates, which are used to tell where a vehicle is along its trip when the feed doesn't say.
+pub(crate) fn match_vehicle_positions<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    index: &EntityIndex,
+    source_id: &str,
+    feed_id: &str,
+    message: &FeedMessage,
+) -> (Vec<TrackedVehicle>, VehiclePositionReport) {
+    let mut report = VehiclePositionReport::default();
+    let mut vehicles = vec![];
+    let trip_updates = timetable.trip_updates();
+    for entity in &message.entity {
+        let vehicle = if let Some(vehicle) = &entity.vehicle {
+            vehicle
+        } else {
+            continue;
+        };
+        let descriptor = vehicle.vehicle.as_ref();
+        let vehicle_id = descriptor
+            .and_then(|descriptor| descriptor.id.clone())
+            .unwrap_or_else(|| entity.id.clone());
+        let position = if let Some(position) = &vehicle.position {
+            position
+        } else {
+            report.record(VehiclePositionIssue::MissingPosition { vehicle_id });
+            continue;
+        };
+        let measured_at = vehicle
+            .timestamp
+            .map(|timestamp| Time::from_epoch_seconds(timestamp as u32))
+            .unwrap_or_else(Time::now);
+
+        let mut trip = None;
+        let mut next_route_stop = None;
+        let mut route_id = vehicle
+            .trip
+            .as_ref()
+            .and_then(|descriptor| descriptor.route_id.clone());
+        let trip_id = vehicle
+            .trip
+            .as_ref()
+            .and_then(|descriptor| descriptor.trip_id.clone());
+        if let (Some(descriptor), Some(trip_id)) = (&vehicle.trip, &trip_id) {
+            match resolve_trip(
+                timetable,
+                &index.trips,
+                feed_id,
+                trip_id,
+                descriptor,
+                measured_at,
+            ) {
+                Ok((matched_trip, service_day_start, metadata)) => {
+                    let route = matched_trip.route(timetable);
+                    let route_stops = route.route_stops(timetable);
+                    let stop_seq = if let Some(stop_sequence) = vehicle.current_stop_sequence {
+                        metadata
+                            .stop_sequences
+                            .iter()
+                            .position(|gtfs_stop_sequence| *gtfs_stop_sequence == stop_sequence)
+                    } else if let Some(stop_id) = &vehicle.stop_id {
+                        route_stops.iter().position(|route_stop| {
+                            route_stop.stop(timetable).metadata(timetable).id == *stop_id
+                        })
+                    } else {
+                        // The vehicle is heading to the first stop it hasn't left yet.
+                        (0..route_stops.len()).find(|stop_seq| {
+                            departure(
+                                timetable,
+                                trip_updates.as_deref(),
+                                &matched_trip,
+                                service_day_start,
+                                *stop_seq,
+                            ) >= measured_at
+                        })
+                    };
+                    next_route_stop = stop_seq.map(|stop_seq| route_stops[stop_seq]);
+                    route_id = route_id.or(metadata.gtfs_route_id);
+                    trip = Some((matched_trip, service_day_start));
+                    report.vehicles_on_trips += 1;
+                }
+                Err(issue) => report.record(VehiclePositionIssue::UnmatchedTrip {
+                    vehicle_id: vehicle_id.clone(),
+                    trip_id: trip_id.clone(),
+                    reason: issue.to_string(),
+                }),
+            }
+        }
+
+        report.vehicles += 1;
+        vehicles.push(TrackedVehicle {
+            source_id: source_id.to_string(),
+            position: VehiclePosition {
+                vehicle_id,
+                label: descriptor.and_then(|descriptor| descriptor.label.clone()),
+                lat: position.latitude as f64,
+                lon: position.longitude as f64,
+                bearing: position.bearing,
+                speed: position.speed,
+                timestamp: vehicle.timestamp,
+                feed_id: feed_id.to_string(),
+                route_id,
+                trip_id,
+                next_stop: next_route_stop
+                    .and_then(|route_stop| route_stop.stop(timetable).metadata(timetable).name),
+                status: vehicle.current_status().as_str_name().to_string(),
+            },
+            trip,
+            next_route_stop,
+        });
+    }
+    (vehicles, report)
+}
+
+/// When a trip leaves a stop, as predicted if there's a prediction, otherwise as scheduled.
+fn departure<'a>(
+    timetable: &'a dyn Timetable<'a>,
+    trip_updates: Option<&TripUpdates>,
+    trip: &Trip,
+    service_day_start: Time,
+    stop_seq: usize,
+) -> Time {
+    trip_updates
+        .and_then(|trip_updates| trip_updates.stop_time(trip, service_day_start, stop_seq))
+        .map(|realtime| realtime.departure)
+        .unwrap_or_else(|| {
+            service_day_start.plus_seconds(trip.stop_times(timetable)[stop_seq].departure_offset())
+        })
+}
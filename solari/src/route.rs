@@ -1,8 +1,8 @@
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     path::PathBuf,
-    sync::Arc,
+    sync::{Arc, RwLock},
 };
 
 use geo::ClosestPoint;
@@ -12,7 +12,9 @@ use s2::latlng::LatLng;
 use serde::Serialize;
 use solari_geomath::EARTH_RADIUS_APPROX;
 use solari_spatial::SphereIndexMmap;
-use solari_transfers::{fast_paths::FastGraphStatic, TransferGraph, TransferGraphSearcher};
+use solari_transfers::{
+    fast_paths::FastGraphStatic, TransferGraph, TransferProvider, TransferSearcher,
+};
 use time::OffsetDateTime;
 
 use crate::{
@@ -20,44 +22,95 @@ use crate::{
         response::{ResponseStatus, SolariResponse},
         SolariItinerary, SolariLeg,
     },
-    raptor::timetable::TripStopTime,
+    fares::{itinerary_fare, price_legs, FareCalculator, FareLeg, FareOptions, FareStop},
+    realtime::{
+        added_trips::{AddedTrip, AddedTrips},
+        alerts::{Alerts, ServiceAlert},
+        trip_updates::TripUpdates,
+        vehicle_positions::VehiclePosition,
+    },
     spatial::FAKE_WALK_SPEED_SECONDS_PER_METER,
+    stations::PathwayMode,
 };
 
-use crate::raptor::timetable::{Route, RouteStop, Stop, Time, Timetable, Trip};
+use crate::raptor::timetable::{
+    Route, RouteStop, ScheduledTrip, ServiceWindow, Stop, Time, Timetable,
+};
+
+use self::fare_search::FareSearch;
+
+mod fare_search;
+
+/// The fare search considers itineraries that arrive up to this long after the fastest one, or longer if the fastest one is slower than this.
+const MIN_FARE_SEARCH_SLACK_SECONDS: u32 = 30 * 60;
+/// Multi-criteria rounds are much more expensive than regular ones, so the number of rides is capped.
+const MAX_FARE_SEARCH_ROUNDS: usize = 6;
 
 pub struct Router<'a, T: Timetable<'a>> {
     timetable: T,
-    transfer_graph: Arc<TransferGraph<FastGraphStatic<'a>, SphereIndexMmap<'a, usize>>>,
+    transfer_provider: Arc<dyn TransferProvider + 'a>,
+    /// Fare calculators built so far, by feed ID. `None` means the feed has no fare data.
+    fare_calculators: RwLock<HashMap<String, Option<Arc<FareCalculator>>>>,
 }
 
 impl<'a, T: Timetable<'a>> Router<'a, T> {
     pub fn new(timetable: T, transfer_graph_path: PathBuf) -> Result<Router<'a, T>, anyhow::Error> {
-        info!("Opening transfer graph metadata db.");
-        let database = Arc::new(redb::Database::open(
-            transfer_graph_path.join("graph_metadata.db"),
-        )?);
         info!("Opening transfer graph.");
         let transfer_graph = Arc::new(
-            TransferGraph::<FastGraphStatic, SphereIndexMmap<usize>>::read_from_dir(
-                transfer_graph_path.clone(),
-                database,
+            TransferGraph::<FastGraphStatic, SphereIndexMmap<usize>>::open_dir(
+                transfer_graph_path,
             )?,
         );
+        Ok(Self::with_transfer_provider(timetable, transfer_graph))
+    }
+
+    /// Build a router that uses an arbitrary source of transfer paths, e.g. `GeometricTransfers` when no Valhalla-derived transfer graph is available.
+    pub fn with_transfer_provider(
+        timetable: T,
+        transfer_provider: Arc<dyn TransferProvider + 'a>,
+    ) -> Router<'a, T> {
         info!("Built router");
-        Ok(Router {
+        Router {
             timetable,
-            transfer_graph,
-        })
+            transfer_provider,
+            fare_calculators: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn fare_calculator(&'a self, feed_id: &str) -> Option<Arc<FareCalculator>> {
+        if let Some(calculator) = self.fare_calculators.read().unwrap().get(feed_id) {
+            return calculator.clone();
+        }
+        let calculator = self
+            .timetable
+            .feed_fares(feed_id)
+            .map(|fares| Arc::new(FareCalculator::new(fares)));
+        self.fare_calculators
+            .write()
+            .unwrap()
+            .insert(feed_id.to_string(), calculator.clone());
+        calculator
+    }
+
+    /// The timetable being routed on.
+    pub fn timetable(&'a self) -> &'a T {
+        &self.timetable
     }
 
+    /// The service days the timetable covers, if it records them.
+    pub fn service_window(&'a self) -> Option<ServiceWindow> {
+        self.timetable.service_window()
+    }
+
+    /// The stops closest to `location`, along with how many seconds it takes to walk to each of them.
     pub fn nearest_stops(
         &'a self,
         location: LatLng,
         max_stops: Option<usize>,
         max_distance: Option<f64>,
-    ) -> Vec<&'a Stop> {
-        let mut stops: Vec<&'a Stop> = vec![];
+    ) -> Vec<(&'a Stop, u32)> {
+        let mut stops: Vec<(&'a Stop, u32)> = vec![];
+        let mut searcher = self.transfer_provider.searcher();
         assert!(max_stops.is_some() || max_distance.is_some());
         for (count, (stop, dist_sq)) in self
             .timetable
@@ -75,11 +128,50 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
                     break;
                 }
             }
-            stops.push(self.timetable.stop(stop.id()));
+            let stop = self.timetable.stop(stop.id());
+            stops.push((
+                stop,
+                self.access_seconds(searcher.as_mut(), stop, &location),
+            ));
         }
         stops
     }
 
+    /// How long it takes to walk between `location` and `stop`. Stations with entrances are entered through whichever one is quickest, going by the transfer graph, since the platforms may be underground.
+    fn access_seconds(
+        &'a self,
+        searcher: &mut dyn TransferSearcher,
+        stop: &'a Stop,
+        location: &LatLng,
+    ) -> u32 {
+        let straight_line_meters = |to: &LatLng| to.distance(location).rad() * EARTH_RADIUS_APPROX;
+        let walk_seconds = |meters: f64| (FAKE_WALK_SPEED_SECONDS_PER_METER * meters) as u32;
+        let entrances = stop.entrances(&self.timetable);
+        if entrances.is_empty() {
+            return walk_seconds(straight_line_meters(&stop.location()));
+        }
+        let from = Coord {
+            x: location.lng.deg(),
+            y: location.lat.deg(),
+        };
+        entrances
+            .iter()
+            .map(|entrance| {
+                let entrance_location = entrance.location();
+                let to = Coord {
+                    x: entrance_location.lng.deg(),
+                    y: entrance_location.lat.deg(),
+                };
+                let meters = searcher
+                    .transfer_distance_mm(&from, &to)
+                    .map(|mm| mm as f64 / 1000.0)
+                    .unwrap_or_else(|_| straight_line_meters(&entrance_location));
+                walk_seconds(meters) + entrance.seconds()
+            })
+            .min()
+            .unwrap()
+    }
+
     pub async fn route(
         &'a self,
         route_start_time: Time,
@@ -89,7 +181,24 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
         max_candidate_stops_each_side: Option<usize>,
         max_transfers: Option<usize>,
         max_transfer_delta: Option<usize>,
+        fare_options: &FareOptions,
     ) -> SolariResponse {
+        if let Some(service_window) = self.timetable.service_window() {
+            let status = if route_start_time < service_window.earliest_time() {
+                Some(ResponseStatus::TooEarly)
+            } else if route_start_time > service_window.latest_time() {
+                Some(ResponseStatus::TooLate)
+            } else {
+                None
+            };
+            if let Some(status) = status {
+                return SolariResponse {
+                    status,
+                    itineraries: vec![],
+                };
+            }
+        }
+
         let start_stops = self.nearest_stops(
             start_location,
             max_candidate_stops_each_side,
@@ -103,37 +212,32 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
 
         let target_costs: Vec<(usize, u32)> = target_stops
             .iter()
-            .map(|stop| {
-                (
-                    stop.id(),
-                    (FAKE_WALK_SPEED_SECONDS_PER_METER
-                        * stop.location().distance(&target_location).rad()
-                        * EARTH_RADIUS_APPROX) as u32,
-                )
-            })
+            .map(|(stop, cost)| (stop.id(), *cost))
             .collect();
 
         let mut context = RouterContext {
             best_times_global: vec![None; self.timetable.stop_count()],
             best_times_per_round: Vec::new(),
             marked_stops: vec![false; self.timetable.stop_count()],
-            marked_routes: RefCell::new(vec![
-                TripStopTime::marked();
-                self.timetable.routes().len()
-            ]),
+            marked_routes: RefCell::new(vec![None; self.timetable.routes().len()]),
             timetable: &self.timetable,
             round: 0,
             targets: target_costs.clone(),
             max_transfers,
             max_transfer_delta,
+            trip_updates: self.timetable.trip_updates(),
+            added_trips: self.timetable.added_trips(),
+            alerts: self.timetable.alerts(),
             step_log: vec![InternalStep {
                 previous_step: 0usize,
                 from: InternalStepLocation::Location(LatLng::from_degrees(0.0, 0.0)),
                 to: InternalStepLocation::Location(LatLng::from_degrees(0.0, 0.0)),
                 route: None,
+                stop_seqs: None,
                 departure: Time::epoch(),
                 arrival: Time::epoch(),
                 trip: None,
+                added_trip: None,
             }],
         };
         context
@@ -141,21 +245,80 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
             .await;
         context.route().await;
 
-        let best_itineraries = self
+        let mut best_itineraries: Vec<SolariItinerary> = self
             .pick_best_itineraries(&context, &target_costs)
             .iter()
             .map(|itinerary| {
                 self.unwind_itinerary(
-                    &context,
+                    &context.step_log,
                     itinerary,
                     route_start_time,
                     &target_costs,
                     start_location,
                     target_location,
+                    fare_options,
                 )
             })
             .collect();
 
+        let fastest_arrival = best_itineraries
+            .iter()
+            .map(|itinerary| itinerary.end_time.unix_timestamp() as u32)
+            .min();
+        if let Some(fastest_arrival) = fastest_arrival {
+            if fare_options.max_fare.is_some()
+                || best_itineraries
+                    .iter()
+                    .any(|itinerary| itinerary.fare.is_some())
+            {
+                // Look for cheaper itineraries that take at most twice as long as the fastest one.
+                let slack = u32::max(
+                    fastest_arrival.saturating_sub(route_start_time.epoch_seconds()),
+                    MIN_FARE_SEARCH_SLACK_SECONDS,
+                );
+                let max_rounds = max_transfers
+                    .map_or(MAX_FARE_SEARCH_ROUNDS, |transfers| {
+                        transfers.saturating_add(1)
+                    })
+                    .min(MAX_FARE_SEARCH_ROUNDS);
+                let (step_log, fare_itineraries) = FareSearch::new(
+                    self,
+                    fare_options.clone(),
+                    &target_costs,
+                    Time::from_epoch_seconds(fastest_arrival + slack),
+                    max_rounds,
+                )
+                .run(route_start_time, start_location, &start_stops);
+                for itinerary in &fare_itineraries {
+                    let itinerary = self.unwind_itinerary(
+                        &step_log,
+                        itinerary,
+                        route_start_time,
+                        &target_costs,
+                        start_location,
+                        target_location,
+                        fare_options,
+                    );
+                    if !best_itineraries
+                        .iter()
+                        .any(|other| same_itinerary(other, &itinerary))
+                    {
+                        best_itineraries.push(itinerary);
+                    }
+                }
+            }
+        }
+
+        if let Some(max_fare) = fare_options.max_fare {
+            best_itineraries.retain(|itinerary| {
+                itinerary
+                    .fare
+                    .as_ref()
+                    .and_then(|fare| fare.total.as_ref())
+                    .is_none_or(|total| total.amount <= max_fare)
+            });
+        }
+
         SolariResponse {
             status: ResponseStatus::Ok,
             itineraries: best_itineraries,
@@ -164,17 +327,28 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
 
     fn unwind_itinerary(
         &'a self,
-        context: &RouterContext<'a, T>,
+        step_log: &[InternalStep<'a>],
         itinerary: &InternalItinerary,
         route_start_time: Time,
         target_costs: &[(usize, u32)],
         start_location: LatLng,
         target_location: LatLng,
+        fare_options: &FareOptions,
     ) -> SolariItinerary {
         let mut steps = vec![];
+        let mut fare_legs = vec![];
+        let mut search_context = self.transfer_provider.searcher();
+        let timetable: &'a T = &self.timetable;
+        let alerts = timetable.alerts();
+        let leg_alerts = |trip: Option<&ScheduledTrip>, stops: &[&Stop], start: Time, end: Time| {
+            alerts
+                .as_deref()
+                .map(|alerts| alerts.matching(timetable, trip, stops, start, end))
+                .unwrap_or_default()
+        };
         let mut step_cursor = itinerary.last_step;
         {
-            let step = &context.step_log[step_cursor];
+            let step = &step_log[step_cursor];
             let from = if let InternalStepLocation::Stop(stop) = step.to {
                 stop
             } else {
@@ -202,8 +376,8 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
                 step_cursor,
             ));
         }
-        while context.step_log[step_cursor].previous_step != 0 {
-            let step = &context.step_log[step_cursor];
+        while step_log[step_cursor].previous_step != 0 {
+            let step = &step_log[step_cursor];
             let to = if let InternalStepLocation::Stop(stop) = step.to {
                 stop
             } else {
@@ -218,41 +392,97 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
             let from_location = from.location();
 
             steps.push((
-                if step.route.is_none() {
+                if !step.is_ride() {
+                    let from_metadata = from.metadata(&self.timetable);
+                    let to_metadata = to.metadata(&self.timetable);
+                    let pathway_modes = self
+                        .timetable
+                        .transfers_from(from.id())
+                        .iter()
+                        .find(|transfer| transfer.to(&self.timetable).id() == to.id())
+                        .map(|transfer| transfer.pathway_modes())
+                        .unwrap_or_default();
+                    // Walks between stations go out one entrance and in another, not straight through the buildings.
+                    let walk = if !pathway_modes.is_empty() {
+                        None
+                    } else if from.shares_entrances_with(to, &self.timetable) {
+                        Some((from_location, to_location))
+                    } else {
+                        from.walk_between_stations(to, &self.timetable, search_context.as_mut())
+                            .map(|(walk_from, walk_to, _)| (walk_from, walk_to))
+                            .or(Some((from_location, to_location)))
+                    };
                     Step::Transfer(TransferStep {
-                        from_stop: from.metadata(&self.timetable).name.clone(),
+                        from_stop: from_metadata.name.clone(),
                         from_stop_latlng: [from_location.lat.deg(), from_location.lng.deg()],
-                        to_stop: to.metadata(&self.timetable).name.clone(),
+                        from_stop_timezone: from_metadata.timezone.clone(),
+                        to_stop: to_metadata.name.clone(),
                         to_stop_latlng: [to_location.lat.deg(), to_location.lng.deg()],
+                        to_stop_timezone: to_metadata.timezone.clone(),
                         departure_epoch_seconds: step.departure.epoch_seconds() as u64,
                         arrival_epoch_seconds: step.arrival.epoch_seconds() as u64,
+                        walk_latlngs: walk.map(|(walk_from, walk_to)| {
+                            [
+                                [walk_from.lat.deg(), walk_from.lng.deg()],
+                                [walk_to.lat.deg(), walk_to.lng.deg()],
+                            ]
+                        }),
+                        pathway_modes,
+                        alerts: leg_alerts(None, &[from, to], step.departure, step.arrival),
                     })
                 } else {
                     let to_location = to.location();
                     let from_location = from.location();
 
-                    let shape = self.clip_shape(step);
+                    let (trip_metadata, headway_secs, shape, alerts, vehicle) =
+                        if let Some(added_trip) = &step.added_trip {
+                            // Added trips have no shape, and only alerts on their stops apply to them.
+                            (
+                                added_trip.metadata().clone(),
+                                None,
+                                None,
+                                leg_alerts(None, &[from, to], step.departure, step.arrival),
+                                None,
+                            )
+                        } else {
+                            let trip = step.trip.unwrap();
+                            (
+                                trip.trip().metadata(&self.timetable),
+                                trip.headway_secs(),
+                                self.clip_shape(step),
+                                leg_alerts(Some(&trip), &[from, to], step.departure, step.arrival),
+                                step.stop_seqs.and_then(|(_, arrival_stop_seq)| {
+                                    self.timetable.vehicle_position(&trip, arrival_stop_seq)
+                                }),
+                            )
+                        };
+                    let from_metadata = from.metadata(&self.timetable);
+                    let to_metadata = to.metadata(&self.timetable);
+
+                    fare_legs.push(FareLeg {
+                        feed_id: trip_metadata.feed_id.clone(),
+                        route_id: trip_metadata.gtfs_route_id.clone(),
+                        from: FareStop::from(&from_metadata),
+                        to: FareStop::from(&to_metadata),
+                        departure_epoch_seconds: step.departure.epoch_seconds() as u64,
+                        arrival_epoch_seconds: step.arrival.epoch_seconds() as u64,
+                    });
 
                     Step::Trip(TripStep {
-                        on_route: step
-                            .trip
-                            .unwrap()
-                            .metadata(&self.timetable)
-                            .route_name
-                            .clone(),
-                        agency: step
-                            .trip
-                            .unwrap()
-                            .metadata(&self.timetable)
-                            .agency_name
-                            .clone(),
-                        departure_stop: from.metadata(&self.timetable).name.clone(),
+                        on_route: trip_metadata.route_name.clone(),
+                        agency: trip_metadata.agency_name.clone(),
+                        departure_stop: from_metadata.name.clone(),
                         departure_stop_latlng: [from_location.lat.deg(), from_location.lng.deg()],
+                        departure_stop_timezone: from_metadata.timezone.clone(),
                         departure_epoch_seconds: step.departure.epoch_seconds() as u64,
-                        arrival_stop: to.metadata(&self.timetable).name.clone(),
+                        arrival_stop: to_metadata.name.clone(),
                         arrival_stop_latlng: [to_location.lat.deg(), to_location.lng.deg()],
+                        arrival_stop_timezone: to_metadata.timezone.clone(),
                         arrival_epoch_seconds: step.arrival.epoch_seconds() as u64,
+                        headway_secs,
                         shape,
+                        alerts,
+                        vehicle,
                     })
                 },
                 step_cursor,
@@ -264,8 +494,6 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
         } else {
             panic!("First step is not a Begin step.");
         };
-        let transfer_graph = self.transfer_graph.clone();
-        let mut search_context = TransferGraphSearcher::new(transfer_graph);
         let legs = steps
             .iter()
             .rev()
@@ -283,40 +511,40 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
                         lat: trip.departure_stop_latlng[0],
                         lon: trip.departure_stop_latlng[1],
                         stop: trip.departure_stop.clone(),
+                        timezone: trip.departure_stop_timezone.clone(),
                     },
                     end_location: crate::api::LatLng {
                         lat: trip.arrival_stop_latlng[0],
                         lon: trip.arrival_stop_latlng[1],
                         stop: trip.arrival_stop.clone(),
+                        timezone: trip.arrival_stop_timezone.clone(),
                     },
                     transit_route: trip.on_route.clone(),
                     transit_agency: trip.agency.clone(),
                     route_shape: trip.shape.clone(),
+                    headway_secs: trip.headway_secs,
+                    alerts: trip.alerts.clone(),
+                    vehicle: trip.vehicle.clone(),
                 }),
                 Step::Transfer(transfer) => {
-                    let from_coord = Coord {
-                        y: transfer.from_stop_latlng[0],
-                        x: transfer.from_stop_latlng[1],
-                    };
-                    let to_coord = Coord {
-                        y: transfer.to_stop_latlng[0],
-                        x: transfer.to_stop_latlng[1],
-                    };
-                    let transfer_shape = match self.transfer_graph.transfer_path(
-                        &mut search_context,
-                        &from_coord,
-                        &to_coord,
-                    ) {
-                        Ok(transfer_path) => Some(transfer_path.shape),
-                        Err(err) => {
-                            log::error!(
-                                "Failed to calculate transfer path: {}, step: {:?}",
-                                err,
-                                transfer
-                            );
-                            None
+                    let transfer_shape = transfer.walk_latlngs.and_then(|[from, to]| {
+                        let from_coord = Coord {
+                            y: from[0],
+                            x: from[1],
+                        };
+                        let to_coord = Coord { y: to[0], x: to[1] };
+                        match search_context.transfer_path(&from_coord, &to_coord) {
+                            Ok(transfer_path) => Some(transfer_path.shape),
+                            Err(err) => {
+                                log::error!(
+                                    "Failed to calculate transfer path: {}, step: {:?}",
+                                    err,
+                                    transfer
+                                );
+                                None
+                            }
                         }
-                    };
+                    });
                     Some(SolariLeg::Transfer {
                         start_time: OffsetDateTime::from_unix_timestamp(
                             transfer.departure_epoch_seconds as i64,
@@ -330,28 +558,48 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
                             lat: transfer.from_stop_latlng[0],
                             lon: transfer.from_stop_latlng[1],
                             stop: transfer.from_stop.clone(),
+                            timezone: transfer.from_stop_timezone.clone(),
                         },
                         end_location: crate::api::LatLng {
                             lat: transfer.to_stop_latlng[0],
                             lon: transfer.to_stop_latlng[1],
                             stop: transfer.to_stop.clone(),
+                            timezone: transfer.to_stop_timezone.clone(),
                         },
                         route_shape: transfer_shape,
+                        pathway_modes: transfer.pathway_modes.clone(),
+                        alerts: transfer.alerts.clone(),
                     })
                 }
                 _ => None,
             })
             .collect::<Vec<_>>();
+
+        // Steps were collected back to front.
+        fare_legs.reverse();
+        let transit_leg_indices: Vec<usize> = legs
+            .iter()
+            .enumerate()
+            .filter(|(_, leg)| matches!(leg, SolariLeg::Transit { .. }))
+            .map(|(idx, _)| idx)
+            .collect();
+        let fare_prices = price_legs(&fare_legs, fare_options, |feed_id| {
+            self.fare_calculator(feed_id)
+        });
+        let fare = itinerary_fare(&transit_leg_indices, &fare_prices);
+
         SolariItinerary {
             start_location: crate::api::LatLng {
                 lat: start_location.lat.deg(),
                 lon: start_location.lng.deg(),
                 stop: None,
+                timezone: None,
             },
             end_location: crate::api::LatLng {
                 lat: target_location.lat.deg(),
                 lon: target_location.lng.deg(),
                 stop: None,
+                timezone: None,
             },
             start_time:
                 OffsetDateTime::from_unix_timestamp(route_start_time.epoch_seconds() as i64)
@@ -359,6 +607,7 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
             end_time: OffsetDateTime::from_unix_timestamp(end_time as i64)
                 .expect("Invalid Unix timestamp"),
             legs,
+            fare,
         }
     }
 
@@ -369,7 +618,7 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
         scalar: f64,
     ) -> u32 {
         let last_step = &context.step_log[itinerary.last_step];
-        if last_step.trip.is_none() {
+        if !last_step.is_ride() {
             let last_step_duration =
                 last_step.arrival.epoch_seconds() - last_step.departure.epoch_seconds();
             let scaled = last_step_duration as f64 * scalar;
@@ -412,26 +661,12 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
     fn clip_shape(&'a self, step: &InternalStep) -> Option<String> {
         if let Some(route) = &step.route {
             if let Some(shape) = self.timetable.route_shape(route) {
-                let departure_stop_distance = if let InternalStepLocation::Stop(stop) = step.from {
-                    route
-                        .route_stops(&self.timetable)
-                        .iter()
-                        .filter(|route_stop| route_stop.stop(&self.timetable).id() == stop.id())
-                        .next()
-                        .map(|route_stop| route_stop.distance_along_route())?
-                } else {
-                    return None;
-                };
-                let arrival_stop_distance = if let InternalStepLocation::Stop(stop) = step.to {
-                    route
-                        .route_stops(&self.timetable)
-                        .iter()
-                        .filter(|route_stop| route_stop.stop(&self.timetable).id() == stop.id())
-                        .next()
-                        .map(|route_stop| route_stop.distance_along_route())?
-                } else {
-                    return None;
-                };
+                // Look the stops up by sequence, since a route can visit the same stop more than once.
+                let (departure_stop_seq, arrival_stop_seq) = step.stop_seqs?;
+                let route_stops = route.route_stops(&self.timetable);
+                let departure_stop_distance =
+                    route_stops[departure_stop_seq].distance_along_route();
+                let arrival_stop_distance = route_stops[arrival_stop_seq].distance_along_route();
                 let mut coords = shape
                     .iter()
                     .skip_while(|coord| {
@@ -463,10 +698,10 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
                     let start =
                         Point::new(step.from.latlng().lng.deg(), step.from.latlng().lat.deg());
                     let end = Point::new(step.to.latlng().lng.deg(), step.to.latlng().lat.deg());
-                    if let (Some((start_idx, start_point)), Some((end_idx, end_point))) = (
-                        Self::closest_point(&start, &points),
-                        Self::closest_point(&end, &points),
-                    ) {
+                    // The end is searched for after the start, so that loops are clipped in the right order.
+                    if let Some((start_idx, start_point)) = Self::closest_point(&start, &points) {
+                        let (end_idx, end_point) = Self::closest_point(&end, &points[start_idx..])
+                            .map(|(idx, point)| (start_idx + idx, point))?;
                         coords = shape
                             .iter()
                             .skip(start_idx + 1)
@@ -488,7 +723,7 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
         None
     }
 
-    fn closest_point(target: &Point, points: &Vec<Coord>) -> Option<(usize, Point)> {
+    fn closest_point(target: &Point, points: &[Coord]) -> Option<(usize, Point)> {
         let (idx, closest) = points
             .windows(2)
             .map(|window| Line::new(window[0], window[1]))
@@ -511,15 +746,46 @@ impl<'a, T: Timetable<'a>> Router<'a, T> {
     }
 }
 
+/// Whether two itineraries arrive at the same time by riding the same routes at the same times.
+fn same_itinerary(a: &SolariItinerary, b: &SolariItinerary) -> bool {
+    fn leg_key(leg: &SolariLeg) -> (OffsetDateTime, Option<&String>) {
+        match leg {
+            SolariLeg::Transit {
+                start_time,
+                transit_route,
+                ..
+            } => (*start_time, transit_route.as_ref()),
+            SolariLeg::Transfer { start_time, .. } => (*start_time, None),
+        }
+    }
+    a.end_time == b.end_time
+        && a.legs.len() == b.legs.len()
+        && a.legs
+            .iter()
+            .zip(&b.legs)
+            .all(|(a, b)| leg_key(a) == leg_key(b))
+}
+
 #[derive(Debug, Clone)]
 struct InternalStep<'a> {
     previous_step: usize,
     from: InternalStepLocation<'a>,
     to: InternalStepLocation<'a>,
     route: Option<Route>,
+    /// Where along the route the trip was boarded and left, for transit steps.
+    stop_seqs: Option<(usize, usize)>,
     departure: Time,
     arrival: Time,
-    trip: Option<Trip>,
+    trip: Option<ScheduledTrip>,
+    /// The trip ridden, if it came from a real-time feed rather than the timetable. `stop_seqs` are then along this trip.
+    added_trip: Option<Arc<AddedTrip>>,
+}
+
+impl InternalStep<'_> {
+    /// Whether the step rides a trip rather than walking.
+    fn is_ride(&self) -> bool {
+        self.route.is_some() || self.added_trip.is_some()
+    }
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -543,21 +809,34 @@ pub struct TripStep {
     pub agency: Option<String>,
     pub departure_stop: Option<String>,
     pub departure_stop_latlng: [f64; 2],
+    pub departure_stop_timezone: Option<String>,
     pub departure_epoch_seconds: u64,
     pub arrival_stop: Option<String>,
     pub arrival_stop_latlng: [f64; 2],
+    pub arrival_stop_timezone: Option<String>,
     pub arrival_epoch_seconds: u64,
+    /// For headway-based service, how often vehicles come. The departure and arrival are then estimates.
+    pub headway_secs: Option<u32>,
     pub shape: Option<String>,
+    pub alerts: Vec<ServiceAlert>,
+    /// Where the vehicle is, if the trip is underway and a real-time feed is tracking it.
+    pub vehicle: Option<VehiclePosition>,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TransferStep {
     pub from_stop: Option<String>,
     pub from_stop_latlng: [f64; 2],
+    pub from_stop_timezone: Option<String>,
     pub to_stop: Option<String>,
     pub to_stop_latlng: [f64; 2],
+    pub to_stop_timezone: Option<String>,
     pub departure_epoch_seconds: u64,
     pub arrival_epoch_seconds: u64,
+    /// Where the walking part of the transfer starts and ends, which for stations with entrances is at the entrances. Absent if the transfer stays inside a station.
+    pub walk_latlngs: Option<[[f64; 2]; 2]>,
+    pub pathway_modes: Vec<PathwayMode>,
+    pub alerts: Vec<ServiceAlert>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -581,15 +860,28 @@ pub struct RouterContext<'a, T: Timetable<'a>> {
     best_times_global: Vec<Option<InternalItinerary>>,
     best_times_per_round: Vec<Vec<Option<InternalItinerary>>>,
     marked_stops: Vec<bool>,
-    marked_routes: RefCell<Vec<TripStopTime>>,
+    marked_routes: RefCell<Vec<Option<MarkedRoute>>>,
     timetable: &'a T,
     round: u32,
     targets: Vec<(usize, u32)>,
     max_transfers: Option<usize>,
     max_transfer_delta: Option<usize>,
+    /// Real-time predictions for scheduled trips, as of the start of the query.
+    trip_updates: Option<Arc<TripUpdates>>,
+    /// Trips added by real-time feeds, as of the start of the query.
+    added_trips: Option<Arc<AddedTrips>>,
+    /// Service alerts, as of the start of the query.
+    alerts: Option<Arc<Alerts>>,
     step_log: Vec<InternalStep<'a>>,
 }
 
+/// Where a scan of a route starts in a round: the marked stop with the earliest departure.
+#[derive(Debug, Clone, Copy)]
+struct MarkedRoute {
+    stop_seq: usize,
+    departure: Time,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum InternalStepLocation<'a> {
     Stop(&'a Stop),
@@ -628,7 +920,9 @@ where
         to: &InternalStepLocation<'a>,
         arrival_time: Time,
         via: Option<Route>,
-        on_trip: Option<Trip>,
+        stop_seqs: Option<(usize, usize)>,
+        on_trip: Option<ScheduledTrip>,
+        on_added_trip: Option<Arc<AddedTrip>>,
         previous_step: usize,
     ) -> bool {
         if let InternalStepLocation::Stop(stop) = to {
@@ -652,7 +946,9 @@ where
                     from: from.clone(),
                     to: to.clone(),
                     route: via,
+                    stop_seqs,
                     trip: on_trip,
+                    added_trip: on_added_trip,
                     departure: departure_time.clone(),
                     arrival: arrival_time.clone(),
                     previous_step,
@@ -679,50 +975,23 @@ where
         }
     }
 
-    async fn init(&mut self, time: Time, start_location: LatLng, starts: &[&'a Stop]) {
+    async fn init(&mut self, time: Time, start_location: LatLng, starts: &[(&'a Stop, u32)]) {
         self.best_times_per_round
             .push(vec![None; self.timetable.stop_count()]);
 
-        let start_costs: HashMap<usize, u32> = starts
-            .iter()
-            .enumerate()
-            .map(|(i, start)| {
-                (
-                    i,
-                    (FAKE_WALK_SPEED_SECONDS_PER_METER
-                        * start.location().distance(&start_location).rad()
-                        * EARTH_RADIUS_APPROX) as u32,
-                )
-            })
-            .collect();
-        for (stop_option_index, stop) in starts.iter().enumerate() {
-            if let Some(cost) = start_costs.get(&stop_option_index) {
-                self.maybe_update_arrival_time_and_route(
-                    0u32,
-                    &InternalStepLocation::Location(start_location),
-                    time.clone(),
-                    &InternalStepLocation::Stop(stop),
-                    time.clone().plus_seconds(*cost),
-                    None,
-                    None,
-                    0,
-                );
-            }
-        }
-    }
-
-    fn earliest_trip_from(&self, route_stop: &RouteStop, not_before: &Time) -> Option<Trip> {
-        let trips = route_stop.route(self.timetable).route_trips(self.timetable);
-        let position = match trips.binary_search_by_key(not_before, |trip| {
-            trip.stop_times(self.timetable)[route_stop.stop_seq()].departure()
-        }) {
-            Ok(position) => position,
-            Err(position) => position,
-        };
-        if position >= trips.len() {
-            None
-        } else {
-            Some(trips[position])
+        for (stop, cost) in starts {
+            self.maybe_update_arrival_time_and_route(
+                0u32,
+                &InternalStepLocation::Location(start_location),
+                time.clone(),
+                &InternalStepLocation::Stop(stop),
+                time.clone().plus_seconds(*cost),
+                None,
+                None,
+                None,
+                None,
+                0,
+            );
         }
     }
 
@@ -730,7 +999,7 @@ where
         {
             let mut marked_routes = self.marked_routes.borrow_mut();
             for val in &mut (*marked_routes) {
-                *val = TripStopTime::marked();
+                *val = None;
             }
             for (stop_id, stop_marked) in self.marked_stops.iter().enumerate() {
                 if !*stop_marked {
@@ -743,70 +1012,84 @@ where
                 );
             }
         }
+        let marked_added_trips = self.explore_added_trips();
         for stop_marked in &mut self.marked_stops {
             *stop_marked = false;
         }
 
         let mut marked_stops_count = 0usize;
         let marked_routes = self.marked_routes.clone();
-        for (route_id, departure) in marked_routes.borrow_mut().iter().enumerate() {
-            if departure.trip_index == usize::MAX {
+        for (route_id, marked_route) in marked_routes.borrow().iter().enumerate() {
+            let marked_route = if let Some(marked_route) = marked_route {
+                marked_route
+            } else {
                 continue;
-            }
+            };
             let route = self.timetable.route(route_id);
-            let mut current_trip: Option<(Trip, RouteStop)> = None;
-            let mut found_first_stop = false;
-            let mut departure_stop_seq = 0usize;
+            let route_stops = route.route_stops(self.timetable);
+            let departure_stop_seq = marked_route.stop_seq;
+            let departure_route_stop = &route_stops[departure_stop_seq];
+            let mut current_trip: Option<(ScheduledTrip, RouteStop)> = None;
 
-            for route_stop in route.route_stops(self.timetable) {
-                if route_stop.id() == departure.route_stop(self.timetable).id() {
-                    found_first_stop = true;
-                }
-                if !found_first_stop {
-                    departure_stop_seq += 1;
-                    continue;
-                }
+            for route_stop in &route_stops[departure_stop_seq..] {
                 if let Some((current_trip, current_trip_start)) = &mut current_trip {
+                    if !current_trip.drop_off_allowed(
+                        self.timetable,
+                        self.trip_updates.as_deref(),
+                        self.alerts.as_deref(),
+                        route_stop.stop_seq(),
+                    ) {
+                        continue;
+                    }
                     // TODO: local pruning, target pruning
-                    let departure_trip_stop_time =
-                        &current_trip.stop_times(self.timetable)[departure_stop_seq];
                     let previous_step = if let Some(previous_step) = self.best_times_global
-                        [departure.route_stop(self.timetable).id()]
+                        [departure_route_stop.id()]
                     .as_ref()
                     .map(|step| step.last_step.clone())
                     {
                         previous_step
                     } else {
-                        log::error!(
-                            "No best time for stop {:?}",
-                            departure.route_stop(self.timetable)
-                        );
+                        log::error!("No best time for stop {:?}", departure_route_stop);
                         continue;
                     };
                     if self.maybe_update_arrival_time_and_route(
                         self.round,
                         &InternalStepLocation::Stop(current_trip_start.stop(self.timetable)),
-                        departure_trip_stop_time.departure(),
+                        current_trip.departure(
+                            self.timetable,
+                            self.trip_updates.as_deref(),
+                            current_trip_start.stop_seq(),
+                        ),
                         &InternalStepLocation::Stop(route_stop.stop(self.timetable)),
-                        current_trip.stop_times(self.timetable)[route_stop.stop_seq()].arrival(),
-                        Some(current_trip.route(self.timetable)),
-                        Some(current_trip.clone()),
+                        current_trip.arrival(
+                            self.timetable,
+                            self.trip_updates.as_deref(),
+                            route_stop.stop_seq(),
+                        ),
+                        Some(*route),
+                        Some((current_trip_start.stop_seq(), route_stop.stop_seq())),
+                        Some(*current_trip),
+                        None,
                         previous_step,
                     ) {
                         marked_stops_count += 1;
 
-                        if let Some(trip) = self.earliest_trip_from(
-                            departure.route_stop(self.timetable),
-                            &self.best_times_global[departure.route_stop(self.timetable).id()]
-                                .as_ref()
-                                .unwrap()
-                                .final_time,
+                        let best_departure_time = self.best_times_global[departure_route_stop.id()]
+                            .as_ref()
+                            .unwrap()
+                            .final_time;
+                        if let Some(trip) = route.earliest_trip(
+                            self.timetable,
+                            self.trip_updates.as_deref(),
+                            self.alerts.as_deref(),
+                            departure_stop_seq,
+                            best_departure_time,
                         ) {
-                            if trip.stop_times(self.timetable)[route_stop.stop_seq()].arrival()
-                                < self.best_times_global[departure.route_stop(self.timetable).id()]
-                                    .as_ref()
-                                    .unwrap()
-                                    .final_time
+                            if trip.arrival(
+                                self.timetable,
+                                self.trip_updates.as_deref(),
+                                route_stop.stop_seq(),
+                            ) < best_departure_time
                             {
                                 *current_trip = trip;
                             }
@@ -815,13 +1098,57 @@ where
                 }
 
                 if current_trip.is_none() {
-                    current_trip = self
-                        .earliest_trip_from(route_stop, &departure.arrival())
+                    current_trip = route
+                        .earliest_trip(
+                            self.timetable,
+                            self.trip_updates.as_deref(),
+                            self.alerts.as_deref(),
+                            route_stop.stop_seq(),
+                            marked_route.departure,
+                        )
                         .map(|trip| (trip, route_stop.clone()));
                 }
             }
         }
 
+        if let Some(added_trips) = self.added_trips.clone() {
+            for (trip_idx, departure_stop_seq) in marked_added_trips {
+                let trip = added_trips.trip(trip_idx);
+                let departure = trip.stop_times()[departure_stop_seq];
+                let previous_step = if let Some(previous_step) = self.best_times_global
+                    [departure.stop]
+                    .as_ref()
+                    .map(|step| step.last_step)
+                {
+                    previous_step
+                } else {
+                    log::error!("No best time for stop {}", departure.stop);
+                    continue;
+                };
+                for (stop_seq, stop_time) in trip
+                    .stop_times()
+                    .iter()
+                    .enumerate()
+                    .skip(departure_stop_seq + 1)
+                {
+                    if self.maybe_update_arrival_time_and_route(
+                        self.round,
+                        &InternalStepLocation::Stop(self.timetable.stop(departure.stop)),
+                        departure.departure,
+                        &InternalStepLocation::Stop(self.timetable.stop(stop_time.stop)),
+                        stop_time.arrival,
+                        None,
+                        Some((departure_stop_seq, stop_seq)),
+                        None,
+                        Some(trip.clone()),
+                        previous_step,
+                    ) {
+                        marked_stops_count += 1;
+                    }
+                }
+            }
+        }
+
         let mut marked_transfers_count = 0usize;
         let mut total_transfers_count = 0usize;
         let marked_stops = self.marked_stops.clone();
@@ -844,7 +1171,7 @@ where
                     continue;
                 };
                 // Don't transfer twice in a row.
-                if self.step_log[last_step].route.is_none() {
+                if !self.step_log[last_step].is_ride() {
                     continue;
                 }
                 let best_arrival_at_transfer_start = self.best_times_global[stop.id()]
@@ -862,6 +1189,8 @@ where
                     arrival_at_transfer_end,
                     None,
                     None,
+                    None,
+                    None,
                     last_step,
                 ) {
                     marked_transfers_count += 1;
@@ -881,46 +1210,56 @@ where
 
     fn explore_routes_for_marked_stop(
         &self,
-        marked_routes: &mut [TripStopTime],
+        marked_routes: &mut [Option<MarkedRoute>],
         marked_stop: &Stop,
         not_before: &Time,
     ) {
         for stop_route in marked_stop.stop_routes(self.timetable) {
             let route = stop_route.route(self.timetable);
-            if marked_routes[route.id()].trip_index == usize::MAX {
-                for trip in route.route_trips(self.timetable) {
-                    let trip_stop_time = &trip.stop_times(self.timetable)[stop_route.stop_seq()];
-                    if &trip_stop_time.departure() < &not_before {
-                        continue;
-                    }
-
-                    // We don't actually need to handle the case where the departure hasn't been added because of the u32::MAX step at the beginning of do_round.
-                    if trip_stop_time.departure() < marked_routes[route.id()].departure() {
-                        marked_routes[route.id()] = *trip_stop_time;
-                        // Any trips after this one do not need to be examined.
-                        break;
-                    }
+            if let Some(trip) = route.earliest_trip(
+                self.timetable,
+                self.trip_updates.as_deref(),
+                self.alerts.as_deref(),
+                stop_route.stop_seq(),
+                *not_before,
+            ) {
+                let departure = trip.departure(
+                    self.timetable,
+                    self.trip_updates.as_deref(),
+                    stop_route.stop_seq(),
+                );
+                if marked_routes[route.id()].is_none_or(|marked| departure < marked.departure) {
+                    marked_routes[route.id()] = Some(MarkedRoute {
+                        stop_seq: stop_route.stop_seq(),
+                        departure,
+                    });
                 }
-            } else {
-                for trip in route.route_trips(self.timetable)
-                    [0..(marked_routes[route.id()].trip_index - route.first_route_trip)]
-                    .iter()
-                    .rev()
-                {
-                    let trip_stop_time = &trip.stop_times(self.timetable)[stop_route.stop_seq()];
-                    if &trip_stop_time.departure() < &not_before {
-                        // We are iterating in reverse, so nothing "after" this (before, temporally) needs to be examined.
-                        break;
-                    }
+            }
+        }
+    }
 
-                    // We don't actually need to handle the case where the departure hasn't been added because of the u32::MAX step at the beginning of do_round.
-                    if trip_stop_time.departure() < marked_routes[route.id()].departure() {
-                        marked_routes[route.id()] = *trip_stop_time;
-                        // We are iterating in reverse, so we can't break here.
-                    }
+    /// The added trips that can be boarded at a marked stop, by index, and the earliest stop along each where they can be.
+    fn explore_added_trips(&self) -> BTreeMap<usize, usize> {
+        let mut marked_trips = BTreeMap::new();
+        let added_trips = if let Some(added_trips) = &self.added_trips {
+            added_trips
+        } else {
+            return marked_trips;
+        };
+        for (stop_id, stop_marked) in self.marked_stops.iter().enumerate() {
+            if !*stop_marked {
+                continue;
+            }
+            let not_before = self.best_times_global[stop_id].as_ref().unwrap().final_time;
+            for &(trip_idx, stop_seq) in added_trips.stopping_at(stop_id) {
+                if added_trips.trip(trip_idx).stop_times()[stop_seq].departure < not_before {
+                    continue;
                 }
+                let marked_stop_seq = marked_trips.entry(trip_idx).or_insert(stop_seq);
+                *marked_stop_seq = (*marked_stop_seq).min(stop_seq);
             }
         }
+        marked_trips
     }
 
     pub async fn route(&mut self) {
@@ -0,0 +1,399 @@
+use std::{collections::HashMap, rc::Rc, sync::Arc};
+
+use s2::latlng::LatLng;
+
+use crate::{
+    fares::{price_legs, FareLeg, FareOptions, FareStop},
+    raptor::timetable::{Route, ScheduledTrip, Stop, Time, Timetable, Trip},
+    realtime::{alerts::Alerts, trip_updates::TripUpdates},
+};
+
+use super::{InternalItinerary, InternalStep, InternalStepLocation, Router};
+
+/// Fares are compared with a small tolerance so that floating point noise doesn't make two equal fares look different.
+const FARE_EPSILON: f64 = 1e-6;
+
+/// A Pareto-optimal way of reaching a stop, in terms of arrival time and fare.
+#[derive(Debug, Clone)]
+struct Label {
+    arrival: Time,
+    fare: f64,
+    step: usize,
+    round: u32,
+    /// The transit legs taken so far, needed to price the next one since transfer rules depend on history.
+    legs: Rc<Vec<FareLeg>>,
+    /// Whether the last step was a walk. Two walks in a row are never useful.
+    on_foot: bool,
+}
+
+impl Label {
+    fn dominates(&self, arrival: Time, fare: f64) -> bool {
+        self.arrival <= arrival && self.fare <= fare + FARE_EPSILON
+    }
+}
+
+/// A trip that's been boarded during a route scan, along with the label it was boarded from.
+#[derive(Debug, Clone)]
+struct RouteLabel<'a> {
+    trip: ScheduledTrip,
+    board_seq: usize,
+    board_stop: &'a Stop,
+    parent: Label,
+}
+
+/// A multi-criteria RAPTOR search that keeps a bag of labels per stop instead of a single best arrival, so that slower but cheaper itineraries survive. Fares are calculated by pricing the whole chain of legs at each step, so transfer discounts are accounted for, although a label that's more expensive now but would have earned a discount later can be pruned.
+pub(super) struct FareSearch<'a, T: Timetable<'a>> {
+    router: &'a Router<'a, T>,
+    timetable: &'a T,
+    fare_options: FareOptions,
+    bags: Vec<Vec<Label>>,
+    target_costs: HashMap<usize, u32>,
+    /// Final arrival times and fares of the labels that have reached a target, for pruning.
+    target_labels: Vec<(Time, f64)>,
+    arrival_bound: Time,
+    max_rounds: usize,
+    step_log: Vec<InternalStep<'a>>,
+    fare_stops: HashMap<usize, FareStop>,
+    trip_routes: HashMap<Trip, (String, Option<String>)>,
+    /// Real-time predictions for scheduled trips, as of the start of the search.
+    trip_updates: Option<Arc<TripUpdates>>,
+    /// Service alerts, as of the start of the search.
+    alerts: Option<Arc<Alerts>>,
+}
+
+impl<'a, T: Timetable<'a>> FareSearch<'a, T> {
+    pub(super) fn new(
+        router: &'a Router<'a, T>,
+        fare_options: FareOptions,
+        target_costs: &[(usize, u32)],
+        arrival_bound: Time,
+        max_rounds: usize,
+    ) -> FareSearch<'a, T> {
+        FareSearch {
+            router,
+            timetable: &router.timetable,
+            fare_options,
+            bags: vec![vec![]; router.timetable.stop_count()],
+            target_costs: target_costs.iter().cloned().collect(),
+            target_labels: vec![],
+            arrival_bound,
+            max_rounds,
+            step_log: vec![InternalStep {
+                previous_step: 0usize,
+                from: InternalStepLocation::Location(LatLng::from_degrees(0.0, 0.0)),
+                to: InternalStepLocation::Location(LatLng::from_degrees(0.0, 0.0)),
+                route: None,
+                stop_seqs: None,
+                departure: Time::epoch(),
+                arrival: Time::epoch(),
+                trip: None,
+                added_trip: None,
+            }],
+            fare_stops: HashMap::new(),
+            trip_routes: HashMap::new(),
+            trip_updates: router.timetable.trip_updates(),
+            alerts: router.timetable.alerts(),
+        }
+    }
+
+    /// Run the search, returning the step log and the Pareto-optimal itineraries found.
+    pub(super) fn run(
+        mut self,
+        start_time: Time,
+        start_location: LatLng,
+        start_stops: &[(&'a Stop, u32)],
+    ) -> (Vec<InternalStep<'a>>, Vec<InternalItinerary>) {
+        for &(stop, cost) in start_stops {
+            let arrival = start_time.plus_seconds(cost);
+            self.try_insert(
+                stop,
+                InternalStep {
+                    previous_step: 0,
+                    from: InternalStepLocation::Location(start_location),
+                    to: InternalStepLocation::Stop(stop),
+                    route: None,
+                    stop_seqs: None,
+                    departure: start_time,
+                    arrival,
+                    trip: None,
+                    added_trip: None,
+                },
+                0.0,
+                0,
+                Rc::new(vec![]),
+                true,
+            );
+        }
+
+        for round in 1..=self.max_rounds as u32 {
+            let scanned = self.scan_routes(round);
+            let walked = self.walk_transfers(round);
+            if !scanned && !walked {
+                break;
+            }
+        }
+
+        let mut candidates: Vec<(Time, f64, InternalItinerary)> = vec![];
+        for (stop_id, cost) in &self.target_costs {
+            for label in &self.bags[*stop_id] {
+                // Walking from the start straight to the destination is handled elsewhere.
+                if label.legs.is_empty() {
+                    continue;
+                }
+                candidates.push((
+                    label.arrival.plus_seconds(*cost),
+                    label.fare,
+                    InternalItinerary {
+                        last_step: label.step,
+                        final_time: label.arrival,
+                    },
+                ));
+            }
+        }
+        let itineraries = candidates
+            .iter()
+            .filter(|(time, fare, _)| {
+                !candidates.iter().any(|(other_time, other_fare, _)| {
+                    (other_time < time && *other_fare <= fare + FARE_EPSILON)
+                        || (other_time <= time && *other_fare + FARE_EPSILON < *fare)
+                })
+            })
+            .map(|(_, _, itinerary)| itinerary.clone())
+            .collect();
+        (self.step_log, itineraries)
+    }
+
+    fn scan_routes(&mut self, round: u32) -> bool {
+        // Find the earliest stop on each route where a label from the previous round can board.
+        let mut routes: HashMap<usize, usize> = HashMap::new();
+        for (stop_id, bag) in self.bags.iter().enumerate() {
+            if !bag.iter().any(|label| label.round == round - 1) {
+                continue;
+            }
+            for stop_route in self.timetable.stop(stop_id).stop_routes(self.timetable) {
+                let seq = routes.entry(stop_route.route_id()).or_insert(usize::MAX);
+                *seq = (*seq).min(stop_route.stop_seq());
+            }
+        }
+
+        let mut improved = false;
+        for (route_id, first_seq) in routes {
+            let route = self.timetable.route(route_id);
+            let route_stops = route.route_stops(self.timetable);
+            let mut route_bag: Vec<RouteLabel<'a>> = vec![];
+            for seq in first_seq..route_stops.len() {
+                let stop = route_stops[seq].stop(self.timetable);
+                for route_label in route_bag.clone() {
+                    if route_label.trip.drop_off_allowed(
+                        self.timetable,
+                        self.trip_updates.as_deref(),
+                        self.alerts.as_deref(),
+                        seq,
+                    ) {
+                        improved |= self.alight(route, &route_label, seq, stop, round);
+                    }
+                }
+                let boardable: Vec<Label> = self.bags[stop.id()]
+                    .iter()
+                    .filter(|label| label.round == round - 1)
+                    .cloned()
+                    .collect();
+                for label in boardable {
+                    if let Some(trip) = route.earliest_trip(
+                        self.timetable,
+                        self.trip_updates.as_deref(),
+                        self.alerts.as_deref(),
+                        seq,
+                        label.arrival,
+                    ) {
+                        let departure =
+                            trip.departure(self.timetable, self.trip_updates.as_deref(), seq);
+                        // An earlier trip boarded for no more money gets everywhere first.
+                        let dominated = route_bag.iter().any(|other| {
+                            other
+                                .trip
+                                .departure(self.timetable, self.trip_updates.as_deref(), seq)
+                                <= departure
+                                && other.parent.fare <= label.fare + FARE_EPSILON
+                        });
+                        if !dominated {
+                            route_bag.push(RouteLabel {
+                                trip,
+                                board_seq: seq,
+                                board_stop: stop,
+                                parent: label,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        improved
+    }
+
+    fn alight(
+        &mut self,
+        route: &'a Route,
+        route_label: &RouteLabel<'a>,
+        seq: usize,
+        stop: &'a Stop,
+        round: u32,
+    ) -> bool {
+        let departure = route_label.trip.departure(
+            self.timetable,
+            self.trip_updates.as_deref(),
+            route_label.board_seq,
+        );
+        let arrival = route_label
+            .trip
+            .arrival(self.timetable, self.trip_updates.as_deref(), seq);
+        // Fares never go down by riding further, so there's no point pricing a leg that's already dominated.
+        if !self.is_useful(stop.id(), arrival, route_label.parent.fare) {
+            return false;
+        }
+        let (feed_id, route_id) = self.trip_route(route_label.trip.trip());
+        let leg = FareLeg {
+            feed_id,
+            route_id,
+            from: self.fare_stop(route_label.board_stop),
+            to: self.fare_stop(stop),
+            departure_epoch_seconds: departure.epoch_seconds() as u64,
+            arrival_epoch_seconds: arrival.epoch_seconds() as u64,
+        };
+        let mut legs = (*route_label.parent.legs).clone();
+        legs.push(leg);
+        let fare: f64 = price_legs(&legs, &self.fare_options, |feed_id| {
+            self.router.fare_calculator(feed_id)
+        })
+        .iter()
+        .flatten()
+        .map(|price| price.amount)
+        .sum();
+        self.try_insert(
+            stop,
+            InternalStep {
+                previous_step: route_label.parent.step,
+                from: InternalStepLocation::Stop(route_label.board_stop),
+                to: InternalStepLocation::Stop(stop),
+                route: Some(*route),
+                stop_seqs: Some((route_label.board_seq, seq)),
+                departure,
+                arrival,
+                trip: Some(route_label.trip),
+                added_trip: None,
+            },
+            fare,
+            round,
+            Rc::new(legs),
+            false,
+        )
+    }
+
+    fn walk_transfers(&mut self, round: u32) -> bool {
+        let mut walks: Vec<(usize, Label)> = vec![];
+        for (stop_id, bag) in self.bags.iter().enumerate() {
+            for label in bag {
+                if label.round == round && !label.on_foot {
+                    walks.push((stop_id, label.clone()));
+                }
+            }
+        }
+        let mut improved = false;
+        for (stop_id, label) in walks {
+            let stop = self.timetable.stop(stop_id);
+            for transfer in self.timetable.transfers_from(stop_id) {
+                let to = transfer.to(self.timetable);
+                let arrival = label.arrival.plus_seconds(transfer.time_seconds());
+                improved |= self.try_insert(
+                    to,
+                    InternalStep {
+                        previous_step: label.step,
+                        from: InternalStepLocation::Stop(stop),
+                        to: InternalStepLocation::Stop(to),
+                        route: None,
+                        stop_seqs: None,
+                        departure: label.arrival,
+                        arrival,
+                        trip: None,
+                        added_trip: None,
+                    },
+                    label.fare,
+                    round,
+                    label.legs.clone(),
+                    true,
+                );
+            }
+        }
+        improved
+    }
+
+    /// Whether a label with this arrival and fare would be kept at the given stop.
+    fn is_useful(&self, stop_id: usize, arrival: Time, fare: f64) -> bool {
+        if arrival > self.arrival_bound {
+            return false;
+        }
+        // Arrival times at the targets include the walk to the destination, so they're a lower bound for anything arriving later.
+        if self
+            .target_labels
+            .iter()
+            .any(|(target_arrival, target_fare)| {
+                *target_arrival <= arrival && *target_fare <= fare + FARE_EPSILON
+            })
+        {
+            return false;
+        }
+        !self.bags[stop_id]
+            .iter()
+            .any(|label| label.dominates(arrival, fare))
+    }
+
+    fn try_insert(
+        &mut self,
+        stop: &'a Stop,
+        step: InternalStep<'a>,
+        fare: f64,
+        round: u32,
+        legs: Rc<Vec<FareLeg>>,
+        on_foot: bool,
+    ) -> bool {
+        let arrival = step.arrival;
+        if !self.is_useful(stop.id(), arrival, fare) {
+            return false;
+        }
+        let label = Label {
+            arrival,
+            fare,
+            step: self.step_log.len(),
+            round,
+            legs,
+            on_foot,
+        };
+        self.step_log.push(step);
+        let bag = &mut self.bags[stop.id()];
+        bag.retain(|other| !label.dominates(other.arrival, other.fare));
+        bag.push(label);
+        if let Some(cost) = self.target_costs.get(&stop.id()) {
+            self.target_labels.push((arrival.plus_seconds(*cost), fare));
+        }
+        true
+    }
+
+    fn fare_stop(&mut self, stop: &Stop) -> FareStop {
+        let timetable = self.timetable;
+        self.fare_stops
+            .entry(stop.id())
+            .or_insert_with(|| FareStop::from(&stop.metadata(timetable)))
+            .clone()
+    }
+
+    fn trip_route(&mut self, trip: &Trip) -> (String, Option<String>) {
+        let timetable = self.timetable;
+        self.trip_routes
+            .entry(*trip)
+            .or_insert_with(|| {
+                let metadata = trip.metadata(timetable);
+                (metadata.feed_id, metadata.gtfs_route_id)
+            })
+            .clone()
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use tokio::sync::Notify;
+
+use crate::{raptor::timetable::mmap::MmapTimetable, realtime::RealtimeTimetable, route::Router};
+
+/// A router over a memory-mapped timetable with real-time updates, as served.
+pub type SolariRouter = Router<'static, RealtimeTimetable<'static, MmapTimetable<'static>>>;
+
+/// A router borrowed for as long as a request holds on to it. The timetable's slices point into its own memory maps, so they live as long as the router does rather than for `'static`.
+pub type BorrowedRouter<'r> = Router<'r, RealtimeTimetable<'r, MmapTimetable<'r>>>;
+
+pub fn borrow_router(router: &SolariRouter) -> &BorrowedRouter<'_> {
+    router
+}
+
+type OpenRouter = dyn Fn(&Path) -> Result<SolariRouter, anyhow::Error> + Send + Sync;
+
+/// The router queries are answered with. Reloading the timetable swaps in a new router, while queries already running finish on the old one, whose files are unmapped once the last of them is done.
+pub struct Routers {
+    current: RwLock<Arc<SolariRouter>>,
+    /// The timetable directory the current router was opened from. Held while reloading, so that reloads don't overlap.
+    base_path: tokio::sync::Mutex<PathBuf>,
+    open: Arc<OpenRouter>,
+    reloaded: Notify,
+}
+
+impl Routers {
+    /// Open the timetable in `base_path` with `open`, which reloads use to open timetables too.
+    pub fn open(
+        base_path: PathBuf,
+        open: impl Fn(&Path) -> Result<SolariRouter, anyhow::Error> + Send + Sync + 'static,
+    ) -> Result<Routers, anyhow::Error> {
+        let router = open(&base_path)?;
+        log_service_window(&router);
+        Ok(Routers {
+            current: RwLock::new(Arc::new(router)),
+            base_path: tokio::sync::Mutex::new(base_path),
+            open: Arc::new(open),
+            reloaded: Notify::new(),
+        })
+    }
+
+    pub fn current(&self) -> Arc<SolariRouter> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Notified each time a reload swaps in a new router.
+    pub fn reloaded(&self) -> &Notify {
+        &self.reloaded
+    }
+
+    /// Open the timetable in `base_path`, or the one the current router was opened from, and swap it in once it's ready. Queries are answered by the current router in the meantime, and keep being answered by it if the new timetable fails to open. Returns the path the new router was opened from.
+    pub async fn reload(&self, base_path: Option<PathBuf>) -> Result<PathBuf, anyhow::Error> {
+        let mut current_path = self.base_path.lock().await;
+        let base_path = base_path.unwrap_or_else(|| current_path.clone());
+        let router = {
+            let open = self.open.clone();
+            let base_path = base_path.clone();
+            tokio::task::spawn_blocking(move || {
+                open(&base_path)
+                    .with_context(|| format!("failed to open timetable in {}", base_path.display()))
+            })
+            .await??
+        };
+        log_service_window(&router);
+        *self.current.write().unwrap() = Arc::new(router);
+        log::info!("Reloaded timetable from {}", base_path.display());
+        *current_path = base_path.clone();
+        self.reloaded.notify_one();
+        Ok(base_path)
+    }
+}
+
+fn log_service_window(router: &SolariRouter) {
+    if let Some(service_window) = borrow_router(router).service_window() {
+        log::info!(
+            "Timetable covers service days {} through {}",
+            service_window.start_date,
+            service_window.end_date()
+        );
+    } else {
+        log::warn!(
+            "Timetable doesn't record its service window, queries won't be checked against it"
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use chrono::{NaiveDate, TimeZone};
+    use gtfs_structures::GtfsReader;
+    use s2::latlng::LatLng;
+    use solari_transfers::geometric::GeometricTransfers;
+    use tempdir::TempDir;
+
+    use crate::{
+        api::response::ResponseStatus,
+        fares::FareOptions,
+        raptor::timetable::{
+            in_memory::{InMemoryTimetableBuilder, TimetableBuildOptions},
+            mmap::MmapTimetable,
+            ServiceWindow, Time, Timetable,
+        },
+        realtime::RealtimeTimetable,
+        route::Router,
+    };
+
+    use super::{borrow_router, Routers, SolariRouter};
+
+    async fn build_timetable(name: &str, base_path: &Path) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(name);
+        let gtfs = GtfsReader::default()
+            .read_from_path(path.to_str().unwrap())
+            .unwrap();
+        // 2030-01-07 is a Monday.
+        let service_window = ServiceWindow::new(NaiveDate::from_ymd_opt(2030, 1, 7).unwrap(), 3);
+        let builder = InMemoryTimetableBuilder::with_options(
+            &gtfs,
+            TimetableBuildOptions {
+                feed_id: name.to_string(),
+                service_window,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let feed = MmapTimetable::from_in_memory(&builder, &base_path.join(name)).unwrap();
+        MmapTimetable::concatenate(
+            &[feed],
+            &base_path.to_path_buf(),
+            &GeometricTransfers::new(1.3),
+            None,
+        )
+        .await;
+    }
+
+    fn open(base_path: &Path) -> Result<SolariRouter, anyhow::Error> {
+        let timetable = RealtimeTimetable::new(MmapTimetable::open(&base_path.to_path_buf())?);
+        Ok(Router::with_transfer_provider(
+            timetable,
+            Arc::new(GeometricTransfers::new(1.3)),
+        ))
+    }
+
+    fn stop_count(router: &SolariRouter) -> usize {
+        borrow_router(router).timetable().stops().len()
+    }
+
+    #[tokio::test]
+    async fn reload_swaps_in_a_new_router() {
+        let first_dir = TempDir::new("first").unwrap();
+        let second_dir = TempDir::new("second").unwrap();
+        build_timetable("simple", first_dir.path()).await;
+        build_timetable("single_agency", second_dir.path()).await;
+
+        let routers = Routers::open(first_dir.path().to_path_buf(), open).unwrap();
+        let old = routers.current();
+        let old_stops = stop_count(&old);
+
+        let reloaded = routers
+            .reload(Some(second_dir.path().to_path_buf()))
+            .await
+            .unwrap();
+        assert_eq!(reloaded, second_dir.path());
+        assert_ne!(stop_count(&routers.current()), old_stops);
+
+        // Queries that started before the reload keep their router, and it still answers them.
+        assert_eq!(stop_count(&old), old_stops);
+        let start = chrono_tz::America::Los_Angeles
+            .with_ymd_and_hms(2030, 1, 7, 7, 50, 0)
+            .unwrap();
+        let response = borrow_router(&old)
+            .route(
+                Time::from_epoch_seconds(start.timestamp() as u32),
+                LatLng::from_degrees(47.5995, -122.33),
+                LatLng::from_degrees(47.6405, -122.3308),
+                Some(1500f64),
+                Some(1000),
+                Some(5),
+                Some(2),
+                &FareOptions::default(),
+            )
+            .await;
+        assert!(matches!(response.status, ResponseStatus::Ok));
+        assert!(!response.itineraries.is_empty());
+        drop(old);
+
+        // A timetable that can't be opened leaves the current router in place, and later reloads go back to it.
+        let missing = first_dir.path().join("missing");
+        assert!(routers.reload(Some(missing)).await.is_err());
+        let current = routers.current();
+        assert_eq!(
+            stop_count(&current),
+            stop_count(&open(second_dir.path()).unwrap())
+        );
+        assert_eq!(routers.reload(None).await.unwrap(), second_dir.path());
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    path::Path,
+};
+
+use gtfs_structures::Gtfs;
+use serde::{Deserialize, Serialize};
+use solari_geomath::haversine_distance_meters;
+
+use crate::{gtfs_files::read_optional, spatial::WALK_SPEED_MM_PER_SECOND};
+
+/// How long it takes to wait for and ride an elevator, before counting the levels it travels.
+const ELEVATOR_WAIT_SECONDS: u32 = 60;
+/// Climbing a level of stairs or riding an escalator between levels, when the feed doesn't say.
+const SECONDS_PER_LEVEL: u32 = 20;
+const SECONDS_PER_STAIR: f64 = 1.0;
+/// Used for pathways that have neither a traversal time, a length, nor endpoints with coordinates.
+const DEFAULT_PATHWAY_SECONDS: u32 = 30;
+
+/// The kind of a pathway in a station, from `pathway_mode` in pathways.txt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathwayMode {
+    Walkway,
+    Stairs,
+    MovingSidewalk,
+    Escalator,
+    Elevator,
+    FareGate,
+    ExitGate,
+}
+
+impl PathwayMode {
+    const ALL: [PathwayMode; 7] = [
+        PathwayMode::Walkway,
+        PathwayMode::Stairs,
+        PathwayMode::MovingSidewalk,
+        PathwayMode::Escalator,
+        PathwayMode::Elevator,
+        PathwayMode::FareGate,
+        PathwayMode::ExitGate,
+    ];
+
+    fn from_gtfs(pathway_mode: u8) -> Option<PathwayMode> {
+        Self::ALL
+            .get((pathway_mode as usize).checked_sub(1)?)
+            .copied()
+    }
+
+    fn flag(&self) -> u32 {
+        1 << (*self as u32)
+    }
+
+    /// The modes whose flags are set in `flags`, as stored in a `Transfer`.
+    pub fn from_flags(flags: u32) -> Vec<PathwayMode> {
+        Self::ALL
+            .into_iter()
+            .filter(|mode| flags & mode.flag() != 0)
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PathwayRecord {
+    from_stop_id: String,
+    to_stop_id: String,
+    pathway_mode: u8,
+    is_bidirectional: u8,
+    length: Option<f64>,
+    traversal_time: Option<u32>,
+    stair_count: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LevelRecord {
+    level_id: String,
+    level_index: f64,
+}
+
+#[derive(Debug, Clone)]
+struct PathwayEdge {
+    to: String,
+    seconds: u32,
+    mode: PathwayMode,
+}
+
+/// The fastest way between two stops through a station's pathways.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathwayRoute {
+    pub seconds: u32,
+    /// Flags of every `PathwayMode` used along the way. See `PathwayMode::from_flags`.
+    pub modes: u32,
+}
+
+/// The pathways of every station in a feed, from pathways.txt and levels.txt.
+#[derive(Debug, Default)]
+pub struct StationGraph {
+    edges: HashMap<String, Vec<PathwayEdge>>,
+}
+
+impl StationGraph {
+    /// Read the pathways of the feed at `feed_path`. Returns `None` if the feed has no pathways.txt. `stop_locations` is used to estimate how long pathways without a traversal time or length take.
+    pub fn read(
+        feed_path: &Path,
+        gtfs: &Gtfs,
+        stop_locations: &HashMap<String, (f64, f64)>,
+    ) -> Result<Option<StationGraph>, anyhow::Error> {
+        let pathways: Vec<PathwayRecord> =
+            if let Some(pathways) = read_optional(feed_path, "pathways.txt")? {
+                pathways
+            } else {
+                return Ok(None);
+            };
+        let levels: HashMap<String, f64> = read_optional::<LevelRecord>(feed_path, "levels.txt")?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|level| (level.level_id, level.level_index))
+            .collect();
+        let level_index = |stop_id: &str| {
+            let level_id = gtfs.stops.get(stop_id)?.level_id.as_ref()?;
+            levels.get(level_id).copied()
+        };
+
+        let mut graph = StationGraph::default();
+        for pathway in pathways {
+            let mode = if let Some(mode) = PathwayMode::from_gtfs(pathway.pathway_mode) {
+                mode
+            } else {
+                continue;
+            };
+            let levels_apart = level_index(&pathway.from_stop_id)
+                .zip(level_index(&pathway.to_stop_id))
+                .map(|(from, to)| (from - to).abs());
+            let distance_meters = pathway.length.or_else(|| {
+                let from = stop_locations.get(&pathway.from_stop_id)?;
+                let to = stop_locations.get(&pathway.to_stop_id)?;
+                Some(haversine_distance_meters(from.0, from.1, to.0, to.1))
+            });
+            let seconds = traversal_seconds(&pathway, mode, distance_meters, levels_apart);
+            graph
+                .edges
+                .entry(pathway.from_stop_id.clone())
+                .or_default()
+                .push(PathwayEdge {
+                    to: pathway.to_stop_id.clone(),
+                    seconds,
+                    mode,
+                });
+            if pathway.is_bidirectional == 1 {
+                graph
+                    .edges
+                    .entry(pathway.to_stop_id)
+                    .or_default()
+                    .push(PathwayEdge {
+                        to: pathway.from_stop_id,
+                        seconds,
+                        mode,
+                    });
+            }
+        }
+        Ok(Some(graph))
+    }
+
+    /// The fastest routes from `from_stop_id` to every stop reachable through pathways, keyed by GTFS stop ID.
+    pub fn routes_from(&self, from_stop_id: &str) -> HashMap<String, PathwayRoute> {
+        let mut routes: HashMap<String, PathwayRoute> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        queue.push(Reverse((0u32, 0u32, from_stop_id.to_string())));
+        while let Some(Reverse((seconds, modes, stop_id))) = queue.pop() {
+            if routes.contains_key(&stop_id) {
+                continue;
+            }
+            for edge in self.edges.get(&stop_id).into_iter().flatten() {
+                if !routes.contains_key(&edge.to) {
+                    queue.push(Reverse((
+                        seconds + edge.seconds,
+                        modes | edge.mode.flag(),
+                        edge.to.clone(),
+                    )));
+                }
+            }
+            routes.insert(stop_id, PathwayRoute { seconds, modes });
+        }
+        routes.remove(from_stop_id);
+        routes
+    }
+}
+
+/// How long a pathway takes to traverse. Feeds often leave out `traversal_time`, so it's estimated from the length, stair count, or the levels it connects.
+fn traversal_seconds(
+    pathway: &PathwayRecord,
+    mode: PathwayMode,
+    distance_meters: Option<f64>,
+    levels_apart: Option<f64>,
+) -> u32 {
+    if let Some(traversal_time) = pathway.traversal_time {
+        return traversal_time;
+    }
+    let walk_seconds = distance_meters
+        .map(|meters| (meters * 1000.0 / WALK_SPEED_MM_PER_SECOND as f64).round() as u32);
+    let level_seconds = levels_apart.map(|levels| (levels * SECONDS_PER_LEVEL as f64) as u32);
+    match mode {
+        PathwayMode::Elevator => ELEVATOR_WAIT_SECONDS + level_seconds.unwrap_or(0),
+        PathwayMode::Stairs => pathway
+            .stair_count
+            .map(|stairs| (stairs.unsigned_abs() as f64 * SECONDS_PER_STAIR) as u32)
+            .or(level_seconds)
+            .unwrap_or(DEFAULT_PATHWAY_SECONDS)
+            .max(walk_seconds.unwrap_or(0)),
+        PathwayMode::Escalator => level_seconds
+            .or(walk_seconds)
+            .unwrap_or(DEFAULT_PATHWAY_SECONDS),
+        _ => walk_seconds.unwrap_or(DEFAULT_PATHWAY_SECONDS),
+    }
+}
@@ -0,0 +1,1016 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use gtfs_rt::{
+    trip_update::{StopTimeEvent, StopTimeUpdate},
+    FeedEntity, FeedHeader, FeedMessage, TripDescriptor, TripUpdate,
+};
+use gtfs_structures::{Gtfs, GtfsReader};
+use prost::Message;
+use s2::latlng::LatLng;
+use solari::{
+    api::{
+        response::{ResponseStatus, SolariResponse},
+        SolariItinerary, SolariLeg,
+    },
+    fares::FareOptions,
+    feeds::{read_registries, FeedSecret, FeedSecrets},
+    raptor::timetable::{
+        in_memory::{
+            FeedIssue, InMemoryTimetable, InMemoryTimetableBuilder, TimetableBuildOptions,
+        },
+        ServiceWindow, Time, Timetable,
+    },
+    realtime::{
+        alerts::AlertIssue,
+        poller::{
+            realtime_sources, PollError, PollOptions, PollOutcome, RealtimeKind, RealtimePoller,
+        },
+        read_feed_message,
+        trip_updates::TripUpdateIssue,
+        vehicle_positions::VehiclePositionIssue,
+        RealtimeTimetable,
+    },
+    route::Router,
+    stations::PathwayMode,
+};
+use solari_transfers::geometric::GeometricTransfers;
+use support::Reply;
+use tempdir::TempDir;
+
+mod support;
+
+fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+fn fixture(name: &str) -> Gtfs {
+    let path = fixture_path(name);
+    GtfsReader::default()
+        .read_from_path(path.to_str().unwrap())
+        .expect("Failed to read fixture feed")
+}
+
+/// The first day of every timetable's service window. The fixtures run every day, and 2030-01-07 is a Monday.
+fn first_service_date() -> NaiveDate {
+    NaiveDate::from_ymd_opt(2030, 1, 7).unwrap()
+}
+
+/// The given local time on the first service date in the fixture's timezone.
+fn monday_at(hour: u32, minute: u32) -> Time {
+    date_at(first_service_date(), hour, minute)
+}
+
+/// The given local time on `date` in the fixture's timezone.
+fn date_at(date: NaiveDate, hour: u32, minute: u32) -> Time {
+    let tz: Tz = "America/Los_Angeles".parse().unwrap();
+    let local = date.and_time(NaiveTime::from_hms_opt(hour, minute, 0).unwrap());
+    Time::from_epoch_seconds(tz.from_local_datetime(&local).unwrap().timestamp() as u32)
+}
+
+fn build_options(name: &str) -> TimetableBuildOptions {
+    TimetableBuildOptions {
+        feed_id: name.to_string(),
+        feed_path: Some(fixture_path(name)),
+        service_window: ServiceWindow::new(first_service_date(), 3),
+    }
+}
+
+fn builder_for(gtfs: &Gtfs, name: &str) -> InMemoryTimetableBuilder {
+    InMemoryTimetableBuilder::with_options(gtfs, build_options(name)).unwrap()
+}
+
+fn router_for<'a>(builder: InMemoryTimetableBuilder) -> Router<'a, InMemoryTimetable> {
+    let transfers = GeometricTransfers::new(1.3);
+    let timetable = builder.build(&transfers);
+    Router::with_transfer_provider(timetable, Arc::new(transfers))
+}
+
+fn fixture_router<'a>(name: &str) -> Router<'a, InMemoryTimetable> {
+    router_for(builder_for(&fixture(name), name))
+}
+
+async fn route<'a, T: Timetable<'a>>(
+    router: &'a Router<'a, T>,
+    start_time: Time,
+    from: LatLng,
+    to: LatLng,
+) -> SolariResponse {
+    route_with_fares(router, start_time, from, to, &FareOptions::default()).await
+}
+
+async fn route_with_fares<'a, T: Timetable<'a>>(
+    router: &'a Router<'a, T>,
+    start_time: Time,
+    from: LatLng,
+    to: LatLng,
+    fare_options: &FareOptions,
+) -> SolariResponse {
+    router
+        .route(
+            start_time,
+            from,
+            to,
+            Some(1500f64),
+            Some(1000),
+            Some(5),
+            Some(2),
+            fare_options,
+        )
+        .await
+}
+
+fn earliest_itinerary(response: SolariResponse) -> SolariItinerary {
+    response
+        .itineraries
+        .into_iter()
+        .min_by_key(|itinerary| itinerary.end_time)
+        .expect("No itinerary found")
+}
+
+async fn earliest_arrival<'a, T: Timetable<'a>>(
+    router: &'a Router<'a, T>,
+    start_time: Time,
+    from: LatLng,
+    to: LatLng,
+) -> u32 {
+    let response = route(router, start_time, from, to).await;
+    earliest_itinerary(response).end_time.unix_timestamp() as u32
+}
+
+fn transit_routes(itinerary: &SolariItinerary) -> Vec<String> {
+    itinerary
+        .legs
+        .iter()
+        .filter_map(|leg| match leg {
+            SolariLeg::Transit { transit_route, .. } => transit_route.clone(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn fare_total(itinerary: &SolariItinerary) -> f64 {
+    itinerary
+        .fare
+        .as_ref()
+        .and_then(|fare| fare.total.as_ref())
+        .expect("Itinerary has no fare")
+        .amount
+}
+
+#[tokio::test]
+async fn routes_with_a_transfer() {
+    let router = fixture_router("simple");
+
+    let response = route(
+        &router,
+        monday_at(7, 50),
+        LatLng::from_degrees(47.5995, -122.33),
+        LatLng::from_degrees(47.6405, -122.3308),
+    )
+    .await;
+
+    assert!(matches!(response.status, ResponseStatus::Ok));
+    let itinerary = earliest_itinerary(response);
+    assert_eq!(
+        transit_routes(&itinerary),
+        vec!["1".to_string(), "2".to_string()]
+    );
+    assert!(itinerary
+        .legs
+        .iter()
+        .any(|leg| matches!(leg, SolariLeg::Transfer { .. })));
+    // The second trip arrives at 08:40.
+    let end_time = itinerary.end_time.unix_timestamp() as u32;
+    assert!(end_time >= monday_at(8, 40).epoch_seconds());
+    assert!(end_time <= monday_at(8, 45).epoch_seconds());
+}
+
+#[tokio::test]
+async fn returns_cheaper_itineraries() {
+    let router = fixture_router("fares");
+
+    let fares_by_route = |fare_options: &FareOptions| {
+        let router = &router;
+        let fare_options = fare_options.clone();
+        async move {
+            let response = route_with_fares(
+                router,
+                monday_at(7, 50),
+                LatLng::from_degrees(47.5995, -122.33),
+                LatLng::from_degrees(47.6505, -122.33),
+                &fare_options,
+            )
+            .await;
+            let mut fares: Vec<(String, f64)> = response
+                .itineraries
+                .iter()
+                .map(|itinerary| (transit_routes(itinerary).join(","), fare_total(itinerary)))
+                .collect();
+            fares.sort_by(|a, b| a.0.cmp(&b.0));
+            fares
+        }
+    };
+
+    // The express is faster, but the local is cheaper.
+    assert_eq!(
+        fares_by_route(&FareOptions::default()).await,
+        vec![("L".to_string(), 2.0), ("X".to_string(), 5.0)]
+    );
+    assert_eq!(
+        fares_by_route(&FareOptions {
+            max_fare: Some(3.0),
+            ..Default::default()
+        })
+        .await,
+        vec![("L".to_string(), 2.0)]
+    );
+}
+
+#[tokio::test]
+async fn applies_fare_timeframes_on_their_service_days() {
+    let gtfs = fixture("fares_v2");
+    // The window runs through Saturday.
+    let router = router_for(
+        InMemoryTimetableBuilder::with_options(
+            &gtfs,
+            TimetableBuildOptions {
+                service_window: ServiceWindow::new(first_service_date(), 7),
+                ..build_options("fares_v2")
+            },
+        )
+        .unwrap(),
+    );
+
+    let fare_on = |date: NaiveDate| {
+        let router = &router;
+        async move {
+            let response = route(
+                router,
+                date_at(date, 7, 50),
+                LatLng::from_degrees(47.5995, -122.33),
+                LatLng::from_degrees(47.6505, -122.33),
+            )
+            .await;
+            fare_total(&earliest_itinerary(response))
+        }
+    };
+
+    // The weekday timeframe's service doesn't run on Saturday, so the fare without a timeframe applies then.
+    assert_eq!(fare_on(first_service_date()).await, 3.0);
+    assert_eq!(
+        fare_on(NaiveDate::from_ymd_opt(2030, 1, 12).unwrap()).await,
+        1.0
+    );
+}
+
+#[tokio::test]
+async fn rejects_queries_outside_the_service_window() {
+    let router = fixture_router("simple");
+
+    let response = route(
+        &router,
+        monday_at(7, 50).plus_seconds(30 * 86_400),
+        LatLng::from_degrees(47.5995, -122.33),
+        LatLng::from_degrees(47.6405, -122.3308),
+    )
+    .await;
+    assert!(matches!(response.status, ResponseStatus::TooLate));
+    assert!(response.itineraries.is_empty());
+}
+
+#[tokio::test]
+async fn boards_trips_on_the_next_service_day() {
+    let router = fixture_router("simple");
+
+    // The last trip of the day has already left, so the first trips of the next day are used.
+    let end_time = earliest_arrival(
+        &router,
+        monday_at(9, 30),
+        LatLng::from_degrees(47.5995, -122.33),
+        LatLng::from_degrees(47.6405, -122.3308),
+    )
+    .await;
+    let tuesday = first_service_date().succ_opt().unwrap();
+    assert!(end_time >= date_at(tuesday, 8, 40).epoch_seconds());
+    assert!(end_time <= date_at(tuesday, 8, 45).epoch_seconds());
+}
+
+#[tokio::test]
+async fn routes_on_frequency_based_trips() {
+    let router = fixture_router("frequencies");
+    let first_leg = |response: SolariResponse| {
+        earliest_itinerary(response)
+            .legs
+            .into_iter()
+            .find_map(|leg| match leg {
+                SolariLeg::Transit {
+                    transit_route,
+                    end_time,
+                    headway_secs,
+                    ..
+                } => Some((
+                    transit_route,
+                    end_time.unix_timestamp() as u32,
+                    headway_secs,
+                )),
+                SolariLeg::Transfer { .. } => None,
+            })
+            .expect("No transit leg")
+    };
+    let from = LatLng::from_degrees(47.6, -122.33);
+    let to = LatLng::from_degrees(47.65, -122.33);
+
+    // The express runs at exact times every half hour, so the 08:00 departure is taken.
+    let response = route(&router, monday_at(7, 55), from, to).await;
+    let (route_id, end_time, headway_secs) = first_leg(response);
+    assert_eq!(route_id.as_deref(), Some("X"));
+    assert_eq!(end_time, monday_at(8, 10).epoch_seconds());
+    assert_eq!(headway_secs, None);
+
+    // After the last express, only the metro is left. It runs every ten minutes without a schedule, so a full headway of waiting is assumed.
+    let response = route(&router, monday_at(8, 35), from, to).await;
+    let (route_id, end_time, headway_secs) = first_leg(response);
+    assert_eq!(route_id.as_deref(), Some("M"));
+    assert!(end_time >= monday_at(9, 5).epoch_seconds());
+    assert!(end_time <= monday_at(9, 6).epoch_seconds());
+    assert_eq!(headway_secs, Some(600));
+}
+
+#[tokio::test]
+async fn boards_at_stops_between_timepoints() {
+    let gtfs = fixture("untimed");
+    let builder = builder_for(&gtfs, "untimed");
+    // The 9:00 has no time at Downtown, so there's nothing to place it on the service day by.
+    assert_eq!(
+        builder.report().issues,
+        vec![FeedIssue::UntimedTripEnd {
+            trip_id: "LOC-0900".to_string()
+        }]
+    );
+    assert_eq!(builder.report().skipped_trips, 1);
+    let router = router_for(builder);
+
+    // Midtown has no times of its own, and is a fifth of the way from Downtown to Uptown. Its platform has no coordinates, so that's measured from its station.
+    let response = route(
+        &router,
+        monday_at(8, 2),
+        LatLng::from_degrees(47.61, -122.33),
+        LatLng::from_degrees(47.65, -122.33),
+    )
+    .await;
+    let (start_time, end_time) = response
+        .itineraries
+        .iter()
+        .flat_map(|itinerary| &itinerary.legs)
+        .find_map(|leg| match leg {
+            SolariLeg::Transit {
+                start_time,
+                end_time,
+                ..
+            } => Some((
+                start_time.unix_timestamp() as u32,
+                end_time.unix_timestamp() as u32,
+            )),
+            SolariLeg::Transfer { .. } => None,
+        })
+        .expect("No transit leg");
+    assert_eq!(start_time, monday_at(8, 5).epoch_seconds());
+    assert_eq!(end_time, monday_at(8, 25).epoch_seconds());
+}
+
+#[tokio::test]
+async fn respects_pickup_and_drop_off_types() {
+    let router = fixture_router("pickup_drop_off");
+
+    // The express only drops off at Uptown, so the slower local has to be taken from there.
+    let arrival = earliest_arrival(
+        &router,
+        monday_at(8, 5),
+        LatLng::from_degrees(47.65, -122.33),
+        LatLng::from_degrees(47.70, -122.33),
+    )
+    .await;
+    assert_eq!(arrival, monday_at(8, 40).epoch_seconds());
+}
+
+#[tokio::test]
+async fn boards_at_the_second_visit_to_a_stop() {
+    let router = fixture_router("loop");
+
+    // The trip passes through the transit center at 08:10 and again at 08:30 on its way to Eastside.
+    let arrival = earliest_arrival(
+        &router,
+        monday_at(8, 15),
+        LatLng::from_degrees(47.65, -122.33),
+        LatLng::from_degrees(47.65, -122.25),
+    )
+    .await;
+    assert_eq!(arrival, monday_at(8, 40).epoch_seconds());
+}
+
+#[tokio::test]
+async fn reports_bad_timezones_and_shows_stop_timezones() {
+    let gtfs = fixture("timezones");
+    let builder = builder_for(&gtfs, "timezones");
+    let report = builder.report();
+    assert!(report.issues.contains(&FeedIssue::InvalidAgencyTimezone {
+        agency_id: "bad".to_string(),
+        timezone: "Mars/Olympus_Mons".to_string(),
+    }));
+    assert!(report.issues.contains(&FeedIssue::InvalidStopTimezone {
+        stop_id: "MID".to_string(),
+        timezone: "Nowhere/Special".to_string(),
+    }));
+    // A route without an agency ID is ambiguous when the feed has more than one agency.
+    assert!(report.issues.contains(&FeedIssue::MissingAgencyId {
+        route_id: "ORPHAN".to_string(),
+    }));
+    assert_eq!(report.skipped_trips, 2);
+    let router = router_for(builder);
+
+    // Stop times are in the agency's timezone, so the 18:00 arrival in Chicago is 15:00 in Los Angeles.
+    let response = route(
+        &router,
+        monday_at(4, 55),
+        LatLng::from_degrees(40.75, -73.993),
+        LatLng::from_degrees(41.878, -87.64),
+    )
+    .await;
+    let itinerary = earliest_itinerary(response);
+    assert_eq!(
+        itinerary.end_time.unix_timestamp() as u32,
+        monday_at(15, 0).epoch_seconds()
+    );
+    let (start_location, end_location) = itinerary
+        .legs
+        .iter()
+        .find_map(|leg| match leg {
+            SolariLeg::Transit {
+                start_location,
+                end_location,
+                ..
+            } => Some((start_location, end_location)),
+            SolariLeg::Transfer { .. } => None,
+        })
+        .expect("No transit leg");
+    assert_eq!(start_location.timezone.as_deref(), Some("America/New_York"));
+    assert_eq!(end_location.timezone.as_deref(), Some("America/Chicago"));
+}
+
+#[tokio::test]
+async fn routes_without_an_agency_id_use_the_only_agency() {
+    let gtfs = fixture("single_agency");
+    let builder = builder_for(&gtfs, "single_agency");
+    assert!(builder.report().is_empty());
+    assert_eq!(builder.report().skipped_trips, 0);
+    let router = router_for(builder);
+
+    let response = route(
+        &router,
+        monday_at(7, 55),
+        LatLng::from_degrees(47.6, -122.33),
+        LatLng::from_degrees(47.65, -122.33),
+    )
+    .await;
+    let itinerary = earliest_itinerary(response);
+    assert_eq!(
+        itinerary.end_time.unix_timestamp() as u32,
+        monday_at(8, 20).epoch_seconds()
+    );
+    let agency = itinerary.legs.iter().find_map(|leg| match leg {
+        SolariLeg::Transit { transit_agency, .. } => transit_agency.clone(),
+        SolariLeg::Transfer { .. } => None,
+    });
+    assert_eq!(agency.as_deref(), Some("Small Town Transit"));
+    // The fare is restricted to the agency, so it applies to the route too.
+    assert_eq!(fare_total(&itinerary), 1.5);
+}
+
+#[tokio::test]
+async fn stops_without_coordinates_use_their_parent_station() {
+    let gtfs = fixture("parent_coordinates");
+    let builder = builder_for(&gtfs, "parent_coordinates");
+    assert_eq!(
+        builder.report().issues,
+        vec![FeedIssue::UnlocatableStop {
+            stop_id: "NOWHERE".to_string()
+        }]
+    );
+    assert_eq!(builder.report().skipped_trips, 0);
+    let router = router_for(builder);
+
+    let arrival = earliest_arrival(
+        &router,
+        monday_at(7, 55),
+        LatLng::from_degrees(47.6, -122.33),
+        LatLng::from_degrees(47.65, -122.33),
+    )
+    .await;
+    assert_eq!(arrival, monday_at(8, 20).epoch_seconds());
+}
+
+#[tokio::test]
+async fn transfers_inside_stations_use_pathways() {
+    let router = fixture_router("pathways");
+
+    let response = route(
+        &router,
+        monday_at(7, 45),
+        LatLng::from_degrees(47.55, -122.33),
+        LatLng::from_degrees(47.65, -122.33),
+    )
+    .await;
+    let itinerary = earliest_itinerary(response);
+    // The platforms are a few meters apart, but the elevator between them takes four minutes, which is too long to make the 08:03.
+    assert_eq!(
+        itinerary.end_time.unix_timestamp() as u32,
+        monday_at(8, 27).epoch_seconds()
+    );
+    let pathway_modes = itinerary.legs.iter().find_map(|leg| match leg {
+        SolariLeg::Transfer { pathway_modes, .. } if !pathway_modes.is_empty() => {
+            Some(pathway_modes.clone())
+        }
+        _ => None,
+    });
+    assert_eq!(pathway_modes, Some(vec![PathwayMode::Elevator]));
+}
+
+#[tokio::test]
+async fn walks_to_stations_through_their_entrances() {
+    let router = fixture_router("entrances");
+
+    // The rider is next to the north entrance, 200 meters from the middle of the platform.
+    let arrival = earliest_arrival(
+        &router,
+        monday_at(8, 2),
+        LatLng::from_degrees(47.602, -122.33),
+        LatLng::from_degrees(47.65, -122.33),
+    )
+    .await;
+    assert_eq!(arrival, monday_at(8, 20).epoch_seconds());
+}
+
+#[tokio::test]
+async fn transfers_through_the_quickest_entrance() {
+    let router = fixture_router("entrance_choice");
+
+    // The north entrance is closest to the bus stop, but it's ten minutes from the platform. Going around to the east entrance makes the 8:00.
+    let arrival = earliest_arrival(
+        &router,
+        monday_at(7, 35),
+        LatLng::from_degrees(47.55, -122.33),
+        LatLng::from_degrees(47.65, -122.33),
+    )
+    .await;
+    assert_eq!(arrival, monday_at(8, 15).epoch_seconds());
+}
+
+/// The real-time fixtures are for 2030-01-08, the second day of the service window.
+fn realtime_router<'a>() -> Router<'a, RealtimeTimetable<'a, InMemoryTimetable>> {
+    realtime_router_for("realtime", "realtime")
+}
+
+fn realtime_router_for<'a>(
+    name: &str,
+    feed_id: &str,
+) -> Router<'a, RealtimeTimetable<'a, InMemoryTimetable>> {
+    let gtfs = fixture(name);
+    let transfers = GeometricTransfers::new(1.3);
+    let timetable = InMemoryTimetableBuilder::with_options(
+        &gtfs,
+        TimetableBuildOptions {
+            feed_id: feed_id.to_string(),
+            service_window: ServiceWindow::new(first_service_date(), 3),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    .build(&transfers);
+    Router::with_transfer_provider(RealtimeTimetable::new(timetable), Arc::new(transfers))
+}
+
+#[tokio::test]
+async fn applies_trip_updates() {
+    let router = realtime_router();
+    let service_date = NaiveDate::from_ymd_opt(2030, 1, 8).unwrap();
+    let downtown = LatLng::from_degrees(47.60, -122.33);
+    let uptown = LatLng::from_degrees(47.65, -122.33);
+    let university = LatLng::from_degrees(47.70, -122.33);
+    let northgate = LatLng::from_degrees(47.75, -122.33);
+
+    // R-0800 is 10 minutes late from Uptown on, R-0820 is canceled, and there's no trip NOPE.
+    let message = read_feed_message(&fixture_path("realtime").join("trip_updates.pb")).unwrap();
+    let report = router
+        .timetable()
+        .update_trips("trip-updates", "realtime", &message);
+    assert_eq!(report.updated_trips, 1);
+    assert_eq!(report.canceled_trips, 1);
+    assert_eq!(
+        report.issues,
+        vec![TripUpdateIssue::UnknownTrip {
+            trip_id: "NOPE".to_string()
+        }]
+    );
+    // R-0800 was due to leave Uptown at 08:10, but is still there at 08:15.
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), uptown, northgate).await,
+        date_at(service_date, 8, 40).epoch_seconds()
+    );
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), downtown, northgate).await,
+        date_at(service_date, 9, 10).epoch_seconds()
+    );
+
+    // Newer updates from the source replace the old ones: R-0820 runs again, but skips University.
+    let message = read_feed_message(&fixture_path("realtime").join("skipped.pb")).unwrap();
+    let report = router
+        .timetable()
+        .update_trips("trip-updates", "realtime", &message);
+    assert_eq!(report.updated_trips, 1);
+    assert!(report.is_empty());
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), downtown, northgate).await,
+        date_at(service_date, 8, 50).epoch_seconds()
+    );
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), downtown, university).await,
+        date_at(service_date, 9, 0).epoch_seconds()
+    );
+}
+
+#[tokio::test]
+async fn dates_trip_updates_past_midnight_without_a_start_date() {
+    let router = realtime_router_for("night", "night");
+    let next_date = NaiveDate::from_ymd_opt(2030, 1, 9).unwrap();
+    let pier = LatLng::from_degrees(47.60, -122.33);
+    let quarry = LatLng::from_degrees(47.65, -122.33);
+
+    // At 01:10 on the 9th, the 8th's N-2500 is on its way to Quarry, due there at 25:30. Its update doesn't say which day it's from.
+    let message = FeedMessage {
+        header: FeedHeader {
+            gtfs_realtime_version: "2.0".to_string(),
+            timestamp: Some(date_at(next_date, 1, 10).epoch_seconds() as u64),
+            ..Default::default()
+        },
+        entity: vec![FeedEntity {
+            id: "1".to_string(),
+            trip_update: Some(TripUpdate {
+                trip: TripDescriptor {
+                    trip_id: Some("N-2500".to_string()),
+                    ..Default::default()
+                },
+                stop_time_update: vec![StopTimeUpdate {
+                    stop_id: Some("Q".to_string()),
+                    arrival: Some(StopTimeEvent {
+                        delay: Some(600),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+    };
+    let report = router
+        .timetable()
+        .update_trips("trip-updates", "night", &message);
+    assert!(report.is_empty());
+    assert_eq!(report.updated_trips, 1);
+    assert_eq!(
+        earliest_arrival(&router, date_at(next_date, 0, 55), pier, quarry).await,
+        date_at(next_date, 1, 40).epoch_seconds()
+    );
+}
+
+#[tokio::test]
+async fn keeps_trip_updates_from_each_source() {
+    let router = realtime_router();
+    let service_date = NaiveDate::from_ymd_opt(2030, 1, 8).unwrap();
+    let downtown = LatLng::from_degrees(47.60, -122.33);
+    let uptown = LatLng::from_degrees(47.65, -122.33);
+    let northgate = LatLng::from_degrees(47.75, -122.33);
+
+    // Two sources for the same feed: one delays R-0800 and cancels R-0820, the other adds X-0830 and replaces R-0840.
+    let message = read_feed_message(&fixture_path("realtime").join("trip_updates.pb")).unwrap();
+    router
+        .timetable()
+        .update_trips("delays", "realtime", &message);
+    let message = read_feed_message(&fixture_path("realtime").join("added.pb")).unwrap();
+    router
+        .timetable()
+        .update_trips("additions", "realtime", &message);
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), uptown, northgate).await,
+        date_at(service_date, 8, 40).epoch_seconds()
+    );
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 25), downtown, northgate).await,
+        date_at(service_date, 8, 45).epoch_seconds()
+    );
+
+    // Clearing one source leaves the other's updates: R-0820 is still canceled, so R-0840 is next.
+    router
+        .timetable()
+        .update_trips("additions", "realtime", &Default::default());
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), downtown, northgate).await,
+        date_at(service_date, 9, 10).epoch_seconds()
+    );
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), uptown, northgate).await,
+        date_at(service_date, 8, 40).epoch_seconds()
+    );
+}
+
+#[tokio::test]
+async fn routes_on_added_and_replacement_trips() {
+    let router = realtime_router();
+    let service_date = NaiveDate::from_ymd_opt(2030, 1, 8).unwrap();
+    let downtown = LatLng::from_degrees(47.60, -122.33);
+    let northgate = LatLng::from_degrees(47.75, -122.33);
+
+    // An express X-0830 is added from Downtown to Northgate, and R-0840 is replaced by a faster trip. Y has too few stops to ride.
+    let message = read_feed_message(&fixture_path("realtime").join("added.pb")).unwrap();
+    let report = router
+        .timetable()
+        .update_trips("trip-updates", "realtime", &message);
+    assert_eq!(report.added_trips, 2);
+    assert_eq!(
+        report.issues,
+        vec![TripUpdateIssue::TooFewStops {
+            trip_id: "Y".to_string()
+        }]
+    );
+
+    let response = route(&router, date_at(service_date, 8, 25), downtown, northgate).await;
+    let itinerary = earliest_itinerary(response);
+    assert_eq!(
+        itinerary.end_time.unix_timestamp() as u32,
+        date_at(service_date, 8, 45).epoch_seconds()
+    );
+    // The added trip is named for the route it's on.
+    assert!(itinerary.legs.iter().any(|leg| matches!(
+        leg,
+        SolariLeg::Transit { transit_route, .. } if transit_route.as_deref() == Some("R")
+    )));
+
+    // The replacement leaves Downtown at 08:35 rather than 08:40, so it's still there at 08:32.
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 32), downtown, northgate).await,
+        date_at(service_date, 9, 0).epoch_seconds()
+    );
+
+    // Once the feed's updates are gone, so are its trips, and R-0840 runs as scheduled.
+    router
+        .timetable()
+        .update_trips("trip-updates", "realtime", &Default::default());
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 25), downtown, northgate).await,
+        date_at(service_date, 9, 10).epoch_seconds()
+    );
+}
+
+#[tokio::test]
+async fn applies_service_alerts() {
+    let router = realtime_router();
+    let service_date = NaiveDate::from_ymd_opt(2030, 1, 8).unwrap();
+    let downtown = LatLng::from_degrees(47.60, -122.33);
+    let university = LatLng::from_degrees(47.70, -122.33);
+
+    // University's elevator is out, R-0820 doesn't run on the 8th, and route R doesn't run at all on the 9th.
+    let message = read_feed_message(&fixture_path("realtime").join("alerts.pb")).unwrap();
+    let report = router
+        .timetable()
+        .update_alerts("alerts", "realtime", &message);
+    assert_eq!(report.alerts, 3);
+    assert_eq!(
+        report.issues,
+        vec![AlertIssue::UnknownStop {
+            alert_id: "4".to_string(),
+            stop_id: "Z".to_string()
+        }]
+    );
+
+    let response = route(&router, date_at(service_date, 8, 15), downtown, university).await;
+    let itinerary = earliest_itinerary(response);
+    assert_eq!(
+        itinerary.end_time.unix_timestamp() as u32,
+        date_at(service_date, 9, 0).epoch_seconds()
+    );
+    let alert_ids: Vec<_> = itinerary
+        .legs
+        .iter()
+        .flat_map(|leg| match leg {
+            SolariLeg::Transit { alerts, .. } => alerts.clone(),
+            SolariLeg::Transfer { .. } => vec![],
+        })
+        .map(|alert| (alert.id, alert.effect))
+        .collect();
+    assert_eq!(
+        alert_ids,
+        vec![("1".to_string(), "ACCESSIBILITY_ISSUE".to_string())]
+    );
+
+    let response = route(
+        &router,
+        date_at(service_date.succ_opt().unwrap(), 8, 15),
+        downtown,
+        university,
+    )
+    .await;
+    assert!(response.itineraries.is_empty());
+}
+
+#[tokio::test]
+async fn applies_vehicle_positions() {
+    let router = realtime_router();
+    let service_date = NaiveDate::from_ymd_opt(2030, 1, 8).unwrap();
+    let downtown = LatLng::from_degrees(47.60, -122.33);
+    let university = LatLng::from_degrees(47.70, -122.33);
+
+    // At 08:25 on the 8th, R-0820 is between Downtown and Uptown and R-0800 is stopped at University. Another vehicle is on a trip that isn't in the timetable, and R-0840's has no position.
+    let message =
+        read_feed_message(&fixture_path("realtime").join("vehicle_positions.pb")).unwrap();
+    let report =
+        router
+            .timetable()
+            .update_vehicle_positions("vehicle-positions", "realtime", &message);
+    assert_eq!(report.vehicles, 3);
+    assert_eq!(report.vehicles_on_trips, 2);
+    assert!(matches!(
+        report.issues.as_slice(),
+        [
+            VehiclePositionIssue::UnmatchedTrip { vehicle_id, trip_id, .. },
+            VehiclePositionIssue::MissingPosition { vehicle_id: missing },
+        ] if vehicle_id == "bus-99" && trip_id == "NOPE" && missing == "bus-40"
+    ));
+
+    let mut on_route: Vec<_> = router
+        .timetable()
+        .vehicles_on_route("realtime", "R")
+        .into_iter()
+        .map(|vehicle| (vehicle.vehicle_id, vehicle.next_stop, vehicle.status))
+        .collect();
+    on_route.sort();
+    assert_eq!(
+        on_route,
+        vec![
+            (
+                "bus-00".to_string(),
+                Some("University".to_string()),
+                "STOPPED_AT".to_string()
+            ),
+            (
+                "bus-20".to_string(),
+                Some("Uptown".to_string()),
+                "IN_TRANSIT_TO".to_string()
+            ),
+        ]
+    );
+    let within: Vec<_> = router
+        .timetable()
+        .vehicles_within(47.85, -122.40, 47.95, -122.30)
+        .into_iter()
+        .map(|vehicle| (vehicle.vehicle_id, vehicle.trip_id))
+        .collect();
+    assert_eq!(
+        within,
+        vec![("bus-99".to_string(), Some("NOPE".to_string()))]
+    );
+
+    let response = route(&router, date_at(service_date, 8, 15), downtown, university).await;
+    let itinerary = earliest_itinerary(response);
+    let vehicles: Vec<_> = itinerary
+        .legs
+        .iter()
+        .filter_map(|leg| match leg {
+            SolariLeg::Transit { vehicle, .. } => vehicle.clone(),
+            SolariLeg::Transfer { .. } => None,
+        })
+        .map(|vehicle| vehicle.vehicle_id)
+        .collect();
+    assert_eq!(vehicles, vec!["bus-20".to_string()]);
+}
+
+/// A stand-in for a GTFS-RT server, answering every request with `response` and recording the requests' heads.
+async fn serve_feed(
+    response: Arc<Mutex<(u16, Vec<u8>)>>,
+    requests: Arc<Mutex<Vec<String>>>,
+) -> SocketAddr {
+    support::serve(move |head| {
+        requests.lock().unwrap().push(head.to_string());
+        let (status, body) = response.lock().unwrap().clone();
+        Reply::new(status, body)
+    })
+    .await
+}
+
+#[tokio::test]
+async fn polls_realtime_feeds() {
+    // The feed is dated a minute from now, so that it's never older than the zero maximum age below, whenever the test runs. Only failing to poll it makes its updates too old to keep.
+    let mut message = read_feed_message(&fixture_path("realtime").join("trip_updates.pb")).unwrap();
+    message.header.timestamp = Some(Utc::now().timestamp() as u64 + 60);
+    let response = Arc::new(Mutex::new((200, message.encode_to_vec())));
+    let requests = Arc::new(Mutex::new(vec![]));
+    let addr = serve_feed(response.clone(), requests.clone()).await;
+
+    // The static feed and its real-time feed are separate DMFR records, tied together by their operator.
+    let dmfr_dir = TempDir::new("dmfr").unwrap();
+    let dmfr_path = dmfr_dir.path().join("test.dmfr.json");
+    std::fs::write(
+        &dmfr_path,
+        serde_json::json!({
+            "feeds": [
+                {
+                    "id": "f-test",
+                    "spec": "gtfs",
+                    "urls": { "static_current": format!("http://{addr}/gtfs.zip") }
+                },
+                {
+                    "id": "f-test~rt",
+                    "spec": "gtfs-rt",
+                    "urls": { "realtime_trip_updates": format!("http://{addr}/trip_updates.pb") },
+                    "authorization": { "type": "header", "param_name": "x-api-key" }
+                }
+            ],
+            "operators": [
+                {
+                    "onestop_id": "o-test",
+                    "name": "Test Transit",
+                    "associated_feeds": [
+                        { "feed_onestop_id": "f-test" },
+                        { "feed_onestop_id": "f-test~rt" }
+                    ]
+                }
+            ]
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let secrets = FeedSecrets::new(vec![FeedSecret {
+        feed: "f-test~rt".to_string(),
+        key: Some("s3cret".to_string()),
+        username: None,
+        password: None,
+        replace_url: None,
+    }]);
+    let registries = read_registries(dmfr_dir.path()).unwrap();
+    let (_, dmfr) = &registries[0];
+    let (sources, errors) = realtime_sources(dmfr, &secrets);
+    assert!(errors.is_empty());
+    assert_eq!(sources.len(), 1);
+    assert_eq!(sources[0].feed_id, "f-test");
+    assert_eq!(sources[0].source_id, "f-test~rt trip updates");
+    assert_eq!(sources[0].kind, RealtimeKind::TripUpdates);
+
+    let router = realtime_router_for("realtime", "f-test");
+    let service_date = NaiveDate::from_ymd_opt(2030, 1, 8).unwrap();
+    let uptown = LatLng::from_degrees(47.65, -122.33);
+    let northgate = LatLng::from_degrees(47.75, -122.33);
+    let mut poller = RealtimePoller::new(
+        sources,
+        PollOptions {
+            max_age: Duration::ZERO,
+            ..Default::default()
+        },
+    );
+
+    let outcomes = poller.poll(router.timetable()).await;
+    assert!(matches!(outcomes.as_slice(), [PollOutcome::Applied]));
+    assert!(requests.lock().unwrap()[0]
+        .to_lowercase()
+        .contains("x-api-key: s3cret"));
+    // R-0800 is 10 minutes late.
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), uptown, northgate).await,
+        date_at(service_date, 8, 40).epoch_seconds()
+    );
+
+    // Once the feed goes down, its updates are dropped when they're older than the maximum age.
+    *response.lock().unwrap() = (503, vec![]);
+    let outcomes = poller.poll(router.timetable()).await;
+    assert!(matches!(
+        outcomes.as_slice(),
+        [PollOutcome::Expired(PollError::Status { .. })]
+    ));
+    assert_eq!(
+        earliest_arrival(&router, date_at(service_date, 8, 15), uptown, northgate).await,
+        date_at(service_date, 8, 50).epoch_seconds()
+    );
+    let outcomes = poller.poll(router.timetable()).await;
+    assert!(matches!(
+        outcomes.as_slice(),
+        [PollOutcome::Failed(PollError::Status { .. })]
+    ));
+}
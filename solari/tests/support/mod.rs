@@ -0,0 +1,63 @@
+//! A stand-in HTTP server for tests. It's shared by the library's unit tests, which include it from `lib.rs`, and the integration tests, which declare it as a module.
+
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// What the server answers a request with.
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn new(status: u16, body: Vec<u8>) -> Reply {
+        Reply {
+            status,
+            headers: vec![],
+            body,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Reply {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Serve HTTP on a local port until the test's runtime shuts down, answering each request with `respond`. It's given the head of the request: its request line and headers, as sent.
+pub async fn serve(mut respond: impl FnMut(&str) -> Reply + Send + 'static) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut head = vec![];
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => head.extend_from_slice(&buf[..read]),
+                }
+            }
+            let reply = respond(&String::from_utf8_lossy(&head));
+            let mut bytes = format!("HTTP/1.1 {} Status\r\n", reply.status);
+            for (name, value) in &reply.headers {
+                bytes.push_str(&format!("{name}: {value}\r\n"));
+            }
+            bytes.push_str(&format!(
+                "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                reply.body.len()
+            ));
+            let mut bytes = bytes.into_bytes();
+            bytes.extend(reply.body);
+            let _ = stream.write_all(&bytes).await;
+        }
+    });
+    addr
+}